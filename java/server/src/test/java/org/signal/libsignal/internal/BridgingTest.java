@@ -36,6 +36,13 @@ public class BridgingTest {
     assertTrue(e.getCause().toString(), e.getCause() instanceof AssertionError);
   }
 
+  @Test
+  public void testCleanupOrder() throws Exception {
+    Native.TESTING_TakeCleanupOrder();
+    Native.TESTING_CleanupOrder(null, null, null);
+    assertArrayEquals(new byte[] {2, 1, 0}, Native.TESTING_TakeCleanupOrder());
+  }
+
   @Test
   public void testPanicInBody() throws Exception {
     assertThrows(AssertionError.class, () -> Native.TESTING_PanicInBodySync(null));
@@ -154,6 +154,10 @@ public final class Native {
   public static native byte[] CallLinkSecretParams_DeriveFromRootKey(byte[] rootKey);
   public static native byte[] CallLinkSecretParams_GetPublicParams(byte[] paramsBytes);
 
+  public static native void CancellationHandle_Cancel(long handle);
+  public static native void CancellationHandle_Destroy(long handle);
+  public static native long CancellationHandle_New();
+
   public static native long Cds2ClientState_New(byte[] mrenclave, byte[] attestationMsg, long currentTimestamp);
 
   public static native Map Cds2Metrics_extract(byte[] attestationMsg);
@@ -497,6 +501,7 @@ public final class Native {
   public static native long SessionRecord_Deserialize(byte[] data);
   public static native void SessionRecord_Destroy(long handle);
   public static native byte[] SessionRecord_GetAliceBaseKey(long obj);
+  public static native byte[] SessionRecord_GetCurrentRatchetPublicKey(long obj);
   public static native byte[] SessionRecord_GetLocalIdentityKeyPublic(long obj);
   public static native int SessionRecord_GetLocalRegistrationId(long obj);
   public static native byte[] SessionRecord_GetReceiverChainKeyValue(long sessionState, long key);
@@ -540,6 +545,7 @@ public final class Native {
 
   public static native long Svr2Client_New(byte[] mrenclave, byte[] attestationMsg, long currentTimestamp);
 
+  public static native void TESTING_CleanupOrder(Object a, Object b, Object c);
   public static native void TESTING_ErrorOnBorrowAsync(Object input);
   public static native Future TESTING_ErrorOnBorrowIo(long asyncRuntime, Object input);
   public static native void TESTING_ErrorOnBorrowSync(Object input);
@@ -561,6 +567,7 @@ public final class Native {
   public static native Object TESTING_PanicOnReturnAsync(Object needsCleanup);
   public static native Future<Object> TESTING_PanicOnReturnIo(long asyncRuntime, Object needsCleanup);
   public static native Object TESTING_PanicOnReturnSync(Object needsCleanup);
+  public static native byte[] TESTING_TakeCleanupOrder();
 
   public static native long UnidentifiedSenderMessageContent_Deserialize(byte[] data);
   public static native void UnidentifiedSenderMessageContent_Destroy(long handle);
@@ -94,6 +94,7 @@ export function BackupAuthCredentialResponse_CheckValidContents(responseBytes: B
 export function BackupAuthCredential_CheckValidContents(paramsBytes: Buffer): void;
 export function BackupAuthCredential_GetBackupId(credentialBytes: Buffer): Buffer;
 export function BackupAuthCredential_PresentDeterministic(credentialBytes: Buffer, serverParamsBytes: Buffer, randomness: Buffer): Buffer;
+export function BridgeRuntime_new(kind: number): BridgeRuntime;
 export function CallLinkAuthCredentialPresentation_CheckValidContents(presentationBytes: Buffer): void;
 export function CallLinkAuthCredentialPresentation_GetUserId(presentationBytes: Buffer): Serialized<UuidCiphertext>;
 export function CallLinkAuthCredentialPresentation_Verify(presentationBytes: Buffer, now: Timestamp, serverParamsBytes: Buffer, callLinkParamsBytes: Buffer): void;
@@ -107,8 +108,19 @@ export function CallLinkSecretParams_CheckValidContents(paramsBytes: Buffer): vo
 export function CallLinkSecretParams_DecryptUserId(paramsBytes: Buffer, userId: Serialized<UuidCiphertext>): Buffer;
 export function CallLinkSecretParams_DeriveFromRootKey(rootKey: Buffer): Buffer;
 export function CallLinkSecretParams_GetPublicParams(paramsBytes: Buffer): Buffer;
+export function CancellationHandle_Cancel(handle: Wrapper<CancellationHandle>): void;
+export function CancellationHandle_New(): CancellationHandle;
 export function Cds2ClientState_New(mrenclave: Buffer, attestationMsg: Buffer, currentTimestamp: Timestamp): SgxClientState;
 export function CdsiLookup(asyncRuntime: Wrapper<TokioAsyncContext>, connectionManager: Wrapper<ConnectionManager>, username: string, password: string, request: Wrapper<LookupRequest>, timeoutMillis: number): Promise<LookupResponse>;
+export function ChatResponse_GetServerTime(response: Buffer): Timestamp | null;
+export function ChatService_ConnectAndSend(asyncRuntime: Wrapper<TokioAsyncContext>, environment: number, message: Buffer, timeoutMillis: number, cancellation: Wrapper<CancellationHandle>): Promise<Buffer>;
+export function ChatService_GetAlpn(chat: Wrapper<ChatService>): string | null;
+export function ChatService_GetPeerSpki(chat: Wrapper<ChatService>): string | null;
+export function ChatService_GetTlsVersion(chat: Wrapper<ChatService>): string | null;
+export function ChatService_SendBatch(chat: Wrapper<ChatService>, messages: Buffer[], timeoutMillis: number, callback: (error: Error | null, results?: (Error | { status: number, headers: string[], body: Buffer | null })[]) => void): void;
+export function ChatService_SendWithCallback(chat: Wrapper<ChatService>, message: Buffer, timeoutMillis: number, callback: (error: Error | null, status?: number, headers?: string[], body?: Buffer | null) => void): void;
+export function ChatService_SetCloseListener(chat: Wrapper<ChatService>, callback: (reason: { kind: 'local' | 'remote' | 'error', detail: string | null }) => void): void;
+export function ChatService_new(asyncRuntime: Wrapper<TokioAsyncContext>, environment: number): ChatService;
 export function CiphertextMessage_FromPlaintextContent(m: Wrapper<PlaintextContent>): CiphertextMessage;
 export function CiphertextMessage_Serialize(obj: Wrapper<CiphertextMessage>): Buffer;
 export function CiphertextMessage_Type(msg: Wrapper<CiphertextMessage>): number;
@@ -348,6 +360,7 @@ export function SessionCipher_EncryptMessage(ptext: Buffer, protocolAddress: Wra
 export function SessionRecord_ArchiveCurrentState(sessionRecord: Wrapper<SessionRecord>): void;
 export function SessionRecord_CurrentRatchetKeyMatches(s: Wrapper<SessionRecord>, key: Wrapper<PublicKey>): boolean;
 export function SessionRecord_Deserialize(data: Buffer): SessionRecord;
+export function SessionRecord_GetCurrentRatchetPublicKey(obj: Wrapper<SessionRecord>): Buffer;
 export function SessionRecord_GetLocalRegistrationId(obj: Wrapper<SessionRecord>): number;
 export function SessionRecord_GetRemoteRegistrationId(obj: Wrapper<SessionRecord>): number;
 export function SessionRecord_HasUsableSenderChain(s: Wrapper<SessionRecord>, now: Timestamp): boolean;
@@ -372,7 +385,13 @@ export function SignedPreKeyRecord_GetSignature(obj: Wrapper<SignedPreKeyRecord>
 export function SignedPreKeyRecord_GetTimestamp(obj: Wrapper<SignedPreKeyRecord>): Timestamp;
 export function SignedPreKeyRecord_New(id: number, timestamp: Timestamp, pubKey: Wrapper<PublicKey>, privKey: Wrapper<PrivateKey>, signature: Buffer): SignedPreKeyRecord;
 export function SignedPreKeyRecord_Serialize(obj: Wrapper<SignedPreKeyRecord>): Buffer;
+export function TESTING_BridgeRuntime_FutureSuccess(asyncRuntime: Wrapper<BridgeRuntime>, input: number): Promise<number>;
 export function TESTING_CdsiLookupResponseConvert(): LookupResponse;
+export function TESTING_ChatNetworkErrorConvert(code: number): void;
+export function TESTING_ChatService_CloseInMemoryConnection(asyncRuntime: Wrapper<TokioAsyncContext>, chat: Wrapper<ChatService>): Promise<void>;
+export function TESTING_ChatService_NewInMemory(asyncRuntime: Wrapper<TokioAsyncContext>): ChatService;
+export function TESTING_ChatService_SimulateClose(asyncRuntime: Wrapper<TokioAsyncContext>, callback: (reason: { kind: 'local' | 'remote' | 'error', detail: string | null }) => void): void;
+export function TESTING_CleanupOrder(_a: null, _b: null, _c: null): void;
 export function TESTING_ErrorOnBorrowAsync(_input: null): Promise<void>;
 export function TESTING_ErrorOnBorrowIo(asyncRuntime: Wrapper<NonSuspendingBackgroundThreadRuntime>, _input: null): Promise<void>;
 export function TESTING_ErrorOnBorrowSync(_input: null): void;
@@ -381,6 +400,9 @@ export function TESTING_ErrorOnReturnIo(asyncRuntime: Wrapper<NonSuspendingBackg
 export function TESTING_ErrorOnReturnSync(_needsCleanup: null): null;
 export function TESTING_FutureFailure(asyncRuntime: Wrapper<NonSuspendingBackgroundThreadRuntime>, _input: number): Promise<number>;
 export function TESTING_FutureSuccess(asyncRuntime: Wrapper<NonSuspendingBackgroundThreadRuntime>, input: number): Promise<number>;
+export function TESTING_FutureTimeout(asyncRuntime: Wrapper<TokioAsyncContext>, delayMs: number, timeoutMs: number): Promise<number>;
+export function TESTING_ImmediateInlineRuntime_FutureSuccess(asyncRuntime: Wrapper<ImmediateInlineRuntime>, input: number): Promise<number>;
+export function TESTING_ImmediateInlineRuntime_New(): ImmediateInlineRuntime;
 export function TESTING_NonSuspendingBackgroundThreadRuntime_New(): NonSuspendingBackgroundThreadRuntime;
 export function TESTING_PanicInBodyAsync(_input: null): Promise<void>;
 export function TESTING_PanicInBodyIo(asyncRuntime: Wrapper<NonSuspendingBackgroundThreadRuntime>, _input: null): Promise<void>;
@@ -394,6 +416,7 @@ export function TESTING_PanicOnLoadSync(_needsCleanup: null, _input: null): void
 export function TESTING_PanicOnReturnAsync(_needsCleanup: null): Promise<null>;
 export function TESTING_PanicOnReturnIo(asyncRuntime: Wrapper<NonSuspendingBackgroundThreadRuntime>, _needsCleanup: null): Promise<null>;
 export function TESTING_PanicOnReturnSync(_needsCleanup: null): null;
+export function TESTING_TakeCleanupOrder(): Buffer;
 export function TokioAsyncContext_new(): TokioAsyncContext;
 export function UnidentifiedSenderMessageContent_Deserialize(data: Buffer): UnidentifiedSenderMessageContent;
 export function UnidentifiedSenderMessageContent_GetContentHint(m: Wrapper<UnidentifiedSenderMessageContent>): number;
@@ -420,6 +443,9 @@ interface AuthCredential { readonly __type: unique symbol; }
 interface AuthCredentialResponse { readonly __type: unique symbol; }
 interface AuthCredentialWithPni { readonly __type: unique symbol; }
 interface AuthCredentialWithPniResponse { readonly __type: unique symbol; }
+interface BridgeRuntime { readonly __type: unique symbol; }
+interface CancellationHandle { readonly __type: unique symbol; }
+interface ChatService { readonly __type: unique symbol; }
 interface CiphertextMessage { readonly __type: unique symbol; }
 interface ConnectionManager { readonly __type: unique symbol; }
 interface DecryptionErrorMessage { readonly __type: unique symbol; }
@@ -430,6 +456,7 @@ interface GroupMasterKey { readonly __type: unique symbol; }
 interface GroupPublicParams { readonly __type: unique symbol; }
 interface GroupSecretParams { readonly __type: unique symbol; }
 interface HsmEnclaveClient { readonly __type: unique symbol; }
+interface ImmediateInlineRuntime { readonly __type: unique symbol; }
 interface IncrementalMac { readonly __type: unique symbol; }
 interface KyberKeyPair { readonly __type: unique symbol; }
 interface KyberPreKeyRecord { readonly __type: unique symbol; }
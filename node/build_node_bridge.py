@@ -66,6 +66,9 @@ def main(args=None):
     cmdline = ['cargo', 'build', '--target', cargo_target, '-p', 'libsignal-node']
     if configuration_name == 'Release':
         cmdline.append('--release')
+    # Only enabled for local and CI test runs, so that release builds don't ship them.
+    if 'LIBSIGNAL_TESTING_FNS' in os.environ:
+        cmdline += ['--features', 'testing-fns']
     print("Running '%s'" % (' '.join(cmdline)))
 
     cargo_env = os.environ.copy()
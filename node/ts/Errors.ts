@@ -34,6 +34,11 @@ export enum ErrorCode {
   InvalidUsernameLinkEncryptedData,
 
   RateLimitedError,
+
+  ChatConnectionFailed,
+  ChatRequestFailed,
+  Cancelled,
+  ChatInvalidRequest,
 }
 
 export class LibSignalErrorBase extends Error {
@@ -155,6 +160,8 @@ export type InvalidUsernameLinkEncryptedData = LibSignalErrorCommon & {
 
 export type IoError = LibSignalErrorCommon & {
   code: ErrorCode.IoError;
+  // Set for errors of the chat service, identifies the specific failure.
+  readonly chatErrorCode?: number;
 };
 
 export type InvalidMediaInputError = LibSignalErrorCommon & {
@@ -170,6 +177,22 @@ export type RateLimitedError = LibSignalErrorBase & {
   readonly retryAfterSecs: number;
 };
 
+export type ChatConnectionFailedError = LibSignalErrorCommon & {
+  code: ErrorCode.ChatConnectionFailed;
+};
+
+export type ChatRequestFailedError = LibSignalErrorCommon & {
+  code: ErrorCode.ChatRequestFailed;
+};
+
+export type CancelledError = LibSignalErrorCommon & {
+  code: ErrorCode.Cancelled;
+};
+
+export type ChatInvalidRequestError = LibSignalErrorCommon & {
+  code: ErrorCode.ChatInvalidRequest;
+};
+
 export type LibSignalError =
   | GenericError
   | DuplicatedMessageError
@@ -190,4 +213,8 @@ export type LibSignalError =
   | InvalidUsernameLinkEncryptedData
   | IoError
   | InvalidMediaInputError
-  | UnsupportedMediaInputError;
+  | UnsupportedMediaInputError
+  | ChatConnectionFailedError
+  | ChatRequestFailedError
+  | CancelledError
+  | ChatInvalidRequestError;
@@ -53,6 +53,12 @@ describe('bridge_fn', () => {
     );
   });
 
+  it('cleans up arguments in reverse order', () => {
+    Native.TESTING_TakeCleanupOrder();
+    Native.TESTING_CleanupOrder(null, null, null);
+    assert.deepEqual(Native.TESTING_TakeCleanupOrder(), Buffer.of(2, 1, 0));
+  });
+
   it('handles panics in the function body', async () => {
     assert.throws(() => Native.TESTING_PanicInBodySync(null), Error);
 
@@ -35,3 +35,63 @@ describe('Async runtime not on the Node executor', () => {
     }
   });
 });
+
+describe('Async runtime running futures inline', () => {
+  it('handles success', async () => {
+    const runtime = {
+      _nativeHandle: Native.TESTING_ImmediateInlineRuntime_New(),
+    };
+    assert.equal(
+      await Native.TESTING_ImmediateInlineRuntime_FutureSuccess(runtime, 21),
+      42
+    );
+  });
+});
+
+describe('Async runtime backed by tokio', () => {
+  function makeTokioRuntime(): Native.Wrapper<Native.TokioAsyncContext> {
+    return { _nativeHandle: Native.TokioAsyncContext_new() };
+  }
+
+  it('completes before the timeout', async () => {
+    const runtime = makeTokioRuntime();
+    assert.equal(await Native.TESTING_FutureTimeout(runtime, 10, 1000), 10);
+  });
+
+  it('fails after the timeout', async () => {
+    const runtime = makeTokioRuntime();
+    try {
+      await Native.TESTING_FutureTimeout(runtime, 1000, 10);
+      assert.fail('should have thrown an error');
+    } catch (e) {
+      assert.instanceOf(e, LibSignalErrorBase);
+      const err = e as LibSignalError;
+      assert.equal(err.code, ErrorCode.IoError);
+    }
+  });
+});
+
+describe('Async runtime picked at run time', () => {
+  const kinds = { multiThread: 0, currentThread: 1, testing: 2 };
+
+  for (const [name, kind] of Object.entries(kinds)) {
+    it(`runs futures on the ${name} runtime`, async () => {
+      const runtime = { _nativeHandle: Native.BridgeRuntime_new(kind) };
+      assert.equal(
+        await Native.TESTING_BridgeRuntime_FutureSuccess(runtime, 21),
+        42
+      );
+    });
+  }
+
+  it('rejects unknown kinds', () => {
+    try {
+      Native.BridgeRuntime_new(3);
+      assert.fail('should have thrown an error');
+    } catch (e) {
+      assert.instanceOf(e, LibSignalErrorBase);
+      const err = e as LibSignalError;
+      assert.equal(err.code, ErrorCode.Generic);
+    }
+  });
+});
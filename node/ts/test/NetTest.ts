@@ -3,11 +3,14 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
-import { config, expect } from 'chai';
+import { config, expect, use } from 'chai';
+import * as chaiAsPromised from 'chai-as-promised';
 import * as util from './util';
 import { Aci, Pni } from '../Address';
 import * as Native from '../../Native';
+import { ErrorCode, LibSignalErrorBase } from '../Errors';
 
+use(chaiAsPromised);
 util.initLogger();
 config.truncateThreshold = 0;
 
@@ -34,3 +37,191 @@ describe('cdsi lookup', () => {
     });
   });
 });
+
+describe('chat service errors', () => {
+  // Keep in sync with ChatNetworkErrorCode in rust/bridge/shared/src/net.rs.
+  const firstCode = 1;
+  const lastCode = 34;
+  const channelClosedWithErrorCode = 22;
+
+  it('are converted with their code', () => {
+    for (let code = firstCode; code <= lastCode; code++) {
+      if (code === channelClosedWithErrorCode) {
+        // can't be produced by the testing function
+        continue;
+      }
+      expect(() => Native.TESTING_ChatNetworkErrorConvert(code))
+        .throws(LibSignalErrorBase)
+        .with.property('chatErrorCode', code);
+    }
+  });
+});
+
+describe('chat service connect and send', () => {
+  it('rejects invalid requests without connecting', async () => {
+    const runtime = { _nativeHandle: Native.TokioAsyncContext_new() };
+    const cancellation = { _nativeHandle: Native.CancellationHandle_New() };
+    // Not a valid WebSocketMessage.
+    const message = Buffer.from([0xff]);
+    const staging = 0;
+    await expect(
+      Native.ChatService_ConnectAndSend(
+        runtime,
+        staging,
+        message,
+        1000,
+        cancellation
+      )
+    )
+      .to.be.rejectedWith(LibSignalErrorBase)
+      .and.eventually.have.property('code', ErrorCode.ChatInvalidRequest);
+  });
+});
+
+type CloseReason = {
+  kind: 'local' | 'remote' | 'error';
+  detail: string | null;
+};
+
+describe('chat service close listener', () => {
+  it('is called with the close reason', async () => {
+    const runtime = { _nativeHandle: Native.TokioAsyncContext_new() };
+    const reasons = await new Promise<CloseReason[]>((resolve) => {
+      const received: CloseReason[] = [];
+      Native.TESTING_ChatService_SimulateClose(runtime, (reason) => {
+        received.push(reason);
+        if (received.length === 2) {
+          resolve(received);
+        }
+      });
+    });
+    expect(reasons).deep.equals([
+      { kind: 'remote', detail: null },
+      { kind: 'error', detail: 'simulated failure' },
+    ]);
+  });
+});
+
+describe('chat service connection', () => {
+  type Chat = Parameters<typeof Native.ChatService_SendWithCallback>[0];
+  type Response = { status: number; body: Buffer | null };
+
+  // A WebSocketMessage carrying a `PUT /v1/echo` request with `hello` as the body.
+  const request = Buffer.concat([
+    Buffer.from([0x0a, 0x03]),
+    Buffer.from('PUT'),
+    Buffer.from([0x12, 0x08]),
+    Buffer.from('/v1/echo'),
+    Buffer.from([0x1a, 0x05]),
+    Buffer.from('hello'),
+  ]);
+  const message = Buffer.concat([
+    Buffer.from([0x08, 0x01, 0x12, request.length]),
+    request,
+  ]);
+
+  function send(chat: Chat): Promise<Response> {
+    return new Promise((resolve, reject) => {
+      Native.ChatService_SendWithCallback(
+        chat,
+        message,
+        5000,
+        (error, status, _headers, body) => {
+          if (error) {
+            reject(error);
+          } else {
+            resolve({ status: status ?? 0, body: body ?? null });
+          }
+        }
+      );
+    });
+  }
+
+  function sendBatch(chat: Chat, count: number): Promise<Response[]> {
+    return new Promise((resolve, reject) => {
+      Native.ChatService_SendBatch(
+        chat,
+        new Array<Buffer>(count).fill(message),
+        5000,
+        (error, results) => {
+          if (error) {
+            reject(error);
+            return;
+          }
+          const responses: Response[] = [];
+          for (const result of results ?? []) {
+            if (result instanceof Error) {
+              reject(result);
+              return;
+            }
+            responses.push({ status: result.status, body: result.body });
+          }
+          resolve(responses);
+        }
+      );
+    });
+  }
+
+  const runtime = { _nativeHandle: Native.TokioAsyncContext_new() };
+  const echoed = { status: 200, body: Buffer.from('hello') };
+
+  before(function () {
+    // Only available when built with LIBSIGNAL_TESTING_FNS, see build_node_bridge.py.
+    if (typeof Native.TESTING_ChatService_NewInMemory !== 'function') {
+      this.skip();
+    }
+  });
+
+  it('reconnects for requests sent after the server closed the connection', async () => {
+    const chat = {
+      _nativeHandle: Native.TESTING_ChatService_NewInMemory(runtime),
+    };
+    expect(await send(chat)).deep.equals(echoed);
+
+    await Native.TESTING_ChatService_CloseInMemoryConnection(runtime, chat);
+    expect(await send(chat)).deep.equals(echoed);
+  });
+
+  it('reconnects for batches sent after the server closed the connection', async () => {
+    const chat = {
+      _nativeHandle: Native.TESTING_ChatService_NewInMemory(runtime),
+    };
+    expect(await sendBatch(chat, 2)).deep.equals([echoed, echoed]);
+
+    await Native.TESTING_ChatService_CloseInMemoryConnection(runtime, chat);
+    expect(await sendBatch(chat, 2)).deep.equals([echoed, echoed]);
+  });
+
+  it('reports the negotiated parameters only while connected', async () => {
+    const chat = {
+      _nativeHandle: Native.TESTING_ChatService_NewInMemory(runtime),
+    };
+    expect(Native.ChatService_GetAlpn(chat)).is.null;
+    expect(Native.ChatService_GetTlsVersion(chat)).is.null;
+
+    await send(chat);
+    expect(Native.ChatService_GetAlpn(chat)).equals('h2');
+    expect(Native.ChatService_GetTlsVersion(chat)).equals('TLSv1.3');
+    expect(Native.ChatService_GetPeerSpki(chat)).is.null;
+
+    await Native.TESTING_ChatService_CloseInMemoryConnection(runtime, chat);
+    expect(Native.ChatService_GetAlpn(chat)).is.null;
+    expect(Native.ChatService_GetTlsVersion(chat)).is.null;
+
+    await send(chat);
+    expect(Native.ChatService_GetAlpn(chat)).equals('h2');
+  });
+
+  it('reports to the close listener when the server closed the connection', async () => {
+    const chat = {
+      _nativeHandle: Native.TESTING_ChatService_NewInMemory(runtime),
+    };
+    const reason = new Promise<CloseReason>((resolve) => {
+      Native.ChatService_SetCloseListener(chat, resolve);
+    });
+    expect(await send(chat)).deep.equals(echoed);
+
+    await Native.TESTING_ChatService_CloseInMemoryConnection(runtime, chat);
+    expect(await reason).deep.equals({ kind: 'remote', detail: null });
+  });
+});
@@ -0,0 +1,104 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::future::Future;
+
+use futures_util::future::Either;
+use libsignal_bridge_macros::*;
+use tokio_util::sync::CancellationToken;
+
+use crate::support::*;
+use crate::*;
+
+/// Lets the app abort an in-flight `bridge_io` operation that takes this handle as an argument.
+///
+/// The operation decides what cancellation produces, which is usually an error that the bridge
+/// reports to the app like any other. Cancelling after the operation has completed has no effect.
+#[derive(Default)]
+pub struct CancellationHandle(CancellationToken);
+
+impl CancellationHandle {
+    /// Runs `future` to completion, unless the handle is cancelled first.
+    ///
+    /// In the latter case `future` is dropped and the result is produced by `on_cancel`.
+    pub async fn run<T>(
+        &self,
+        future: impl Future<Output = T>,
+        on_cancel: impl FnOnce() -> T,
+    ) -> T {
+        let cancelled = std::pin::pin!(self.0.cancelled());
+        let future = std::pin::pin!(future);
+        match futures_util::future::select(cancelled, future).await {
+            Either::Left(((), _)) => on_cancel(),
+            Either::Right((result, _)) => result,
+        }
+    }
+
+    /// Returns a token that is cancelled together with this handle, for operations that want to
+    /// clean up after themselves instead of being dropped.
+    pub fn token(&self) -> CancellationToken {
+        self.0.clone()
+    }
+}
+
+bridge_handle!(CancellationHandle, clone = false);
+
+#[bridge_fn]
+fn CancellationHandle_New() -> CancellationHandle {
+    CancellationHandle::default()
+}
+
+#[bridge_fn]
+fn CancellationHandle_Cancel(handle: &CancellationHandle) {
+    handle.0.cancel()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::task::{Context, Poll};
+
+    use futures_util::FutureExt as _;
+
+    use super::*;
+
+    #[test]
+    fn run_without_cancel() {
+        let handle = CancellationHandle_New();
+        let result = handle.run(async { "done" }, || "cancelled");
+        assert_eq!(result.now_or_never(), Some("done"));
+    }
+
+    #[test]
+    fn cancel_in_flight() {
+        let handle = CancellationHandle_New();
+        let dropped = AtomicBool::new(false);
+        let guard = scopeguard::guard((), |()| dropped.store(true, Ordering::SeqCst));
+        let mut result = std::pin::pin!(handle.run(
+            async move {
+                let _guard = guard;
+                std::future::pending::<&str>().await
+            },
+            || "cancelled",
+        ));
+
+        let mut cx = Context::from_waker(futures_util::task::noop_waker_ref());
+        assert_eq!(result.as_mut().poll(&mut cx), Poll::Pending);
+        assert!(!dropped.load(Ordering::SeqCst));
+
+        CancellationHandle_Cancel(&handle);
+        assert!(handle.token().is_cancelled());
+        assert_eq!(result.as_mut().poll(&mut cx), Poll::Ready("cancelled"));
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn cancel_before_run() {
+        let handle = CancellationHandle_New();
+        CancellationHandle_Cancel(&handle);
+        let result = handle.run(std::future::pending(), || "cancelled");
+        assert_eq!(result.now_or_never(), Some("cancelled"));
+    }
+}
@@ -52,6 +52,7 @@ mod pin;
 #[cfg(any(feature = "jni", feature = "ffi"))]
 mod svr2;
 
+pub mod cancellation;
 pub mod incremental_mac;
 pub mod usernames;
 
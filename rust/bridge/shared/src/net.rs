@@ -4,19 +4,37 @@
 //
 
 use std::convert::TryInto as _;
+use std::fmt::Write as _;
 use std::future::Future;
 use std::num::ParseIntError;
+use std::sync::Arc;
 use std::time::Duration;
 
 use libsignal_bridge_macros::{bridge_fn, bridge_io};
 use libsignal_net::cdsi::{self, AciAndAccessKey, LookupResponse};
+use libsignal_net::chat::errors::{ChatNetworkError, ConnectAndSendError};
+use libsignal_net::chat::http::{ChatOverHttp2, ChatOverHttp2ServiceConnector};
+use libsignal_net::chat::{self, ChatService as _, MessageProto, ResponseProto};
 use libsignal_net::env::{CdsiEndpointConnection, Env};
 use libsignal_net::infra::certs::RootCertificates;
 use libsignal_net::infra::connection_manager::MultiRouteConnectionManager;
 use libsignal_net::infra::dns::DnsResolver;
-use libsignal_net::infra::{ConnectionParams, HttpRequestDecorator, HttpRequestDecoratorSeq};
+#[cfg(feature = "testing-fns")]
+use libsignal_net::infra::in_memory;
+use libsignal_net::infra::{
+    AddressFamily, CloseReason, ConnectionEvent, ConnectionInfo, ConnectionParams,
+    HttpRequestDecorator, HttpRequestDecoratorSeq, TcpSocketOptions, TlsVersion,
+    DEFAULT_HTTP2_KEEPALIVE_JITTER_PERCENT, DEFAULT_USER_AGENT,
+};
 use libsignal_protocol::{Aci, SignalProtocolError};
+use prost::Message as _;
+use tokio::sync::broadcast;
+#[cfg(feature = "testing-fns")]
+use tokio_util::sync::CancellationToken;
 
+use crate::cancellation::CancellationHandle;
+use crate::node::TypedArray as _;
+use crate::protocol::Timestamp;
 use crate::support::*;
 use crate::*;
 
@@ -36,6 +54,98 @@ impl<F: Future<Output = ()> + Send + 'static> AsyncRuntime<F> for TokioAsyncCont
 
 bridge_handle!(TokioAsyncContext, clone = false);
 
+/// Runs futures on a tokio current-thread runtime driven by a dedicated background thread.
+///
+/// The background thread exits, dropping any unfinished futures, when this is dropped.
+pub struct CurrentThreadAsyncContext {
+    handle: tokio::runtime::Handle,
+    _shutdown: tokio::sync::oneshot::Sender<()>,
+}
+
+impl CurrentThreadAsyncContext {
+    fn new() -> Self {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("failed to create runtime");
+        let handle = runtime.handle().clone();
+        let (shutdown, shutdown_rx) = tokio::sync::oneshot::channel();
+        std::thread::spawn(move || {
+            // Spawned futures only make progress while the runtime is blocked on something.
+            let _ = runtime.block_on(shutdown_rx);
+        });
+        Self {
+            handle,
+            _shutdown: shutdown,
+        }
+    }
+}
+
+impl<F: Future<Output = ()> + Send + 'static> AsyncRuntime<F> for CurrentThreadAsyncContext {
+    fn run_future(&self, future: F) {
+        #[allow(clippy::let_underscore_future)]
+        let _: tokio::task::JoinHandle<()> = self.handle.spawn(future);
+    }
+}
+
+#[derive(num_enum::TryFromPrimitive)]
+#[repr(u8)]
+pub enum BridgeRuntimeKind {
+    MultiThread = 0,
+    CurrentThread = 1,
+    Testing = 2,
+}
+
+/// An [`AsyncRuntime`] whose backend is picked when it is created rather than at compile time.
+pub enum BridgeRuntime {
+    MultiThread(TokioAsyncContext),
+    CurrentThread(CurrentThreadAsyncContext),
+    #[cfg(not(any(target_os = "android", ios_device_as_detected_in_build_rs)))]
+    Testing(crate::testing::NonSuspendingBackgroundThreadRuntime),
+}
+
+impl BridgeRuntime {
+    fn new(kind: BridgeRuntimeKind) -> Result<Self, SignalProtocolError> {
+        match kind {
+            BridgeRuntimeKind::MultiThread => Ok(Self::MultiThread(TokioAsyncContext(
+                tokio::runtime::Runtime::new().expect("failed to create runtime"),
+            ))),
+            BridgeRuntimeKind::CurrentThread => {
+                Ok(Self::CurrentThread(CurrentThreadAsyncContext::new()))
+            }
+            #[cfg(not(any(target_os = "android", ios_device_as_detected_in_build_rs)))]
+            BridgeRuntimeKind::Testing => Ok(Self::Testing(
+                crate::testing::NonSuspendingBackgroundThreadRuntime,
+            )),
+            #[cfg(any(target_os = "android", ios_device_as_detected_in_build_rs))]
+            BridgeRuntimeKind::Testing => Err(SignalProtocolError::InvalidArgument(
+                "testing runtime is not available on this platform".to_string(),
+            )),
+        }
+    }
+}
+
+impl<F: Future<Output = ()> + Send + 'static> AsyncRuntime<F> for BridgeRuntime {
+    fn run_future(&self, future: F) {
+        match self {
+            Self::MultiThread(runtime) => runtime.run_future(future),
+            Self::CurrentThread(runtime) => runtime.run_future(future),
+            #[cfg(not(any(target_os = "android", ios_device_as_detected_in_build_rs)))]
+            Self::Testing(runtime) => runtime.run_future(future),
+        }
+    }
+}
+
+#[bridge_fn(ffi = false)]
+fn BridgeRuntime_new(kind: u8) -> Result<BridgeRuntime, SignalProtocolError> {
+    let kind = BridgeRuntimeKind::try_from(kind).map_err(|_| {
+        SignalProtocolError::InvalidArgument(format!("{kind} is not a runtime kind"))
+    })?;
+    BridgeRuntime::new(kind)
+}
+
+bridge_handle!(BridgeRuntime, clone = false);
+
 #[derive(num_enum::TryFromPrimitive)]
 #[repr(u8)]
 pub enum Environment {
@@ -61,6 +171,20 @@ impl Environment {
                     http_request_decorator: HttpRequestDecorator::PathPrefix("/service").into(),
                     certs: RootCertificates::Native,
                     dns_resolver: DnsResolver::System,
+                    pinned_spki: vec![],
+                    proxy: None,
+                    http2_keepalive_interval: None,
+                    http2_keepalive_timeout: None,
+                    http2_keepalive_jitter_percent: DEFAULT_HTTP2_KEEPALIVE_JITTER_PERCENT,
+                    max_concurrent_streams: None,
+                    auth: None,
+                    user_agent: DEFAULT_USER_AGENT.into(),
+                    tcp_options: TcpSocketOptions::default(),
+                    address_family: AddressFamily::default(),
+                    bind_address: None,
+                    client_identity: None,
+                    min_tls_version: TlsVersion::default(),
+                    max_tls_version: None,
                 },
                 ConnectionParams {
                     sni: "pintrest.com".into(),
@@ -69,6 +193,20 @@ impl Environment {
                     http_request_decorator: HttpRequestDecoratorSeq::default(),
                     certs: RootCertificates::Native,
                     dns_resolver: DnsResolver::System,
+                    pinned_spki: vec![],
+                    proxy: None,
+                    http2_keepalive_interval: None,
+                    http2_keepalive_timeout: None,
+                    http2_keepalive_jitter_percent: DEFAULT_HTTP2_KEEPALIVE_JITTER_PERCENT,
+                    max_concurrent_streams: None,
+                    auth: None,
+                    user_agent: DEFAULT_USER_AGENT.into(),
+                    tcp_options: TcpSocketOptions::default(),
+                    address_family: AddressFamily::default(),
+                    bind_address: None,
+                    client_identity: None,
+                    min_tls_version: TlsVersion::default(),
+                    max_tls_version: None,
                 },
             ],
             Environment::Staging => vec![ConnectionParams {
@@ -78,6 +216,20 @@ impl Environment {
                 http_request_decorator: HttpRequestDecorator::PathPrefix("/service-staging").into(),
                 certs: RootCertificates::Native,
                 dns_resolver: DnsResolver::System,
+                pinned_spki: vec![],
+                proxy: None,
+                http2_keepalive_interval: None,
+                http2_keepalive_timeout: None,
+                http2_keepalive_jitter_percent: DEFAULT_HTTP2_KEEPALIVE_JITTER_PERCENT,
+                max_concurrent_streams: None,
+                auth: None,
+                user_agent: DEFAULT_USER_AGENT.into(),
+                tcp_options: TcpSocketOptions::default(),
+                address_family: AddressFamily::default(),
+                bind_address: None,
+                client_identity: None,
+                min_tls_version: TlsVersion::default(),
+                max_tls_version: None,
             }],
         }
     }
@@ -189,3 +341,588 @@ async fn CdsiLookup(
     )
     .await
 }
+
+/// Identifies a [`ChatNetworkError`] variant, so that the app can tell the errors apart
+/// without parsing their messages.
+///
+/// The values are part of the bridge API: they must never change or be reused,
+/// new variants are only ever added at the end.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, num_enum::TryFromPrimitive)]
+#[repr(u8)]
+pub enum ChatNetworkErrorCode {
+    IncomingDataInvalid = 1,
+    RequestMissingId = 2,
+    RequestMissingVerbOrPath = 3,
+    RequestHeaderInvalid = 4,
+    RequestTooLarge = 5,
+    ResponseTooLarge = 6,
+    UnknownVerbInRequest = 7,
+    FailedToSendWebSocket = 8,
+    FailedToSendHttp = 9,
+    FailedToConnectHttp = 10,
+    DnsFailure = 11,
+    TcpConnectFailure = 12,
+    TlsHandshakeFailure = 13,
+    FailedToPassMessageToSenderTask = 14,
+    ResponseNotReceived = 15,
+    UnexpectedFrameReceived = 16,
+    Timeout = 17,
+    RateLimited = 18,
+    Cancelled = 19,
+    ChannelClosed = 20,
+    WebSocketError = 21,
+    ChannelClosedWithError = 22,
+    ChannelClosedByRemotePeer = 23,
+    GoAway = 24,
+    ChannelClosedByLocalPeer = 25,
+    ChannelIdle = 26,
+    NoServiceConnection = 27,
+    FailedToConnectWebSocket = 28,
+    UnexpectedMessageType = 29,
+    ServerRequestMissingId = 30,
+    FailedToPassMessageToIncomingChannel = 31,
+    RequestIdCollision = 32,
+    RequestPathInvalid = 33,
+    QueueFull = 34,
+}
+
+impl From<&ChatNetworkError> for ChatNetworkErrorCode {
+    fn from(error: &ChatNetworkError) -> Self {
+        match error {
+            ChatNetworkError::IncomingDataInvalid => Self::IncomingDataInvalid,
+            ChatNetworkError::RequestMissingId => Self::RequestMissingId,
+            ChatNetworkError::RequestMissingVerbOrPath => Self::RequestMissingVerbOrPath,
+            ChatNetworkError::RequestHeaderInvalid => Self::RequestHeaderInvalid,
+            ChatNetworkError::RequestTooLarge => Self::RequestTooLarge,
+            ChatNetworkError::ResponseTooLarge => Self::ResponseTooLarge,
+            ChatNetworkError::UnknownVerbInRequest => Self::UnknownVerbInRequest,
+            ChatNetworkError::FailedToSendWebSocket(_) => Self::FailedToSendWebSocket,
+            ChatNetworkError::FailedToSendHttp(_) => Self::FailedToSendHttp,
+            ChatNetworkError::FailedToConnectHttp(_) => Self::FailedToConnectHttp,
+            ChatNetworkError::DnsFailure => Self::DnsFailure,
+            ChatNetworkError::TcpConnectFailure => Self::TcpConnectFailure,
+            ChatNetworkError::TlsHandshakeFailure => Self::TlsHandshakeFailure,
+            ChatNetworkError::FailedToPassMessageToSenderTask => {
+                Self::FailedToPassMessageToSenderTask
+            }
+            ChatNetworkError::ResponseNotReceived => Self::ResponseNotReceived,
+            ChatNetworkError::UnexpectedFrameReceived => Self::UnexpectedFrameReceived,
+            ChatNetworkError::Timeout { .. } => Self::Timeout,
+            ChatNetworkError::RateLimited { .. } => Self::RateLimited,
+            ChatNetworkError::Cancelled => Self::Cancelled,
+            ChatNetworkError::ChannelClosed => Self::ChannelClosed,
+            ChatNetworkError::WebSocketError(_) => Self::WebSocketError,
+            ChatNetworkError::ChannelClosedWithError(_) => Self::ChannelClosedWithError,
+            ChatNetworkError::ChannelClosedByRemotePeer => Self::ChannelClosedByRemotePeer,
+            ChatNetworkError::GoAway { .. } => Self::GoAway,
+            ChatNetworkError::ChannelClosedByLocalPeer => Self::ChannelClosedByLocalPeer,
+            ChatNetworkError::ChannelIdle => Self::ChannelIdle,
+            ChatNetworkError::NoServiceConnection => Self::NoServiceConnection,
+            ChatNetworkError::FailedToConnectWebSocket => Self::FailedToConnectWebSocket,
+            ChatNetworkError::UnexpectedMessageType => Self::UnexpectedMessageType,
+            ChatNetworkError::ServerRequestMissingId => Self::ServerRequestMissingId,
+            ChatNetworkError::FailedToPassMessageToIncomingChannel => {
+                Self::FailedToPassMessageToIncomingChannel
+            }
+            ChatNetworkError::RequestIdCollision => Self::RequestIdCollision,
+            ChatNetworkError::RequestPathInvalid => Self::RequestPathInvalid,
+            ChatNetworkError::QueueFull => Self::QueueFull,
+        }
+    }
+}
+
+/// A connection to the chat server for the Node bridge.
+///
+/// The connection is established on the first request, and re-used by the following ones
+/// until it's closed; the next request after that establishes a new one.
+pub struct ChatService {
+    runtime: tokio::runtime::Handle,
+    connection: Arc<ChatConnection>,
+}
+
+#[cfg(feature = "testing-fns")]
+impl ChatService {
+    /// Creates a service that connects to an in-memory echo server instead of the chat server,
+    /// see `TESTING_ChatService_NewInMemory`.
+    pub(crate) fn new_in_memory(async_runtime: &TokioAsyncContext) -> Self {
+        Self {
+            runtime: async_runtime.0.handle().clone(),
+            connection: Arc::new(ChatConnection {
+                connector: ChatOverHttp2ServiceConnector::default(),
+                route: ChatRoute::InMemory {
+                    close: Default::default(),
+                },
+                service: Default::default(),
+                close_listener: Default::default(),
+            }),
+        }
+    }
+
+    /// Makes the in-memory echo server close the current connection, if any, and waits for the
+    /// service to notice.
+    ///
+    /// # Panics
+    ///
+    /// If the service was not created with [`ChatService::new_in_memory`].
+    pub(crate) async fn close_in_memory_connection(&self) {
+        let close = match &self.connection.route {
+            ChatRoute::InMemory { close } => close,
+            ChatRoute::Direct(_) => panic!("not an in-memory service"),
+        };
+        let service = self.connection.service.lock().await.clone();
+        std::mem::take(&mut *close.lock().expect("not poisoned")).cancel();
+        if let Some(service) = service {
+            service.closed().await;
+        }
+    }
+}
+
+struct ChatConnection {
+    connector: ChatOverHttp2ServiceConnector,
+    route: ChatRoute,
+    /// The service for the current connection, if one was established.
+    ///
+    /// It's kept even after the connection is closed, and only replaced on the next request.
+    service: tokio::sync::Mutex<Option<ChatOverHttp2>>,
+    /// The task reporting close reasons to the listener set with `ChatService_SetCloseListener`.
+    close_listener: std::sync::Mutex<Option<tokio::task::JoinHandle<()>>>,
+}
+
+enum ChatRoute {
+    Direct(ConnectionParams),
+    /// An echo server over in-memory connections, for testing.
+    ///
+    /// Cancelling `close` makes the server close the connection it was passed to; a fresh token
+    /// is used for the next connection.
+    #[cfg(feature = "testing-fns")]
+    InMemory {
+        close: std::sync::Mutex<CancellationToken>,
+    },
+}
+
+impl ChatConnection {
+    /// Returns the service for the current connection, establishing a new connection first if
+    /// there is none or the previous one was closed.
+    async fn service(&self) -> Result<ChatOverHttp2, ChatNetworkError> {
+        let mut guard = self.service.lock().await;
+        match &*guard {
+            Some(service) if !service.is_closed() => Ok(service.clone()),
+            _ => {
+                let service = self.connect().await?;
+                Ok(guard.insert(service).clone())
+            }
+        }
+    }
+
+    async fn connect(&self) -> Result<ChatOverHttp2, ChatNetworkError> {
+        match &self.route {
+            ChatRoute::Direct(connection_params) => self.connector.connect(connection_params).await,
+            #[cfg(feature = "testing-fns")]
+            ChatRoute::InMemory { close } => {
+                let close = close.lock().expect("not poisoned").clone();
+                Ok(self
+                    .connector
+                    .connect_in_memory(|io| in_memory::serve_echo(io, close))
+                    .await)
+            }
+        }
+    }
+
+    async fn send(
+        &self,
+        message: &MessageProto,
+        timeout: Duration,
+    ) -> Result<ResponseProto, ChatNetworkError> {
+        self.service().await?.send(message, timeout).await
+    }
+
+    /// Sends the `messages` concurrently over one connection; the `timeout` covers establishing
+    /// the connection too.
+    ///
+    /// Fails as a whole only if the connection can't be established.
+    async fn send_batch(
+        &self,
+        messages: &[MessageProto],
+        timeout: Duration,
+    ) -> Result<Vec<Result<ResponseProto, ChatNetworkError>>, ChatNetworkError> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let service = self.service().await?;
+        Ok(chat::send_batch(&service, messages, deadline).await)
+    }
+
+    /// Returns what was negotiated with the server, or `None` if there is no open connection
+    /// (including while one is being established).
+    fn connection_info(&self) -> Option<ConnectionInfo> {
+        let guard = self.service.try_lock().ok()?;
+        guard
+            .as_ref()
+            .filter(|service| !service.is_closed())
+            .map(|service| service.connection_info().clone())
+    }
+}
+
+#[bridge_fn(ffi = false, jni = false)]
+fn ChatService_new(async_runtime: &TokioAsyncContext, environment: u8) -> ChatService {
+    let environment: Environment = environment.try_into().expect("is valid environment value");
+    ChatService {
+        runtime: async_runtime.0.handle().clone(),
+        connection: Arc::new(ChatConnection {
+            connector: ChatOverHttp2ServiceConnector::default(),
+            route: ChatRoute::Direct(environment.env().chat_direct_connection()),
+            service: Default::default(),
+            close_listener: Default::default(),
+        }),
+    }
+}
+
+bridge_handle!(ChatService, clone = false);
+
+/// Returns the application protocol negotiated with the chat server (e.g. `h2`), or `null` if
+/// there is no open connection or the server didn't select one.
+#[bridge_fn(ffi = false, jni = false)]
+fn ChatService_GetAlpn(chat: &ChatService) -> Option<String> {
+    let alpn = chat.connection.connection_info()?.alpn?;
+    Some(String::from_utf8_lossy(&alpn).into_owned())
+}
+
+/// Returns the TLS version of the connection to the chat server (e.g. `TLSv1.3`), or `null` if
+/// there is no open connection.
+#[bridge_fn(ffi = false, jni = false)]
+fn ChatService_GetTlsVersion(chat: &ChatService) -> Option<String> {
+    Some(chat.connection.connection_info()?.tls_version.to_owned())
+}
+
+/// Returns the SHA-256 hash of the SubjectPublicKeyInfo of the chat server certificate as
+/// lowercase hex, or `null` if there is no open connection.
+#[bridge_fn(ffi = false, jni = false)]
+fn ChatService_GetPeerSpki(chat: &ChatService) -> Option<String> {
+    let spki = chat.connection.connection_info()?.peer_cert_spki?;
+    Some(
+        spki.iter()
+            .fold(String::with_capacity(64), |mut hex, byte| {
+                write!(hex, "{byte:02x}").expect("can write to String");
+                hex
+            }),
+    )
+}
+
+/// Connects to the chat server, sends a serialized [`MessageProto`], and returns the serialized
+/// [`ResponseProto`], closing the connection afterwards.
+///
+/// The timeout covers connecting as well as sending. The error tells which stage failed; see
+/// [`ConnectAndSendError`].
+#[bridge_io(TokioAsyncContext, ffi = false, jni = false)]
+async fn ChatService_ConnectAndSend(
+    environment: u8,
+    message: &[u8],
+    timeout_millis: u32,
+    cancellation: &CancellationHandle,
+) -> Result<Vec<u8>, ConnectAndSendError> {
+    let environment: Environment = environment.try_into().expect("is valid environment value");
+    let message = MessageProto::decode(message).map_err(|_| {
+        ConnectAndSendError::InvalidRequest(ChatNetworkError::UnexpectedMessageType)
+    })?;
+    let response = ChatOverHttp2ServiceConnector::default()
+        .connect_and_send(
+            &environment.env().chat_direct_connection(),
+            &message,
+            Duration::from_millis(timeout_millis.into()),
+            cancellation.token(),
+        )
+        .await?;
+    Ok(response.encode_to_vec())
+}
+
+/// Returns the time from the `Date` header of a serialized [`ResponseProto`], or `null` if
+/// the header is missing or malformed.
+#[bridge_fn(ffi = false, jni = false)]
+fn ChatResponse_GetServerTime(response: &[u8]) -> Result<Option<Timestamp>, ChatNetworkError> {
+    let response =
+        ResponseProto::decode(response).map_err(|_| ChatNetworkError::IncomingDataInvalid)?;
+    Ok(response
+        .server_time()
+        .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .and_then(|since_epoch| u64::try_from(since_epoch.as_millis()).ok())
+        .map(Timestamp::from))
+}
+
+/// Sends a serialized [`MessageProto`] and reports the response through `callback`, rather than
+/// through a Promise.
+///
+/// The callback is called with either an error, or with `null` followed by the status, the headers
+/// and the body of the response.
+///
+/// ts: export function ChatService_SendWithCallback(chat: Wrapper<ChatService>, message: Buffer, timeoutMillis: number, callback: (error: Error | null, status?: number, headers?: string[], body?: Buffer | null) => void): void
+#[allow(non_snake_case)]
+fn node_ChatService_SendWithCallback(
+    mut cx: node::FunctionContext,
+) -> node::JsResult<node::JsValue> {
+    let (runtime, connection) = {
+        let chat_arg = cx.argument::<<&ChatService as node::ArgTypeInfo>::ArgType>(0)?;
+        let mut chat_stored = <&ChatService as node::ArgTypeInfo>::borrow(&mut cx, chat_arg)?;
+        let chat = <&ChatService as node::ArgTypeInfo>::load_from(&mut chat_stored);
+        (chat.runtime.clone(), chat.connection.clone())
+    };
+    let message_arg = cx.argument::<node::JsBuffer>(1)?;
+    let message = match MessageProto::decode(message_arg.as_slice(&cx)) {
+        Ok(message) => message,
+        Err(_) => return cx.throw_type_error("message is not a valid MessageProto"),
+    };
+    let timeout_millis = cx.argument::<node::JsNumber>(2)?.value(&mut cx);
+    let timeout = Duration::from_millis(timeout_millis as u64);
+    let callback = cx.argument::<node::JsFunction>(3)?.root(&mut cx);
+    let error_module = cx.this().root(&mut cx);
+    let channel = cx.channel();
+
+    #[allow(clippy::let_underscore_future)]
+    let _: tokio::task::JoinHandle<()> = runtime.spawn(async move {
+        let result = connection.send(&message, timeout).await;
+        // If the event loop has already shut down, there's nobody left to report the result to.
+        let _ = channel.try_send(move |mut cx| {
+            let callback = callback.into_inner(&mut cx);
+            let error_module = error_module.into_inner(&mut cx);
+            let args: Vec<node::Handle<node::JsValue>> = match result {
+                Ok(response) => {
+                    let headers = headers_to_js(&mut cx, response.headers)?;
+                    vec![
+                        cx.null().upcast(),
+                        cx.number(response.status.unwrap_or_default()).upcast(),
+                        headers.upcast(),
+                        node::ResultTypeInfo::convert_into(response.body, &mut cx)?.upcast(),
+                    ]
+                }
+                Err(error) => vec![chat_error_to_js(
+                    &mut cx,
+                    error,
+                    error_module,
+                    "ChatService_SendWithCallback",
+                )],
+            };
+            let undefined = cx.undefined();
+            callback.call(&mut cx, undefined, args)?;
+            Ok(())
+        });
+    });
+    Ok(cx.undefined().upcast())
+}
+node_register!(ChatService_SendWithCallback);
+
+/// Sends serialized [`MessageProto`]s concurrently over one connection, and reports their results
+/// through `callback`, in the same order as the messages.
+///
+/// The timeout is shared by all the requests. The callback is called with an error if no
+/// connection could be established, otherwise with `null` followed by an array where each element
+/// is either the error or the response for the message at the same index.
+///
+/// ts: export function ChatService_SendBatch(chat: Wrapper<ChatService>, messages: Buffer[], timeoutMillis: number, callback: (error: Error | null, results?: (Error | { status: number, headers: string[], body: Buffer | null })[]) => void): void
+#[allow(non_snake_case)]
+fn node_ChatService_SendBatch(mut cx: node::FunctionContext) -> node::JsResult<node::JsValue> {
+    let (runtime, connection) = {
+        let chat_arg = cx.argument::<<&ChatService as node::ArgTypeInfo>::ArgType>(0)?;
+        let mut chat_stored = <&ChatService as node::ArgTypeInfo>::borrow(&mut cx, chat_arg)?;
+        let chat = <&ChatService as node::ArgTypeInfo>::load_from(&mut chat_stored);
+        (chat.runtime.clone(), chat.connection.clone())
+    };
+    let message_args = cx.argument::<node::JsArray>(1)?.to_vec(&mut cx)?;
+    let mut messages = Vec::with_capacity(message_args.len());
+    for message_arg in message_args {
+        let message_arg = message_arg.downcast_or_throw::<node::JsBuffer, _>(&mut cx)?;
+        match MessageProto::decode(message_arg.as_slice(&cx)) {
+            Ok(message) => messages.push(message),
+            Err(_) => return cx.throw_type_error("message is not a valid MessageProto"),
+        }
+    }
+    let timeout_millis = cx.argument::<node::JsNumber>(2)?.value(&mut cx);
+    let timeout = Duration::from_millis(timeout_millis as u64);
+    let callback = cx.argument::<node::JsFunction>(3)?.root(&mut cx);
+    let error_module = cx.this().root(&mut cx);
+    let channel = cx.channel();
+
+    #[allow(clippy::let_underscore_future)]
+    let _: tokio::task::JoinHandle<()> = runtime.spawn(async move {
+        let result = connection.send_batch(&messages, timeout).await;
+        // If the event loop has already shut down, there's nobody left to report the result to.
+        let _ = channel.try_send(move |mut cx| {
+            let callback = callback.into_inner(&mut cx);
+            let error_module = error_module.into_inner(&mut cx);
+            let args: Vec<node::Handle<node::JsValue>> = match result {
+                Ok(results) => {
+                    let results_array = node::JsArray::new(&mut cx, results.len() as u32);
+                    for (i, result) in results.into_iter().enumerate() {
+                        let value = match result {
+                            Ok(response) => {
+                                let response_object = cx.empty_object();
+                                let status = cx.number(response.status.unwrap_or_default());
+                                response_object.set(&mut cx, "status", status)?;
+                                let headers = headers_to_js(&mut cx, response.headers)?;
+                                response_object.set(&mut cx, "headers", headers)?;
+                                let body =
+                                    node::ResultTypeInfo::convert_into(response.body, &mut cx)?;
+                                response_object.set(&mut cx, "body", body)?;
+                                response_object.upcast()
+                            }
+                            Err(error) => chat_error_to_js(
+                                &mut cx,
+                                error,
+                                error_module,
+                                "ChatService_SendBatch",
+                            ),
+                        };
+                        results_array.set(&mut cx, i as u32, value)?;
+                    }
+                    vec![cx.null().upcast(), results_array.upcast()]
+                }
+                Err(error) => vec![chat_error_to_js(
+                    &mut cx,
+                    error,
+                    error_module,
+                    "ChatService_SendBatch",
+                )],
+            };
+            let undefined = cx.undefined();
+            callback.call(&mut cx, undefined, args)?;
+            Ok(())
+        });
+    });
+    Ok(cx.undefined().upcast())
+}
+node_register!(ChatService_SendBatch);
+
+/// Sets `callback` to be called with the reason whenever a connection of this service is closed,
+/// replacing the listener set before, if any.
+///
+/// The reason is a `{ kind, detail }` object, see [`ChatCloseReason`].
+///
+/// ts: export function ChatService_SetCloseListener(chat: Wrapper<ChatService>, callback: (reason: { kind: 'local' | 'remote' | 'error', detail: string | null }) => void): void
+#[allow(non_snake_case)]
+fn node_ChatService_SetCloseListener(
+    mut cx: node::FunctionContext,
+) -> node::JsResult<node::JsValue> {
+    let (runtime, connection) = {
+        let chat_arg = cx.argument::<<&ChatService as node::ArgTypeInfo>::ArgType>(0)?;
+        let mut chat_stored = <&ChatService as node::ArgTypeInfo>::borrow(&mut cx, chat_arg)?;
+        let chat = <&ChatService as node::ArgTypeInfo>::load_from(&mut chat_stored);
+        (chat.runtime.clone(), chat.connection.clone())
+    };
+    let callback = cx.argument::<node::JsFunction>(1)?.root(&mut cx);
+    let mut channel = cx.channel();
+    // Listening for closes shouldn't keep the process alive on its own.
+    channel.unref(&mut cx);
+
+    let events = connection.connector.subscribe();
+    let listener = runtime.spawn(report_close_reasons(events, channel, callback));
+    let previous = connection
+        .close_listener
+        .lock()
+        .expect("not poisoned")
+        .replace(listener);
+    if let Some(previous) = previous {
+        previous.abort();
+    }
+    Ok(cx.undefined().upcast())
+}
+node_register!(ChatService_SetCloseListener);
+
+/// Why a chat connection was closed, as reported to JavaScript.
+///
+/// Converted to an object whose `kind` is `local`, `remote` or `error`, and whose `detail`
+/// is the description of the error for `error`, and `null` otherwise.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum ChatCloseReason {
+    /// Closed by this side, e.g. after being idle for too long
+    Local,
+    /// Closed by the server, or the server stopped responding
+    Remote,
+    /// Failed with the described error
+    Error(String),
+}
+
+impl ChatCloseReason {
+    /// Returns the close reason carried by `event`, if it's about a connection being closed.
+    fn from_event(event: ConnectionEvent) -> Option<Self> {
+        match event {
+            ConnectionEvent::Connected => None,
+            ConnectionEvent::Closed(CloseReason::LocalPeer) => Some(Self::Local),
+            ConnectionEvent::Closed(CloseReason::RemotePeer) => Some(Self::Remote),
+            ConnectionEvent::Error(description) => Some(Self::Error(description)),
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Local => "local",
+            Self::Remote => "remote",
+            Self::Error(_) => "error",
+        }
+    }
+
+    fn to_js<'a>(&self, cx: &mut impl node::Context<'a>) -> node::JsResult<'a, node::JsObject> {
+        let object = cx.empty_object();
+        let kind = cx.string(self.kind());
+        object.set(cx, "kind", kind)?;
+        let detail: node::Handle<node::JsValue> = match self {
+            Self::Error(description) => cx.string(description).upcast(),
+            Self::Local | Self::Remote => cx.null().upcast(),
+        };
+        object.set(cx, "detail", detail)?;
+        Ok(object)
+    }
+}
+
+/// Calls `callback` on the JavaScript thread with the reason of every connection close published
+/// to `events`, until there are no more publishers.
+pub(crate) async fn report_close_reasons(
+    mut events: broadcast::Receiver<ConnectionEvent>,
+    channel: node::Channel,
+    callback: node::Root<node::JsFunction>,
+) {
+    let callback = Arc::new(callback);
+    loop {
+        let reason = match events.recv().await {
+            Ok(event) => match ChatCloseReason::from_event(event) {
+                Some(reason) => reason,
+                None => continue,
+            },
+            Err(broadcast::error::RecvError::Lagged(missed)) => {
+                log::warn!("missed {missed} chat connection events");
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        let callback = callback.clone();
+        // If the event loop has already shut down, there's nobody left to report the reason to.
+        let _ = channel.try_send(move |mut cx| {
+            let reason = reason.to_js(&mut cx)?;
+            let callback = callback.to_inner(&mut cx);
+            let undefined = cx.undefined();
+            callback.call(&mut cx, undefined, vec![reason.upcast::<node::JsValue>()])?;
+            Ok(())
+        });
+    }
+}
+
+fn headers_to_js<'a>(
+    cx: &mut impl node::Context<'a>,
+    headers: Vec<String>,
+) -> node::JsResult<'a, node::JsArray> {
+    let headers_array = node::JsArray::new(cx, headers.len() as u32);
+    for (i, header) in headers.into_iter().enumerate() {
+        let header = cx.string(header);
+        headers_array.set(cx, i as u32, header)?;
+    }
+    Ok(headers_array)
+}
+
+/// Returns the JavaScript error that `operation_name` would throw for `error`,
+/// to be passed to a callback instead.
+fn chat_error_to_js<'a>(
+    cx: &mut impl node::Context<'a>,
+    error: ChatNetworkError,
+    error_module: node::Handle<'a, node::JsObject>,
+    operation_name: &str,
+) -> node::Handle<'a, node::JsValue> {
+    let thrown =
+        cx.try_catch(|cx| node::SignalNodeError::throw(error, cx, error_module, operation_name));
+    let (Ok(error) | Err(error)) = thrown;
+    error
+}
@@ -86,6 +86,10 @@ pub trait SignalNodeError: Sized + fmt::Display {
 
 const RATE_LIMITED_ERROR: &str = "RateLimitedError";
 const IO_ERROR: &str = "IoError";
+const CHAT_CONNECTION_FAILED: &str = "ChatConnectionFailed";
+const CHAT_REQUEST_FAILED: &str = "ChatRequestFailed";
+const CHAT_INVALID_REQUEST: &str = "ChatInvalidRequest";
+const CANCELLED: &str = "Cancelled";
 const INVALID_MEDIA_INPUT: &str = "InvalidMediaInput";
 const UNSUPPORTED_MEDIA_INPUT: &str = "UnsupportedMediaInput";
 
@@ -353,6 +357,53 @@ impl SignalNodeError for libsignal_net::cdsi::Error {
     }
 }
 
+impl SignalNodeError for libsignal_net::chat::errors::ChatNetworkError {
+    fn throw<'a>(
+        self,
+        cx: &mut impl Context<'a>,
+        module: Handle<'a, JsObject>,
+        operation_name: &str,
+    ) -> JsResult<'a, JsValue> {
+        let code = crate::net::ChatNetworkErrorCode::from(&self);
+        let props = cx.empty_object();
+        let code = cx.number(code as u8);
+        props.set(cx, "chatErrorCode", code)?;
+        let message = self.to_string();
+        new_js_error(
+            cx,
+            module,
+            Some(IO_ERROR),
+            &message,
+            operation_name,
+            Some(props),
+        )
+        .map(|e| cx.throw(e))
+        // Make sure we still throw something.
+        .unwrap_or_else(|| cx.throw_error(&message))
+    }
+}
+
+impl SignalNodeError for libsignal_net::chat::errors::ConnectAndSendError {
+    fn throw<'a>(
+        self,
+        cx: &mut impl Context<'a>,
+        module: Handle<'a, JsObject>,
+        operation_name: &str,
+    ) -> JsResult<'a, JsValue> {
+        let name = match self {
+            Self::InvalidRequest(_) => Some(CHAT_INVALID_REQUEST),
+            Self::Connect(_) => Some(CHAT_CONNECTION_FAILED),
+            Self::Send(_) => Some(CHAT_REQUEST_FAILED),
+            Self::Cancelled => Some(CANCELLED),
+        };
+        let message = self.to_string();
+        new_js_error(cx, module, name, &message, operation_name, None)
+            .map(|e| cx.throw(e))
+            // Make sure we still throw something.
+            .unwrap_or_else(|| cx.throw_error(&message))
+    }
+}
+
 /// Represents an error returned by a callback.
 #[derive(Debug)]
 struct CallbackError {
@@ -999,6 +999,7 @@ bridge_get!(
 );
 bridge_get!(SessionRecord::local_registration_id -> u32);
 bridge_get!(SessionRecord::remote_registration_id -> u32);
+bridge_get!(SessionRecord::current_ratchet_public as GetCurrentRatchetPublicKey -> Vec<u8>);
 
 bridge_get!(SealedSenderDecryptionResult::sender_uuid -> String, ffi = false, jni = false);
 bridge_get!(SealedSenderDecryptionResult::sender_e164 -> Option<String>, ffi = false, jni = false);
@@ -17,6 +17,19 @@ mod net;
 mod types;
 use types::*;
 
+/// Crashes the process after logging the panic's message.
+///
+/// Panics in the body of a bridged function are already caught and reported as errors through
+/// the promise, so a panic that makes it out to the runtime means the result couldn't be
+/// delivered at all. Since this is a testing runtime, make sure such panics aren't missed.
+fn abort_on_uncaught_panic(panic: Box<dyn std::any::Any + Send>) -> ! {
+    log::error!(
+        "uncaught panic in testing runtime: {}",
+        describe_panic(&panic)
+    );
+    std::process::abort()
+}
+
 pub struct NonSuspendingBackgroundThreadRuntime;
 bridge_handle!(
     NonSuspendingBackgroundThreadRuntime,
@@ -36,10 +49,7 @@ where
                     .now_or_never()
                     .expect("no need to suspend in testing methods")
             }))
-            .unwrap_or_else(|_| {
-                // Since this is a testing method, make sure we crash on uncaught panics.
-                std::process::abort()
-            })
+            .unwrap_or_else(abort_on_uncaught_panic)
         });
     }
 }
@@ -49,11 +59,47 @@ fn TESTING_NonSuspendingBackgroundThreadRuntime_New() -> NonSuspendingBackground
     NonSuspendingBackgroundThreadRuntime
 }
 
+/// Runs each future to completion on the calling thread before `run_future` returns.
+///
+/// Unlike [`NonSuspendingBackgroundThreadRuntime`], the result of a call is available as soon as
+/// the call returns, which keeps tests deterministic.
+pub struct ImmediateInlineRuntime;
+bridge_handle!(
+    ImmediateInlineRuntime,
+    clone = false,
+    ffi = false,
+    jni = false
+);
+
+impl<F> AsyncRuntime<F> for ImmediateInlineRuntime
+where
+    F: Future<Output = ()> + 'static,
+{
+    fn run_future(&self, future: F) {
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || {
+            future
+                .now_or_never()
+                .expect("no need to suspend in testing methods")
+        }))
+        .unwrap_or_else(abort_on_uncaught_panic)
+    }
+}
+
+#[bridge_fn(ffi = false, jni = false)]
+fn TESTING_ImmediateInlineRuntime_New() -> ImmediateInlineRuntime {
+    ImmediateInlineRuntime
+}
+
 #[bridge_io(NonSuspendingBackgroundThreadRuntime)]
 async fn TESTING_FutureSuccess(input: u8) -> i32 {
     i32::from(input) * 2
 }
 
+#[bridge_io(ImmediateInlineRuntime, ffi = false, jni = false)]
+async fn TESTING_ImmediateInlineRuntime_FutureSuccess(input: u8) -> i32 {
+    i32::from(input) * 2
+}
+
 #[bridge_io(NonSuspendingBackgroundThreadRuntime)]
 async fn TESTING_FutureFailure(_input: u8) -> Result<i32, SignalProtocolError> {
     Err(SignalProtocolError::InvalidArgument("failure".to_string()))
@@ -142,3 +188,21 @@ async fn TESTING_ErrorOnReturnAsync(
 async fn TESTING_ErrorOnReturnIo(_needs_cleanup: Ignored<NeedsCleanup>) -> Ignored<ErrorOnReturn> {
     ErrorOnReturn
 }
+
+#[bridge_fn]
+fn TESTING_CleanupOrder(
+    _a: Ignored<RecordsCleanup>,
+    _b: Ignored<RecordsCleanup>,
+    _c: Ignored<RecordsCleanup>,
+) {
+}
+
+/// Returns the order in which the arguments of the preceding [`TESTING_CleanupOrder`] calls
+/// were cleaned up, as the positions of the arguments.
+///
+/// The cleanup happens after the bridged function has returned, so it can only be observed
+/// by a separate call.
+#[bridge_fn]
+fn TESTING_TakeCleanupOrder() -> Vec<u8> {
+    take_cleanup_order()
+}
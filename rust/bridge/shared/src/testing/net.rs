@@ -3,12 +3,21 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
+use std::time::Duration;
+
 use libsignal_bridge_macros::*;
-use libsignal_net::cdsi::{LookupResponse, LookupResponseEntry, E164};
+use libsignal_net::cdsi::{self, LookupResponse, LookupResponseEntry, E164};
+use libsignal_net::chat::errors::ChatNetworkError;
+use libsignal_net::infra::errors::NetError;
+use libsignal_net::infra::{CloseReason, ConnectionEvent};
 use libsignal_protocol::{Aci, Pni};
 use nonzero_ext::nonzero;
+use tokio::sync::broadcast;
 use uuid::Uuid;
 
+use crate::net::{
+    report_close_reasons, BridgeRuntime, ChatNetworkErrorCode, ChatService, TokioAsyncContext,
+};
 use crate::support::*;
 use crate::*;
 
@@ -43,3 +52,139 @@ fn TESTING_CdsiLookupResponseConvert() -> LookupResponse {
         ],
     }
 }
+
+/// Completes with `delay_ms` after sleeping that long, unless `timeout_ms` elapses first.
+#[bridge_io(TokioAsyncContext, ffi = false, jni = false)]
+async fn TESTING_FutureTimeout(delay_ms: u32, timeout_ms: u32) -> Result<i32, cdsi::Error> {
+    let delay = tokio::time::sleep(Duration::from_millis(delay_ms.into()));
+    match tokio::time::timeout(Duration::from_millis(timeout_ms.into()), delay).await {
+        Ok(()) => Ok(i32::try_from(delay_ms).unwrap_or(i32::MAX)),
+        Err(_) => Err(NetError::Timeout.into()),
+    }
+}
+
+/// Fails with an example of the [`ChatNetworkError`] variant identified by `code`,
+/// so that the app can check how each of them is reported.
+///
+/// [`ChatNetworkError::ChannelClosedWithError`] is the only variant that can't be produced:
+/// it wraps a `hyper::Error`, and those can only be created by hyper itself.
+#[bridge_fn(ffi = false, jni = false)]
+fn TESTING_ChatNetworkErrorConvert(code: u8) -> Result<(), ChatNetworkError> {
+    let code = ChatNetworkErrorCode::try_from(code).expect("is valid error code");
+    let error = match code {
+        ChatNetworkErrorCode::IncomingDataInvalid => ChatNetworkError::IncomingDataInvalid,
+        ChatNetworkErrorCode::RequestMissingId => ChatNetworkError::RequestMissingId,
+        ChatNetworkErrorCode::RequestMissingVerbOrPath => {
+            ChatNetworkError::RequestMissingVerbOrPath
+        }
+        ChatNetworkErrorCode::RequestHeaderInvalid => ChatNetworkError::RequestHeaderInvalid,
+        ChatNetworkErrorCode::RequestTooLarge => ChatNetworkError::RequestTooLarge,
+        ChatNetworkErrorCode::ResponseTooLarge => ChatNetworkError::ResponseTooLarge,
+        ChatNetworkErrorCode::UnknownVerbInRequest => ChatNetworkError::UnknownVerbInRequest,
+        ChatNetworkErrorCode::FailedToSendWebSocket => {
+            ChatNetworkError::FailedToSendWebSocket(tungstenite::Error::ConnectionClosed)
+        }
+        ChatNetworkErrorCode::FailedToSendHttp => {
+            ChatNetworkError::FailedToSendHttp(NetError::ConnectionInterrupted)
+        }
+        ChatNetworkErrorCode::FailedToConnectHttp => {
+            ChatNetworkError::FailedToConnectHttp(NetError::Http2FailedHandshake)
+        }
+        ChatNetworkErrorCode::DnsFailure => ChatNetworkError::DnsFailure,
+        ChatNetworkErrorCode::TcpConnectFailure => ChatNetworkError::TcpConnectFailure,
+        ChatNetworkErrorCode::TlsHandshakeFailure => ChatNetworkError::TlsHandshakeFailure,
+        ChatNetworkErrorCode::FailedToPassMessageToSenderTask => {
+            ChatNetworkError::FailedToPassMessageToSenderTask
+        }
+        ChatNetworkErrorCode::ResponseNotReceived => ChatNetworkError::ResponseNotReceived,
+        ChatNetworkErrorCode::UnexpectedFrameReceived => ChatNetworkError::UnexpectedFrameReceived,
+        ChatNetworkErrorCode::Timeout => ChatNetworkError::Timeout {
+            elapsed: Duration::from_secs(1),
+        },
+        ChatNetworkErrorCode::RateLimited => ChatNetworkError::RateLimited {
+            retry_after: Duration::from_secs(10),
+        },
+        ChatNetworkErrorCode::Cancelled => ChatNetworkError::Cancelled,
+        ChatNetworkErrorCode::ChannelClosed => ChatNetworkError::ChannelClosed,
+        ChatNetworkErrorCode::WebSocketError => {
+            ChatNetworkError::WebSocketError(tungstenite::Error::AlreadyClosed)
+        }
+        ChatNetworkErrorCode::ChannelClosedWithError => return Ok(()),
+        ChatNetworkErrorCode::ChannelClosedByRemotePeer => {
+            ChatNetworkError::ChannelClosedByRemotePeer
+        }
+        ChatNetworkErrorCode::GoAway => ChatNetworkError::GoAway { error_code: 11 },
+        ChatNetworkErrorCode::ChannelClosedByLocalPeer => {
+            ChatNetworkError::ChannelClosedByLocalPeer
+        }
+        ChatNetworkErrorCode::ChannelIdle => ChatNetworkError::ChannelIdle,
+        ChatNetworkErrorCode::NoServiceConnection => ChatNetworkError::NoServiceConnection,
+        ChatNetworkErrorCode::FailedToConnectWebSocket => {
+            ChatNetworkError::FailedToConnectWebSocket
+        }
+        ChatNetworkErrorCode::UnexpectedMessageType => ChatNetworkError::UnexpectedMessageType,
+        ChatNetworkErrorCode::ServerRequestMissingId => ChatNetworkError::ServerRequestMissingId,
+        ChatNetworkErrorCode::FailedToPassMessageToIncomingChannel => {
+            ChatNetworkError::FailedToPassMessageToIncomingChannel
+        }
+        ChatNetworkErrorCode::RequestIdCollision => ChatNetworkError::RequestIdCollision,
+        ChatNetworkErrorCode::RequestPathInvalid => ChatNetworkError::RequestPathInvalid,
+        ChatNetworkErrorCode::QueueFull => ChatNetworkError::QueueFull,
+    };
+    Err(error)
+}
+
+/// Reports a connection closed by the server, followed by one that failed with an error, to
+/// `callback` the same way as the listener set with `ChatService_SetCloseListener`.
+///
+/// ts: export function TESTING_ChatService_SimulateClose(asyncRuntime: Wrapper<TokioAsyncContext>, callback: (reason: { kind: 'local' | 'remote' | 'error', detail: string | null }) => void): void
+#[allow(non_snake_case)]
+fn node_TESTING_ChatService_SimulateClose(
+    mut cx: node::FunctionContext,
+) -> node::JsResult<node::JsValue> {
+    let callback = cx.argument::<node::JsFunction>(1)?.root(&mut cx);
+    let channel = cx.channel();
+
+    let (events_sender, events) = broadcast::channel(4);
+    for event in [
+        ConnectionEvent::Connected,
+        ConnectionEvent::Closed(CloseReason::RemotePeer),
+        ConnectionEvent::Connected,
+        ConnectionEvent::Error("simulated failure".to_string()),
+    ] {
+        events_sender.send(event).expect("has a subscriber");
+    }
+    // the listener stops once all the events are reported
+    drop(events_sender);
+
+    let async_runtime_arg = cx.argument::<<&TokioAsyncContext as node::ArgTypeInfo>::ArgType>(0)?;
+    let mut async_runtime_stored =
+        <&TokioAsyncContext as node::ArgTypeInfo>::borrow(&mut cx, async_runtime_arg)?;
+    let async_runtime =
+        <&TokioAsyncContext as node::ArgTypeInfo>::load_from(&mut async_runtime_stored);
+    async_runtime.run_future(report_close_reasons(events, channel, callback));
+    Ok(cx.undefined().upcast())
+}
+node_register!(TESTING_ChatService_SimulateClose);
+
+/// Creates a [`ChatService`] that connects to an in-memory server instead of the chat server.
+///
+/// The server responds to every request with `200` and the body of the request.
+#[cfg(feature = "testing-fns")]
+#[bridge_fn(ffi = false, jni = false)]
+fn TESTING_ChatService_NewInMemory(async_runtime: &TokioAsyncContext) -> ChatService {
+    ChatService::new_in_memory(async_runtime)
+}
+
+/// Makes the server of a service created with `TESTING_ChatService_NewInMemory` close the
+/// current connection, and completes once the service noticed.
+#[cfg(feature = "testing-fns")]
+#[bridge_io(TokioAsyncContext, ffi = false, jni = false)]
+async fn TESTING_ChatService_CloseInMemoryConnection(chat: &ChatService) {
+    chat.close_in_memory_connection().await
+}
+
+#[bridge_io(BridgeRuntime, ffi = false, jni = false)]
+async fn TESTING_BridgeRuntime_FutureSuccess(input: u8) -> i32 {
+    i32::from(input) * 2
+}
@@ -120,6 +120,94 @@ impl<'storage> node::AsyncArgTypeInfo<'storage> for NeedsCleanup {
     }
 }
 
+/// Ids of the [`CleanupRecorder`]s, in the order they were cleaned up, along with the id
+/// for the next one.
+static CLEANUP_LOG: std::sync::Mutex<(u8, Vec<u8>)> = std::sync::Mutex::new((0, Vec::new()));
+
+/// A type whose storage records when it's cleaned up, to check the order in which the bridges
+/// clean up the arguments.
+///
+/// Each argument gets the next id as it's borrowed, starting from 0 after the last
+/// [`take_cleanup_order`], so the arguments that are borrowed left to right and cleaned up in
+/// reverse record their ids in descending order.
+pub struct RecordsCleanup;
+
+/// The storage of [`RecordsCleanup`].
+pub struct CleanupRecorder(u8);
+
+impl CleanupRecorder {
+    fn new() -> Self {
+        let mut log = CLEANUP_LOG.lock().expect("not poisoned");
+        let id = log.0;
+        log.0 = id.wrapping_add(1);
+        Self(id)
+    }
+}
+
+impl Drop for CleanupRecorder {
+    fn drop(&mut self) {
+        CLEANUP_LOG.lock().expect("not poisoned").1.push(self.0);
+    }
+}
+
+/// Returns the ids of the [`RecordsCleanup`] arguments in the order they were cleaned up,
+/// and starts over.
+pub fn take_cleanup_order() -> Vec<u8> {
+    let mut log = CLEANUP_LOG.lock().expect("not poisoned");
+    log.0 = 0;
+    std::mem::take(&mut log.1)
+}
+
+#[cfg(feature = "ffi")]
+impl<'storage> ffi::ArgTypeInfo<'storage> for RecordsCleanup {
+    type ArgType = *const libc::c_void;
+    type StoredType = CleanupRecorder;
+
+    fn borrow(_foreign: Self::ArgType) -> ffi::SignalFfiResult<Self::StoredType> {
+        Ok(CleanupRecorder::new())
+    }
+
+    fn load_from(_stored: &'storage mut Self::StoredType) -> Self {
+        Self
+    }
+}
+
+#[cfg(feature = "jni")]
+impl<'storage, 'param: 'storage, 'context: 'param> jni::ArgTypeInfo<'storage, 'param, 'context>
+    for RecordsCleanup
+{
+    type ArgType = jni::JObject<'context>;
+    type StoredType = CleanupRecorder;
+
+    fn borrow(
+        _env: &mut jni::JNIEnv<'context>,
+        _foreign: &'param Self::ArgType,
+    ) -> jni::SignalJniResult<Self::StoredType> {
+        Ok(CleanupRecorder::new())
+    }
+
+    fn load_from(_stored: &'storage mut Self::StoredType) -> Self {
+        Self
+    }
+}
+
+#[cfg(feature = "node")]
+impl<'storage, 'context: 'storage> node::ArgTypeInfo<'storage, 'context> for RecordsCleanup {
+    type ArgType = node::JsNull;
+    type StoredType = CleanupRecorder;
+
+    fn borrow(
+        _cx: &mut neon::prelude::FunctionContext<'context>,
+        _foreign: neon::prelude::Handle<'context, Self::ArgType>,
+    ) -> neon::result::NeonResult<Self::StoredType> {
+        Ok(CleanupRecorder::new())
+    }
+
+    fn load_from(_stored: &'storage mut Self::StoredType) -> Self {
+        Self
+    }
+}
+
 /// A type that implements ArgTypeInfo but always produces an error when "borrowed" from the
 /// app-provided arguments.
 pub struct ErrorOnBorrow;
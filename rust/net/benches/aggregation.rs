@@ -0,0 +1,107 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::convert::Infallible;
+
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use futures_util::{stream, FutureExt};
+use http_body_util::{BodyExt, Limited, StreamBody};
+use hyper::body::Frame;
+use libsignal_net::infra::BufferPool;
+
+const CHUNKS_PER_BODY: usize = 4;
+const CHUNK_SIZE: usize = 256;
+
+/// A small response body as it arrives over HTTP/2, split into several data frames.
+fn small_body(
+    chunk: &Bytes,
+) -> StreamBody<impl futures_util::Stream<Item = Result<Frame<Bytes>, Infallible>>> {
+    let frames: Vec<_> = (0..CHUNKS_PER_BODY)
+        .map(|_| Ok(Frame::data(chunk.clone())))
+        .collect();
+    StreamBody::new(stream::iter(frames))
+}
+
+/// A small response body that arrives in a single data frame.
+fn single_frame_body(
+    body: &Bytes,
+) -> StreamBody<impl futures_util::Stream<Item = Result<Frame<Bytes>, Infallible>>> {
+    StreamBody::new(stream::iter([Ok(Frame::data(body.clone()))]))
+}
+
+pub fn aggregation(c: &mut Criterion) {
+    let chunk = Bytes::from(vec![0x5a; CHUNK_SIZE]);
+    let content_length = CHUNKS_PER_BODY * CHUNK_SIZE;
+    let whole_body = Bytes::from(vec![0x5a; content_length]);
+    let pool = BufferPool::new(1, 64 * 1024);
+
+    let mut group = c.benchmark_group("aggregate small responses");
+
+    group.bench_function("without pool", |b| {
+        b.iter_batched(
+            || small_body(&chunk),
+            |body| {
+                Limited::new(body, content_length)
+                    .collect()
+                    .now_or_never()
+                    .expect("sync")
+                    .expect("valid body")
+                    .to_bytes()
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.bench_function("with pool", |b| {
+        b.iter_batched(
+            || small_body(&chunk),
+            |body| {
+                pool.aggregate(body, content_length)
+                    .now_or_never()
+                    .expect("sync")
+                    .expect("valid body")
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.finish();
+
+    let mut group = c.benchmark_group("aggregate single-frame responses");
+
+    group.bench_function("without pool", |b| {
+        b.iter_batched(
+            || single_frame_body(&whole_body),
+            |body| {
+                Limited::new(body, content_length)
+                    .collect()
+                    .now_or_never()
+                    .expect("sync")
+                    .expect("valid body")
+                    .to_bytes()
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.bench_function("with pool", |b| {
+        b.iter_batched(
+            || single_frame_body(&whole_body),
+            |body| {
+                pool.aggregate(body, content_length)
+                    .now_or_never()
+                    .expect("sync")
+                    .expect("valid body")
+            },
+            BatchSize::SmallInput,
+        )
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, aggregation);
+criterion_main!(benches);
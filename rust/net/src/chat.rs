@@ -3,26 +3,37 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
-use crate::chat::errors::ChatNetworkError;
-use crate::chat::http::ChatOverHttp2ServiceConnector;
+use crate::chat::errors::{ChatNetworkError, ConnectAndSendError};
+use crate::chat::http::{ChatOverHttp2Config, ChatOverHttp2ServiceConnector};
 use crate::chat::ws::{ChatOverWebSocketServiceConnector, ChatOverWebsocketConfig, ServerRequest};
+use crate::infra::clock::{self, SystemClock};
 use crate::infra::connection_manager::{
-    MultiRouteConnectionManager, SingleRouteThrottlingConnectionManager,
+    RacingMultiRouteConnectionManager, SingleRouteThrottlingConnectionManager,
 };
-use crate::infra::reconnect::ServiceWithReconnect;
-use crate::infra::{ConnectionParams, HttpRequestDecorator};
+use crate::infra::reconnect::{ServiceConnector, ServiceWithReconnect};
+use crate::infra::{AuthStrategy, ConnectionParams};
 use crate::proto;
-use crate::utils::basic_authorization;
 use ::http::{HeaderName, HeaderValue};
 use async_trait::async_trait;
 use bytes::Bytes;
+use futures_util::future::join_all;
+use futures_util::{Stream, StreamExt};
+use std::collections::HashMap;
 use std::str::FromStr;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
 
+#[cfg(feature = "blocking")]
+pub mod blocking;
 pub mod chat_reconnect;
 pub mod errors;
+#[cfg(any(test, feature = "test-util"))]
+pub mod fake;
 pub mod http;
+pub mod path;
+pub(crate) mod spans;
 pub mod ws;
 
 pub type MessageProto = proto::chat_websocket::WebSocketMessage;
@@ -30,10 +41,158 @@ pub type RequestProto = proto::chat_websocket::WebSocketRequestMessage;
 pub type ResponseProto = proto::chat_websocket::WebSocketResponseMessage;
 pub type ChatMessageType = proto::chat_websocket::web_socket_message::Type;
 
+impl ResponseProto {
+    /// Returns response headers grouped by their lowercase name.
+    ///
+    /// On the wire, headers are represented as a list of `"name: value"` strings.
+    /// Multi-valued headers (e.g. `Set-Cookie`) keep all their values in the order
+    /// in which they were received. Entries that are not in the `"name: value"` form are skipped.
+    pub fn headers_map(&self) -> HashMap<String, Vec<String>> {
+        let mut headers_map: HashMap<String, Vec<String>> = HashMap::new();
+        for header_str in self.headers.iter() {
+            if let Some((name, value)) = header_str.split_once(':') {
+                headers_map
+                    .entry(name.trim().to_ascii_lowercase())
+                    .or_default()
+                    .push(value.trim().to_string());
+            }
+        }
+        headers_map
+    }
+
+    /// Returns the time on the server when the response was sent, from the `Date` header,
+    /// e.g. to check the freshness of time-limited tokens or to detect clock skew.
+    ///
+    /// Returns `None` if the header is missing or isn't a valid HTTP date.
+    pub fn server_time(&self) -> Option<SystemTime> {
+        let headers = self.headers_map();
+        let date = headers.get("date")?.first()?;
+        httpdate::parse_http_date(date).ok()
+    }
+
+    /// Returns the links to the next and previous pages of a paginated response,
+    /// from its `Link` headers (RFC 8288).
+    ///
+    /// Returns `None` if there are no such links. Link values that can't be parsed are skipped,
+    /// and if a relation is given more than once, its first link is used.
+    pub fn pagination(&self) -> Option<PaginationLinks> {
+        let headers = self.headers_map();
+        let mut pagination = PaginationLinks::default();
+        for (target, relations) in headers
+            .get("link")?
+            .iter()
+            .flat_map(|value| parse_link_header(value))
+        {
+            for relation in relations.split_ascii_whitespace() {
+                let link = if relation.eq_ignore_ascii_case("next") {
+                    &mut pagination.next
+                } else if relation.eq_ignore_ascii_case("prev")
+                    || relation.eq_ignore_ascii_case("previous")
+                {
+                    &mut pagination.prev
+                } else {
+                    continue;
+                };
+                link.get_or_insert_with(|| target.to_string());
+            }
+        }
+        (pagination != PaginationLinks::default()).then_some(pagination)
+    }
+}
+
+/// Links to the neighbouring pages of a paginated response, see [ResponseProto::pagination].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PaginationLinks {
+    /// Target of the `rel="next"` link
+    pub next: Option<String>,
+    /// Target of the `rel="prev"` (or `rel="previous"`) link
+    pub prev: Option<String>,
+}
+
+/// Parses the value of a `Link` header into the targets of its links along with
+/// their `rel` parameters, skipping the links that are malformed or have no `rel`.
+fn parse_link_header(value: &str) -> Vec<(&str, String)> {
+    split_unquoted(value, ',')
+        .into_iter()
+        .filter_map(|link| {
+            let (target, params) = link.trim().strip_prefix('<')?.split_once('>')?;
+            let relations = split_unquoted(params, ';').into_iter().find_map(|param| {
+                let (name, value) = param.split_once('=')?;
+                name.trim()
+                    .eq_ignore_ascii_case("rel")
+                    .then(|| unquote(value.trim()))
+            })?;
+            Some((target.trim(), relations))
+        })
+        .collect()
+}
+
+/// Splits `value` on the `separator`s that are neither in a quoted string nor in a `<...>`
+/// link target.
+fn split_unquoted(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = vec![];
+    let mut start = 0;
+    let mut in_quotes = false;
+    let mut in_target = false;
+    let mut escaped = false;
+    for (i, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' if !in_target => in_quotes = !in_quotes,
+            '<' if !in_quotes => in_target = true,
+            '>' if !in_quotes => in_target = false,
+            c if c == separator && !in_quotes && !in_target => {
+                parts.push(&value[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&value[start..]);
+    parts
+}
+
+/// Returns the contents of a quoted string with the escapes resolved, or `value` itself
+/// if it's not quoted.
+fn unquote(value: &str) -> String {
+    match value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+    {
+        Some(quoted) => {
+            let mut unquoted = String::with_capacity(quoted.len());
+            let mut chars = quoted.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => unquoted.extend(chars.next()),
+                    c => unquoted.push(c),
+                }
+            }
+            unquoted
+        }
+        None => value.to_string(),
+    }
+}
+
 const HTTP_ONLY_ENDPOINTS: [&str; 2] = ["/v1/accounts", "/v2/keys"];
 const ROUTE_CONNECTION_TIMEOUT: Duration = Duration::from_secs(1);
+const ROUTE_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
 const TOTAL_CONNECTION_TIMEOUT: Duration = Duration::from_secs(3);
 
+/// How urgently a request needs to be sent, relative to the other requests on the same connection.
+///
+/// See [ChatService::send_with_priority].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RequestPriority {
+    /// e.g. syncing or sending receipts
+    Background,
+    #[default]
+    Normal,
+    /// e.g. sending a message the user is waiting for
+    Interactive,
+}
+
 #[async_trait]
 pub trait ChatService {
     /// Sends request and get a response from the Chat Service.
@@ -50,6 +209,238 @@ pub trait ChatService {
         msg: &MessageProto,
         timeout: Duration,
     ) -> Result<ResponseProto, ChatNetworkError>;
+
+    /// Same as [ChatService::send], but the request has to complete by the given `deadline`
+    /// rather than within a duration.
+    ///
+    /// This allows several operations to share one overall time budget. If the `deadline`
+    /// has already passed, fails with [ChatNetworkError::Timeout] without sending the request.
+    async fn send_by(
+        &mut self,
+        msg: &MessageProto,
+        deadline: Instant,
+    ) -> Result<ResponseProto, ChatNetworkError> {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(ChatNetworkError::Timeout {
+                elapsed: Duration::ZERO,
+            });
+        }
+        self.send(msg, remaining).await
+    }
+
+    /// Same as [ChatService::send], but requests with a higher `priority` are sent first
+    /// when the connection is saturated.
+    ///
+    /// Requests with the same priority are sent in the order they were made. The default
+    /// implementation ignores the priority and calls [ChatService::send].
+    async fn send_with_priority(
+        &mut self,
+        msg: &MessageProto,
+        priority: RequestPriority,
+        timeout: Duration,
+    ) -> Result<ResponseProto, ChatNetworkError> {
+        let _ = priority;
+        self.send(msg, timeout).await
+    }
+
+    /// Sends request with a body produced by the given `body_stream`.
+    ///
+    /// The body of the request in `msg` (if any) is ignored. Transports that are capable of it
+    /// send the chunks as they are produced by the stream. The default implementation collects
+    /// the whole body first and then calls [ChatService::send].
+    async fn send_streaming<S>(
+        &mut self,
+        msg: &MessageProto,
+        body_stream: S,
+        timeout: Duration,
+    ) -> Result<ResponseProto, ChatNetworkError>
+    where
+        S: Stream<Item = Bytes> + Send + 'static,
+    {
+        let chunks: Vec<Bytes> = body_stream.collect().await;
+        let mut msg = msg.clone();
+        let req = msg
+            .request
+            .as_mut()
+            .ok_or(ChatNetworkError::UnexpectedMessageType)?;
+        req.body = Some(chunks.concat());
+        self.send(&msg, timeout).await
+    }
+
+    /// Same as [ChatService::send], but the given `extra_headers` are added to the request.
+    ///
+    /// If a header with the same name is already present in `msg`, the value
+    /// from `extra_headers` takes precedence. The default implementation merges the headers
+    /// into a copy of `msg` and then calls [ChatService::send].
+    async fn send_with_headers(
+        &mut self,
+        msg: &MessageProto,
+        extra_headers: &[(HeaderName, HeaderValue)],
+        timeout: Duration,
+    ) -> Result<ResponseProto, ChatNetworkError> {
+        let mut msg = msg.clone();
+        let req = msg
+            .request
+            .as_mut()
+            .ok_or(ChatNetworkError::UnexpectedMessageType)?;
+        merge_headers_into_proto(req, extra_headers)?;
+        self.send(&msg, timeout).await
+    }
+
+    /// Same as [ChatService::send], but the request body may be sent compressed, if the transport
+    /// is configured to compress request bodies.
+    ///
+    /// Only use this for bodies that don't hold secrets next to data that an attacker can
+    /// influence: the size of a compressed body reveals how much of it repeats, which lets
+    /// an attacker who sees the encrypted traffic guess the secrets (as in the BREACH attack).
+    /// The default implementation calls [ChatService::send].
+    async fn send_compressed(
+        &mut self,
+        msg: &MessageProto,
+        timeout: Duration,
+    ) -> Result<ResponseProto, ChatNetworkError> {
+        self.send(msg, timeout).await
+    }
+
+    /// Same as [ChatService::send], but the response body is passed to `on_chunk`
+    /// piece by piece as it's received, rather than returned in the [ResponseProto].
+    ///
+    /// The returned [ResponseProto] has no body. `on_chunk` is called on the task that reads
+    /// the response, so it must return quickly, otherwise it holds back the reading of the rest
+    /// of the response. The default implementation waits for the whole response and then
+    /// passes its body to `on_chunk` at once.
+    async fn send_with_chunks<F>(
+        &mut self,
+        msg: &MessageProto,
+        mut on_chunk: F,
+        timeout: Duration,
+    ) -> Result<ResponseProto, ChatNetworkError>
+    where
+        F: FnMut(&[u8]) + Send,
+    {
+        let mut response = self.send(msg, timeout).await?;
+        if let Some(body) = response.body.take() {
+            on_chunk(&body);
+        }
+        Ok(response)
+    }
+
+    /// Same as [ChatService::send], but the request can also be aborted by cancelling
+    /// the given `cancellation_token`, in which case [ChatNetworkError::Cancelled] is returned.
+    ///
+    /// Cancellation drops the in-flight request future. For HTTP/2 transports,
+    /// this resets the corresponding stream instead of leaving it dangling.
+    async fn send_cancellable(
+        &mut self,
+        msg: &MessageProto,
+        timeout: Duration,
+        cancellation_token: CancellationToken,
+    ) -> Result<ResponseProto, ChatNetworkError> {
+        tokio::select! {
+            result = self.send(msg, timeout) => result,
+            _ = cancellation_token.cancelled() => Err(ChatNetworkError::Cancelled),
+        }
+    }
+
+    /// Checks that the server is reachable by sending a lightweight request to [KEEPALIVE_PATH].
+    ///
+    /// Any response from the server counts as a success, regardless of its status.
+    /// Transports that keep track of their connection status mark the connection as closed
+    /// when the check fails, so that a dead connection is replaced as early as possible.
+    async fn keepalive(&mut self, timeout: Duration) -> Result<(), ChatNetworkError> {
+        self.send(&keepalive_request(), timeout).await.map(|_| ())
+    }
+}
+
+pub const KEEPALIVE_PATH: &str = "/v1/keepalive";
+
+/// Sends all the `messages` at the same time, each over its own clone of `service`,
+/// and returns their results in the same order as the `messages`.
+///
+/// All the requests have to complete by the same `deadline`, see [ChatService::send_by].
+/// A failed request doesn't affect the others.
+pub async fn send_batch<S>(
+    service: &S,
+    messages: &[MessageProto],
+    deadline: Instant,
+) -> Vec<Result<ResponseProto, ChatNetworkError>>
+where
+    S: ChatService + Clone + Send,
+{
+    join_all(messages.iter().map(|msg| {
+        let mut service = service.clone();
+        async move { service.send_by(msg, deadline).await }
+    }))
+    .await
+}
+
+/// Connects with the `connector`, sends `msg` over the new connection, and closes the connection
+/// once the response is received, all by the given `deadline`.
+///
+/// The time it takes to connect is taken from the time left for the request, so the whole
+/// operation is bounded by the `deadline`, see [ChatService::send_by]. Cancelling
+/// `cancellation_token` aborts whichever stage is in progress.
+pub(crate) async fn connect_and_send_by<C>(
+    connector: &C,
+    connection_params: &ConnectionParams,
+    msg: &MessageProto,
+    deadline: Instant,
+    cancellation_token: CancellationToken,
+) -> Result<ResponseProto, ConnectAndSendError>
+where
+    C: ServiceConnector<Error = ChatNetworkError> + Sync,
+    C::Service: ChatService + Send,
+{
+    let start = Instant::now();
+    let connect = clock::timeout_at(
+        &SystemClock,
+        deadline,
+        connector.connect_channel(connection_params),
+    );
+    let channel = tokio::select! {
+        result = connect => match result {
+            Some(result) => result.map_err(ConnectAndSendError::Connect)?,
+            None => {
+                return Err(ConnectAndSendError::Connect(ChatNetworkError::Timeout {
+                    elapsed: start.elapsed(),
+                }))
+            }
+        },
+        _ = cancellation_token.cancelled() => return Err(ConnectAndSendError::Cancelled),
+    };
+    let (mut service, service_status) = connector.start_service(channel);
+
+    let result = tokio::select! {
+        result = service.send_by(msg, deadline) => result.map_err(ConnectAndSendError::Send),
+        _ = cancellation_token.cancelled() => Err(ConnectAndSendError::Cancelled),
+    };
+    service_status.stop_service();
+    result
+}
+
+/// Logs the error of a failed request along with the request `id`, so that failures
+/// of concurrent requests can be told apart.
+pub(crate) fn log_request_failure<T>(msg: &MessageProto, result: &Result<T, ChatNetworkError>) {
+    if let Err(error) = result {
+        match msg.request.as_ref().and_then(|req| req.id) {
+            Some(id) => log::debug!("request {id} failed: {error}"),
+            None => log::debug!("request failed: {error}"),
+        }
+    }
+}
+
+pub(crate) fn keepalive_request() -> MessageProto {
+    MessageProto {
+        r#type: Some(ChatMessageType::Request.into()),
+        request: Some(RequestProto {
+            id: Some(rand::random()),
+            verb: Some("GET".to_string()),
+            path: Some(KEEPALIVE_PATH.to_string()),
+            ..Default::default()
+        }),
+        response: None,
+    }
 }
 
 pub struct Chat<AuthService, UnauthService> {
@@ -151,6 +542,15 @@ where
             self.send_ws(msg, timeout).await
         }
     }
+
+    async fn keepalive(&mut self, timeout: Duration) -> Result<(), ChatNetworkError> {
+        match self.ws_service.keepalive(timeout).await {
+            Err(ChatNetworkError::NoServiceConnection) => {
+                self.http_service.keepalive(timeout).await
+            }
+            result => result,
+        }
+    }
 }
 
 fn is_http_only_request(req: &RequestProto) -> bool {
@@ -161,6 +561,39 @@ fn is_http_only_request(req: &RequestProto) -> bool {
     })
 }
 
+/// Replaces all headers of the `req` that have the same name as one of the `extra_headers`
+/// with the corresponding value from `extra_headers`.
+fn merge_headers_into_proto(
+    req: &mut RequestProto,
+    extra_headers: &[(HeaderName, HeaderValue)],
+) -> Result<(), ChatNetworkError> {
+    for (name, value) in extra_headers {
+        let value = value
+            .to_str()
+            .map_err(|_| ChatNetworkError::RequestHeaderInvalid)?;
+        req.headers.retain(|header_str| {
+            header_str.split_once(':').map_or(true, |(key, _)| {
+                !key.trim().eq_ignore_ascii_case(name.as_str())
+            })
+        });
+        req.headers.push(format!("{}:{}", name, value));
+    }
+    Ok(())
+}
+
+/// Adds the `extra_headers` to the request being built, replacing the headers with
+/// the same name that are already there.
+pub(crate) fn add_extra_headers(
+    mut builder: ::http::request::Builder,
+    extra_headers: &[(HeaderName, HeaderValue)],
+) -> ::http::request::Builder {
+    let headers_map = builder.headers_mut().expect("have headers");
+    for (name, value) in extra_headers {
+        headers_map.insert(name.clone(), value.clone());
+    }
+    builder
+}
+
 pub(crate) fn proto_to_request(
     req: &RequestProto,
 ) -> Result<(String, ::http::request::Builder, Bytes), ChatNetworkError> {
@@ -172,6 +605,7 @@ pub(crate) fn proto_to_request(
         } => Ok((v, p, &req.headers, &req.body)),
         _ => Err(ChatNetworkError::RequestMissingVerbOrPath),
     }?;
+    path::validate(path)?;
 
     let method = ::http::method::Method::from_str(verb.as_str())
         .map_err(|_| ChatNetworkError::UnknownVerbInRequest)?;
@@ -187,8 +621,8 @@ pub(crate) fn proto_to_request(
     for header_str in headers.iter() {
         if let Some((key, value)) = header_str.split_once(':') {
             headers_map.insert(
-                HeaderName::from_str(key).expect("can parse header name"),
-                HeaderValue::from_str(value).expect("can parse header value"),
+                HeaderName::from_str(key).map_err(|_| ChatNetworkError::RequestHeaderInvalid)?,
+                HeaderValue::from_str(value).map_err(|_| ChatNetworkError::RequestHeaderInvalid)?,
             );
         }
     }
@@ -212,6 +646,10 @@ where
     ) -> Result<ResponseProto, ChatNetworkError> {
         self.inner.send(msg, timeout).await
     }
+
+    async fn keepalive(&mut self, timeout: Duration) -> Result<(), ChatNetworkError> {
+        self.inner.keepalive(timeout).await
+    }
 }
 
 pub struct AuthorizedChatService<T> {
@@ -230,6 +668,10 @@ where
     ) -> Result<ResponseProto, ChatNetworkError> {
         self.inner.send(msg, timeout).await
     }
+
+    async fn keepalive(&mut self, timeout: Duration) -> Result<(), ChatNetworkError> {
+        self.inner.keepalive(timeout).await
+    }
 }
 
 fn build_authorized_chat_service(
@@ -242,11 +684,7 @@ fn build_authorized_chat_service(
     let connection_params_list_auth: Vec<ConnectionParams> = connection_params_list
         .iter()
         .cloned()
-        .map(|cp| {
-            cp.with_decorator(HttpRequestDecorator::HeaderAuth(basic_authorization(
-                &username, &password,
-            )))
-        })
+        .map(|cp| cp.with_auth(AuthStrategy::Basic(username.clone(), password.clone())))
         .collect();
 
     // http authorized
@@ -305,7 +743,7 @@ pub(crate) fn chat_service(
 ) -> Chat<impl ChatService, impl ChatService> {
     let service_connector_ws =
         ChatOverWebSocketServiceConnector::new(ChatOverWebsocketConfig::default(), incoming_tx);
-    let service_connector_http = ChatOverHttp2ServiceConnector {};
+    let service_connector_http = ChatOverHttp2ServiceConnector::new(ChatOverHttp2Config::default());
 
     Chat::new(
         build_authorized_chat_service(
@@ -323,10 +761,568 @@ pub(crate) fn chat_service(
     )
 }
 
-fn multi_route_manager(routes: &[ConnectionParams]) -> MultiRouteConnectionManager {
+fn multi_route_manager(routes: &[ConnectionParams]) -> RacingMultiRouteConnectionManager {
     let single_route_managers = routes
         .iter()
         .map(|cp| SingleRouteThrottlingConnectionManager::new(cp.clone(), ROUTE_CONNECTION_TIMEOUT))
         .collect();
-    MultiRouteConnectionManager::new(single_route_managers, TOTAL_CONNECTION_TIMEOUT)
+    RacingMultiRouteConnectionManager::new(
+        single_route_managers,
+        ROUTE_ATTEMPT_DELAY,
+        TOTAL_CONNECTION_TIMEOUT,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use assert_matches::assert_matches;
+    use async_trait::async_trait;
+    use tokio::time::Instant;
+    use tokio_util::sync::CancellationToken;
+
+    use crate::chat::errors::{ChatNetworkError, ConnectAndSendError};
+    use crate::chat::fake::FakeChatService;
+    use crate::chat::{
+        add_extra_headers, connect_and_send_by, merge_headers_into_proto, proto_to_request,
+        send_batch, ChatService, MessageProto, PaginationLinks, RequestProto, ResponseProto,
+        KEEPALIVE_PATH,
+    };
+    use crate::infra::reconnect::{ServiceConnector, ServiceStatus};
+    use crate::infra::ConnectionParams;
+    use ::http::{HeaderName, HeaderValue};
+    use proptest::prelude::*;
+
+    struct NeverRespondingChatService;
+
+    #[async_trait]
+    impl ChatService for NeverRespondingChatService {
+        async fn send(
+            &mut self,
+            _msg: &MessageProto,
+            _timeout: Duration,
+        ) -> Result<ResponseProto, ChatNetworkError> {
+            std::future::pending().await
+        }
+    }
+
+    #[derive(Default)]
+    struct TimeoutRecordingChatService {
+        timeouts: Vec<Duration>,
+    }
+
+    #[async_trait]
+    impl ChatService for TimeoutRecordingChatService {
+        async fn send(
+            &mut self,
+            _msg: &MessageProto,
+            timeout: Duration,
+        ) -> Result<ResponseProto, ChatNetworkError> {
+            self.timeouts.push(timeout);
+            Ok(ResponseProto::default())
+        }
+    }
+
+    struct RespondingChatService {
+        status: u32,
+        requests: Vec<RequestProto>,
+    }
+
+    #[async_trait]
+    impl ChatService for RespondingChatService {
+        async fn send(
+            &mut self,
+            msg: &MessageProto,
+            _timeout: Duration,
+        ) -> Result<ResponseProto, ChatNetworkError> {
+            let request = msg.request.clone().expect("request");
+            let response = ResponseProto {
+                id: request.id,
+                status: Some(self.status),
+                ..Default::default()
+            };
+            self.requests.push(request);
+            Ok(response)
+        }
+    }
+
+    #[test]
+    fn extra_headers_override_proto_headers() {
+        let mut req = RequestProto {
+            verb: Some("GET".to_string()),
+            path: Some("/v1/profile".to_string()),
+            headers: vec![
+                "X-Trace-Id:old".to_string(),
+                "Content-Type:application/json".to_string(),
+            ],
+            ..Default::default()
+        };
+        let extra_headers = [
+            (
+                HeaderName::from_static("x-trace-id"),
+                HeaderValue::from_static("new"),
+            ),
+            (
+                HeaderName::from_static("authorization"),
+                HeaderValue::from_static("Bearer token"),
+            ),
+        ];
+
+        merge_headers_into_proto(&mut req, &extra_headers).unwrap();
+        assert_eq!(
+            req.headers,
+            vec![
+                "Content-Type:application/json",
+                "x-trace-id:new",
+                "authorization:Bearer token"
+            ]
+        );
+
+        let (_, builder, _) = proto_to_request(&req).unwrap();
+        let headers = builder.headers_ref().unwrap();
+        assert_eq!(headers.get("x-trace-id").unwrap(), "new");
+        assert_eq!(headers.get("authorization").unwrap(), "Bearer token");
+    }
+
+    #[test]
+    fn extra_headers_override_builder_headers() {
+        let builder = ::http::request::Request::builder().header("x-trace-id", "old");
+        let builder = add_extra_headers(
+            builder,
+            &[(
+                HeaderName::from_static("x-trace-id"),
+                HeaderValue::from_static("new"),
+            )],
+        );
+        let headers = builder.headers_ref().unwrap();
+        assert_eq!(headers.get_all("x-trace-id").iter().count(), 1);
+        assert_eq!(headers.get("x-trace-id").unwrap(), "new");
+    }
+
+    #[tokio::test]
+    async fn send_cancellable_returns_cancelled() {
+        let cancellation_token = CancellationToken::new();
+        let mut service = NeverRespondingChatService;
+        let send_future = service.send_cancellable(
+            &MessageProto::default(),
+            Duration::from_secs(1),
+            cancellation_token.clone(),
+        );
+        cancellation_token.cancel();
+        assert_matches!(send_future.await, Err(ChatNetworkError::Cancelled));
+    }
+
+    #[tokio::test]
+    async fn send_batch_returns_results_in_order_despite_failures() {
+        let fake = FakeChatService::new();
+        for (path, status) in [("/v1/a", 200), ("/v1/c", 404)] {
+            fake.push_response(
+                path,
+                ResponseProto {
+                    status: Some(status),
+                    ..Default::default()
+                },
+            );
+        }
+        fake.push_error("/v1/d", ChatNetworkError::ChannelClosedByRemotePeer);
+        let messages: Vec<MessageProto> = ["/v1/a", "/v1/b", "/v1/c", "/v1/d"]
+            .into_iter()
+            .enumerate()
+            .map(|(id, path)| MessageProto {
+                request: Some(RequestProto {
+                    id: Some(id as u64),
+                    verb: Some("GET".to_string()),
+                    path: Some(path.to_string()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            })
+            .collect();
+
+        let results = send_batch(&fake, &messages, Instant::now() + Duration::from_secs(1)).await;
+
+        assert_matches!(
+            &results[..],
+            [
+                Ok(ResponseProto {
+                    id: Some(0),
+                    status: Some(200),
+                    ..
+                }),
+                // no response was scripted for /v1/b
+                Err(ChatNetworkError::ResponseNotReceived),
+                Ok(ResponseProto {
+                    id: Some(2),
+                    status: Some(404),
+                    ..
+                }),
+                Err(ChatNetworkError::ChannelClosedByRemotePeer),
+            ]
+        );
+        assert!(fake.all_responses_consumed());
+    }
+
+    #[tokio::test]
+    async fn send_batch_fails_all_requests_after_deadline() {
+        let fake = FakeChatService::new();
+        let messages = vec![MessageProto::default(); 2];
+
+        let results = send_batch(&fake, &messages, Instant::now()).await;
+
+        assert_matches!(
+            &results[..],
+            [
+                Err(ChatNetworkError::Timeout { .. }),
+                Err(ChatNetworkError::Timeout { .. })
+            ]
+        );
+        assert!(fake.sent_messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn keepalive_sends_get_request_and_accepts_any_status() {
+        let mut service = RespondingChatService {
+            status: 404,
+            requests: vec![],
+        };
+        service.keepalive(Duration::from_secs(1)).await.unwrap();
+
+        assert_eq!(service.requests.len(), 1);
+        let request = &service.requests[0];
+        assert_eq!(request.verb.as_deref(), Some("GET"));
+        assert_eq!(request.path.as_deref(), Some(KEEPALIVE_PATH));
+        assert!(request.id.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn send_by_uses_remaining_time_until_deadline() {
+        let mut service = TimeoutRecordingChatService::default();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        tokio::time::advance(Duration::from_secs(2)).await;
+
+        service
+            .send_by(&MessageProto::default(), deadline)
+            .await
+            .expect("sent");
+        assert_eq!(service.timeouts, vec![Duration::from_secs(3)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn send_by_fails_right_away_after_deadline() {
+        let mut service = RespondingChatService {
+            status: 200,
+            requests: vec![],
+        };
+        let deadline = Instant::now();
+        tokio::time::advance(Duration::from_millis(1)).await;
+
+        assert_matches!(
+            service.send_by(&MessageProto::default(), deadline).await,
+            Err(ChatNetworkError::Timeout { elapsed }) if elapsed.is_zero()
+        );
+        assert!(service.requests.is_empty());
+    }
+
+    /// Connects after `connect_delay`, to a service that responds after `response_delay`
+    /// unless the request times out first.
+    struct SlowServiceConnector {
+        connect_delay: Duration,
+        response_delay: Duration,
+    }
+
+    #[derive(Clone)]
+    struct SlowChatService {
+        response_delay: Duration,
+    }
+
+    #[async_trait]
+    impl ChatService for SlowChatService {
+        async fn send(
+            &mut self,
+            _msg: &MessageProto,
+            timeout: Duration,
+        ) -> Result<ResponseProto, ChatNetworkError> {
+            tokio::time::timeout(timeout, tokio::time::sleep(self.response_delay))
+                .await
+                .map_err(|_| ChatNetworkError::Timeout { elapsed: timeout })?;
+            Ok(ResponseProto {
+                status: Some(200),
+                ..Default::default()
+            })
+        }
+    }
+
+    #[async_trait]
+    impl ServiceConnector for SlowServiceConnector {
+        type Service = SlowChatService;
+        type Channel = ();
+        type Error = ChatNetworkError;
+
+        async fn connect_channel(
+            &self,
+            _connection_params: &ConnectionParams,
+        ) -> Result<Self::Channel, Self::Error> {
+            tokio::time::sleep(self.connect_delay).await;
+            Ok(())
+        }
+
+        fn start_service(
+            &self,
+            _channel: Self::Channel,
+        ) -> (Self::Service, ServiceStatus<Self::Error>) {
+            let service = SlowChatService {
+                response_delay: self.response_delay,
+            };
+            (service, ServiceStatus::new())
+        }
+    }
+
+    async fn connect_and_send_within(
+        total: Duration,
+        connect_delay: Duration,
+        response_delay: Duration,
+    ) -> Result<ResponseProto, ConnectAndSendError> {
+        let connector = SlowServiceConnector {
+            connect_delay,
+            response_delay,
+        };
+        connect_and_send_by(
+            &connector,
+            &crate::env::STAGING.chat_direct_connection(),
+            &MessageProto::default(),
+            Instant::now() + total,
+            CancellationToken::new(),
+        )
+        .await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connect_and_send_by_shares_deadline_between_connect_and_send() {
+        let start = Instant::now();
+        let result = connect_and_send_within(
+            Duration::from_secs(5),
+            Duration::from_secs(1),
+            Duration::from_secs(2),
+        )
+        .await;
+        assert_matches!(result, Ok(response) if response.status == Some(200));
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+
+        // each stage would fit in the total on its own, but not both of them
+        let start = Instant::now();
+        let result = connect_and_send_within(
+            Duration::from_secs(5),
+            Duration::from_secs(4),
+            Duration::from_secs(2),
+        )
+        .await;
+        assert_matches!(
+            result,
+            Err(ConnectAndSendError::Send(ChatNetworkError::Timeout { elapsed }))
+                if elapsed == Duration::from_secs(1)
+        );
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connect_and_send_by_times_out_while_connecting() {
+        let start = Instant::now();
+        let result = connect_and_send_within(
+            Duration::from_secs(5),
+            Duration::from_secs(6),
+            Duration::ZERO,
+        )
+        .await;
+        assert_matches!(
+            result,
+            Err(ConnectAndSendError::Connect(ChatNetworkError::Timeout { elapsed }))
+                if elapsed == Duration::from_secs(5)
+        );
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn headers_map_groups_multi_valued_headers() {
+        let response = ResponseProto {
+            id: Some(1),
+            status: Some(200),
+            message: Some("OK".to_string()),
+            headers: vec![
+                "content-type: application/json".to_string(),
+                "Set-Cookie: a=1".to_string(),
+                "set-cookie: b=2".to_string(),
+                "malformed".to_string(),
+            ],
+            body: None,
+            trailers: vec![],
+        };
+        let headers = response.headers_map();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(
+            headers.get("content-type"),
+            Some(&vec!["application/json".to_string()])
+        );
+        assert_eq!(
+            headers.get("set-cookie"),
+            Some(&vec!["a=1".to_string(), "b=2".to_string()])
+        );
+    }
+
+    #[test]
+    fn server_time_is_parsed_from_date_header() {
+        let response_with_headers = |headers: &[&str]| ResponseProto {
+            headers: headers.iter().map(|h| h.to_string()).collect(),
+            ..Default::default()
+        };
+        let expected = Some(SystemTime::UNIX_EPOCH + Duration::from_secs(784111777));
+        for date in [
+            // IMF-fixdate
+            "Sun, 06 Nov 1994 08:49:37 GMT",
+            // obsolete RFC 850 format
+            "Sunday, 06-Nov-94 08:49:37 GMT",
+            // ANSI C's asctime() format
+            "Sun Nov  6 08:49:37 1994",
+        ] {
+            let response = response_with_headers(&[&format!("Date: {date}")]);
+            assert_eq!(response.server_time(), expected, "{date}");
+        }
+
+        assert_eq!(response_with_headers(&[]).server_time(), None);
+        assert_eq!(
+            response_with_headers(&["date: yesterday"]).server_time(),
+            None
+        );
+    }
+
+    #[test]
+    fn pagination_links_are_parsed_from_link_headers() {
+        let response_with_headers = |headers: &[&str]| ResponseProto {
+            headers: headers.iter().map(|h| h.to_string()).collect(),
+            ..Default::default()
+        };
+        let links = |next: Option<&str>, prev: Option<&str>| {
+            Some(PaginationLinks {
+                next: next.map(str::to_string),
+                prev: prev.map(str::to_string),
+            })
+        };
+
+        // several relations in one header, with other parameters and relations around them
+        let response = response_with_headers(&[
+            r#"Link: </v1/items?page=3>; rel="next", </v1/items?page=1>; title="a; b, c"; rel=prev, </v1/items?page=9>; rel="last""#,
+        ]);
+        assert_eq!(
+            response.pagination(),
+            links(Some("/v1/items?page=3"), Some("/v1/items?page=1"))
+        );
+
+        // one link with several relations, split across headers, with the first link winning
+        let response = response_with_headers(&[
+            r#"link: <https://chat.signal.org/v1/items?page=2>; REL="first Previous""#,
+            "Link: </v1/items?page=4>; rel=next",
+            "Link: </v1/items?page=5>; rel=next",
+        ]);
+        assert_eq!(
+            response.pagination(),
+            links(
+                Some("/v1/items?page=4"),
+                Some("https://chat.signal.org/v1/items?page=2")
+            )
+        );
+
+        // malformed values are skipped
+        let response = response_with_headers(&[
+            r#"Link: /v1/items?page=0; rel="prev", <a,b>; rel="next", <broken; rel="prev""#,
+        ]);
+        assert_eq!(response.pagination(), links(Some("a,b"), None));
+
+        assert_eq!(response_with_headers(&[]).pagination(), None);
+        assert_eq!(
+            response_with_headers(&["Link: </v1/items?page=9>; rel=last"]).pagination(),
+            None
+        );
+    }
+
+    #[test]
+    fn invalid_request_headers_are_rejected() {
+        for header in ["bad name:value", "name:bad\nvalue"] {
+            let req = RequestProto {
+                verb: Some("GET".to_string()),
+                path: Some("/v1/test".to_string()),
+                headers: vec![header.to_string()],
+                ..Default::default()
+            };
+            assert_matches!(
+                proto_to_request(&req),
+                Err(ChatNetworkError::RequestHeaderInvalid)
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_request_paths_are_rejected() {
+        for path in ["v1/test", "/v1/a b", "/v1/\u{7f}"] {
+            let req = RequestProto {
+                verb: Some("GET".to_string()),
+                path: Some(path.to_string()),
+                ..Default::default()
+            };
+            assert_matches!(
+                proto_to_request(&req),
+                Err(ChatNetworkError::RequestPathInvalid)
+            );
+        }
+    }
+
+    fn arbitrary_request() -> impl Strategy<Value = RequestProto> {
+        let verb = prop_oneof![
+            prop::sample::select(vec!["GET", "PUT", "POST", "DELETE", "PATCH"])
+                .prop_map(str::to_string),
+            any::<String>(),
+        ];
+        let header = prop_oneof![
+            ("[a-zA-Z0-9-]{1,16}", "[ -~]{0,32}")
+                .prop_map(|(name, value)| format!("{name}:{value}")),
+            any::<String>(),
+        ];
+        (
+            proptest::option::of(verb),
+            proptest::option::of(any::<String>()),
+            proptest::option::of(proptest::collection::vec(any::<u8>(), 0..64)),
+            proptest::collection::vec(header, 0..8),
+            proptest::option::of(any::<u64>()),
+        )
+            .prop_map(|(verb, path, body, headers, id)| RequestProto {
+                verb,
+                path,
+                body,
+                headers,
+                id,
+            })
+    }
+
+    #[test]
+    fn proto_to_request_never_panics() {
+        proptest!(|(req in arbitrary_request())| {
+            match proto_to_request(&req) {
+                Ok((path, builder, body)) => {
+                    prop_assert_eq!(Some(path), req.path);
+                    prop_assert_eq!(body.to_vec(), req.body.unwrap_or_default());
+                    prop_assert!(builder.body(()).is_ok());
+                }
+                Err(e) => prop_assert!(
+                    matches!(
+                        e,
+                        ChatNetworkError::RequestMissingVerbOrPath
+                            | ChatNetworkError::UnknownVerbInRequest
+                            | ChatNetworkError::RequestHeaderInvalid
+                            | ChatNetworkError::RequestPathInvalid
+                    ),
+                    "unexpected error: {}",
+                    e
+                ),
+            }
+        });
+    }
 }
@@ -0,0 +1,114 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::time::Duration;
+
+use tokio::runtime::Runtime;
+
+use crate::chat::errors::ChatNetworkError;
+use crate::chat::{ChatService, MessageProto, ResponseProto};
+
+/// A blocking wrapper around a [ChatService], for callers that don't have an async runtime.
+///
+/// Requests are sent on a runtime owned by the wrapper, which also runs the background tasks
+/// of the wrapped service (e.g. the one driving the HTTP/2 connection of
+/// [ChatOverHttp2](crate::chat::http::ChatOverHttp2)). Such a service has to be connected on
+/// that runtime as well, so it should be created with [ChatServiceBlocking::with_runtime]:
+///
+/// ```ignore
+/// let runtime = tokio::runtime::Builder::new_multi_thread()
+///     .worker_threads(1)
+///     .enable_all()
+///     .build()?;
+/// let service = runtime.block_on(connector.connect(&connection_params))?;
+/// let mut chat = ChatServiceBlocking::with_runtime(runtime, service);
+/// let response = chat.send(&request, Duration::from_secs(10))?;
+/// ```
+///
+/// The wrapper must not be used, nor dropped, from within an async context: tokio panics
+/// when a runtime is blocked on or shut down from within another one.
+pub struct ChatServiceBlocking<T> {
+    // Declared before the runtime so that the service is dropped while the runtime
+    // is still around.
+    service: T,
+    runtime: Runtime,
+}
+
+impl<T: ChatService> ChatServiceBlocking<T> {
+    /// Wraps a `service` that doesn't depend on any runtime, creating a dedicated one
+    /// for it with a single worker thread.
+    pub fn new(service: T) -> std::io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("libsignal-net-blocking")
+            .enable_all()
+            .build()?;
+        Ok(Self::with_runtime(runtime, service))
+    }
+
+    /// Wraps a `service` that was created on the given `runtime`.
+    pub fn with_runtime(runtime: Runtime, service: T) -> Self {
+        Self { service, runtime }
+    }
+
+    /// Sends `msg` with [ChatService::send], blocking the current thread until the response
+    /// is received or the request fails.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called from within an async context.
+    pub fn send(
+        &mut self,
+        msg: &MessageProto,
+        timeout: Duration,
+    ) -> Result<ResponseProto, ChatNetworkError> {
+        let service = &mut self.service;
+        self.runtime.block_on(service.send(msg, timeout))
+    }
+
+    /// Returns the wrapped service, e.g. to call its methods that are not async.
+    pub fn service(&self) -> &T {
+        &self.service
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::chat::blocking::ChatServiceBlocking;
+    use crate::chat::fake::FakeChatService;
+    use crate::chat::{MessageProto, RequestProto, ResponseProto};
+
+    #[test]
+    fn send_blocks_until_response_is_received() {
+        let fake = FakeChatService::new();
+        fake.push_response(
+            "/v1/test",
+            ResponseProto {
+                status: Some(200),
+                ..Default::default()
+            },
+        );
+
+        let response = std::thread::spawn(move || {
+            let mut chat = ChatServiceBlocking::new(fake).expect("runtime is created");
+            let msg = MessageProto {
+                request: Some(RequestProto {
+                    verb: Some("GET".to_string()),
+                    path: Some("/v1/test".to_string()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+            chat.send(&msg, Duration::from_secs(5))
+        })
+        .join()
+        .expect("thread doesn't panic")
+        .expect("response is received");
+
+        assert_eq!(response.status, Some(200));
+    }
+}
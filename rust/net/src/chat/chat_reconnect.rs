@@ -4,15 +4,22 @@
 //
 
 use std::fmt::Debug;
+use std::future::Future;
+use std::pin::pin;
+use std::str::FromStr;
+use std::task::Poll;
 use std::time::Duration;
 
 use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::Stream;
+use http::{HeaderName, HeaderValue, Method};
 
 use crate::chat::errors::ChatNetworkError;
-use crate::chat::{ChatService, MessageProto, ResponseProto};
+use crate::chat::{ChatService, MessageProto, RequestPriority, ResponseProto};
 use crate::infra::connection_manager::ConnectionManager;
 use crate::infra::errors::LogSafeDisplay;
-use crate::infra::reconnect::{ServiceConnector, ServiceWithReconnect};
+use crate::infra::reconnect::{SendQueueError, ServiceConnector, ServiceWithReconnect};
 
 #[async_trait]
 impl<C, M> ChatService for ServiceWithReconnect<C, M>
@@ -28,10 +35,564 @@ where
         msg: &MessageProto,
         timeout: Duration,
     ) -> Result<ResponseProto, ChatNetworkError> {
+        self.send_reconnecting(
+            msg,
+            timeout,
+            |mut s| async move { s.send(msg, timeout).await },
+        )
+        .await
+    }
+
+    async fn send_with_priority(
+        &mut self,
+        msg: &MessageProto,
+        priority: RequestPriority,
+        timeout: Duration,
+    ) -> Result<ResponseProto, ChatNetworkError> {
+        self.send_reconnecting(msg, timeout, |mut s| async move {
+            s.send_with_priority(msg, priority, timeout).await
+        })
+        .await
+    }
+
+    async fn send_streaming<S>(
+        &mut self,
+        msg: &MessageProto,
+        body_stream: S,
+        timeout: Duration,
+    ) -> Result<ResponseProto, ChatNetworkError>
+    where
+        S: Stream<Item = Bytes> + Send + 'static,
+    {
+        let service = self.service_clone().await;
+        match service {
+            Some(mut s) => s.send_streaming(msg, body_stream, timeout).await,
+            None => Err(ChatNetworkError::NoServiceConnection),
+        }
+    }
+
+    async fn send_with_headers(
+        &mut self,
+        msg: &MessageProto,
+        extra_headers: &[(HeaderName, HeaderValue)],
+        timeout: Duration,
+    ) -> Result<ResponseProto, ChatNetworkError> {
+        self.send_reconnecting(msg, timeout, |mut s| async move {
+            s.send_with_headers(msg, extra_headers, timeout).await
+        })
+        .await
+    }
+
+    async fn send_compressed(
+        &mut self,
+        msg: &MessageProto,
+        timeout: Duration,
+    ) -> Result<ResponseProto, ChatNetworkError> {
+        self.send_reconnecting(msg, timeout, |mut s| async move {
+            s.send_compressed(msg, timeout).await
+        })
+        .await
+    }
+
+    async fn send_with_chunks<F>(
+        &mut self,
+        msg: &MessageProto,
+        on_chunk: F,
+        timeout: Duration,
+    ) -> Result<ResponseProto, ChatNetworkError>
+    where
+        F: FnMut(&[u8]) + Send,
+    {
+        let service = self.service_clone().await;
+        match service {
+            Some(mut s) => s.send_with_chunks(msg, on_chunk, timeout).await,
+            None => Err(ChatNetworkError::NoServiceConnection),
+        }
+    }
+
+    async fn keepalive(&mut self, timeout: Duration) -> Result<(), ChatNetworkError> {
         let service = self.service_clone().await;
         match service {
-            Some(mut s) => s.send(msg, timeout).await,
+            Some(mut s) => s.keepalive(timeout).await,
             None => Err(ChatNetworkError::NoServiceConnection),
         }
     }
 }
+
+impl<C, M> ServiceWithReconnect<C, M>
+where
+    M: ConnectionManager + 'static,
+    C: ServiceConnector + Send + Sync + 'static,
+    C::Service: ChatService + Clone + Sync + Send + 'static,
+    C::Channel: Send + Sync,
+    C::Error: Send + Sync + Debug + LogSafeDisplay,
+{
+    /// Sends `msg` using `send_attempt`, reconnecting and re-sending it once
+    /// if the connection turns out to be closed.
+    ///
+    /// Only requests with idempotent methods are re-sent, since a request
+    /// that fails this way may still have been processed by the server.
+    /// Re-sending takes a token from the retry budget, if the service has one.
+    ///
+    /// If the service has a send queue, the request waits in it while the service
+    /// is reconnecting, for at most `timeout`. It leaves the queue once it's dispatched to
+    /// the connection, i.e. after `send_attempt` was first polled, rather than once it's
+    /// answered, so the requests queued after it don't wait for its response.
+    async fn send_reconnecting<F, Fut>(
+        &mut self,
+        msg: &MessageProto,
+        timeout: Duration,
+        mut send_attempt: F,
+    ) -> Result<ResponseProto, ChatNetworkError>
+    where
+        F: FnMut(C::Service) -> Fut + Send,
+        Fut: Future<Output = Result<ResponseProto, ChatNetworkError>> + Send,
+    {
+        let queue_ticket = self
+            .wait_in_send_queue(is_idempotent(msg), timeout)
+            .await
+            .map_err(|e| match e {
+                SendQueueError::Full => ChatNetworkError::QueueFull,
+                SendQueueError::TimedOut => ChatNetworkError::Timeout { elapsed: timeout },
+            })?;
+        let service = match self.service_clone().await {
+            Some(s) => s,
+            None => return Err(ChatNetworkError::NoServiceConnection),
+        };
+        let first_attempt = {
+            let mut attempt = pin!(send_attempt(service));
+            match futures_util::poll!(&mut attempt) {
+                Poll::Ready(result) => result,
+                Poll::Pending => {
+                    drop(queue_ticket);
+                    attempt.await
+                }
+            }
+        };
+        match first_attempt {
+            Err(e) if is_connection_closed(&e) && is_idempotent(msg) => {
+                if !self.try_acquire_retry() {
+                    log::info!("connection closed while sending a request, retry budget exhausted");
+                    return Err(e);
+                }
+                log::info!(
+                    "connection closed while sending a request, reconnecting: {}",
+                    e
+                );
+                match self.service_clone().await {
+                    Some(service) => send_attempt(service).await,
+                    None => Err(ChatNetworkError::NoServiceConnection),
+                }
+            }
+            result => result,
+        }
+    }
+}
+
+/// Whether a request failed because the connection it was sent over is gone,
+/// so that a new connection needs to be established to send it again.
+fn is_connection_closed(error: &ChatNetworkError) -> bool {
+    matches!(
+        error,
+        ChatNetworkError::ChannelClosed
+            | ChatNetworkError::ChannelClosedByRemotePeer
+            | ChatNetworkError::ChannelClosedWithError(_)
+            | ChatNetworkError::GoAway { .. }
+    )
+}
+
+fn is_idempotent(msg: &MessageProto) -> bool {
+    msg.request
+        .as_ref()
+        .and_then(|req| req.verb.as_deref())
+        .and_then(|verb| Method::from_str(verb).ok())
+        .is_some_and(|method| method.is_idempotent())
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use assert_matches::assert_matches;
+    use async_trait::async_trait;
+    use futures_util::future::join_all;
+
+    use crate::chat::errors::ChatNetworkError;
+    use crate::chat::fake::FakeChatService;
+    use crate::chat::{ChatService, MessageProto, RequestProto, ResponseProto};
+    use crate::infra::certs::RootCertificates;
+    use crate::infra::connection_manager::SingleRouteThrottlingConnectionManager;
+    use crate::infra::dns::DnsResolver;
+    use crate::infra::reconnect::{
+        ConnectionState, SendQueueConfig, ServiceConnector, ServiceStatus, ServiceWithReconnect,
+    };
+    use crate::infra::test::shared::TIMEOUT_DURATION;
+    use crate::infra::{ConnectionParams, HttpRequestDecoratorSeq};
+
+    const PATH: &str = "/v1/test";
+    const CONNECT_DELAY: Duration = Duration::from_millis(50);
+    const RESPONSE_DELAY: Duration = Duration::from_millis(200);
+
+    /// A connection to a [FakeChatService] that stops the service when
+    /// a request fails because the remote peer closed the channel.
+    ///
+    /// The responses arrive `response_delay` after the requests are sent.
+    #[derive(Clone)]
+    struct TestChatService {
+        fake: FakeChatService,
+        service_status: ServiceStatus<ChatNetworkError>,
+        response_delay: Duration,
+    }
+
+    #[async_trait]
+    impl ChatService for TestChatService {
+        async fn send(
+            &mut self,
+            msg: &MessageProto,
+            timeout: Duration,
+        ) -> Result<ResponseProto, ChatNetworkError> {
+            if self.service_status.is_stopped() {
+                return Err(ChatNetworkError::ChannelClosed);
+            }
+            let result = self.fake.send(msg, timeout).await;
+            tokio::time::sleep(self.response_delay).await;
+            if let Err(ChatNetworkError::ChannelClosedByRemotePeer) = result {
+                self.service_status.stop_service();
+            }
+            result
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct TestChatServiceConnector {
+        fake: FakeChatService,
+        attempts: Arc<AtomicU32>,
+        connect_delay: Arc<Mutex<Duration>>,
+        response_delay: Arc<Mutex<Duration>>,
+    }
+
+    impl TestChatServiceConnector {
+        fn attempts_made(&self) -> u32 {
+            self.attempts.load(Ordering::Relaxed)
+        }
+
+        fn set_connect_delay(&self, connect_delay: Duration) {
+            *self.connect_delay.lock().expect("not poisoned") = connect_delay;
+        }
+
+        /// Sets the response delay of the services started from now on.
+        fn set_response_delay(&self, response_delay: Duration) {
+            *self.response_delay.lock().expect("not poisoned") = response_delay;
+        }
+    }
+
+    #[async_trait]
+    impl ServiceConnector for TestChatServiceConnector {
+        type Service = TestChatService;
+        type Channel = ();
+        type Error = ChatNetworkError;
+
+        async fn connect_channel(
+            &self,
+            _connection_params: &ConnectionParams,
+        ) -> Result<Self::Channel, Self::Error> {
+            self.attempts.fetch_add(1, Ordering::Relaxed);
+            let connect_delay = *self.connect_delay.lock().expect("not poisoned");
+            tokio::time::sleep(connect_delay).await;
+            Ok(())
+        }
+
+        fn start_service(
+            &self,
+            _channel: Self::Channel,
+        ) -> (Self::Service, ServiceStatus<Self::Error>) {
+            let service_status = ServiceStatus::new();
+            let service = TestChatService {
+                fake: self.fake.clone(),
+                service_status: service_status.clone(),
+                response_delay: *self.response_delay.lock().expect("not poisoned"),
+            };
+            (service, service_status)
+        }
+    }
+
+    type TestReconnectingService =
+        ServiceWithReconnect<TestChatServiceConnector, SingleRouteThrottlingConnectionManager>;
+
+    fn reconnecting_service(connector: &TestChatServiceConnector) -> TestReconnectingService {
+        let connection_params = ConnectionParams::new(
+            "chat.signal.org",
+            "chat.signal.org",
+            443,
+            HttpRequestDecoratorSeq::default(),
+            RootCertificates::Signal,
+            DnsResolver::System,
+        );
+        let manager =
+            SingleRouteThrottlingConnectionManager::new(connection_params, TIMEOUT_DURATION);
+        ServiceWithReconnect::new(connector.clone(), manager, TIMEOUT_DURATION)
+    }
+
+    fn request(verb: &str) -> MessageProto {
+        request_to(verb, PATH)
+    }
+
+    fn request_to(verb: &str, path: &str) -> MessageProto {
+        MessageProto {
+            request: Some(RequestProto {
+                id: Some(1),
+                verb: Some(verb.to_string()),
+                path: Some(path.to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn ok_response() -> ResponseProto {
+        ResponseProto {
+            status: Some(200),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn idempotent_request_is_resent_after_disconnect_mid_send() {
+        let connector = TestChatServiceConnector::default();
+        connector
+            .fake
+            .push_error(PATH, ChatNetworkError::ChannelClosedByRemotePeer);
+        connector.fake.push_response(PATH, ok_response());
+        let mut service = reconnecting_service(&connector);
+
+        let response = service
+            .send(&request("GET"), TIMEOUT_DURATION)
+            .await
+            .expect("response");
+
+        assert_eq!(response.status, Some(200));
+        assert_eq!(connector.attempts_made(), 2);
+        assert_eq!(connector.fake.sent_paths(), vec![PATH, PATH]);
+        assert_eq!(service.connection_state(), ConnectionState::Connected);
+    }
+
+    #[tokio::test]
+    async fn non_idempotent_request_is_not_resent_after_disconnect_mid_send() {
+        let connector = TestChatServiceConnector::default();
+        connector
+            .fake
+            .push_error(PATH, ChatNetworkError::ChannelClosedByRemotePeer);
+        connector.fake.push_response(PATH, ok_response());
+        let mut service = reconnecting_service(&connector);
+
+        assert_matches!(
+            service.send(&request("POST"), TIMEOUT_DURATION).await,
+            Err(ChatNetworkError::ChannelClosedByRemotePeer)
+        );
+        assert_eq!(connector.attempts_made(), 1);
+        assert_eq!(service.connection_state(), ConnectionState::Disconnected);
+
+        // the next request goes over a new connection
+        let response = service
+            .send(&request("POST"), TIMEOUT_DURATION)
+            .await
+            .expect("response");
+        assert_eq!(response.status, Some(200));
+        assert_eq!(connector.attempts_made(), 2);
+    }
+
+    #[tokio::test]
+    async fn request_is_resent_at_most_once() {
+        let connector = TestChatServiceConnector::default();
+        for _ in 0..2 {
+            connector
+                .fake
+                .push_error(PATH, ChatNetworkError::ChannelClosedByRemotePeer);
+        }
+        connector.fake.push_response(PATH, ok_response());
+        let mut service = reconnecting_service(&connector);
+
+        assert_matches!(
+            service.send(&request("GET"), TIMEOUT_DURATION).await,
+            Err(ChatNetworkError::ChannelClosedByRemotePeer)
+        );
+        assert_eq!(connector.attempts_made(), 2);
+        assert_eq!(connector.fake.sent_paths().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn request_is_not_resent_on_other_errors() {
+        let connector = TestChatServiceConnector::default();
+        connector
+            .fake
+            .push_error(PATH, ChatNetworkError::ResponseNotReceived);
+        connector.fake.push_response(PATH, ok_response());
+        let mut service = reconnecting_service(&connector);
+
+        assert_matches!(
+            service.send(&request("GET"), TIMEOUT_DURATION).await,
+            Err(ChatNetworkError::ResponseNotReceived)
+        );
+        assert_eq!(connector.attempts_made(), 1);
+        assert_eq!(service.connection_state(), ConnectionState::Connected);
+    }
+
+    /// Connects the `service` and has the server close the connection,
+    /// so that the next request makes the service reconnect.
+    async fn connect_and_drop_connection(
+        connector: &TestChatServiceConnector,
+        service: &mut TestReconnectingService,
+    ) {
+        connector
+            .fake
+            .push_error(PATH, ChatNetworkError::ChannelClosedByRemotePeer);
+        assert_matches!(
+            service.send(&request("POST"), TIMEOUT_DURATION).await,
+            Err(ChatNetworkError::ChannelClosedByRemotePeer)
+        );
+        connector.set_connect_delay(CONNECT_DELAY);
+    }
+
+    /// Sends the `requests` at the same time, in order.
+    async fn send_concurrently(
+        service: &TestReconnectingService,
+        requests: impl IntoIterator<Item = MessageProto>,
+    ) -> Vec<Result<ResponseProto, ChatNetworkError>> {
+        join_all(requests.into_iter().map(|msg| {
+            let mut service = service.clone();
+            async move { service.send(&msg, TIMEOUT_DURATION).await }
+        }))
+        .await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_queued_while_reconnecting_are_sent_in_order() {
+        let connector = TestChatServiceConnector::default();
+        let mut service = reconnecting_service(&connector).with_send_queue(SendQueueConfig {
+            capacity: 3,
+            queue_non_idempotent: false,
+        });
+        connect_and_drop_connection(&connector, &mut service).await;
+
+        let paths = ["/v1/first", "/v1/second", "/v1/third", "/v1/fourth"];
+        for path in paths {
+            connector.fake.push_response(path, ok_response());
+        }
+        let results = send_concurrently(&service, paths.map(|path| request_to("GET", path))).await;
+
+        for result in results {
+            assert_eq!(result.expect("response").status, Some(200));
+        }
+        assert_eq!(connector.attempts_made(), 2);
+        assert_eq!(
+            connector.fake.sent_paths(),
+            [PATH, "/v1/first", "/v1/second", "/v1/third", "/v1/fourth"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn queued_requests_leave_the_queue_once_sent() {
+        let connector = TestChatServiceConnector::default();
+        let mut service = reconnecting_service(&connector).with_send_queue(SendQueueConfig {
+            capacity: 3,
+            queue_non_idempotent: false,
+        });
+        connect_and_drop_connection(&connector, &mut service).await;
+        connector.set_response_delay(RESPONSE_DELAY);
+
+        let paths = ["/v1/first", "/v1/second", "/v1/third", "/v1/fourth"];
+        for path in paths {
+            connector.fake.push_response(path, ok_response());
+        }
+        let start = tokio::time::Instant::now();
+        let results = send_concurrently(&service, paths.map(|path| request_to("GET", path))).await;
+
+        for result in results {
+            assert_eq!(result.expect("response").status, Some(200));
+        }
+        assert_eq!(
+            connector.fake.sent_paths(),
+            [PATH, "/v1/first", "/v1/second", "/v1/third", "/v1/fourth"]
+        );
+        // the queued requests don't wait for the responses to the requests before them
+        assert!(start.elapsed() < CONNECT_DELAY + RESPONSE_DELAY * 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_beyond_queue_capacity_fail() {
+        let connector = TestChatServiceConnector::default();
+        let mut service = reconnecting_service(&connector).with_send_queue(SendQueueConfig {
+            capacity: 2,
+            queue_non_idempotent: false,
+        });
+        connect_and_drop_connection(&connector, &mut service).await;
+
+        let paths = ["/v1/first", "/v1/second", "/v1/third", "/v1/fourth"];
+        for path in paths {
+            connector.fake.push_response(path, ok_response());
+        }
+        let results = send_concurrently(&service, paths.map(|path| request_to("GET", path))).await;
+
+        // the first request isn't queued, it's the one reconnecting
+        assert_matches!(
+            &results[..],
+            [Ok(_), Ok(_), Ok(_), Err(ChatNetworkError::QueueFull)]
+        );
+        assert_eq!(
+            connector.fake.sent_paths(),
+            [PATH, "/v1/first", "/v1/second", "/v1/third"]
+        );
+
+        // once the queue is drained, requests go through again
+        let response = service
+            .send(&request_to("GET", "/v1/fourth"), TIMEOUT_DURATION)
+            .await
+            .expect("response");
+        assert_eq!(response.status, Some(200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn non_idempotent_requests_are_queued_only_if_enabled() {
+        for queue_non_idempotent in [false, true] {
+            let connector = TestChatServiceConnector::default();
+            let mut service = reconnecting_service(&connector).with_send_queue(SendQueueConfig {
+                capacity: 1,
+                queue_non_idempotent,
+            });
+            connect_and_drop_connection(&connector, &mut service).await;
+
+            for path in ["/v1/first", "/v1/second", "/v1/third"] {
+                connector.fake.push_response(path, ok_response());
+            }
+            let results = send_concurrently(
+                &service,
+                [
+                    request_to("GET", "/v1/first"),
+                    request_to("GET", "/v1/second"),
+                    request_to("POST", "/v1/third"),
+                ],
+            )
+            .await;
+
+            // the GET fills the queue, so the POST only fails if it's queued too
+            if queue_non_idempotent {
+                assert_matches!(
+                    &results[..],
+                    [Ok(_), Ok(_), Err(ChatNetworkError::QueueFull)]
+                );
+            } else {
+                assert_matches!(&results[..], [Ok(_), Ok(_), Ok(_)]);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn disconnected_before_first_request() {
+        let connector = TestChatServiceConnector::default();
+        let service = reconnecting_service(&connector);
+        assert_eq!(service.connection_state(), ConnectionState::Disconnected);
+        assert_eq!(connector.attempts_made(), 0);
+    }
+}
@@ -3,6 +3,8 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
+use std::time::Duration;
+
 use crate::infra::errors::{LogSafeDisplay, NetError};
 
 #[derive(displaydoc::Display, Debug)]
@@ -13,6 +15,12 @@ pub enum ChatNetworkError {
     RequestMissingId,
     /// Request object must have an `verb` and `path` fields to be sent over HTTP
     RequestMissingVerbOrPath,
+    /// Request header value can't be represented in the request message
+    RequestHeaderInvalid,
+    /// Request body exceeds the size limit
+    RequestTooLarge,
+    /// Response body exceeds the size limit
+    ResponseTooLarge,
     /// Requested HTTP method is not recognized
     UnknownVerbInRequest,
     /// Failed to send message over WebSocket
@@ -21,14 +29,24 @@ pub enum ChatNetworkError {
     FailedToSendHttp(NetError),
     /// Failed to connect over HTTP
     FailedToConnectHttp(NetError),
+    /// DNS lookup failed
+    DnsFailure,
+    /// Failed to establish TCP connection
+    TcpConnectFailure,
+    /// Failed to establish a secure connection
+    TlsHandshakeFailure,
     /// Failed to pass message to the writer task
     FailedToPassMessageToSenderTask,
     /// Response to a request was not received
     ResponseNotReceived,
     /// Received a WebSocket frame of an unexpected type
     UnexpectedFrameReceived,
-    /// Request timed out
-    Timeout,
+    /// Request timed out after {elapsed:?}
+    Timeout { elapsed: Duration },
+    /// Rate limited by the server; retry after {retry_after:?}
+    RateLimited { retry_after: Duration },
+    /// Request was cancelled
+    Cancelled,
     /// Tried to use closed channel
     ChannelClosed,
     /// WebSocket error
@@ -37,6 +55,8 @@ pub enum ChatNetworkError {
     ChannelClosedWithError(hyper::Error),
     /// Channel closed by remote peer
     ChannelClosedByRemotePeer,
+    /// Server closed the connection with GOAWAY, error code {error_code}
+    GoAway { error_code: u32 },
     /// Channel closed by local peer
     ChannelClosedByLocalPeer,
     /// No incoming messages on the WebSocket channel
@@ -53,6 +73,81 @@ pub enum ChatNetworkError {
     FailedToPassMessageToIncomingChannel,
     /// Request with the same `id` is already in-flight
     RequestIdCollision,
+    /// Request path is not a valid URI path
+    RequestPathInvalid,
+    /// Too many requests are waiting for the service to reconnect
+    QueueFull,
 }
 
 impl LogSafeDisplay for ChatNetworkError {}
+
+/// Error returned by [crate::chat::http::ChatOverHttp2ServiceConnector::connect_and_send],
+/// telling which stage of the operation failed.
+#[derive(displaydoc::Display, Debug)]
+pub enum ConnectAndSendError {
+    /// Request is invalid: {0}
+    InvalidRequest(ChatNetworkError),
+    /// Failed to connect: {0}
+    Connect(ChatNetworkError),
+    /// Failed to send the request: {0}
+    Send(ChatNetworkError),
+    /// Operation was cancelled
+    Cancelled,
+}
+
+impl LogSafeDisplay for ConnectAndSendError {}
+
+/// Maps an error that occurred while establishing a connection, so that the network
+/// being unavailable, the server being unreachable, and the security errors can be told apart.
+///
+/// Errors that don't fall into any of these categories are mapped with `other`.
+pub(crate) fn connect_error(
+    error: NetError,
+    other: fn(NetError) -> ChatNetworkError,
+) -> ChatNetworkError {
+    match error {
+        NetError::DnsError => ChatNetworkError::DnsFailure,
+        NetError::TcpConnectionFailed => ChatNetworkError::TcpConnectFailure,
+        NetError::SslFailedHandshake | NetError::PinningFailure => {
+            ChatNetworkError::TlsHandshakeFailure
+        }
+        error => other(error),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+
+    use crate::chat::errors::{connect_error, ChatNetworkError};
+    use crate::infra::errors::NetError;
+
+    #[test]
+    fn connect_errors_are_distinguished() {
+        let http = ChatNetworkError::FailedToConnectHttp;
+        assert_matches!(
+            connect_error(NetError::DnsError, http),
+            ChatNetworkError::DnsFailure
+        );
+        assert_matches!(
+            connect_error(NetError::TcpConnectionFailed, http),
+            ChatNetworkError::TcpConnectFailure
+        );
+        assert_matches!(
+            connect_error(NetError::SslFailedHandshake, http),
+            ChatNetworkError::TlsHandshakeFailure
+        );
+        assert_matches!(
+            connect_error(NetError::PinningFailure, http),
+            ChatNetworkError::TlsHandshakeFailure
+        );
+        assert_matches!(
+            connect_error(NetError::ClientAuthFailed, http),
+            ChatNetworkError::FailedToConnectHttp(NetError::ClientAuthFailed)
+        );
+        assert_matches!(
+            connect_error(NetError::Http2FailedHandshake, http),
+            ChatNetworkError::FailedToConnectHttp(NetError::Http2FailedHandshake)
+        );
+    }
+}
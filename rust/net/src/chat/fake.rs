@@ -0,0 +1,179 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::chat::errors::ChatNetworkError;
+use crate::chat::{ChatService, MessageProto, ResponseProto};
+
+/// An in-process [ChatService] that never touches the network.
+///
+/// Responses are scripted per request path: every request to a path gets the next response
+/// queued for that path, in the order they were queued. Requests to paths with no queued
+/// responses fail with [ChatNetworkError::ResponseNotReceived]. All sent messages are recorded
+/// so that tests can assert on them.
+///
+/// Clones share the same script and records, so a test can keep a clone around
+/// after handing the service over to the code under test.
+#[derive(Clone, Default)]
+pub struct FakeChatService {
+    state: Arc<Mutex<FakeChatServiceState>>,
+}
+
+#[derive(Default)]
+struct FakeChatServiceState {
+    responses: HashMap<String, VecDeque<Result<ResponseProto, ChatNetworkError>>>,
+    sent_messages: Vec<MessageProto>,
+}
+
+impl FakeChatService {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues a `response` for a request to `path`.
+    pub fn push_response(&self, path: &str, response: ResponseProto) {
+        self.push_result(path, Ok(response))
+    }
+
+    /// Queues an `error` to be returned for a request to `path`.
+    pub fn push_error(&self, path: &str, error: ChatNetworkError) {
+        self.push_result(path, Err(error))
+    }
+
+    /// Returns all messages sent so far, in the order they were sent.
+    pub fn sent_messages(&self) -> Vec<MessageProto> {
+        self.state
+            .lock()
+            .expect("not poisoned")
+            .sent_messages
+            .clone()
+    }
+
+    /// Returns the paths of the requests sent so far, in the order they were sent.
+    pub fn sent_paths(&self) -> Vec<String> {
+        self.sent_messages()
+            .iter()
+            .filter_map(|msg| msg.request.as_ref()?.path.clone())
+            .collect()
+    }
+
+    /// Whether all the queued responses were consumed.
+    pub fn all_responses_consumed(&self) -> bool {
+        self.state
+            .lock()
+            .expect("not poisoned")
+            .responses
+            .values()
+            .all(VecDeque::is_empty)
+    }
+
+    fn push_result(&self, path: &str, result: Result<ResponseProto, ChatNetworkError>) {
+        self.state
+            .lock()
+            .expect("not poisoned")
+            .responses
+            .entry(path.to_string())
+            .or_default()
+            .push_back(result)
+    }
+}
+
+#[async_trait]
+impl ChatService for FakeChatService {
+    async fn send(
+        &mut self,
+        msg: &MessageProto,
+        _timeout: Duration,
+    ) -> Result<ResponseProto, ChatNetworkError> {
+        let mut state = self.state.lock().expect("not poisoned");
+        state.sent_messages.push(msg.clone());
+        let req = msg
+            .request
+            .as_ref()
+            .ok_or(ChatNetworkError::UnexpectedMessageType)?;
+        let path = req
+            .path
+            .as_ref()
+            .ok_or(ChatNetworkError::RequestMissingVerbOrPath)?;
+        let mut response = state
+            .responses
+            .get_mut(path)
+            .and_then(VecDeque::pop_front)
+            .unwrap_or(Err(ChatNetworkError::ResponseNotReceived))?;
+        if response.id.is_none() {
+            response.id = req.id;
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use assert_matches::assert_matches;
+
+    use crate::chat::errors::ChatNetworkError;
+    use crate::chat::fake::FakeChatService;
+    use crate::chat::{ChatService, MessageProto, RequestProto, ResponseProto};
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    fn request(id: u64, path: &str) -> MessageProto {
+        MessageProto {
+            request: Some(RequestProto {
+                id: Some(id),
+                verb: Some("GET".to_string()),
+                path: Some(path.to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn response(status: u32) -> ResponseProto {
+        ResponseProto {
+            status: Some(status),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn scripted_responses_are_returned_in_order() {
+        let fake = FakeChatService::new();
+        fake.push_response("/v1/a", response(200));
+        fake.push_error("/v1/a", ChatNetworkError::Timeout { elapsed: TIMEOUT });
+        fake.push_response("/v1/b", response(404));
+
+        let mut service = fake.clone();
+        let first = service.send(&request(1, "/v1/a"), TIMEOUT).await.unwrap();
+        assert_eq!(first.status, Some(200));
+        assert_eq!(first.id, Some(1));
+        let second = service.send(&request(2, "/v1/b"), TIMEOUT).await.unwrap();
+        assert_eq!(second.status, Some(404));
+        assert_matches!(
+            service.send(&request(3, "/v1/a"), TIMEOUT).await,
+            Err(ChatNetworkError::Timeout { .. })
+        );
+
+        assert!(fake.all_responses_consumed());
+        assert_eq!(fake.sent_paths(), vec!["/v1/a", "/v1/b", "/v1/a"]);
+    }
+
+    #[tokio::test]
+    async fn unscripted_request_fails() {
+        let fake = FakeChatService::new();
+        assert_matches!(
+            fake.clone().send(&request(1, "/v1/a"), TIMEOUT).await,
+            Err(ChatNetworkError::ResponseNotReceived)
+        );
+        assert_eq!(fake.sent_messages(), vec![request(1, "/v1/a")]);
+    }
+}
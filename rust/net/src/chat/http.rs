@@ -3,50 +3,452 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
-use crate::chat::errors::ChatNetworkError;
-use crate::chat::{proto_to_request, ChatService, MessageProto, ResponseProto};
+use crate::chat::errors::{connect_error, ChatNetworkError, ConnectAndSendError};
+use crate::chat::spans::{connect_in_span, send_in_span};
+use crate::chat::{
+    add_extra_headers, connect_and_send_by, keepalive_request, log_request_failure,
+    proto_to_request, ChatService, MessageProto, RequestPriority, ResponseProto,
+};
+use crate::infra::clock::{self, Clock, SystemClock};
 use crate::infra::errors::NetError;
 use crate::infra::http::{
-    http2_channel, AggregatingHttp2Client, AggregatingHttpClient, Http2Channel, Http2Connection,
+    decompress_body, http2_channel, AggregatingHttp2Client, AggregatingHttpClient, ContentEncoding,
+    Http2Channel, PooledHttp2Channel, RequestCompression, ResponseTrailers,
+};
+use crate::infra::reconnect::{
+    CloseReason, ConnectionEvent, ReconnectBackoff, RetryBudget, ServiceConnector, ServiceStatus,
+    CONNECTION_EVENTS_CAPACITY,
 };
-use crate::infra::reconnect::{ServiceConnector, ServiceStatus};
-use crate::infra::ConnectionParams;
-use crate::utils::timeout;
+use crate::infra::{BufferPool, ConnectionInfo, ConnectionParams, Http2ConnectionPool};
+use crate::utils::timeout_with_elapsed;
 use async_trait::async_trait;
-use futures_util::TryFutureExt;
-use std::time::Duration;
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt, TryFutureExt};
+use http::header::{ACCEPT_ENCODING, CONTENT_ENCODING, RETRY_AFTER};
+use http::response::Parts;
+use http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use rand::Rng;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::{broadcast, oneshot, Notify, RwLock};
+use tokio_util::sync::CancellationToken;
+
+#[derive(Debug, Clone)]
+pub struct ChatOverHttp2Config {
+    pub connect_timeout: Duration,
+    /// See [ChatOverHttp2::max_idempotent_retries].
+    pub max_idempotent_retries: u32,
+    /// Compressed response bodies that exceed this size once decompressed are rejected.
+    pub max_decompressed_body_size: usize,
+    /// See [ChatOverHttp2::max_request_bytes].
+    pub max_request_bytes: usize,
+    /// See [ChatOverHttp2::max_response_bytes].
+    pub max_response_bytes: usize,
+    /// See [ChatOverHttp2::retry_after_policy].
+    pub retry_after_policy: Option<RetryAfterPolicy>,
+    /// See [ChatOverHttp2::response_header_allow_list].
+    pub response_header_allow_list: Option<Vec<HeaderName>>,
+    /// If set, the connection is closed once no request has been in flight for this long.
+    ///
+    /// The service then fails requests with [ChatNetworkError::ChannelClosed], same as after
+    /// the connection is closed for any other reason.
+    pub idle_timeout: Option<Duration>,
+    /// If set, the connection is rotated once it has been open for this long, so that
+    /// long-lived clients get spread over the server instances.
+    ///
+    /// The service is then reported as stopped, so that [ServiceWithReconnect] establishes
+    /// a new connection for the next request, but the requests already in flight are allowed
+    /// to complete before the connection is closed.
+    ///
+    /// [ServiceWithReconnect]: crate::infra::reconnect::ServiceWithReconnect
+    pub max_connection_lifetime: Option<Duration>,
+    /// The maximum number of requests that are sent at the same time over a connection.
+    ///
+    /// Once it's reached, new requests wait until one of the requests in flight completes,
+    /// and the waiting requests are let through in the order of their [RequestPriority].
+    /// The wait doesn't count towards the request timeout. Zero is treated as one.
+    pub max_in_flight: usize,
+    /// See [ChatOverHttp2::request_compression].
+    pub request_compression: Option<RequestCompression>,
+    /// See [ChatOverHttp2::retry_budget].
+    pub retry_budget: Option<Arc<RetryBudget>>,
+    /// See [ChatOverHttp2::buffer_pool].
+    pub buffer_pool: Option<Arc<BufferPool>>,
+    /// See [ChatOverHttp2::expect_continue].
+    pub expect_continue: Option<ExpectContinuePolicy>,
+    /// If set, connections are taken from this pool, and shared with the other services
+    /// (and clients) using it.
+    ///
+    /// A pooled connection is kept open by the pool rather than by the service, so it's not
+    /// closed when the service reaches its `idle_timeout` or `max_connection_lifetime`:
+    /// the service just stops using it. The pool's own limits decide when a new connection
+    /// is established.
+    pub connection_pool: Option<Http2ConnectionPool>,
+    /// Measures the timeouts, the idle time and lifetime of the connection, and the waits
+    /// before re-sending rate limited requests.
+    ///
+    /// The HTTP/2 keepalive PINGs are timed by the tokio timer regardless.
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for ChatOverHttp2Config {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(2),
+            max_idempotent_retries: 0,
+            max_decompressed_body_size: 10 * 1024 * 1024,
+            max_request_bytes: 16 * 1024 * 1024,
+            max_response_bytes: 16 * 1024 * 1024,
+            retry_after_policy: None,
+            response_header_allow_list: None,
+            idle_timeout: None,
+            max_connection_lifetime: None,
+            max_in_flight: 64,
+            request_compression: None,
+            retry_budget: None,
+            buffer_pool: None,
+            expect_continue: None,
+            connection_pool: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+/// Controls how [ChatOverHttp2] handles `429 Too Many Requests` and `503 Service Unavailable`
+/// responses.
+///
+/// The request is re-sent once after waiting for the duration from the `Retry-After` header,
+/// or for the first delay of `fallback` if there is no such header, plus a random duration
+/// of up to `jitter`. If the wait would be longer than `max_wait`, or the re-sent request is
+/// rejected as well, the request fails with [ChatNetworkError::RateLimited]. Its `retry_after`
+/// is the total wait: the time already spent waiting before the request was re-sent, plus the
+/// wait the server asked for last.
+#[derive(Clone, Debug)]
+pub struct RetryAfterPolicy {
+    pub jitter: Duration,
+    pub max_wait: Duration,
+    pub fallback: ReconnectBackoff,
+}
+
+impl Default for RetryAfterPolicy {
+    fn default() -> Self {
+        Self {
+            jitter: Duration::from_millis(500),
+            max_wait: Duration::from_secs(10),
+            fallback: ReconnectBackoff::default(),
+        }
+    }
+}
+
+impl RetryAfterPolicy {
+    /// Returns how long to wait before re-sending the request,
+    /// or `None` if the response is not a rate limiting one.
+    fn requested_wait(&self, parts: &Parts) -> Option<Duration> {
+        if !matches!(
+            parts.status,
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+        ) {
+            return None;
+        }
+        let retry_after = parts
+            .headers
+            .get(RETRY_AFTER)
+            .and_then(|value| parse_retry_after(value, SystemTime::now()));
+        Some(retry_after.unwrap_or_else(|| self.fallback.delay(1)))
+    }
+
+    fn jitter(&self) -> Duration {
+        if self.jitter.is_zero() {
+            return Duration::ZERO;
+        }
+        rand::thread_rng().gen_range(Duration::ZERO..=self.jitter)
+    }
+}
+
+/// Parses the value of a `Retry-After` header, which is either a number of seconds
+/// or an HTTP date.
+fn parse_retry_after(value: &HeaderValue, now: SystemTime) -> Option<Duration> {
+    let value = value.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(now).unwrap_or(Duration::ZERO))
+}
+
+/// Re-sends the request once if `response` asks to retry later, as described by [RetryAfterPolicy].
+///
+/// If the `retry_budget` is exhausted, the request is not re-sent and fails with
+/// [ChatNetworkError::RateLimited] right away.
+async fn retry_after_rate_limit<F, Fut>(
+    policy: &RetryAfterPolicy,
+    clock: &dyn Clock,
+    retry_budget: Option<&RetryBudget>,
+    response: (Parts, Bytes),
+    send_again: F,
+) -> Result<(Parts, Bytes), ChatNetworkError>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<(Parts, Bytes), ChatNetworkError>>,
+{
+    let wait = match policy.requested_wait(&response.0) {
+        Some(wait) => wait,
+        None => return Ok(response),
+    };
+    if wait > policy.max_wait || !retry_budget.map_or(true, RetryBudget::try_acquire) {
+        return Err(ChatNetworkError::RateLimited { retry_after: wait });
+    }
+    let waited = wait + policy.jitter();
+    clock.sleep(waited).await;
+    let response = send_again().await?;
+    match policy.requested_wait(&response.0) {
+        Some(wait) => Err(ChatNetworkError::RateLimited {
+            retry_after: waited + wait,
+        }),
+        None => Ok(response),
+    }
+}
+
+/// Controls which requests [ChatOverHttp2] sends with `Expect: 100-continue`.
+///
+/// The body of such a request is held back for up to `timeout` after its headers are sent.
+/// If the server responds in the meantime, e.g. rejects the request with `413 Content Too Large`
+/// or `417 Expectation Failed`, the body is never sent (the stream is reset instead) and that
+/// response is returned as it is. Otherwise the body is sent once the `timeout` is up.
+///
+/// The HTTP/2 client doesn't pass interim responses on, so a `100 Continue` from the server
+/// can't be observed: the body of every request the server accepts is delayed by the whole
+/// `timeout`, which should therefore be kept short.
+#[derive(Clone, Debug)]
+pub struct ExpectContinuePolicy {
+    /// Requests with a shorter body (after compression, if any) are sent as usual.
+    pub min_body_size: usize,
+    pub timeout: Duration,
+}
+
+impl Default for ExpectContinuePolicy {
+    fn default() -> Self {
+        Self {
+            min_body_size: 1024 * 1024,
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
 
 #[derive(Clone)]
-pub struct ChatOverHttp2ServiceConnector {}
+pub struct ChatOverHttp2ServiceConnector {
+    config: ChatOverHttp2Config,
+    events: broadcast::Sender<ConnectionEvent>,
+}
+
+impl Default for ChatOverHttp2ServiceConnector {
+    fn default() -> Self {
+        Self::new(ChatOverHttp2Config::default())
+    }
+}
+
+impl ChatOverHttp2ServiceConnector {
+    pub fn new(config: ChatOverHttp2Config) -> Self {
+        Self {
+            config,
+            events: broadcast::channel(CONNECTION_EVENTS_CAPACITY).0,
+        }
+    }
+
+    /// Subscribes to the [ConnectionEvent]s of all connections started by this connector.
+    pub fn subscribe(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.events.subscribe()
+    }
+
+    /// Connects to the server described by `connection_params` and starts the service.
+    ///
+    /// Unlike the services built on top of the reconnect layer, the returned service doesn't
+    /// reconnect when the connection is lost.
+    pub async fn connect(
+        &self,
+        connection_params: &ConnectionParams,
+    ) -> Result<ChatOverHttp2, ChatNetworkError> {
+        let channel = self.connect_channel(connection_params).await?;
+        let (service, _service_status) = self.start_service(channel);
+        Ok(service)
+    }
+
+    /// Connects to the server described by `connection_params`, sends `msg`, and closes the
+    /// connection once the response is received.
+    ///
+    /// The `timeout` covers the whole operation, connecting included, see
+    /// [ChatOverHttp2ServiceConnector::connect_and_send_by].
+    pub async fn connect_and_send(
+        &self,
+        connection_params: &ConnectionParams,
+        msg: &MessageProto,
+        timeout: Duration,
+        cancellation_token: CancellationToken,
+    ) -> Result<ResponseProto, ConnectAndSendError> {
+        self.connect_and_send_by(
+            connection_params,
+            msg,
+            tokio::time::Instant::now() + timeout,
+            cancellation_token,
+        )
+        .await
+    }
+
+    /// Same as [ChatOverHttp2ServiceConnector::connect_and_send], but the operation has to
+    /// complete by the given `deadline`.
+    ///
+    /// The time it takes to connect (which is also limited by
+    /// [ChatOverHttp2Config::connect_timeout]) is taken from the time left for the request.
+    /// The request is validated before connecting. Cancelling `cancellation_token` aborts
+    /// whichever stage is in progress.
+    pub async fn connect_and_send_by(
+        &self,
+        connection_params: &ConnectionParams,
+        msg: &MessageProto,
+        deadline: tokio::time::Instant,
+        cancellation_token: CancellationToken,
+    ) -> Result<ResponseProto, ConnectAndSendError> {
+        let req = msg
+            .request
+            .as_ref()
+            .ok_or(ConnectAndSendError::InvalidRequest(
+                ChatNetworkError::UnexpectedMessageType,
+            ))?;
+        proto_to_request(req).map_err(ConnectAndSendError::InvalidRequest)?;
+
+        connect_and_send_by(self, connection_params, msg, deadline, cancellation_token).await
+    }
+}
+
+/// A connection established by [ChatOverHttp2ServiceConnector].
+pub enum ChatChannel {
+    /// A connection of its own, closed along with the service.
+    Direct(Http2Channel<AggregatingHttp2Client>),
+    /// A connection from [ChatOverHttp2Config::connection_pool].
+    Pooled(PooledHttp2Channel),
+}
 
 #[async_trait]
 impl ServiceConnector for ChatOverHttp2ServiceConnector {
     type Service = ChatOverHttp2;
-    type Channel = Http2Channel<AggregatingHttp2Client>;
+    type Channel = ChatChannel;
     type Error = ChatNetworkError;
 
     async fn connect_channel(
         &self,
         connection_params: &ConnectionParams,
     ) -> Result<Self::Channel, Self::Error> {
-        let connect_future =
-            http2_channel(connection_params).map_err(ChatNetworkError::FailedToConnectHttp);
-        timeout(
-            Duration::from_secs(2),
-            ChatNetworkError::Timeout,
-            connect_future,
+        let connect_future = async {
+            match &self.config.connection_pool {
+                Some(pool) => pool
+                    .get_or_connect_channel(connection_params)
+                    .await
+                    .map(ChatChannel::Pooled),
+                None => http2_channel(connection_params)
+                    .await
+                    .map(ChatChannel::Direct),
+            }
+        }
+        .map_err(|e| connect_error(e, ChatNetworkError::FailedToConnectHttp));
+        connect_in_span(
+            connection_params,
+            self.config.connect_timeout,
+            timeout_with_elapsed(
+                &*self.config.clock,
+                self.config.connect_timeout,
+                |elapsed| ChatNetworkError::Timeout { elapsed },
+                connect_future,
+            ),
         )
         .await
     }
 
     fn start_service(&self, channel: Self::Channel) -> (Self::Service, ServiceStatus<Self::Error>) {
-        let Http2Channel {
-            aggregating_client: request_sender,
+        match channel {
+            ChatChannel::Direct(Http2Channel {
+                aggregating_client: request_sender,
+                connection,
+                connection_info,
+            }) => self.start_service_over(request_sender, connection, connection_info),
+            ChatChannel::Pooled(PooledHttp2Channel {
+                aggregating_client: request_sender,
+                service_status,
+                connection_info,
+            }) => {
+                // the pool drives the connection, the service only needs to know when it's closed
+                let connection = async move {
+                    service_status.stopped().await;
+                    Ok::<(), hyper::Error>(())
+                };
+                self.start_service_over(request_sender, connection, connection_info)
+            }
+        }
+    }
+}
+
+impl ChatOverHttp2ServiceConnector {
+    /// Starts the service over an in-memory connection whose server end is passed to `serve`,
+    /// see [crate::infra::in_memory::connect].
+    #[cfg(any(test, feature = "test-util"))]
+    pub async fn connect_in_memory<F, Fut>(&self, serve: F) -> ChatOverHttp2
+    where
+        F: FnOnce(tokio::io::DuplexStream) -> Fut,
+        Fut: Future + Send + 'static,
+        Fut::Output: Send + 'static,
+    {
+        let (request_sender, connection) = crate::infra::in_memory::connect(serve).await;
+        let (service, _service_status) = self.start_service_over(
+            request_sender,
             connection,
-        } = channel;
-        let service_status = ServiceStatus::new();
-        start_event_listener(connection, service_status.clone());
-        (ChatOverHttp2 { request_sender }, service_status)
+            crate::infra::in_memory::connection_info(),
+        );
+        service
+    }
+
+    /// Starts the service over an established `connection`, regardless of its transport.
+    fn start_service_over(
+        &self,
+        request_sender: AggregatingHttp2Client,
+        connection: impl Future<Output = Result<(), hyper::Error>> + Send + 'static,
+        connection_info: ConnectionInfo,
+    ) -> (ChatOverHttp2, ServiceStatus<ChatNetworkError>) {
+        let service_status = ServiceStatus::with_events(self.events.clone());
+        let idle_tracker = Arc::new(IdleTracker::default());
+        start_event_listener(
+            connection,
+            service_status.clone(),
+            self.config.idle_timeout,
+            self.config.max_connection_lifetime,
+            idle_tracker.clone(),
+            self.config.clock.clone(),
+        );
+        (
+            ChatOverHttp2 {
+                request_sender,
+                max_idempotent_retries: self.config.max_idempotent_retries,
+                max_decompressed_body_size: self.config.max_decompressed_body_size,
+                max_request_bytes: self.config.max_request_bytes,
+                max_response_bytes: self.config.max_response_bytes,
+                retry_after_policy: self.config.retry_after_policy.clone(),
+                response_header_allow_list: self.config.response_header_allow_list.clone(),
+                request_compression: self.config.request_compression.clone(),
+                retry_budget: self.config.retry_budget.clone(),
+                buffer_pool: self.config.buffer_pool.clone(),
+                expect_continue: self.config.expect_continue.clone(),
+                connection_info,
+                clock: self.config.clock.clone(),
+                shutdown: Default::default(),
+                idle_tracker,
+                in_flight_limit: in_flight_limit(self.config.max_in_flight),
+                stats: Default::default(),
+                service_status: service_status.clone(),
+            },
+            service_status,
+        )
     }
 }
 
@@ -56,72 +458,2307 @@ impl ChatService for ChatOverHttp2 {
         &mut self,
         msg: &MessageProto,
         timeout_duration: Duration,
+    ) -> Result<ResponseProto, ChatNetworkError> {
+        self.send_prioritized(
+            msg,
+            &[],
+            RequestPriority::default(),
+            false,
+            timeout_duration,
+        )
+        .await
+    }
+
+    /// The priority only affects the order in which requests waiting for
+    /// [ChatOverHttp2Config::max_in_flight] are sent. HTTP/2 stream priorities are not sent to
+    /// the server, since they are deprecated by RFC 9113 and not supported by the HTTP/2 client.
+    async fn send_with_priority(
+        &mut self,
+        msg: &MessageProto,
+        priority: RequestPriority,
+        timeout_duration: Duration,
+    ) -> Result<ResponseProto, ChatNetworkError> {
+        self.send_prioritized(msg, &[], priority, false, timeout_duration)
+            .await
+    }
+
+    async fn send_with_headers(
+        &mut self,
+        msg: &MessageProto,
+        extra_headers: &[(HeaderName, HeaderValue)],
+        timeout_duration: Duration,
+    ) -> Result<ResponseProto, ChatNetworkError> {
+        self.send_prioritized(
+            msg,
+            extra_headers,
+            RequestPriority::default(),
+            false,
+            timeout_duration,
+        )
+        .await
+    }
+
+    /// The body is compressed as configured by [ChatOverHttp2Config::request_compression].
+    async fn send_compressed(
+        &mut self,
+        msg: &MessageProto,
+        timeout_duration: Duration,
+    ) -> Result<ResponseProto, ChatNetworkError> {
+        self.send_prioritized(msg, &[], RequestPriority::default(), true, timeout_duration)
+            .await
+    }
+
+    async fn send_streaming<S>(
+        &mut self,
+        msg: &MessageProto,
+        body_stream: S,
+        timeout_duration: Duration,
+    ) -> Result<ResponseProto, ChatNetworkError>
+    where
+        S: Stream<Item = Bytes> + Send + 'static,
+    {
+        let shutdown = self.shutdown.clone();
+        let idle_tracker = self.idle_tracker.clone();
+        let _active_request = idle_tracker.start_request();
+        let in_flight_limit = self.in_flight_limit.clone();
+        let result = send_in_span(
+            msg,
+            shutdown.track(async {
+                let _permit = in_flight_limit.acquire(RequestPriority::default()).await;
+                self.send_streaming_untracked(msg, body_stream, timeout_duration)
+                    .await
+            }),
+        )
+        .await;
+        log_request_failure(msg, &result);
+        self.request_completed(&result);
+        result
+    }
+
+    /// The chunks are the data frames of the response as they are received. The body is not
+    /// decoded according to its `Content-Encoding`, the header is kept in the response instead.
+    async fn send_with_chunks<F>(
+        &mut self,
+        msg: &MessageProto,
+        on_chunk: F,
+        timeout_duration: Duration,
+    ) -> Result<ResponseProto, ChatNetworkError>
+    where
+        F: FnMut(&[u8]) + Send,
+    {
+        let shutdown = self.shutdown.clone();
+        let idle_tracker = self.idle_tracker.clone();
+        let _active_request = idle_tracker.start_request();
+        let in_flight_limit = self.in_flight_limit.clone();
+        let result = send_in_span(
+            msg,
+            shutdown.track(async {
+                let _permit = in_flight_limit.acquire(RequestPriority::default()).await;
+                self.send_with_chunks_untracked(msg, on_chunk, timeout_duration)
+                    .await
+            }),
+        )
+        .await;
+        log_request_failure(msg, &result);
+        self.request_completed(&result);
+        result
+    }
+
+    async fn keepalive(&mut self, timeout_duration: Duration) -> Result<(), ChatNetworkError> {
+        let result = self.send(&keepalive_request(), timeout_duration).await;
+        if let Err(e) = &result {
+            log::debug!("keepalive failed: {e}; closing the connection");
+            self.service_status.stop_service();
+        }
+        result.map(|_| ())
+    }
+}
+
+impl ChatOverHttp2 {
+    async fn send_prioritized(
+        &mut self,
+        msg: &MessageProto,
+        extra_headers: &[(HeaderName, HeaderValue)],
+        priority: RequestPriority,
+        compress: bool,
+        timeout_duration: Duration,
+    ) -> Result<ResponseProto, ChatNetworkError> {
+        let shutdown = self.shutdown.clone();
+        let idle_tracker = self.idle_tracker.clone();
+        let _active_request = idle_tracker.start_request();
+        let in_flight_limit = self.in_flight_limit.clone();
+        let result = send_in_span(
+            msg,
+            shutdown.track(async {
+                let _permit = in_flight_limit.acquire(priority).await;
+                self.send_untracked(msg, extra_headers, compress, timeout_duration)
+                    .await
+            }),
+        )
+        .await;
+        log_request_failure(msg, &result);
+        self.request_completed(&result);
+        result
+    }
+
+    /// Returns the parameters that were negotiated with the server when connecting,
+    /// e.g. to check that HTTP/2 was actually used.
+    pub fn connection_info(&self) -> &ConnectionInfo {
+        &self.connection_info
+    }
+
+    /// Returns the counters of the connection used by this service.
+    ///
+    /// All clones of this service share the connection, so the counters
+    /// include their requests as well.
+    pub fn stats(&self) -> ConnectionStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        ConnectionStats {
+            requests_sent: load(&self.stats.requests_sent),
+            bytes_sent: load(&self.stats.bytes_sent),
+            bytes_received: load(&self.stats.bytes_received),
+            active_streams: self.idle_tracker.in_flight.load(Ordering::SeqCst),
+            reset_streams: load(&self.stats.reset_streams),
+            refused_streams: load(&self.stats.refused_streams),
+        }
+    }
+
+    /// Returns `true` once the connection used by this service is closed, by either side.
+    ///
+    /// A closed service fails all requests, and has to be replaced by a newly connected one.
+    pub fn is_closed(&self) -> bool {
+        self.service_status.is_stopped()
+    }
+
+    /// Completes once the connection used by this service is closed, see
+    /// [ChatOverHttp2::is_closed].
+    pub async fn closed(&self) {
+        self.service_status.stopped().await
+    }
+
+    /// Gracefully shuts down the service.
+    ///
+    /// New requests are rejected with [ChatNetworkError::ChannelClosed] right away,
+    /// and the requests that are already in flight are given up to `grace` to complete.
+    /// After that, the requests that are still in flight fail with [ChatNetworkError::Cancelled]
+    /// and the connection is closed.
+    ///
+    /// This affects all clones of this service, since they share the connection.
+    pub async fn shutdown(&mut self, grace: Duration) {
+        self.shutdown.shutdown(&*self.clock, grace).await;
+        self.service_status.stop_service();
+    }
+
+    /// Updates the counters, and reports the connection as degraded while the requests
+    /// on it fail without a response.
+    fn request_completed<T>(&self, result: &Result<T, ChatNetworkError>) {
+        self.stats.request_completed(result);
+        match result {
+            Ok(_) => self.service_status.set_degraded(false),
+            Err(
+                ChatNetworkError::FailedToSendHttp(NetError::ConnectionInterrupted)
+                | ChatNetworkError::Timeout { .. },
+            ) => self.service_status.set_degraded(true),
+            Err(_) => {}
+        }
+    }
+
+    async fn send_untracked(
+        &mut self,
+        msg: &MessageProto,
+        extra_headers: &[(HeaderName, HeaderValue)],
+        compress: bool,
+        timeout_duration: Duration,
     ) -> Result<ResponseProto, ChatNetworkError> {
         let req = msg
             .request
             .as_ref()
             .ok_or(ChatNetworkError::UnexpectedMessageType)?;
         let id = req.id;
-        let (path, builder, body) = proto_to_request(req)?;
-        let response_future =
-            self.request_sender
-                .send_request_aggregate_response(path.as_str(), builder, body);
-        match timeout(timeout_duration, NetError::Timeout, response_future).await {
-            Ok((parts, aggregated_body)) => {
-                let status: Option<u32> = Some(parts.status.as_u16().into());
-                let message: Option<String> = Some(parts.status.to_string());
-                let body = match aggregated_body.len() {
-                    0 => None,
-                    _ => Some(aggregated_body.to_vec()),
+        let (_, builder, body) = proto_to_request(req)?;
+        check_request_size(&body, self.max_request_bytes)?;
+        let method = builder.method_ref().cloned().unwrap_or_default();
+        let builder = add_extra_headers(builder, extra_headers);
+        let compressed_body = if compress {
+            self.compress_request_body(&builder, &body)
+        } else {
+            None
+        };
+        let compressed_body = compressed_body.as_ref();
+        let request_compression = self.request_compression.as_ref();
+        let expect_continue = self.expect_continue.as_ref();
+        let clock = &*self.clock;
+        let stats = &self.stats;
+        let mut send_attempt = || {
+            let mut request_sender = self.request_sender();
+            async move {
+                let (path, builder, body) = proto_to_request(req)?;
+                let builder = add_extra_headers(builder, extra_headers);
+                let (builder, body) =
+                    encode_request(builder, body, request_compression, compressed_body);
+                let expect_continue =
+                    expect_continue.filter(|policy| body.len() >= policy.min_body_size);
+                let response = match expect_continue {
+                    Some(policy) => {
+                        // the body is only counted once it's actually sent
+                        stats.request_sent(0);
+                        let body_stats = stats.clone();
+                        request_sender
+                            .send_request_expect_continue(
+                                path.as_str(),
+                                builder,
+                                body,
+                                policy.timeout,
+                                clock,
+                                move |body| body_stats.add_bytes_sent(body.len()),
+                            )
+                            .await
+                    }
+                    None => {
+                        stats.request_sent(body.len());
+                        request_sender
+                            .send_request_aggregate_response(path.as_str(), builder, body)
+                            .await
+                    }
                 };
-
-                let headers: Vec<String> = parts
-                    .headers
-                    .iter()
-                    .map(|header| {
-                        format!(
-                            "{}: {}",
-                            header.0.as_str(),
-                            header.1.to_str().expect("has header value")
-                        )
+                let (parts, body) = response.map_err(|e| stats.send_failed(e))?;
+                stats.response_received(body.len());
+                Ok((parts, body))
+            }
+        };
+        let max_retries = self.max_idempotent_retries;
+        let retry_after_policy = &self.retry_after_policy;
+        let retry_budget = self.retry_budget.as_deref();
+        let response_future = async {
+            let method = &method;
+            let send_attempt = &mut send_attempt;
+            let response =
+                send_with_retries(method, max_retries, retry_budget, &mut *send_attempt).await?;
+            match retry_after_policy {
+                Some(policy) => {
+                    retry_after_rate_limit(policy, clock, retry_budget, response, move || {
+                        send_with_retries(method, max_retries, retry_budget, send_attempt)
                     })
-                    .collect();
-
-                Ok(ResponseProto {
-                    id,
-                    status,
-                    message,
-                    body,
-                    headers,
-                })
+                    .await
+                }
+                None => Ok(response),
             }
-            Err(err) => Err(ChatNetworkError::FailedToSendHttp(err)),
+        };
+        let (parts, aggregated_body) = timeout_with_elapsed(
+            &*self.clock,
+            timeout_duration,
+            |elapsed| ChatNetworkError::Timeout { elapsed },
+            response_future,
+        )
+        .await?;
+        self.decoded_response_to_proto(id, parts, aggregated_body)
+    }
+
+    async fn send_streaming_untracked<S>(
+        &mut self,
+        msg: &MessageProto,
+        body_stream: S,
+        timeout_duration: Duration,
+    ) -> Result<ResponseProto, ChatNetworkError>
+    where
+        S: Stream<Item = Bytes> + Send + 'static,
+    {
+        let req = msg
+            .request
+            .as_ref()
+            .ok_or(ChatNetworkError::UnexpectedMessageType)?;
+        let id = req.id;
+        let (path, builder, _) = proto_to_request(req)?;
+        let mut request_sender = self.request_sender();
+        self.stats.request_sent(0);
+        let stats = self.stats.clone();
+        let body_stream = body_stream.inspect(move |chunk| stats.add_bytes_sent(chunk.len()));
+        let response_future = request_sender
+            .send_streaming_request_aggregate_response(path.as_str(), builder, body_stream)
+            .map_err(|e| self.stats.send_failed(e));
+        let (parts, aggregated_body) = timeout_with_elapsed(
+            &*self.clock,
+            timeout_duration,
+            |elapsed| ChatNetworkError::Timeout { elapsed },
+            response_future,
+        )
+        .await?;
+        self.stats.response_received(aggregated_body.len());
+        self.decoded_response_to_proto(id, parts, aggregated_body)
+    }
+
+    async fn send_with_chunks_untracked<F>(
+        &mut self,
+        msg: &MessageProto,
+        mut on_chunk: F,
+        timeout_duration: Duration,
+    ) -> Result<ResponseProto, ChatNetworkError>
+    where
+        F: FnMut(&[u8]) + Send,
+    {
+        let req = msg
+            .request
+            .as_ref()
+            .ok_or(ChatNetworkError::UnexpectedMessageType)?;
+        let id = req.id;
+        let (path, builder, body) = proto_to_request(req)?;
+        check_request_size(&body, self.max_request_bytes)?;
+        let (builder, body) =
+            encode_request(builder, body, self.request_compression.as_ref(), None);
+        let mut request_sender = self.request_sender();
+        let stats = &self.stats;
+        stats.request_sent(body.len());
+        let on_chunk = move |chunk: &[u8]| {
+            stats.response_received(chunk.len());
+            on_chunk(chunk)
+        };
+        let response_future = request_sender
+            .send_request_with_chunks(path.as_str(), builder, body, on_chunk)
+            .map_err(|e| stats.send_failed(e));
+        let mut parts = timeout_with_elapsed(
+            &*self.clock,
+            timeout_duration,
+            |elapsed| ChatNetworkError::Timeout { elapsed },
+            response_future,
+        )
+        .await?;
+        if let Some(allow_list) = &self.response_header_allow_list {
+            retain_allowed_headers(&mut parts.headers, allow_list);
+        }
+        Ok(response_to_proto(id, &parts, Bytes::new()))
+    }
+
+    /// Compresses the request `body` as configured by [ChatOverHttp2::request_compression],
+    /// returning `None` if it should be sent as it is.
+    fn compress_request_body(
+        &self,
+        builder: &http::request::Builder,
+        body: &Bytes,
+    ) -> Option<(ContentEncoding, Bytes)> {
+        let compression = self.request_compression.as_ref()?;
+        let already_encoded = builder
+            .headers_ref()
+            .map_or(false, |headers| headers.contains_key(CONTENT_ENCODING));
+        if already_encoded {
+            return None;
+        }
+        compression
+            .compress(body)
+            .map(|compressed| (compression.encoding, compressed))
+    }
+
+    fn request_sender(&self) -> AggregatingHttp2Client {
+        let mut request_sender = self.request_sender.clone();
+        request_sender.max_response_size = self.max_response_bytes;
+        request_sender.buffer_pool = self.buffer_pool.clone();
+        request_sender
+    }
+
+    fn decoded_response_to_proto(
+        &self,
+        id: Option<u64>,
+        mut parts: Parts,
+        aggregated_body: Bytes,
+    ) -> Result<ResponseProto, ChatNetworkError> {
+        let body = decompress_body(&mut parts, aggregated_body, self.max_decompressed_body_size)
+            .map_err(ChatNetworkError::FailedToSendHttp)?;
+        if let Some(allow_list) = &self.response_header_allow_list {
+            retain_allowed_headers(&mut parts.headers, allow_list);
+        }
+        Ok(response_to_proto(id, &parts, body))
+    }
+}
+
+/// Removes the headers that are not in the `allow_list`, keeping the order of the others.
+fn retain_allowed_headers(headers: &mut HeaderMap, allow_list: &[HeaderName]) {
+    let mut allowed = HeaderMap::new();
+    for (name, value) in headers.iter() {
+        if allow_list.contains(name) {
+            allowed.append(name.clone(), value.clone());
         }
     }
+    *headers = allowed;
+}
+
+/// Replaces the request `body` with the `compressed_body`, if any, and sets the
+/// `Content-Encoding` and `Accept-Encoding` headers when `request_compression` is configured.
+fn encode_request(
+    mut builder: http::request::Builder,
+    body: Bytes,
+    request_compression: Option<&RequestCompression>,
+    compressed_body: Option<&(ContentEncoding, Bytes)>,
+) -> (http::request::Builder, Bytes) {
+    if request_compression.is_none() {
+        return (builder, body);
+    }
+    let accepts_encoding = builder
+        .headers_ref()
+        .map_or(false, |headers| headers.contains_key(ACCEPT_ENCODING));
+    if !accepts_encoding {
+        builder = builder.header(ACCEPT_ENCODING, ContentEncoding::ACCEPT_ALL);
+    }
+    match compressed_body {
+        Some((encoding, compressed)) => (
+            builder.header(CONTENT_ENCODING, encoding.name()),
+            compressed.clone(),
+        ),
+        None => (builder, body),
+    }
+}
+
+fn check_request_size(body: &Bytes, max_request_bytes: usize) -> Result<(), ChatNetworkError> {
+    if body.len() > max_request_bytes {
+        return Err(ChatNetworkError::RequestTooLarge);
+    }
+    Ok(())
+}
+
+fn send_error(error: NetError) -> ChatNetworkError {
+    match error {
+        NetError::ResponseTooLarge => ChatNetworkError::ResponseTooLarge,
+        error => ChatNetworkError::FailedToSendHttp(error),
+    }
+}
+
+/// Converts the parts of an HTTP response into a [ResponseProto].
+///
+/// Trailers, if the server sent any, are converted the same way as the headers.
+/// Header values are not guaranteed to be valid UTF-8 (a misbehaving server can send arbitrary
+/// bytes), so they are converted lossily rather than rejected.
+fn response_to_proto(id: Option<u64>, parts: &Parts, aggregated_body: Bytes) -> ResponseProto {
+    let status: Option<u32> = Some(parts.status.as_u16().into());
+    let message: Option<String> = Some(parts.status.to_string());
+    let body = match aggregated_body.len() {
+        0 => None,
+        _ => Some(aggregated_body.to_vec()),
+    };
+
+    let headers = header_strings(&parts.headers);
+    let trailers = parts
+        .extensions
+        .get::<ResponseTrailers>()
+        .map(|ResponseTrailers(trailers)| header_strings(trailers))
+        .unwrap_or_default();
+
+    ResponseProto {
+        id,
+        status,
+        message,
+        body,
+        headers,
+        trailers,
+    }
+}
+
+fn header_strings(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            format!(
+                "{}: {}",
+                name.as_str(),
+                String::from_utf8_lossy(value.as_bytes())
+            )
+        })
+        .collect()
 }
 
 #[derive(Clone)]
 pub struct ChatOverHttp2 {
     request_sender: AggregatingHttp2Client,
+    /// How many times a request with an idempotent method is re-sent
+    /// after the server reset its stream.
+    ///
+    /// The requests that fail because the connection is lost are not re-sent, since
+    /// the service can't reconnect. Requests with non-idempotent methods (e.g. `POST`) and streaming requests
+    /// are never re-sent.
+    ///
+    /// Independently of this, a non-streaming request that the server refused with
+    /// `REFUSED_STREAM` is re-sent once, whatever its method, since it's known not to have
+    /// been processed.
+    pub max_idempotent_retries: u32,
+    max_decompressed_body_size: usize,
+    /// Requests with a longer body fail with [ChatNetworkError::RequestTooLarge] without being sent.
+    ///
+    /// The size of the streaming request bodies is not known in advance, so they are not checked.
+    pub max_request_bytes: usize,
+    /// Responses with a longer body fail with [ChatNetworkError::ResponseTooLarge].
+    pub max_response_bytes: usize,
+    /// If set, requests rejected with `429` or `503` are re-sent once after the server-requested
+    /// delay. Otherwise such responses are returned to the caller as they are.
+    ///
+    /// Streaming requests are never re-sent.
+    pub retry_after_policy: Option<RetryAfterPolicy>,
+    /// If set, only the response headers with these names are copied into [ResponseProto],
+    /// and all the others (e.g. hop-by-hop headers added by proxies) are dropped.
+    ///
+    /// Trailers are not filtered.
+    pub response_header_allow_list: Option<Vec<HeaderName>>,
+    /// If set, the bodies of the requests sent with [ChatService::send_compressed] are sent
+    /// compressed (unless the request already has a `Content-Encoding`), and all requests
+    /// advertise the supported response encodings with `Accept-Encoding`.
+    ///
+    /// Compression is opt-in for every request, since compressing secrets along with data
+    /// an attacker can influence leaks the secrets through the size of the body, even over
+    /// TLS (see BREACH). Streaming request bodies are never compressed.
+    pub request_compression: Option<RequestCompression>,
+    /// If set, every re-sent request takes a token from the budget, and once it's exhausted
+    /// requests fail with the error (or the rate limiting response) of their last attempt.
+    pub retry_budget: Option<Arc<RetryBudget>>,
+    /// If set, the response bodies are aggregated into reused buffers rather than into new ones
+    /// for every request, which saves allocations when many requests are sent.
+    ///
+    /// The bodies in the returned [ResponseProto]s are copies, so they are not affected when
+    /// the buffers are reused. The pool can be shared between services.
+    pub buffer_pool: Option<Arc<BufferPool>>,
+    /// If set, requests with large enough bodies are sent with `Expect: 100-continue`,
+    /// so that the server can reject them before their body is uploaded.
+    ///
+    /// Streaming requests are never sent with the expectation.
+    pub expect_continue: Option<ExpectContinuePolicy>,
+    shutdown: Arc<GracefulShutdown>,
+    idle_tracker: Arc<IdleTracker>,
+    /// See [ChatOverHttp2Config::max_in_flight].
+    in_flight_limit: Arc<InFlightLimit>,
+    stats: Arc<StatsCounters>,
+    service_status: ServiceStatus<ChatNetworkError>,
+    connection_info: ConnectionInfo,
+    /// See [ChatOverHttp2Config::clock].
+    clock: Arc<dyn Clock>,
 }
 
-fn start_event_listener(
-    connection: Http2Connection,
-    service_status: ServiceStatus<ChatNetworkError>,
-) {
-    tokio::spawn(async move {
-        enum Event {
-            Cancellation,
-            ChannelClosed(Result<(), hyper::Error>),
-        }
-        let outcome = match tokio::select! {
-            _ = service_status.stopped() => Event::Cancellation,
-            r = connection => Event::ChannelClosed(r),
-        } {
-            Event::Cancellation => ChatNetworkError::ChannelClosedByLocalPeer,
-            Event::ChannelClosed(Ok(_)) => ChatNetworkError::ChannelClosedByRemotePeer,
-            Event::ChannelClosed(Err(e)) => ChatNetworkError::ChannelClosedWithError(e),
+fn in_flight_limit(max_in_flight: usize) -> Arc<InFlightLimit> {
+    Arc::new(InFlightLimit {
+        state: Mutex::new(InFlightLimitState {
+            available: max_in_flight.max(1),
+            waiting: BinaryHeap::new(),
+            next_waiter_id: 0,
+        }),
+    })
+}
+
+/// Semaphore that lets the waiting requests through in the order of their [RequestPriority],
+/// and in the order they started waiting within the same priority.
+#[derive(Debug)]
+struct InFlightLimit {
+    state: Mutex<InFlightLimitState>,
+}
+
+#[derive(Debug)]
+struct InFlightLimitState {
+    available: usize,
+    waiting: BinaryHeap<InFlightWaiter>,
+    next_waiter_id: u64,
+}
+
+#[derive(Debug)]
+struct InFlightWaiter {
+    priority: RequestPriority,
+    id: u64,
+    permit_sender: oneshot::Sender<InFlightPermit>,
+}
+
+impl InFlightWaiter {
+    fn order_key(&self) -> (RequestPriority, Reverse<u64>) {
+        (self.priority, Reverse(self.id))
+    }
+}
+
+impl PartialEq for InFlightWaiter {
+    fn eq(&self, other: &Self) -> bool {
+        self.order_key() == other.order_key()
+    }
+}
+
+impl Eq for InFlightWaiter {}
+
+impl PartialOrd for InFlightWaiter {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for InFlightWaiter {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.order_key().cmp(&other.order_key())
+    }
+}
+
+/// Allows a request to be in flight; the next waiting request is let through once it's dropped.
+#[derive(Debug)]
+struct InFlightPermit {
+    limit: Option<Arc<InFlightLimit>>,
+}
+
+impl InFlightLimit {
+    async fn acquire(self: &Arc<Self>, priority: RequestPriority) -> InFlightPermit {
+        let permit_receiver = {
+            let mut state = self.state.lock().expect("not poisoned");
+            if state.available > 0 {
+                state.available -= 1;
+                return InFlightPermit {
+                    limit: Some(self.clone()),
+                };
+            }
+            let (permit_sender, permit_receiver) = oneshot::channel();
+            let id = state.next_waiter_id;
+            state.next_waiter_id += 1;
+            state.waiting.push(InFlightWaiter {
+                priority,
+                id,
+                permit_sender,
+            });
+            permit_receiver
         };
-        service_status.stop_service_with_error(outcome);
-    });
+        // If this future is dropped after the permit was sent, the permit is dropped
+        // along with the receiver and passed on to the next waiter.
+        permit_receiver
+            .await
+            .expect("waiters are only dropped after sending them a permit")
+    }
+
+    fn release(&self, mut permit: InFlightPermit) {
+        loop {
+            let waiter = {
+                let mut state = self.state.lock().expect("not poisoned");
+                match state.waiting.pop() {
+                    Some(waiter) => waiter,
+                    None => {
+                        state.available += 1;
+                        // the permit goes back to the pool rather than to a waiter
+                        permit.limit = None;
+                        return;
+                    }
+                }
+            };
+            // A waiter that gave up waiting returns the permit, which goes to the next one.
+            match waiter.permit_sender.send(permit) {
+                Ok(()) => return,
+                Err(returned) => permit = returned,
+            }
+        }
+    }
+}
+
+impl Drop for InFlightPermit {
+    fn drop(&mut self) {
+        if let Some(limit) = self.limit.take() {
+            limit.release(InFlightPermit {
+                limit: Some(limit.clone()),
+            });
+        }
+    }
+}
+
+/// Counters of a connection used by [ChatOverHttp2], see [ChatOverHttp2::stats].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ConnectionStats {
+    /// Number of requests sent, including the re-sent ones
+    pub requests_sent: u64,
+    /// Total size of the request bodies sent
+    pub bytes_sent: u64,
+    /// Total size of the response bodies received, before decompression
+    pub bytes_received: u64,
+    /// Number of requests currently in flight
+    pub active_streams: usize,
+    /// Number of requests whose stream was abandoned before the response was received
+    /// because they were interrupted, timed out, or cancelled
+    pub reset_streams: u64,
+    /// Number of requests the server refused with `REFUSED_STREAM` without processing them
+    pub refused_streams: u64,
+}
+
+#[derive(Default)]
+struct StatsCounters {
+    requests_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    reset_streams: AtomicU64,
+    refused_streams: AtomicU64,
+}
+
+impl StatsCounters {
+    fn request_sent(&self, body_len: usize) {
+        self.requests_sent.fetch_add(1, Ordering::Relaxed);
+        self.add_bytes_sent(body_len);
+    }
+
+    fn add_bytes_sent(&self, len: usize) {
+        self.bytes_sent.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    fn response_received(&self, body_len: usize) {
+        self.bytes_received
+            .fetch_add(body_len as u64, Ordering::Relaxed);
+    }
+
+    fn send_failed(&self, error: NetError) -> ChatNetworkError {
+        if let NetError::StreamRefused = error {
+            self.refused_streams.fetch_add(1, Ordering::Relaxed);
+        }
+        send_error(error)
+    }
+
+    fn request_completed<T>(&self, result: &Result<T, ChatNetworkError>) {
+        if let Err(
+            ChatNetworkError::FailedToSendHttp(
+                NetError::ConnectionInterrupted | NetError::StreamReset,
+            )
+            | ChatNetworkError::Timeout { .. }
+            | ChatNetworkError::Cancelled,
+        ) = result
+        {
+            self.reset_streams.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Counts the requests in flight on a connection, so that the connection can be closed
+/// after [ChatOverHttp2Config::idle_timeout].
+#[derive(Default)]
+struct IdleTracker {
+    in_flight: AtomicUsize,
+    /// Notified whenever a request starts or completes.
+    activity: Notify,
+}
+
+impl IdleTracker {
+    fn start_request(&self) -> ActiveRequest<'_> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        self.activity.notify_one();
+        ActiveRequest(self)
+    }
+
+    fn is_idle(&self) -> bool {
+        self.in_flight.load(Ordering::SeqCst) == 0
+    }
+}
+
+/// Marks a request as in flight until dropped.
+struct ActiveRequest<'a>(&'a IdleTracker);
+
+impl Drop for ActiveRequest<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+        self.0.activity.notify_one();
+    }
+}
+
+/// Keeps track of the requests in flight so that they can be drained
+/// on [ChatOverHttp2::shutdown].
+#[derive(Default)]
+struct GracefulShutdown {
+    /// Every request in flight holds a read lock for its duration.
+    in_flight: RwLock<()>,
+    /// Cancelled once the shutdown starts, after which no new requests are accepted.
+    draining: CancellationToken,
+    /// Cancelled once the grace period is over, aborting the requests still in flight.
+    aborted: CancellationToken,
+}
+
+impl GracefulShutdown {
+    async fn track<T>(
+        &self,
+        request: impl Future<Output = Result<T, ChatNetworkError>>,
+    ) -> Result<T, ChatNetworkError> {
+        if self.draining.is_cancelled() {
+            return Err(ChatNetworkError::ChannelClosed);
+        }
+        let _in_flight = self.in_flight.read().await;
+        tokio::select! {
+            result = request => result,
+            _ = self.aborted.cancelled() => Err(ChatNetworkError::Cancelled),
+        }
+    }
+
+    async fn shutdown(&self, clock: &dyn Clock, grace: Duration) {
+        self.draining.cancel();
+        // the write lock can only be acquired once all requests in flight are completed
+        let _ignore_timeout = clock::timeout(clock, grace, self.in_flight.write()).await;
+        self.aborted.cancel();
+    }
+}
+
+/// Whether a request that failed with the given error may succeed if it's sent again
+/// over the same connection.
+///
+/// Errors that mean the connection itself is gone are not transient: every retry would fail
+/// the same way, since the service can't reconnect.
+fn is_transient(error: &ChatNetworkError) -> bool {
+    matches!(
+        error,
+        ChatNetworkError::FailedToSendHttp(NetError::StreamReset)
+    )
+}
+
+/// Calls `send_attempt` until it succeeds or fails with an error that is not transient.
+///
+/// If `method` is idempotent, up to `max_idempotent_retries` retries are made,
+/// otherwise the result of the first attempt is returned. Each retry takes a token
+/// from the `retry_budget`, and once it's exhausted the last error is returned.
+///
+/// Regardless of the method, a request whose stream was refused by the server is retried once
+/// more, since it's known not to have been processed. That retry doesn't count towards
+/// `max_idempotent_retries`, but still takes a token from the `retry_budget`.
+async fn send_with_retries<T, F, Fut>(
+    method: &Method,
+    max_idempotent_retries: u32,
+    retry_budget: Option<&RetryBudget>,
+    mut send_attempt: F,
+) -> Result<T, ChatNetworkError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, ChatNetworkError>>,
+{
+    let max_retries = if method.is_idempotent() {
+        max_idempotent_retries
+    } else {
+        0
+    };
+    let mut retries = 0;
+    let mut refused_stream_retried = false;
+    loop {
+        match send_attempt().await {
+            Err(ChatNetworkError::FailedToSendHttp(NetError::StreamRefused))
+                if !refused_stream_retried
+                    && retry_budget.map_or(true, RetryBudget::try_acquire) =>
+            {
+                refused_stream_retried = true
+            }
+            Err(e)
+                if retries < max_retries
+                    && is_transient(&e)
+                    && retry_budget.map_or(true, RetryBudget::try_acquire) =>
+            {
+                retries += 1
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Returns the error code of the GOAWAY frame if the connection failed because
+/// the server sent one.
+///
+/// A GOAWAY with `NO_ERROR` doesn't fail the connection: it's closed normally
+/// once the requests in flight complete.
+fn go_away_error_code(error: &hyper::Error) -> Option<u32> {
+    let h2_error = std::error::Error::source(error)?.downcast_ref::<h2::Error>()?;
+    if h2_error.is_go_away() && h2_error.is_remote() {
+        h2_error.reason().map(u32::from)
+    } else {
+        None
+    }
+}
+
+fn start_event_listener(
+    connection: impl Future<Output = Result<(), hyper::Error>> + Send + 'static,
+    service_status: ServiceStatus<ChatNetworkError>,
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    idle_tracker: Arc<IdleTracker>,
+    clock: Arc<dyn Clock>,
+) {
+    service_status.emit_event(ConnectionEvent::Connected);
+    tokio::spawn(async move {
+        enum Event {
+            Cancellation,
+            Idle,
+            Expired,
+            ChannelClosed(Result<(), hyper::Error>),
+        }
+        let mut connection = std::pin::pin!(connection);
+        let mut lifetime_timer = std::pin::pin!(async {
+            match max_lifetime {
+                Some(max_lifetime) => clock.sleep(max_lifetime).await,
+                None => std::future::pending().await,
+            }
+        });
+        let event = loop {
+            let idle_timer = async {
+                match idle_timeout {
+                    Some(idle_timeout) => clock.sleep(idle_timeout).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = service_status.stopped() => break Event::Cancellation,
+                r = connection.as_mut() => break Event::ChannelClosed(r),
+                _ = lifetime_timer.as_mut() => break Event::Expired,
+                // any activity restarts the timer
+                _ = idle_tracker.activity.notified() => continue,
+                _ = idle_timer => {
+                    if idle_tracker.is_idle() {
+                        break Event::Idle;
+                    }
+                }
+            }
+        };
+        let (outcome, connection_event) = match event {
+            Event::Cancellation => (
+                ChatNetworkError::ChannelClosedByLocalPeer,
+                ConnectionEvent::Closed(CloseReason::LocalPeer),
+            ),
+            Event::Idle => {
+                log::info!("closing the connection after it has been idle");
+                (
+                    ChatNetworkError::ChannelClosedByLocalPeer,
+                    ConnectionEvent::Closed(CloseReason::LocalPeer),
+                )
+            }
+            Event::Expired => {
+                log::info!("rotating the connection after it reached its maximum lifetime");
+                // new requests go to a new connection from now on,
+                // while the ones in flight complete on this one
+                service_status.stop_service();
+                while !idle_tracker.is_idle() {
+                    tokio::select! {
+                        _ = connection.as_mut() => break,
+                        // notified whenever a request completes
+                        _ = idle_tracker.activity.notified() => {}
+                    }
+                }
+                (
+                    ChatNetworkError::ChannelClosedByLocalPeer,
+                    ConnectionEvent::Closed(CloseReason::LocalPeer),
+                )
+            }
+            Event::ChannelClosed(Ok(_)) => (
+                ChatNetworkError::ChannelClosedByRemotePeer,
+                ConnectionEvent::Closed(CloseReason::RemotePeer),
+            ),
+            // a PING that wasn't acknowledged in time means that the remote end is gone
+            Event::ChannelClosed(Err(e)) if e.is_timeout() => (
+                ChatNetworkError::ChannelClosedByRemotePeer,
+                ConnectionEvent::Closed(CloseReason::RemotePeer),
+            ),
+            Event::ChannelClosed(Err(e)) => match go_away_error_code(&e) {
+                Some(error_code) => {
+                    log::info!("server closed the connection with GOAWAY, error code {error_code}");
+                    (
+                        ChatNetworkError::GoAway { error_code },
+                        ConnectionEvent::Closed(CloseReason::RemotePeer),
+                    )
+                }
+                None => {
+                    let error = ChatNetworkError::ChannelClosedWithError(e);
+                    let connection_event = ConnectionEvent::Error(error.to_string());
+                    (error, connection_event)
+                }
+            },
+        };
+        service_status.stop_service_with_error(outcome);
+        service_status.emit_event(connection_event);
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use assert_matches::assert_matches;
+    use bytes::Bytes;
+    use futures_util::FutureExt;
+    use http::response::Parts;
+    use http::{HeaderMap, HeaderName, HeaderValue, Method};
+    use http_body_util::{BodyExt, Empty, Full};
+    use hyper::body::Incoming;
+
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use tokio_util::sync::CancellationToken;
+
+    use crate::chat::errors::{ChatNetworkError, ConnectAndSendError};
+    use crate::chat::http::{
+        check_request_size, in_flight_limit, parse_retry_after, response_to_proto,
+        retain_allowed_headers, retry_after_rate_limit, send_error, send_with_retries,
+        start_event_listener, ChatOverHttp2, ChatOverHttp2Config, ChatOverHttp2ServiceConnector,
+        ConnectionStats, ExpectContinuePolicy, GracefulShutdown, IdleTracker, RetryAfterPolicy,
+    };
+    use crate::chat::{ChatMessageType, ChatService, MessageProto, RequestPriority, RequestProto};
+    use crate::infra::clock::{MockClock, SystemClock};
+    use crate::infra::connection_manager::SingleRouteThrottlingConnectionManager;
+    use crate::infra::errors::NetError;
+    use crate::infra::http::{
+        AggregatingHttp2Client, AggregatingHttpClient, ContentEncoding, RequestCompression,
+        ResponseTrailers,
+    };
+    use crate::infra::reconnect::{
+        ReconnectBackoff, RetryBudget, RetryBudgetConfig, ServiceConnector, ServiceStatus,
+        ServiceWithReconnect,
+    };
+    use crate::infra::{in_memory, BufferPool, ConnectionParams};
+
+    const MAX_RETRIES: u32 = 3;
+
+    async fn attempts_until_result(
+        method: Method,
+        error: fn() -> ChatNetworkError,
+    ) -> (u32, Result<(), ChatNetworkError>) {
+        let attempts = AtomicU32::new(0);
+        let result = send_with_retries(&method, MAX_RETRIES, None, || {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            async move { Err(error()) }
+        })
+        .await;
+        (attempts.load(Ordering::Relaxed), result)
+    }
+
+    #[tokio::test]
+    async fn connect_and_send_rejects_invalid_request_without_connecting() {
+        let connector = ChatOverHttp2ServiceConnector::default();
+        // the request is validated before any network activity happens
+        let connection_params = crate::env::STAGING.chat_direct_connection();
+
+        let result = connector
+            .connect_and_send(
+                &connection_params,
+                &MessageProto::default(),
+                Duration::from_secs(1),
+                CancellationToken::new(),
+            )
+            .await;
+        assert_matches!(
+            result,
+            Err(ConnectAndSendError::InvalidRequest(
+                ChatNetworkError::UnexpectedMessageType
+            ))
+        );
+
+        let missing_path = MessageProto {
+            r#type: Some(ChatMessageType::Request.into()),
+            request: Some(RequestProto {
+                verb: Some("GET".to_string()),
+                ..Default::default()
+            }),
+            response: None,
+        };
+        let result = connector
+            .connect_and_send(
+                &connection_params,
+                &missing_path,
+                Duration::from_secs(1),
+                CancellationToken::new(),
+            )
+            .await;
+        assert_matches!(
+            result,
+            Err(ConnectAndSendError::InvalidRequest(
+                ChatNetworkError::RequestMissingVerbOrPath
+            ))
+        );
+    }
+
+    #[test]
+    fn retry_after_is_parsed_in_both_forms() {
+        let now = httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").unwrap();
+        let parse = |value| parse_retry_after(&HeaderValue::from_static(value), now);
+        assert_eq!(parse("120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse(" 0 "), Some(Duration::ZERO));
+        assert_eq!(
+            parse("Wed, 21 Oct 2015 07:30:30 GMT"),
+            Some(Duration::from_secs(150))
+        );
+        // dates in the past mean "now"
+        assert_eq!(parse("Wed, 21 Oct 2015 07:00:00 GMT"), Some(Duration::ZERO));
+        assert_eq!(parse("soon"), None);
+        assert_eq!(parse("-5"), None);
+    }
+
+    fn response_parts(status: u16, retry_after: Option<&'static str>) -> (Parts, Bytes) {
+        let mut builder = http::Response::builder().status(status);
+        if let Some(retry_after) = retry_after {
+            builder = builder.header(http::header::RETRY_AFTER, retry_after);
+        }
+        (builder.body(()).unwrap().into_parts().0, Bytes::new())
+    }
+
+    fn retry_after_policy() -> RetryAfterPolicy {
+        RetryAfterPolicy {
+            jitter: Duration::ZERO,
+            max_wait: Duration::from_secs(10),
+            fallback: ReconnectBackoff {
+                initial: Duration::from_secs(1),
+                max: Duration::from_secs(1),
+                jitter: Duration::ZERO,
+            },
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limited_request_is_retried_once_after_requested_delay() {
+        let start = tokio::time::Instant::now();
+        let (parts, _) = retry_after_rate_limit(
+            &retry_after_policy(),
+            &SystemClock,
+            None,
+            response_parts(429, Some("3")),
+            || async { Ok(response_parts(200, None)) },
+        )
+        .await
+        .unwrap();
+        assert_eq!(parts.status, 200);
+        assert!(start.elapsed() >= Duration::from_secs(3));
+
+        // without `Retry-After`, the fallback backoff is used
+        let start = tokio::time::Instant::now();
+        let (parts, _) = retry_after_rate_limit(
+            &retry_after_policy(),
+            &SystemClock,
+            None,
+            response_parts(503, None),
+            || async { Ok(response_parts(200, None)) },
+        )
+        .await
+        .unwrap();
+        assert_eq!(parts.status, 200);
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limited_error_when_retry_is_exhausted_or_wait_is_too_long() {
+        let result = retry_after_rate_limit(
+            &retry_after_policy(),
+            &SystemClock,
+            None,
+            response_parts(429, Some("1")),
+            || async { Ok(response_parts(429, Some("7"))) },
+        )
+        .await;
+        // the one-second wait before the request was re-sent counts towards the total
+        assert_matches!(
+            result,
+            Err(ChatNetworkError::RateLimited { retry_after }) if retry_after == Duration::from_secs(8)
+        );
+
+        // the wait exceeds `max_wait`, so the request must not be re-sent
+        let result = retry_after_rate_limit(
+            &retry_after_policy(),
+            &SystemClock,
+            None,
+            response_parts(429, Some("60")),
+            || async { Err(ChatNetworkError::RequestIdCollision) },
+        )
+        .await;
+        assert_matches!(
+            result,
+            Err(ChatNetworkError::RateLimited { retry_after }) if retry_after == Duration::from_secs(60)
+        );
+    }
+
+    #[tokio::test]
+    async fn other_responses_are_returned_as_is() {
+        let (parts, _) = retry_after_rate_limit(
+            &retry_after_policy(),
+            &SystemClock,
+            None,
+            response_parts(500, Some("1")),
+            || async { Err(ChatNetworkError::RequestIdCollision) },
+        )
+        .await
+        .unwrap();
+        assert_eq!(parts.status, 500);
+    }
+
+    const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+    #[tokio::test(start_paused = true)]
+    async fn idle_connection_is_closed() {
+        let service_status = ServiceStatus::new();
+        let idle_tracker = Arc::new(IdleTracker::default());
+        start_event_listener(
+            std::future::pending(),
+            service_status.clone(),
+            Some(IDLE_TIMEOUT),
+            None,
+            idle_tracker.clone(),
+            Arc::new(SystemClock),
+        );
+
+        // activity restarts the timer
+        tokio::time::sleep(IDLE_TIMEOUT / 2).await;
+        drop(idle_tracker.start_request());
+        tokio::time::sleep(IDLE_TIMEOUT * 3 / 4).await;
+        assert!(!service_status.is_stopped());
+
+        tokio::time::sleep(IDLE_TIMEOUT).await;
+        assert!(service_status.is_stopped());
+        assert_matches!(
+            service_status.get_error(),
+            Some(ChatNetworkError::ChannelClosedByLocalPeer)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connection_with_request_in_flight_is_not_idle() {
+        let service_status = ServiceStatus::<ChatNetworkError>::new();
+        let idle_tracker = Arc::new(IdleTracker::default());
+        start_event_listener(
+            std::future::pending(),
+            service_status.clone(),
+            Some(IDLE_TIMEOUT),
+            None,
+            idle_tracker.clone(),
+            Arc::new(SystemClock),
+        );
+
+        let active_request = idle_tracker.start_request();
+        tokio::time::sleep(IDLE_TIMEOUT * 3).await;
+        assert!(!service_status.is_stopped());
+
+        drop(active_request);
+        tokio::time::sleep(IDLE_TIMEOUT * 2).await;
+        assert!(service_status.is_stopped());
+    }
+
+    #[tokio::test]
+    async fn idle_timeout_is_measured_by_the_clock() {
+        let clock = MockClock::new();
+        let service_status = ServiceStatus::<ChatNetworkError>::new();
+        start_event_listener(
+            std::future::pending(),
+            service_status.clone(),
+            Some(IDLE_TIMEOUT),
+            None,
+            Arc::new(IdleTracker::default()),
+            Arc::new(clock.clone()),
+        );
+        // lets the listener start its timer
+        tokio::task::yield_now().await;
+        assert!(!service_status.is_stopped());
+
+        // no real time has to pass
+        clock.advance(IDLE_TIMEOUT);
+        tokio::time::timeout(Duration::from_secs(5), service_status.stopped())
+            .await
+            .expect("connection is closed");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connection_without_idle_timeout_stays_open() {
+        let service_status = ServiceStatus::<ChatNetworkError>::new();
+        start_event_listener(
+            std::future::pending(),
+            service_status.clone(),
+            None,
+            None,
+            Arc::new(IdleTracker::default()),
+            Arc::new(SystemClock),
+        );
+        tokio::time::sleep(IDLE_TIMEOUT * 10).await;
+        assert!(!service_status.is_stopped());
+    }
+
+    /// Connects services over in-memory connections to a server that responds to every request
+    /// with `200` after waiting for the number of seconds given by its path, e.g. `/10`.
+    #[derive(Clone)]
+    struct InMemoryServiceConnector {
+        inner: ChatOverHttp2ServiceConnector,
+        connections_made: Arc<AtomicU32>,
+    }
+
+    #[async_trait]
+    impl ServiceConnector for InMemoryServiceConnector {
+        type Service = ChatOverHttp2;
+        // The connection is not `Sync`, which channels have to be, hence the mutex.
+        type Channel = (
+            AggregatingHttp2Client,
+            std::sync::Mutex<Pin<Box<dyn Future<Output = Result<(), hyper::Error>> + Send>>>,
+        );
+        type Error = ChatNetworkError;
+
+        async fn connect_channel(
+            &self,
+            _connection_params: &ConnectionParams,
+        ) -> Result<Self::Channel, Self::Error> {
+            let (request_sender, connection) = in_memory::connect(|io| {
+                in_memory::serve_fn(io, |request| {
+                    let delay = request.uri().path()[1..].parse().unwrap_or(0);
+                    async move {
+                        tokio::time::sleep(Duration::from_secs(delay)).await;
+                        http::Response::builder()
+                            .status(200)
+                            .body(Empty::<Bytes>::new())
+                    }
+                })
+            })
+            .await;
+            self.connections_made.fetch_add(1, Ordering::SeqCst);
+            Ok((request_sender, std::sync::Mutex::new(Box::pin(connection))))
+        }
+
+        fn start_service(
+            &self,
+            (request_sender, connection): Self::Channel,
+        ) -> (Self::Service, ServiceStatus<Self::Error>) {
+            self.inner.start_service_over(
+                request_sender,
+                connection.into_inner().expect("not poisoned"),
+                in_memory::connection_info(),
+            )
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connection_is_replaced_after_max_lifetime_without_dropping_requests() {
+        const MAX_LIFETIME: Duration = Duration::from_secs(60);
+        const TIMEOUT: Duration = Duration::from_secs(5);
+        let connector = InMemoryServiceConnector {
+            inner: ChatOverHttp2ServiceConnector::new(ChatOverHttp2Config {
+                max_connection_lifetime: Some(MAX_LIFETIME),
+                ..Default::default()
+            }),
+            connections_made: Default::default(),
+        };
+        let manager =
+            SingleRouteThrottlingConnectionManager::new(in_memory::connection_params(), TIMEOUT);
+        let mut service_with_reconnect =
+            ServiceWithReconnect::new(connector.clone(), manager, TIMEOUT);
+
+        let mut service = service_with_reconnect
+            .service_clone()
+            .await
+            .expect("connected");
+        assert_eq!(connector.connections_made.load(Ordering::SeqCst), 1);
+
+        // still in flight when the connection reaches its maximum lifetime
+        let in_flight = tokio::spawn(async move {
+            let msg = MessageProto {
+                request: Some(RequestProto {
+                    verb: Some("GET".to_string()),
+                    path: Some("/70".to_string()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+            service.send(&msg, Duration::from_secs(120)).await
+        });
+
+        tokio::time::sleep(MAX_LIFETIME - Duration::from_secs(1)).await;
+        service_with_reconnect
+            .service_clone()
+            .await
+            .expect("connected");
+        assert_eq!(connector.connections_made.load(Ordering::SeqCst), 1);
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        service_with_reconnect
+            .service_clone()
+            .await
+            .expect("connected");
+        assert_eq!(connector.connections_made.load(Ordering::SeqCst), 2);
+        assert!(!in_flight.is_finished());
+
+        let response = in_flight
+            .await
+            .expect("task completes")
+            .expect("response is received");
+        assert_eq!(response.status, Some(200));
+    }
+
+    fn keepalive_connection_params() -> ConnectionParams {
+        ConnectionParams::builder("chat.signal.org")
+            .http2_keepalive_interval(KEEPALIVE_INTERVAL)
+            .http2_keepalive_timeout(KEEPALIVE_TIMEOUT)
+            .http2_keepalive_jitter_percent(0)
+            .build()
+            .expect("valid params")
+    }
+
+    const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+    const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[tokio::test(start_paused = true)]
+    async fn connection_without_ping_acknowledgement_is_closed() {
+        let (_client, connection) =
+            in_memory::connect_with_keepalive(&keepalive_connection_params(), |io| async move {
+                // the connection is never polled after the handshake,
+                // so the PINGs are never acknowledged
+                let _connection = h2::server::handshake(io).await;
+                std::future::pending::<()>().await
+            })
+            .await;
+        let service_status = ServiceStatus::new();
+        start_event_listener(
+            connection,
+            service_status.clone(),
+            None,
+            None,
+            Arc::new(IdleTracker::default()),
+            Arc::new(SystemClock),
+        );
+
+        tokio::time::sleep(KEEPALIVE_INTERVAL - Duration::from_secs(1)).await;
+        assert!(!service_status.is_stopped());
+
+        tokio::time::timeout(
+            KEEPALIVE_TIMEOUT + Duration::from_secs(2),
+            service_status.stopped(),
+        )
+        .await
+        .expect("connection is closed");
+        assert_matches!(
+            service_status.get_error(),
+            Some(ChatNetworkError::ChannelClosedByRemotePeer)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connection_with_ping_acknowledgements_stays_open() {
+        let (_client, connection) =
+            in_memory::connect_with_keepalive(&keepalive_connection_params(), |io| {
+                in_memory::serve_echo(io, CancellationToken::new())
+            })
+            .await;
+        let service_status = ServiceStatus::<ChatNetworkError>::new();
+        start_event_listener(
+            connection,
+            service_status.clone(),
+            None,
+            None,
+            Arc::new(IdleTracker::default()),
+            Arc::new(SystemClock),
+        );
+
+        tokio::time::sleep(KEEPALIVE_INTERVAL * 10).await;
+        assert!(!service_status.is_stopped());
+    }
+
+    #[tokio::test]
+    async fn go_away_from_server_is_reported() {
+        let (mut client, connection) = in_memory::connect(|io| async move {
+            let mut connection = h2::server::handshake(io).await.expect("handshake succeeds");
+            let _request = connection.accept().await;
+            connection.abrupt_shutdown(h2::Reason::ENHANCE_YOUR_CALM);
+            while let Some(Ok(_)) = connection.accept().await {}
+        })
+        .await;
+        let service_status = ServiceStatus::new();
+        start_event_listener(
+            connection,
+            service_status.clone(),
+            None,
+            None,
+            Arc::new(IdleTracker::default()),
+            Arc::new(SystemClock),
+        );
+        tokio::spawn(async move {
+            client
+                .send_request_aggregate_response(
+                    "/v1/keepalive",
+                    http::Request::builder().method(Method::GET),
+                    Bytes::new(),
+                )
+                .await
+        });
+
+        tokio::time::timeout(Duration::from_secs(5), service_status.stopped())
+            .await
+            .expect("connection is closed");
+        assert_matches!(
+            service_status.get_error(),
+            Some(ChatNetworkError::GoAway { error_code: 11 })
+        );
+    }
+
+    #[tokio::test]
+    async fn in_flight_limit_queues_requests() {
+        let limit = in_flight_limit(2);
+        let first = limit.acquire(RequestPriority::Normal).await;
+        let _second = limit.acquire(RequestPriority::Normal).await;
+
+        let queued = tokio::spawn({
+            let limit = limit.clone();
+            async move {
+                let _permit = limit.acquire(RequestPriority::Normal).await;
+            }
+        });
+        tokio::task::yield_now().await;
+        assert!(!queued.is_finished());
+
+        drop(first);
+        queued.await.expect("queued request proceeds");
+
+        let limit = in_flight_limit(0);
+        let _only = limit.acquire(RequestPriority::Normal).await;
+        assert!(limit
+            .acquire(RequestPriority::Normal)
+            .now_or_never()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn in_flight_limit_lets_higher_priority_requests_through_first() {
+        let limit = in_flight_limit(1);
+        let first = limit.acquire(RequestPriority::Normal).await;
+
+        let (order_tx, mut order_rx) = tokio::sync::mpsc::unbounded_channel();
+        let queue = |priority| {
+            let limit = limit.clone();
+            let order_tx = order_tx.clone();
+            tokio::spawn(async move {
+                let _permit = limit.acquire(priority).await;
+                order_tx.send(priority).unwrap();
+            })
+        };
+        let background = queue(RequestPriority::Background);
+        let first_normal = queue(RequestPriority::Normal);
+        tokio::task::yield_now().await;
+        let second_normal = queue(RequestPriority::Normal);
+        let interactive = queue(RequestPriority::Interactive);
+        tokio::task::yield_now().await;
+
+        drop(first);
+        for queued in [background, first_normal, second_normal, interactive] {
+            queued.await.expect("queued request proceeds");
+        }
+        drop(order_tx);
+        let mut order = vec![];
+        while let Some(priority) = order_rx.recv().await {
+            order.push(priority);
+        }
+        assert_eq!(
+            order,
+            [
+                RequestPriority::Interactive,
+                RequestPriority::Normal,
+                RequestPriority::Normal,
+                RequestPriority::Background,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn in_flight_permit_is_passed_on_when_a_waiter_gives_up() {
+        let limit = in_flight_limit(1);
+        let first = limit.acquire(RequestPriority::Normal).await;
+
+        let abandoned = tokio::spawn({
+            let limit = limit.clone();
+            async move {
+                let _permit = limit.acquire(RequestPriority::Interactive).await;
+            }
+        });
+        let queued = tokio::spawn({
+            let limit = limit.clone();
+            async move {
+                let _permit = limit.acquire(RequestPriority::Background).await;
+            }
+        });
+        tokio::task::yield_now().await;
+        abandoned.abort();
+        let _ = abandoned.await;
+
+        drop(first);
+        queued.await.expect("queued request proceeds");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn service_sends_at_most_max_in_flight_requests_at_once() {
+        const MAX_IN_FLIGHT: usize = 2;
+        let active = Arc::new(AtomicU32::new(0));
+        let most_active = Arc::new(AtomicU32::new(0));
+
+        let service = ChatOverHttp2ServiceConnector::new(ChatOverHttp2Config {
+            max_in_flight: MAX_IN_FLIGHT,
+            ..Default::default()
+        })
+        .connect_in_memory({
+            let active = active.clone();
+            let most_active = most_active.clone();
+            move |io| {
+                in_memory::serve_fn(io, move |_request: http::Request<Incoming>| {
+                    let active = active.clone();
+                    let most_active = most_active.clone();
+                    async move {
+                        let now_active = active.fetch_add(1, Ordering::SeqCst) + 1;
+                        most_active.fetch_max(now_active, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        active.fetch_sub(1, Ordering::SeqCst);
+                        Ok::<_, hyper::Error>(http::Response::new(Empty::<Bytes>::new()))
+                    }
+                })
+            }
+        })
+        .await;
+
+        let requests: Vec<_> = (0..3 * MAX_IN_FLIGHT)
+            .map(|_| {
+                let mut service = service.clone();
+                tokio::spawn(async move {
+                    service
+                        .send(&put_request(b"body"), Duration::from_secs(5))
+                        .await
+                })
+            })
+            .collect();
+        for request in requests {
+            let response = request
+                .await
+                .expect("not panicked")
+                .expect("response is received");
+            assert_eq!(response.status, Some(200));
+        }
+
+        assert_eq!(
+            most_active.load(Ordering::SeqCst),
+            MAX_IN_FLIGHT as u32,
+            "requests beyond the limit wait for a permit"
+        );
+    }
+
+    fn stream_reset() -> ChatNetworkError {
+        ChatNetworkError::FailedToSendHttp(NetError::StreamReset)
+    }
+
+    #[tokio::test]
+    async fn idempotent_request_is_retried_on_transient_error() {
+        let (attempts, result) = attempts_until_result(Method::GET, stream_reset).await;
+        assert_eq!(attempts, MAX_RETRIES + 1);
+        assert_matches!(
+            result,
+            Err(ChatNetworkError::FailedToSendHttp(NetError::StreamReset))
+        );
+    }
+
+    #[tokio::test]
+    async fn post_request_is_never_retried() {
+        let (attempts, _) = attempts_until_result(Method::POST, stream_reset).await;
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn idempotent_request_is_not_retried_on_permanent_error() {
+        let (attempts, _) = attempts_until_result(Method::GET, || {
+            ChatNetworkError::FailedToSendHttp(NetError::ContentLengthHeaderInvalid)
+        })
+        .await;
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn idempotent_request_is_not_retried_once_connection_is_lost() {
+        let (attempts, _) = attempts_until_result(Method::GET, || {
+            ChatNetworkError::FailedToSendHttp(NetError::ConnectionInterrupted)
+        })
+        .await;
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn requests_are_not_retried_once_retry_budget_is_exhausted() {
+        let budget = RetryBudget::new(RetryBudgetConfig {
+            capacity: 2,
+            refill_interval: Duration::from_secs(3600),
+        });
+        let attempts_until_error = || async {
+            let attempts = AtomicU32::new(0);
+            let result = send_with_retries(&Method::GET, MAX_RETRIES, Some(&budget), || {
+                attempts.fetch_add(1, Ordering::Relaxed);
+                async { Err::<(), _>(stream_reset()) }
+            })
+            .await;
+            assert_matches!(
+                result,
+                Err(ChatNetworkError::FailedToSendHttp(NetError::StreamReset))
+            );
+            attempts.load(Ordering::Relaxed)
+        };
+
+        // the first attempt and the two retries the budget allows
+        assert_eq!(attempts_until_error().await, 3);
+        assert_eq!(attempts_until_error().await, 1);
+
+        let result = retry_after_rate_limit(
+            &retry_after_policy(),
+            &SystemClock,
+            Some(&budget),
+            response_parts(429, Some("1")),
+            || async { Ok(response_parts(200, None)) },
+        )
+        .await;
+        assert_matches!(
+            result,
+            Err(ChatNetworkError::RateLimited { retry_after }) if retry_after == Duration::from_secs(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn refused_stream_is_retried_once_regardless_of_method() {
+        for method in [Method::POST, Method::GET] {
+            let attempts = AtomicU32::new(0);
+            let result = send_with_retries(&method, 0, None, || {
+                let attempt = attempts.fetch_add(1, Ordering::Relaxed);
+                async move {
+                    match attempt {
+                        0 => Err(ChatNetworkError::FailedToSendHttp(NetError::StreamRefused)),
+                        _ => Ok(()),
+                    }
+                }
+            })
+            .await;
+            assert_matches!(result, Ok(()));
+            assert_eq!(attempts.load(Ordering::Relaxed), 2);
+        }
+
+        let (attempts, result) = attempts_until_result(Method::POST, || {
+            ChatNetworkError::FailedToSendHttp(NetError::StreamRefused)
+        })
+        .await;
+        assert_eq!(attempts, 2);
+        assert_matches!(
+            result,
+            Err(ChatNetworkError::FailedToSendHttp(NetError::StreamRefused))
+        );
+    }
+
+    #[tokio::test]
+    async fn successful_request_is_not_retried() {
+        let attempts = AtomicU32::new(0);
+        let result = send_with_retries(&Method::GET, MAX_RETRIES, None, || {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            async { Ok(()) }
+        })
+        .await;
+        assert_matches!(result, Ok(()));
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn response_trailers_are_converted() {
+        let (mut parts, _) = http::Response::builder()
+            .status(200)
+            .body(())
+            .unwrap()
+            .into_parts();
+        assert!(response_to_proto(None, &parts, Bytes::new())
+            .trailers
+            .is_empty());
+
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        parts.extensions.insert(ResponseTrailers(trailers));
+        let response = response_to_proto(None, &parts, Bytes::new());
+        assert_eq!(response.trailers, vec!["grpc-status: 0".to_string()]);
+    }
+
+    #[test]
+    fn only_allowed_response_headers_are_kept() {
+        let (mut parts, _) = http::Response::builder()
+            .status(200)
+            .header("connection", "keep-alive")
+            .header("transfer-encoding", "chunked")
+            .header("content-type", "application/json")
+            .header("x-signal-timestamp", "1")
+            .header("x-signal-timestamp", "2")
+            .body(())
+            .unwrap()
+            .into_parts();
+        retain_allowed_headers(
+            &mut parts.headers,
+            &[
+                http::header::CONTENT_TYPE,
+                HeaderName::from_static("x-signal-timestamp"),
+            ],
+        );
+
+        let response = response_to_proto(None, &parts, Bytes::new());
+        assert_eq!(
+            response.headers,
+            vec![
+                "content-type: application/json".to_string(),
+                "x-signal-timestamp: 1".to_string(),
+                "x-signal-timestamp: 2".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn response_with_non_ascii_header_value_is_converted() {
+        let (parts, _) = http::Response::builder()
+            .status(200)
+            .header("x-ascii", "value")
+            .header("x-non-ascii", HeaderValue::from_bytes(b"a\xFFb").unwrap())
+            .body(())
+            .unwrap()
+            .into_parts();
+        let response = response_to_proto(Some(7), &parts, Bytes::from_static(b"body"));
+        assert_eq!(response.id, Some(7));
+        assert_eq!(response.status, Some(200));
+        assert_eq!(response.body, Some(b"body".to_vec()));
+        assert_eq!(response.headers.len(), 2);
+        assert!(response.headers.contains(&"x-ascii: value".to_string()));
+        assert!(response
+            .headers
+            .contains(&"x-non-ascii: a\u{FFFD}b".to_string()));
+    }
+
+    const GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_waits_for_requests_in_flight() {
+        let shutdown = Arc::new(GracefulShutdown::default());
+        let in_flight = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move {
+                shutdown
+                    .track(async {
+                        tokio::time::sleep(GRACE_PERIOD / 2).await;
+                        Ok(())
+                    })
+                    .await
+            }
+        });
+        tokio::task::yield_now().await;
+
+        shutdown.shutdown(&SystemClock, GRACE_PERIOD).await;
+        assert_matches!(in_flight.await.unwrap(), Ok(()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_cancels_requests_in_flight_after_grace_period() {
+        let shutdown = Arc::new(GracefulShutdown::default());
+        let in_flight = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move {
+                shutdown
+                    .track(async {
+                        tokio::time::sleep(GRACE_PERIOD * 2).await;
+                        Ok(())
+                    })
+                    .await
+            }
+        });
+        tokio::task::yield_now().await;
+
+        shutdown.shutdown(&SystemClock, GRACE_PERIOD).await;
+        assert_matches!(in_flight.await.unwrap(), Err(ChatNetworkError::Cancelled));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn no_new_requests_accepted_after_shutdown() {
+        let shutdown = GracefulShutdown::default();
+        shutdown.shutdown(&SystemClock, GRACE_PERIOD).await;
+        assert_matches!(
+            shutdown.track(async { Ok(()) }).await,
+            Err(ChatNetworkError::ChannelClosed)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_drains_request_in_flight_after_go_away_from_server() {
+        let (received_sender, received) = tokio::sync::oneshot::channel();
+        let mut service = ChatOverHttp2ServiceConnector::default()
+            .connect_in_memory(|io| async move {
+                let mut connection = h2::server::handshake(io).await.expect("handshake succeeds");
+                let (_request, mut respond) = connection
+                    .accept()
+                    .await
+                    .expect("request is received")
+                    .expect("request is valid");
+                // sends a GOAWAY frame; the request in flight is still answered
+                connection.graceful_shutdown();
+                tokio::spawn(async move {
+                    tokio::time::sleep(GRACE_PERIOD / 2).await;
+                    let response = http::Response::builder().status(200).body(()).unwrap();
+                    let _ignore_error = respond.send_response(response, true);
+                });
+                let _ignore_error = received_sender.send(());
+                while let Some(Ok(_)) = connection.accept().await {}
+            })
+            .await;
+
+        let in_flight = tokio::spawn({
+            let mut service = service.clone();
+            async move {
+                service
+                    .send(&put_request(b""), Duration::from_secs(60))
+                    .await
+            }
+        });
+        received.await.expect("server is running");
+
+        service.shutdown(GRACE_PERIOD).await;
+        let response = in_flight
+            .await
+            .unwrap()
+            .expect("request in flight is answered");
+        assert_eq!(response.status, Some(200));
+        assert!(service.is_closed());
+        assert_matches!(
+            service
+                .send(&put_request(b""), Duration::from_secs(60))
+                .await,
+            Err(ChatNetworkError::ChannelClosed)
+        );
+    }
+
+    #[test]
+    fn request_body_size_is_checked_at_the_boundary() {
+        let body = Bytes::from(vec![0; 16]);
+        assert_matches!(check_request_size(&body, 16), Ok(()));
+        assert_matches!(
+            check_request_size(&body, 15),
+            Err(ChatNetworkError::RequestTooLarge)
+        );
+    }
+
+    #[test]
+    fn response_too_large_is_reported_as_such() {
+        assert_matches!(
+            send_error(NetError::ResponseTooLarge),
+            ChatNetworkError::ResponseTooLarge
+        );
+        assert_matches!(
+            send_error(NetError::ConnectionInterrupted),
+            ChatNetworkError::FailedToSendHttp(NetError::ConnectionInterrupted)
+        );
+    }
+
+    #[tokio::test]
+    async fn stats_count_requests_sent_over_connection() {
+        let mut service = ChatOverHttp2ServiceConnector::default()
+            .connect_in_memory(|io| {
+                in_memory::serve_fn(io, |_request| async {
+                    http::Response::builder()
+                        .status(200)
+                        .header(http::header::CONTENT_LENGTH, 5)
+                        .body(Full::new(Bytes::from_static(b"hello")))
+                })
+            })
+            .await;
+        assert_eq!(service.stats(), ConnectionStats::default());
+
+        for body in [&b"first"[..], &b"second"[..]] {
+            let msg = MessageProto {
+                request: Some(RequestProto {
+                    verb: Some("PUT".to_string()),
+                    path: Some("/v1/test".to_string()),
+                    body: Some(body.to_vec()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+            service
+                .send(&msg, Duration::from_secs(5))
+                .await
+                .expect("response is received");
+        }
+
+        assert_eq!(
+            service.stats(),
+            ConnectionStats {
+                requests_sent: 2,
+                bytes_sent: 11,
+                bytes_received: 10,
+                active_streams: 0,
+                reset_streams: 0,
+                refused_streams: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn refused_stream_is_transparently_retried() {
+        let mut service = ChatOverHttp2ServiceConnector::default()
+            .connect_in_memory(|io| async move {
+                let mut connection = h2::server::handshake(io).await.expect("handshake succeeds");
+                let mut refused = false;
+                while let Some(Ok((_request, mut respond))) = connection.accept().await {
+                    if refused {
+                        let response = http::Response::builder().status(200).body(()).unwrap();
+                        let _ignore_error = respond.send_response(response, true);
+                    } else {
+                        respond.send_reset(h2::Reason::REFUSED_STREAM);
+                        refused = true;
+                    }
+                }
+            })
+            .await;
+
+        let msg = MessageProto {
+            request: Some(RequestProto {
+                verb: Some("POST".to_string()),
+                path: Some("/v1/test".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let response = service
+            .send(&msg, Duration::from_secs(5))
+            .await
+            .expect("response is received");
+        assert_eq!(response.status, Some(200));
+
+        let stats = service.stats();
+        assert_eq!(stats.requests_sent, 2);
+        assert_eq!(stats.refused_streams, 1);
+    }
+
+    #[tokio::test]
+    async fn reset_stream_is_retried_over_the_same_connection() {
+        let mut service = ChatOverHttp2ServiceConnector::default()
+            .connect_in_memory(|io| async move {
+                let mut connection = h2::server::handshake(io).await.expect("handshake succeeds");
+                let mut reset = false;
+                while let Some(Ok((_request, mut respond))) = connection.accept().await {
+                    if reset {
+                        let response = http::Response::builder().status(200).body(()).unwrap();
+                        let _ignore_error = respond.send_response(response, true);
+                    } else {
+                        respond.send_reset(h2::Reason::INTERNAL_ERROR);
+                        reset = true;
+                    }
+                }
+            })
+            .await;
+
+        let msg = MessageProto {
+            request: Some(RequestProto {
+                verb: Some("GET".to_string()),
+                path: Some("/v1/test".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let response = service
+            .send(&msg, Duration::from_secs(5))
+            .await
+            .expect("response is received");
+        assert_eq!(response.status, Some(200));
+        assert_eq!(service.stats().requests_sent, 2);
+        assert!(!service.is_closed());
+    }
+
+    /// Starts a service with the given `config` over an in-memory connection to a server
+    /// that responds with the request body as it was received, and with the request's
+    /// `Content-Encoding` and `Accept-Encoding` copied into `x-content-encoding`
+    /// and `x-accept-encoding`.
+    async fn echo_service(config: ChatOverHttp2Config) -> ChatOverHttp2 {
+        ChatOverHttp2ServiceConnector::new(config)
+            .connect_in_memory(|io| {
+                in_memory::serve_fn(io, |request: http::Request<Incoming>| async move {
+                    let mut response = http::Response::builder().status(200);
+                    for (from, to) in [
+                        (http::header::CONTENT_ENCODING, "x-content-encoding"),
+                        (http::header::ACCEPT_ENCODING, "x-accept-encoding"),
+                    ] {
+                        if let Some(value) = request.headers().get(from) {
+                            response = response.header(to, value);
+                        }
+                    }
+                    let body = request.into_body().collect().await?.to_bytes();
+                    Ok::<_, hyper::Error>(response.body(Full::new(body)).expect("valid response"))
+                })
+            })
+            .await
+    }
+
+    fn put_request(body: &[u8]) -> MessageProto {
+        MessageProto {
+            request: Some(RequestProto {
+                verb: Some("PUT".to_string()),
+                path: Some("/v1/test".to_string()),
+                body: Some(body.to_vec()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn large_request_bodies_are_compressed() {
+        let mut service = echo_service(ChatOverHttp2Config {
+            request_compression: Some(RequestCompression {
+                encoding: ContentEncoding::Zstd,
+                min_size: 64,
+            }),
+            ..Default::default()
+        })
+        .await;
+        let body = [b'a'; 1024];
+
+        let response = service
+            .send_compressed(&put_request(&body), Duration::from_secs(5))
+            .await
+            .expect("response is received");
+        let headers = response.headers_map();
+        assert_eq!(headers["x-content-encoding"], vec!["zstd"]);
+        assert_eq!(headers["x-accept-encoding"], vec!["gzip, br, zstd"]);
+        let received = response.body.expect("has body");
+        assert!(received.len() < body.len());
+        assert_eq!(
+            zstd::stream::decode_all(&received[..]).expect("valid zstd"),
+            body
+        );
+        assert_eq!(service.stats().bytes_sent, received.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn pooled_response_bodies_are_not_affected_by_later_requests() {
+        let buffer_pool = Arc::new(BufferPool::new(1, 1024));
+        let mut service = echo_service(ChatOverHttp2Config {
+            buffer_pool: Some(buffer_pool.clone()),
+            ..Default::default()
+        })
+        .await;
+
+        let mut responses = vec![];
+        for body in [&b"first"[..], b"second", b"third"] {
+            let response = service
+                .send(&put_request(body), Duration::from_secs(5))
+                .await
+                .expect("response is received");
+            responses.push(response.body.expect("has body"));
+        }
+        assert_eq!(responses, [&b"first"[..], b"second", b"third"]);
+        assert_eq!(buffer_pool.available(), 1);
+    }
+
+    /// Received by the server of [expect_continue_service]: the `Expect` header of a request,
+    /// and its body or the error the stream was reset with, or `None` if neither arrived
+    /// within a second.
+    type ReceivedRequest = (Option<HeaderValue>, Option<Result<Bytes, h2::Error>>);
+
+    /// Starts a service that sends the requests with a body of 1 KiB or more with
+    /// `Expect: 100-continue`, holding the body back for half a second, over an in-memory
+    /// connection to a server that reports what it received for each request.
+    ///
+    /// If `reject_early` is set, the server responds with `417 Expectation Failed` as soon as
+    /// it gets the request headers, otherwise it responds with `200` after the body.
+    async fn expect_continue_service(
+        reject_early: bool,
+    ) -> (
+        ChatOverHttp2,
+        tokio::sync::mpsc::UnboundedReceiver<ReceivedRequest>,
+    ) {
+        let (received_sender, received) = tokio::sync::mpsc::unbounded_channel();
+        let service = ChatOverHttp2ServiceConnector::new(ChatOverHttp2Config {
+            expect_continue: Some(ExpectContinuePolicy {
+                min_body_size: 1024,
+                timeout: Duration::from_millis(500),
+            }),
+            ..Default::default()
+        })
+        .connect_in_memory(|io| async move {
+            let mut connection = h2::server::handshake(io).await.expect("handshake succeeds");
+            while let Some(Ok((request, mut respond))) = connection.accept().await {
+                let received_sender = received_sender.clone();
+                tokio::spawn(async move {
+                    let expect = request.headers().get(http::header::EXPECT).cloned();
+                    let mut body = request.into_body();
+                    let response = |status: u16| http::Response::builder().status(status).body(());
+                    if reject_early {
+                        let _ignore_error = respond.send_response(response(417).unwrap(), true);
+                    }
+                    let data = tokio::time::timeout(Duration::from_secs(1), body.data())
+                        .await
+                        .ok()
+                        .flatten();
+                    if !reject_early {
+                        let _ignore_error = respond.send_response(response(200).unwrap(), true);
+                    }
+                    let _ignore_error = received_sender.send((expect, data));
+                });
+            }
+        })
+        .await;
+        (service, received)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn body_is_not_sent_when_server_rejects_expectation() {
+        let (mut service, mut received) = expect_continue_service(true).await;
+
+        let response = service
+            .send(&put_request(&[b'a'; 1024]), Duration::from_secs(5))
+            .await
+            .expect("response is received");
+        assert_eq!(response.status, Some(417));
+
+        let (expect, body) = received.recv().await.expect("request is received");
+        assert_eq!(expect, Some(HeaderValue::from_static("100-continue")));
+        // the stream is reset rather than left open
+        assert_matches!(body, Some(Err(error)) if error.is_reset());
+        assert_eq!(service.stats().bytes_sent, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn body_is_sent_once_continue_timeout_is_up() {
+        let (mut service, mut received) = expect_continue_service(false).await;
+
+        let response = service
+            .send(&put_request(&[b'a'; 1024]), Duration::from_secs(5))
+            .await
+            .expect("response is received");
+        assert_eq!(response.status, Some(200));
+        let (expect, body) = received.recv().await.expect("request is received");
+        assert_eq!(expect, Some(HeaderValue::from_static("100-continue")));
+        assert_eq!(
+            body.expect("body is received")
+                .expect("stream is not reset"),
+            Bytes::from_static(&[b'a'; 1024])
+        );
+        assert_eq!(service.stats().bytes_sent, 1024);
+
+        // requests with smaller bodies are sent as usual
+        service
+            .send(&put_request(b"small"), Duration::from_secs(5))
+            .await
+            .expect("response is received");
+        let (expect, body) = received.recv().await.expect("request is received");
+        assert_eq!(expect, None);
+        assert_eq!(
+            body.expect("body is received")
+                .expect("stream is not reset"),
+            Bytes::from_static(b"small")
+        );
+    }
+
+    #[tokio::test]
+    async fn small_request_bodies_are_not_compressed() {
+        let mut service = echo_service(ChatOverHttp2Config {
+            request_compression: Some(RequestCompression {
+                encoding: ContentEncoding::Gzip,
+                min_size: 64,
+            }),
+            ..Default::default()
+        })
+        .await;
+
+        let response = service
+            .send_compressed(&put_request(b"small"), Duration::from_secs(5))
+            .await
+            .expect("response is received");
+        let headers = response.headers_map();
+        assert!(!headers.contains_key("x-content-encoding"));
+        assert_eq!(headers["x-accept-encoding"], vec!["gzip, br, zstd"]);
+        assert_eq!(response.body.as_deref(), Some(&b"small"[..]));
+    }
+
+    #[tokio::test]
+    async fn request_bodies_are_only_compressed_on_request() {
+        let mut service = echo_service(ChatOverHttp2Config {
+            request_compression: Some(RequestCompression {
+                encoding: ContentEncoding::Gzip,
+                min_size: 64,
+            }),
+            ..Default::default()
+        })
+        .await;
+        let body = [b'a'; 1024];
+
+        let response = service
+            .send(&put_request(&body), Duration::from_secs(5))
+            .await
+            .expect("response is received");
+        let headers = response.headers_map();
+        assert!(!headers.contains_key("x-content-encoding"));
+        assert_eq!(headers["x-accept-encoding"], vec!["gzip, br, zstd"]);
+        assert_eq!(response.body.as_deref(), Some(&body[..]));
+    }
 }
@@ -0,0 +1,154 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::fmt::{Display, Formatter, Write as _};
+use std::str::FromStr;
+
+use ::http::uri::PathAndQuery;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+use crate::chat::errors::ChatNetworkError;
+
+/// Characters that are encoded in query parameter names and values:
+/// everything except the RFC 3986 "unreserved" characters.
+const QUERY_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Path of a chat request, together with its query parameters.
+///
+/// Unlike formatting the string by hand, the query parameters are always percent-encoded,
+/// so a value can't inject extra parameters or change the path. The result is meant to be
+/// put in [RequestProto::path](crate::chat::RequestProto) as is:
+///
+/// ```
+/// use libsignal_net::chat::path::RequestPath;
+///
+/// let path = RequestPath::new("/v1/accounts/username_hash")
+///     .unwrap()
+///     .query("name", "a b&c");
+/// assert_eq!(path.as_str(), "/v1/accounts/username_hash?name=a%20b%26c");
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RequestPath {
+    value: String,
+    has_query: bool,
+}
+
+impl RequestPath {
+    /// Creates a path without query parameters.
+    ///
+    /// Fails with [ChatNetworkError::RequestPathInvalid] if `path` doesn't start with `/`,
+    /// contains a query or a fragment, or has characters that aren't allowed in a URI path.
+    pub fn new(path: &str) -> Result<Self, ChatNetworkError> {
+        if !path.starts_with('/') || path.contains(['?', '#']) {
+            return Err(ChatNetworkError::RequestPathInvalid);
+        }
+        validate(path)?;
+        Ok(Self {
+            value: path.to_string(),
+            has_query: false,
+        })
+    }
+
+    /// Appends a query parameter, percent-encoding both `name` and `value`.
+    pub fn query(mut self, name: &str, value: &str) -> Self {
+        let separator = if self.has_query { '&' } else { '?' };
+        let name = utf8_percent_encode(name, QUERY_COMPONENT);
+        let value = utf8_percent_encode(value, QUERY_COMPONENT);
+        write!(self.value, "{separator}{name}={value}").expect("can write to String");
+        self.has_query = true;
+        self
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.value
+    }
+}
+
+impl Display for RequestPath {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.value)
+    }
+}
+
+impl From<RequestPath> for String {
+    fn from(path: RequestPath) -> Self {
+        path.value
+    }
+}
+
+/// Checks that `path` can be used as the path and query of a request URI.
+pub(crate) fn validate(path: &str) -> Result<(), ChatNetworkError> {
+    match PathAndQuery::from_str(path) {
+        Ok(_) if path.starts_with('/') => Ok(()),
+        _ => Err(ChatNetworkError::RequestPathInvalid),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+
+    use crate::chat::errors::ChatNetworkError;
+    use crate::chat::path::RequestPath;
+
+    fn path_with_query(name: &str, value: &str) -> String {
+        RequestPath::new("/v1/test")
+            .unwrap()
+            .query(name, value)
+            .into()
+    }
+
+    #[test]
+    fn query_spaces_are_encoded() {
+        assert_eq!(path_with_query("q", "a b"), "/v1/test?q=a%20b");
+        assert_eq!(path_with_query("my name", ""), "/v1/test?my%20name=");
+    }
+
+    #[test]
+    fn query_unicode_is_encoded_as_utf8() {
+        assert_eq!(path_with_query("q", "é"), "/v1/test?q=%C3%A9");
+        assert_eq!(path_with_query("q", "🙂"), "/v1/test?q=%F0%9F%99%82");
+    }
+
+    #[test]
+    fn query_reserved_chars_are_encoded() {
+        assert_eq!(
+            path_with_query("q", "a&b=c?d#e/f+g%h"),
+            "/v1/test?q=a%26b%3Dc%3Fd%23e%2Ff%2Bg%25h"
+        );
+        assert_eq!(path_with_query("q", "-._~"), "/v1/test?q=-._~");
+    }
+
+    #[test]
+    fn multiple_query_params_are_joined() {
+        let path = RequestPath::new("/v1/test")
+            .unwrap()
+            .query("a", "1")
+            .query("b", "2");
+        assert_eq!(path.to_string(), "/v1/test?a=1&b=2");
+    }
+
+    #[test]
+    fn invalid_paths_are_rejected() {
+        for path in [
+            "",
+            "v1/test",
+            "/v1/test?q=1",
+            "/v1/test#x",
+            "/v1/a b",
+            "/v1/\n",
+        ] {
+            assert_matches!(
+                RequestPath::new(path),
+                Err(ChatNetworkError::RequestPathInvalid),
+                "{path:?}"
+            );
+        }
+    }
+}
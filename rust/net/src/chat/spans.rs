@@ -0,0 +1,166 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! `tracing` spans around connection attempts and chat requests.
+//!
+//! The spans are only created when the `tracing` feature is enabled; otherwise the wrapped
+//! futures are run as is. Span and field names are stable:
+//! - `chat_connect` with `host`, `port`, `timeout_ms`, and `outcome`,
+//! - `chat_send` with `request_id`, `path`, `status`, `latency_ms`, and `error`.
+//!
+//! Headers (including the `Authorization` header), query strings, and bodies are never recorded.
+//! The `path` is recorded as a route template, with every segment that could identify a user
+//! (usernames, phone numbers, UUIDs, device IDs...) replaced by `*`.
+
+use std::future::Future;
+use std::time::Duration;
+
+use crate::chat::errors::ChatNetworkError;
+use crate::chat::{MessageProto, ResponseProto};
+use crate::infra::ConnectionParams;
+
+/// Runs `connect` in a `chat_connect` span and records whether it succeeded.
+#[cfg(feature = "tracing")]
+pub(crate) async fn connect_in_span<T, F>(
+    connection_params: &ConnectionParams,
+    timeout: Duration,
+    connect: F,
+) -> Result<T, ChatNetworkError>
+where
+    F: Future<Output = Result<T, ChatNetworkError>>,
+{
+    use tracing::Instrument as _;
+
+    let span = tracing::info_span!(
+        "chat_connect",
+        host = %connection_params.host,
+        port = connection_params.port,
+        timeout_ms = timeout.as_millis() as u64,
+        outcome = tracing::field::Empty,
+    );
+    let result = connect.instrument(span.clone()).await;
+    match &result {
+        Ok(_) => span.record("outcome", "connected"),
+        Err(e) => span.record("outcome", tracing::field::display(e)),
+    };
+    result
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) async fn connect_in_span<T, F>(
+    _connection_params: &ConnectionParams,
+    _timeout: Duration,
+    connect: F,
+) -> Result<T, ChatNetworkError>
+where
+    F: Future<Output = Result<T, ChatNetworkError>>,
+{
+    connect.await
+}
+
+/// Runs `send` in a `chat_send` span for `msg` and records the response status or the error,
+/// along with the time it took.
+#[cfg(feature = "tracing")]
+pub(crate) async fn send_in_span<F>(
+    msg: &MessageProto,
+    send: F,
+) -> Result<ResponseProto, ChatNetworkError>
+where
+    F: Future<Output = Result<ResponseProto, ChatNetworkError>>,
+{
+    use tracing::Instrument as _;
+
+    let request = msg.request.as_ref();
+    let span = tracing::info_span!(
+        "chat_send",
+        request_id = request.and_then(|req| req.id),
+        path = request
+            .and_then(|req| req.path.as_deref())
+            .map(|path| tracing::field::display(route_template(path))),
+        status = tracing::field::Empty,
+        latency_ms = tracing::field::Empty,
+        error = tracing::field::Empty,
+    );
+    let start = tokio::time::Instant::now();
+    let result = send.instrument(span.clone()).await;
+    span.record("latency_ms", start.elapsed().as_millis() as u64);
+    match &result {
+        Ok(response) => span.record("status", response.status),
+        Err(e) => span.record("error", tracing::field::display(e)),
+    };
+    result
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) async fn send_in_span<F>(
+    _msg: &MessageProto,
+    send: F,
+) -> Result<ResponseProto, ChatNetworkError>
+where
+    F: Future<Output = Result<ResponseProto, ChatNetworkError>>,
+{
+    send.await
+}
+
+/// Returns `path` without its query, and with all the segments but the API versions and
+/// the lowercase words replaced by `*`.
+#[cfg(feature = "tracing")]
+fn route_template(path: &str) -> String {
+    let path = path.split_once('?').map_or(path, |(path, _query)| path);
+    path.split('/')
+        .map(|segment| {
+            if is_route_segment(segment) {
+                segment
+            } else {
+                "*"
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(feature = "tracing")]
+fn is_route_segment(segment: &str) -> bool {
+    let is_version = segment
+        .strip_prefix('v')
+        .is_some_and(|version| !version.is_empty() && version.bytes().all(|b| b.is_ascii_digit()));
+    let is_word = segment
+        .bytes()
+        .all(|b| b.is_ascii_lowercase() || b == b'_' || b == b'-');
+    is_version || is_word
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod test {
+    use crate::chat::spans::route_template;
+
+    #[test]
+    fn query_is_not_recorded() {
+        assert_eq!(route_template("/v1/keepalive"), "/v1/keepalive");
+        assert_eq!(
+            route_template("/v1/accounts/whoami?token=secret"),
+            "/v1/accounts/whoami"
+        );
+    }
+
+    #[test]
+    fn identifiers_are_not_recorded() {
+        assert_eq!(
+            route_template(
+                "/v1/accounts/username_hash/tS5K3a8JIaMpl4xZfs_aRUHtNZBiXD8rUgCZWHnsYpE"
+            ),
+            "/v1/accounts/username_hash/*"
+        );
+        assert_eq!(
+            route_template("/v2/keys/9d0652a3-dcc3-4d11-975f-74d61598733f/2"),
+            "/v2/keys/*/*"
+        );
+        assert_eq!(
+            route_template("/v1/accounts/account/PNI:796abedb-ca4e-4f18-8803-1fde5b921f9f"),
+            "/v1/accounts/account/*"
+        );
+        assert_eq!(route_template("/v1/profile/+18005551011"), "/v1/profile/*");
+    }
+}
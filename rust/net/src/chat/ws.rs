@@ -13,15 +13,21 @@ use futures_util::{SinkExt, StreamExt, TryFutureExt};
 use prost::Message;
 use tokio::sync::{mpsc, oneshot, Mutex};
 use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
 use tungstenite::protocol::WebSocketConfig;
 
-use crate::chat::errors::ChatNetworkError;
-use crate::chat::{ChatMessageType, ChatService, MessageProto, RequestProto, ResponseProto};
+use crate::chat::errors::{connect_error, ChatNetworkError};
+use crate::chat::spans::{connect_in_span, send_in_span};
+use crate::chat::{
+    keepalive_request, log_request_failure, ChatMessageType, ChatService, MessageProto,
+    RequestProto, ResponseProto,
+};
 use crate::env::constants::WEB_SOCKET_PATH;
+use crate::infra::clock::{Clock, SystemClock};
 use crate::infra::reconnect::{ServiceConnector, ServiceStatus};
 use crate::infra::ws::{connect_websocket, WebSocketStream};
 use crate::infra::ConnectionParams;
-use crate::utils::timeout;
+use crate::utils::timeout_with_elapsed;
 
 #[derive(Default, Eq, Hash, PartialEq, Clone, Copy)]
 struct RequestId {
@@ -80,6 +86,8 @@ pub struct ChatOverWebsocketConfig {
     pub max_idle_time: Duration,
     pub incoming_messages_queue_size: usize,
     pub outgoing_messages_queue_size: usize,
+    /// Measures the connection timeout, the keepalive interval, and the idle time.
+    pub clock: Arc<dyn Clock>,
 }
 
 impl Default for ChatOverWebsocketConfig {
@@ -92,6 +100,7 @@ impl Default for ChatOverWebsocketConfig {
             max_idle_time: Duration::from_secs(15),
             incoming_messages_queue_size: 1024,
             outgoing_messages_queue_size: 256,
+            clock: Arc::new(SystemClock),
         }
     }
 }
@@ -128,11 +137,16 @@ impl ServiceConnector for ChatOverWebSocketServiceConnector {
             self.config.endpoint.clone(),
             self.config.ws_config,
         )
-        .map_err(|_| ChatNetworkError::FailedToConnectWebSocket);
-        timeout(
+        .map_err(|e| connect_error(e, |_| ChatNetworkError::FailedToConnectWebSocket));
+        connect_in_span(
+            connection_params,
             self.config.max_connection_time,
-            ChatNetworkError::Timeout,
-            connect_future,
+            timeout_with_elapsed(
+                &*self.config.clock,
+                self.config.max_connection_time,
+                |elapsed| ChatNetworkError::Timeout { elapsed },
+                connect_future,
+            ),
         )
         .await
     }
@@ -146,6 +160,7 @@ impl ServiceConnector for ChatOverWebSocketServiceConnector {
         tokio::spawn(reader_task(
             ws_incoming,
             self.config.max_idle_time,
+            self.config.clock.clone(),
             pending_messages.clone(),
             self.incoming_tx.clone(),
             outgoing_tx.clone(),
@@ -154,6 +169,7 @@ impl ServiceConnector for ChatOverWebSocketServiceConnector {
         tokio::spawn(writer_task(
             ws_outgoing,
             self.config.keep_alive_interval,
+            self.config.clock.clone(),
             outgoing_rx,
             service_status.clone(),
         ));
@@ -181,6 +197,42 @@ impl ChatService for ChatOverWebSocket {
         &mut self,
         msg: &MessageProto,
         timeout: Duration,
+    ) -> Result<ResponseProto, ChatNetworkError> {
+        self.send_cancellable(msg, timeout, CancellationToken::new())
+            .await
+    }
+
+    async fn send_cancellable(
+        &mut self,
+        msg: &MessageProto,
+        timeout: Duration,
+        cancellation_token: CancellationToken,
+    ) -> Result<ResponseProto, ChatNetworkError> {
+        let result = send_in_span(
+            msg,
+            self.send_and_wait_for_response(msg, timeout, cancellation_token),
+        )
+        .await;
+        log_request_failure(msg, &result);
+        result
+    }
+
+    async fn keepalive(&mut self, timeout: Duration) -> Result<(), ChatNetworkError> {
+        let result = self.send(&keepalive_request(), timeout).await;
+        if let Err(e) = &result {
+            log::debug!("keepalive failed: {e}; closing the connection");
+            self.service_status.stop_service();
+        }
+        result.map(|_| ())
+    }
+}
+
+impl ChatOverWebSocket {
+    async fn send_and_wait_for_response(
+        &mut self,
+        msg: &MessageProto,
+        timeout: Duration,
+        cancellation_token: CancellationToken,
     ) -> Result<ResponseProto, ChatNetworkError> {
         let req = msg
             .request
@@ -211,10 +263,14 @@ impl ChatService for ChatOverWebSocket {
             .await
             .map_err(|_| ChatNetworkError::FailedToPassMessageToSenderTask)?;
 
+        let start = Instant::now();
         let res = tokio::select! {
             result = response_rx => Ok(result.expect("sender is not dropped before receiver")),
-            _ = tokio::time::sleep(timeout) => Err(ChatNetworkError::Timeout),
-            _ = self.service_status.stopped() => Err(ChatNetworkError::ChannelClosed)
+            _ = tokio::time::sleep(timeout) => Err(ChatNetworkError::Timeout {
+                elapsed: start.elapsed(),
+            }),
+            _ = self.service_status.stopped() => Err(ChatNetworkError::ChannelClosed),
+            _ = cancellation_token.cancelled() => Err(ChatNetworkError::Cancelled),
         };
         if res.is_err() {
             // in case of an error we need to clean up the listener from the `pending_messages` map
@@ -228,6 +284,7 @@ impl ChatService for ChatOverWebSocket {
 async fn writer_task(
     mut ws_stream: SplitSink<WebSocketStream, tungstenite::Message>,
     keep_alive_interval: Duration,
+    clock: Arc<dyn Clock>,
     mut outgoing_rx: mpsc::Receiver<Vec<u8>>,
     service_status: ServiceStatus<ChatNetworkError>,
 ) {
@@ -240,7 +297,7 @@ async fn writer_task(
     loop {
         match tokio::select! {
             maybe_msg = outgoing_rx.recv() => Event::Message(maybe_msg),
-            _ = tokio::time::sleep(keep_alive_interval) => Event::KeepAlive,
+            _ = clock.sleep(keep_alive_interval) => Event::KeepAlive,
             _ = service_status.stopped() => Event::Cancellation,
         } {
             Event::Message(Some(msg)) => {
@@ -279,6 +336,7 @@ async fn writer_task(
 async fn reader_task(
     mut ws_stream: SplitStream<WebSocketStream>,
     max_idle_time: Duration,
+    clock: Arc<dyn Clock>,
     pending_messages: Arc<Mutex<PendingMessagesMap>>,
     incoming_tx: mpsc::Sender<ServerRequest>,
     outgoing_tx: mpsc::Sender<Vec<u8>>,
@@ -289,17 +347,17 @@ async fn reader_task(
         IdleCheck,
     }
     loop {
-        let last_event_ts = Instant::now();
+        let last_event_ts = clock.now();
         let data = match tokio::select! {
             ws_event = ws_stream.next() => Event::WsEvent(ws_event),
-            _ = tokio::time::sleep_until(last_event_ts + max_idle_time) => Event::IdleCheck,
+            _ = clock.sleep_until(last_event_ts + max_idle_time) => Event::IdleCheck,
         } {
             Event::WsEvent(Some(Ok(tungstenite::Message::Binary(data)))) => data,
             Event::WsEvent(Some(Ok(tungstenite::Message::Close(_)))) => {
                 // connection is about to be closed
                 // not immediately terminating the task,
                 // but marking our channel as closed
-                service_status.stop_service();
+                stop_on_remote_close(&service_status);
                 continue;
             }
             Event::WsEvent(Some(Ok(tungstenite::Message::Pong(_)))) => {
@@ -313,6 +371,7 @@ async fn reader_task(
             }
             Event::WsEvent(Some(Err(tungstenite::Error::ConnectionClosed))) => {
                 // error, possibly connection closed
+                stop_on_remote_close(&service_status);
                 break;
             }
             Event::WsEvent(Some(Err(err))) => {
@@ -322,10 +381,11 @@ async fn reader_task(
             }
             Event::WsEvent(None) => {
                 // stream is exhausted nothing else will happen, we can exit now
+                stop_on_remote_close(&service_status);
                 break;
             }
             Event::IdleCheck => {
-                if Instant::now() - last_event_ts > max_idle_time {
+                if clock.now() - last_event_ts > max_idle_time {
                     // channel is idle
                     service_status.stop_service_with_error(ChatNetworkError::ChannelIdle);
                     break;
@@ -363,6 +423,14 @@ async fn reader_task(
     service_status.stop_service();
 }
 
+/// Marks the channel as closed by the remote peer, unless it has already been stopped
+/// for some other reason (e.g. we initiated the closing handshake ourselves).
+fn stop_on_remote_close(service_status: &ServiceStatus<ChatNetworkError>) {
+    if !service_status.is_stopped() {
+        service_status.stop_service_with_error(ChatNetworkError::ChannelClosedByRemotePeer);
+    }
+}
+
 async fn send_and_validate(
     ws_stream: &mut SplitSink<WebSocketStream, tungstenite::Message>,
     msg: tungstenite::Message,
@@ -439,5 +507,6 @@ fn response_for_code(id: u64, code: http::status::StatusCode) -> ResponseProto {
         ),
         headers: vec![],
         body: None,
+        trailers: vec![],
     }
 }
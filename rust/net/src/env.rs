@@ -3,19 +3,16 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
-use std::sync::Arc;
 use std::time::Duration;
 
 use hex_literal::hex;
 use http::uri::PathAndQuery;
 
 use crate::cdsi::CdsiConnectionParams;
-use crate::infra::certs::RootCertificates;
 use crate::infra::connection_manager::{
     ConnectionManager, MultiRouteConnectionManager, SingleRouteThrottlingConnectionManager,
 };
-use crate::infra::dns::DnsResolver;
-use crate::infra::{ConnectionParams, HttpRequestDecoratorSeq};
+use crate::infra::ConnectionParams;
 
 #[derive(Copy, Clone)]
 pub struct CdsiEndpointMrEnclave<B>(B);
@@ -34,15 +31,9 @@ pub struct CdsiEndpoint<'a> {
 
 impl CdsiEndpoint<'_> {
     pub fn direct_connection(&self) -> ConnectionParams {
-        let host: Arc<str> = Arc::from(self.host);
-        ConnectionParams {
-            sni: host.clone(),
-            host,
-            port: 443,
-            http_request_decorator: HttpRequestDecoratorSeq::default(),
-            certs: RootCertificates::Signal,
-            dns_resolver: DnsResolver::System,
-        }
+        ConnectionParams::builder(self.host)
+            .build()
+            .expect("valid connection params")
     }
 }
 
@@ -105,6 +96,14 @@ pub struct Env<'a> {
     pub chat_host: &'a str,
 }
 
+impl Env<'_> {
+    pub fn chat_direct_connection(&self) -> ConnectionParams {
+        ConnectionParams::builder(self.chat_host)
+            .build()
+            .expect("valid connection params")
+    }
+}
+
 pub const STAGING: Env<'static> = Env {
     chat_host: "chat.staging.signal.org",
     cdsi: CdsiEndpoint {
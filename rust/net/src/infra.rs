@@ -3,32 +3,50 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
+use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 use std::string::ToString;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 use ::http::uri::PathAndQuery;
 use ::http::Uri;
-use boring::ssl::{SslConnector, SslConnectorBuilder, SslMethod};
-use tokio::net::TcpStream;
+use boring::sha::sha256;
+use boring::ssl::{
+    SslConnector, SslConnectorBuilder, SslMethod, SslRef, SslVerifyMode, SslVersion,
+};
+use boring::x509::X509Ref;
+use tokio::net::{TcpSocket, TcpStream};
 use tokio_boring::SslStream;
 
-use crate::infra::certs::RootCertificates;
+use crate::infra::certs::{ClientCertKey, RootCertificates};
 use crate::infra::dns::DnsResolver;
 use crate::infra::errors::NetError;
+use crate::infra::socks5::{connect_tcp_via_proxy, ProxyConfig};
+use crate::utils::basic_authorization;
 
 pub mod certs;
+pub mod clock;
 pub mod connection_manager;
 pub mod dns;
 pub mod errors;
 pub(crate) mod http;
+#[cfg(any(test, feature = "test-util"))]
+pub mod in_memory;
 pub(crate) mod reconnect;
+pub mod socks5;
 pub(crate) mod tokio_executor;
 pub(crate) mod tokio_io;
+pub(crate) mod tokio_timer;
 pub(crate) mod ws;
 
+pub use http::{BufferPool, Http2ConnectionPool, Http2ConnectionPoolConfig, PooledHttp2Channel};
+pub use reconnect::{CloseReason, ConnectionEvent};
+
 /// A collection of commonly used decorators for HTTP requests.
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub enum HttpRequestDecorator {
     /// Adds the following header to the request:
     /// ```text
@@ -41,6 +59,16 @@ pub enum HttpRequestDecorator {
     Generic(fn(hyper::http::request::Builder) -> hyper::http::request::Builder),
 }
 
+impl std::fmt::Debug for HttpRequestDecorator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::HeaderAuth(_) => f.debug_tuple("HeaderAuth").field(&"[REDACTED]").finish(),
+            Self::PathPrefix(prefix) => f.debug_tuple("PathPrefix").field(prefix).finish(),
+            Self::Generic(decorator) => f.debug_tuple("Generic").field(decorator).finish(),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct HttpRequestDecoratorSeq(Vec<HttpRequestDecorator>);
 
@@ -56,9 +84,38 @@ impl From<HttpRequestDecorator> for HttpRequestDecoratorSeq {
 /// - `port` to connect to,
 /// - `http_request_decorator`, a [HttpRequestDecorator] to apply to all HTTP requests,
 /// - `certs`, [RootCertificates] representing trusted certificates,
-/// - `dns_resolver`, a [DnsResolver] to use when resolving DNS.
-/// This is also applicable to WebSocket connections (in this case, `http_request_decorator` will
-/// only be apllied to the initial connection upgrade request).
+/// - `dns_resolver`, a [DnsResolver] to use when resolving DNS,
+/// - `pinned_spki`, SHA-256 hashes of the DER-encoded SubjectPublicKeyInfo of the server
+///   certificates that are accepted; empty list means that any certificate trusted
+///   by the `certs` is accepted,
+/// - `proxy`, an optional SOCKS5 [ProxyConfig] to establish the TCP connection through,
+/// - `http2_keepalive_interval`, if set, HTTP/2 PING frames are sent at this interval
+///   to keep the connection alive; `None` disables keepalive,
+/// - `http2_keepalive_timeout`, how long to wait for the PING acknowledgement before
+///   considering the connection dead; `None` means hyper's default (20 seconds),
+/// - `http2_keepalive_jitter_percent`, how much the keepalive interval of each connection
+///   randomly differs from `http2_keepalive_interval`, in percent either way, so that the PINGs
+///   of many clients that connected at the same time are spread out;
+///   [DEFAULT_HTTP2_KEEPALIVE_JITTER_PERCENT] unless configured otherwise,
+/// - `max_concurrent_streams`, if set, the maximum number of HTTP/2 requests that
+///   are in flight at the same time on a single connection,
+/// - `auth`, an optional [AuthStrategy] used to set the `Authorization` header
+///   on all HTTP requests,
+/// - `user_agent`, the `User-Agent` header value for the HTTP requests that don't set
+///   their own; [DEFAULT_USER_AGENT] unless configured otherwise,
+/// - `tcp_options`, [TcpSocketOptions] for the TCP connection to the endpoint (or to the proxy),
+/// - `address_family`, an [AddressFamily] selecting which of the resolved addresses are
+///   connected to, and in which order,
+/// - `bind_address`, an optional local address the TCP socket is bound to, e.g. to send
+///   the traffic over a specific interface on a device with a VPN; only the resolved addresses
+///   of the same family are connected to. `None` lets the OS choose the source address,
+/// - `client_identity`, an optional [ClientCertKey] presented to servers that require
+///   client certificate authentication; `None` means no client certificate is sent,
+/// - `min_tls_version` and `max_tls_version`, the range of [TlsVersion]s that can be negotiated;
+///   `None` for the maximum means the newest version supported. Connecting to a server that
+///   doesn't support any version in the range fails the TLS handshake.
+/// This is also applicable to WebSocket connections (in this case, `http_request_decorator`,
+/// `auth`, and `user_agent` will only be apllied to the initial connection upgrade request).
 #[derive(Clone, Debug)]
 pub struct ConnectionParams {
     pub sni: Arc<str>,
@@ -67,6 +124,165 @@ pub struct ConnectionParams {
     pub http_request_decorator: HttpRequestDecoratorSeq,
     pub certs: RootCertificates,
     pub dns_resolver: DnsResolver,
+    pub pinned_spki: Vec<[u8; 32]>,
+    pub proxy: Option<ProxyConfig>,
+    pub http2_keepalive_interval: Option<Duration>,
+    pub http2_keepalive_timeout: Option<Duration>,
+    pub http2_keepalive_jitter_percent: u8,
+    pub max_concurrent_streams: Option<u32>,
+    pub auth: Option<AuthStrategy>,
+    pub user_agent: Arc<str>,
+    pub tcp_options: TcpSocketOptions,
+    pub address_family: AddressFamily,
+    pub bind_address: Option<IpAddr>,
+    pub client_identity: Option<ClientCertKey>,
+    pub min_tls_version: TlsVersion,
+    pub max_tls_version: Option<TlsVersion>,
+}
+
+/// Options set on the TCP socket before connecting.
+///
+/// By default, `TCP_NODELAY` is enabled, since chat messages are small and latency-sensitive,
+/// and everything else is left at the OS defaults.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TcpSocketOptions {
+    /// Disables Nagle's algorithm (`TCP_NODELAY`).
+    pub nodelay: bool,
+    /// Size of the receive buffer (`SO_RCVBUF`).
+    pub recv_buffer_size: Option<usize>,
+    /// Size of the send buffer (`SO_SNDBUF`).
+    pub send_buffer_size: Option<usize>,
+    /// If set, enables `SO_KEEPALIVE`, with the probes sent after the connection
+    /// has been idle for this long, and then repeated at the same interval.
+    pub keepalive: Option<Duration>,
+}
+
+impl Default for TcpSocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            keepalive: None,
+        }
+    }
+}
+
+impl TcpSocketOptions {
+    fn apply(&self, socket: &TcpSocket) -> io::Result<()> {
+        let socket = socket2::SockRef::from(socket);
+        socket.set_nodelay(self.nodelay)?;
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(interval) = self.keepalive {
+            socket.set_tcp_keepalive(
+                &socket2::TcpKeepalive::new()
+                    .with_time(interval)
+                    .with_interval(interval),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Selects which of the resolved IP addresses are used to connect, and in which order
+/// they are tried.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AddressFamily {
+    /// Addresses of both families are tried, alternating between IPv6 and IPv4 starting with
+    /// the family of the first resolved address, as in Happy Eyeballs (RFC 8305).
+    #[default]
+    Auto,
+    Ipv4Only,
+    Ipv6Only,
+    /// All IPv6 addresses are tried before any of the IPv4 ones.
+    PreferIpv6,
+}
+
+impl AddressFamily {
+    fn order_addresses(&self, addresses: &[IpAddr]) -> Vec<IpAddr> {
+        let (ipv6, ipv4): (Vec<IpAddr>, Vec<IpAddr>) =
+            addresses.iter().copied().partition(|ip| ip.is_ipv6());
+        match self {
+            Self::Ipv4Only => ipv4,
+            Self::Ipv6Only => ipv6,
+            Self::PreferIpv6 => ipv6.into_iter().chain(ipv4).collect(),
+            Self::Auto => {
+                let (first, second) = match addresses.first() {
+                    Some(IpAddr::V4(_)) => (ipv4, ipv6),
+                    _ => (ipv6, ipv4),
+                };
+                let mut first = first.into_iter();
+                let mut second = second.into_iter();
+                let mut ordered = Vec::with_capacity(addresses.len());
+                loop {
+                    match (first.next(), second.next()) {
+                        (None, None) => break,
+                        (a, b) => ordered.extend(a.into_iter().chain(b)),
+                    }
+                }
+                ordered
+            }
+        }
+    }
+}
+
+/// Version of the TLS protocol.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    #[default]
+    Tls1_2,
+    Tls1_3,
+}
+
+impl From<TlsVersion> for SslVersion {
+    fn from(version: TlsVersion) -> Self {
+        match version {
+            TlsVersion::Tls1_2 => SslVersion::TLS1_2,
+            TlsVersion::Tls1_3 => SslVersion::TLS1_3,
+        }
+    }
+}
+
+pub const DEFAULT_USER_AGENT: &str = concat!("libsignal/", env!("CARGO_PKG_VERSION"));
+
+pub const DEFAULT_HTTP2_KEEPALIVE_JITTER_PERCENT: u8 = 10;
+
+/// Credentials that are sent in the `Authorization` header of every request.
+///
+/// The `Debug` representation doesn't include the credentials.
+#[derive(Clone, PartialEq, Eq)]
+pub enum AuthStrategy {
+    /// `Authorization: Bearer <token>`
+    Bearer(String),
+    /// `Authorization: Basic base64(<username>:<password>)`
+    Basic(String, String),
+}
+
+impl AuthStrategy {
+    fn header_value(&self) -> String {
+        match self {
+            Self::Bearer(token) => format!("Bearer {}", token),
+            Self::Basic(username, password) => basic_authorization(username, password),
+        }
+    }
+}
+
+impl std::fmt::Debug for AuthStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bearer(_) => f.debug_tuple("Bearer").field(&"[REDACTED]").finish(),
+            Self::Basic(username, _) => f
+                .debug_tuple("Basic")
+                .field(username)
+                .field(&"[REDACTED]")
+                .finish(),
+        }
+    }
 }
 
 impl ConnectionParams {
@@ -86,14 +302,271 @@ impl ConnectionParams {
             http_request_decorator,
             certs,
             dns_resolver,
+            pinned_spki: vec![],
+            proxy: None,
+            http2_keepalive_interval: None,
+            http2_keepalive_timeout: None,
+            http2_keepalive_jitter_percent: DEFAULT_HTTP2_KEEPALIVE_JITTER_PERCENT,
+            max_concurrent_streams: None,
+            auth: None,
+            user_agent: Arc::from(DEFAULT_USER_AGENT),
+            tcp_options: TcpSocketOptions::default(),
+            address_family: AddressFamily::default(),
+            bind_address: None,
+            client_identity: None,
+            min_tls_version: TlsVersion::default(),
+            max_tls_version: None,
         }
     }
 
+    /// Starts building [ConnectionParams] for the given `host`.
+    ///
+    /// See [ConnectionParamsBuilder] for the defaults of the other fields.
+    pub fn builder(host: &str) -> ConnectionParamsBuilder {
+        ConnectionParamsBuilder::new(host)
+    }
+
     pub fn with_decorator(mut self, decorator: HttpRequestDecorator) -> Self {
         let HttpRequestDecoratorSeq(decorators) = &mut self.http_request_decorator;
         decorators.push(decorator);
         self
     }
+
+    pub fn with_auth(mut self, auth: AuthStrategy) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Applies the `http_request_decorator`, sets the `User-Agent` header unless the request
+    /// already has one, and then sets the `Authorization` header if `auth` is present.
+    pub(crate) fn decorate_request(
+        &self,
+        request_builder: hyper::http::request::Builder,
+    ) -> hyper::http::request::Builder {
+        let mut request_builder = self
+            .http_request_decorator
+            .decorate_request(request_builder);
+        let has_user_agent = request_builder.headers_ref().map_or(false, |headers| {
+            headers.contains_key(::http::header::USER_AGENT)
+        });
+        if !has_user_agent {
+            request_builder = request_builder.header(::http::header::USER_AGENT, &*self.user_agent);
+        }
+        match &self.auth {
+            Some(auth) => {
+                request_builder.header(::http::header::AUTHORIZATION, auth.header_value())
+            }
+            None => request_builder,
+        }
+    }
+}
+
+/// Errors that can occur when building [ConnectionParams] with a [ConnectionParamsBuilder].
+#[derive(Debug, displaydoc::Display, thiserror::Error, PartialEq, Eq)]
+pub enum ConfigError {
+    /// host must not be empty
+    EmptyHost,
+    /// SNI must not be empty
+    EmptySni,
+    /// port must not be zero
+    InvalidPort,
+    /// HTTP/2 keepalive timeout is set but keepalive interval is not
+    KeepaliveTimeoutWithoutInterval,
+    /// HTTP/2 keepalive interval must not be zero
+    ZeroKeepaliveInterval,
+    /// HTTP/2 keepalive jitter must be less than 100%
+    InvalidKeepaliveJitter,
+    /// both auth and a HeaderAuth decorator set the Authorization header
+    ConflictingAuthorization,
+    /// User-Agent is not a valid header value
+    InvalidUserAgent,
+    /// TCP keepalive interval must not be zero
+    ZeroTcpKeepalive,
+    /// minimum TLS version is newer than the maximum one
+    InvalidTlsVersionRange,
+}
+
+/// Builder for [ConnectionParams].
+///
+/// Unless overridden, the parameters are the ones used for direct connections to
+/// Signal services: `sni` is the same as `host`, `port` is 443, [RootCertificates::Signal]
+/// and [DnsResolver::System] are used, and all optional settings are left unset.
+#[derive(Clone, Debug)]
+pub struct ConnectionParamsBuilder {
+    params: ConnectionParams,
+}
+
+impl ConnectionParamsBuilder {
+    pub fn new(host: &str) -> Self {
+        let host: Arc<str> = Arc::from(host);
+        Self {
+            params: ConnectionParams {
+                sni: host.clone(),
+                host,
+                port: 443,
+                http_request_decorator: HttpRequestDecoratorSeq::default(),
+                certs: RootCertificates::Signal,
+                dns_resolver: DnsResolver::System,
+                pinned_spki: vec![],
+                proxy: None,
+                http2_keepalive_interval: None,
+                http2_keepalive_timeout: None,
+                http2_keepalive_jitter_percent: DEFAULT_HTTP2_KEEPALIVE_JITTER_PERCENT,
+                max_concurrent_streams: None,
+                auth: None,
+                user_agent: Arc::from(DEFAULT_USER_AGENT),
+                tcp_options: TcpSocketOptions::default(),
+                address_family: AddressFamily::default(),
+                bind_address: None,
+                client_identity: None,
+                min_tls_version: TlsVersion::default(),
+                max_tls_version: None,
+            },
+        }
+    }
+
+    pub fn sni(mut self, sni: &str) -> Self {
+        self.params.sni = Arc::from(sni);
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.params.port = port;
+        self
+    }
+
+    /// Appends `decorator` to the decorators applied to every HTTP request.
+    pub fn decorator(mut self, decorator: HttpRequestDecorator) -> Self {
+        self.params = self.params.with_decorator(decorator);
+        self
+    }
+
+    pub fn certs(mut self, certs: RootCertificates) -> Self {
+        self.params.certs = certs;
+        self
+    }
+
+    pub fn dns_resolver(mut self, dns_resolver: DnsResolver) -> Self {
+        self.params.dns_resolver = dns_resolver;
+        self
+    }
+
+    pub fn pinned_spki(mut self, pinned_spki: Vec<[u8; 32]>) -> Self {
+        self.params.pinned_spki = pinned_spki;
+        self
+    }
+
+    pub fn proxy(mut self, proxy: ProxyConfig) -> Self {
+        self.params.proxy = Some(proxy);
+        self
+    }
+
+    pub fn http2_keepalive_interval(mut self, interval: Duration) -> Self {
+        self.params.http2_keepalive_interval = Some(interval);
+        self
+    }
+
+    pub fn http2_keepalive_timeout(mut self, timeout: Duration) -> Self {
+        self.params.http2_keepalive_timeout = Some(timeout);
+        self
+    }
+
+    pub fn http2_keepalive_jitter_percent(mut self, jitter_percent: u8) -> Self {
+        self.params.http2_keepalive_jitter_percent = jitter_percent;
+        self
+    }
+
+    pub fn max_concurrent_streams(mut self, max_concurrent_streams: u32) -> Self {
+        self.params.max_concurrent_streams = Some(max_concurrent_streams);
+        self
+    }
+
+    pub fn auth(mut self, auth: AuthStrategy) -> Self {
+        self.params.auth = Some(auth);
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.params.user_agent = Arc::from(user_agent);
+        self
+    }
+
+    pub fn tcp_options(mut self, tcp_options: TcpSocketOptions) -> Self {
+        self.params.tcp_options = tcp_options;
+        self
+    }
+
+    pub fn address_family(mut self, address_family: AddressFamily) -> Self {
+        self.params.address_family = address_family;
+        self
+    }
+
+    pub fn bind_address(mut self, bind_address: IpAddr) -> Self {
+        self.params.bind_address = Some(bind_address);
+        self
+    }
+
+    pub fn client_identity(mut self, client_identity: ClientCertKey) -> Self {
+        self.params.client_identity = Some(client_identity);
+        self
+    }
+
+    pub fn min_tls_version(mut self, min_tls_version: TlsVersion) -> Self {
+        self.params.min_tls_version = min_tls_version;
+        self
+    }
+
+    pub fn max_tls_version(mut self, max_tls_version: TlsVersion) -> Self {
+        self.params.max_tls_version = Some(max_tls_version);
+        self
+    }
+
+    pub fn build(self) -> Result<ConnectionParams, ConfigError> {
+        let params = self.params;
+        if params.host.is_empty() {
+            return Err(ConfigError::EmptyHost);
+        }
+        if params.sni.is_empty() {
+            return Err(ConfigError::EmptySni);
+        }
+        if params.port == 0 {
+            return Err(ConfigError::InvalidPort);
+        }
+        if params.tcp_options.keepalive.map_or(false, |d| d.is_zero()) {
+            return Err(ConfigError::ZeroTcpKeepalive);
+        }
+        if ::http::HeaderValue::from_str(&params.user_agent).is_err() {
+            return Err(ConfigError::InvalidUserAgent);
+        }
+        if params
+            .max_tls_version
+            .map_or(false, |max| max < params.min_tls_version)
+        {
+            return Err(ConfigError::InvalidTlsVersionRange);
+        }
+        match (
+            params.http2_keepalive_interval,
+            params.http2_keepalive_timeout,
+        ) {
+            (Some(interval), _) if interval.is_zero() => {
+                return Err(ConfigError::ZeroKeepaliveInterval)
+            }
+            (None, Some(_)) => return Err(ConfigError::KeepaliveTimeoutWithoutInterval),
+            _ => {}
+        }
+        if params.http2_keepalive_jitter_percent >= 100 {
+            return Err(ConfigError::InvalidKeepaliveJitter);
+        }
+        let HttpRequestDecoratorSeq(decorators) = &params.http_request_decorator;
+        if params.auth.is_some()
+            && decorators
+                .iter()
+                .any(|d| matches!(d, HttpRequestDecorator::HeaderAuth(_)))
+        {
+            return Err(ConfigError::ConflictingAuthorization);
+        }
+        Ok(params)
+    }
 }
 
 impl HttpRequestDecoratorSeq {
@@ -135,26 +608,141 @@ pub(crate) async fn connect_ssl(
     connection_params: &ConnectionParams,
     alpn: &[u8],
 ) -> Result<SslStream<TcpStream>, NetError> {
-    let tcp_stream = connect_tcp(
-        &connection_params.dns_resolver,
-        &connection_params.sni,
-        connection_params.port,
-    )
-    .await?;
-
-    let ssl_config = client_ssl_connector_builder(connection_params.certs.clone(), alpn)?
-        .build()
-        .configure()?;
-
-    let ssl_stream = tokio_boring::connect(ssl_config, &connection_params.sni, tcp_stream)
+    let tcp_stream = match &connection_params.proxy {
+        Some(proxy) => {
+            connect_tcp_via_proxy(
+                proxy,
+                &connection_params.dns_resolver,
+                &connection_params.tcp_options,
+                connection_params.address_family,
+                connection_params.bind_address,
+                &connection_params.sni,
+                connection_params.port,
+            )
+            .await?
+        }
+        None => {
+            connect_tcp(
+                &connection_params.dns_resolver,
+                &connection_params.tcp_options,
+                connection_params.address_family,
+                connection_params.bind_address,
+                &connection_params.sni,
+                connection_params.port,
+            )
+            .await?
+        }
+    };
+
+    let mut ssl_builder = client_ssl_connector_builder(connection_params.certs.clone(), alpn)?;
+    ssl_builder.set_min_proto_version(Some(connection_params.min_tls_version.into()))?;
+    ssl_builder.set_max_proto_version(connection_params.max_tls_version.map(Into::into))?;
+    if let Some(client_identity) = &connection_params.client_identity {
+        client_identity.apply(&mut ssl_builder)?;
+    }
+    let pinning_failed = Arc::new(AtomicBool::new(false));
+    if !connection_params.pinned_spki.is_empty() {
+        let pinned_spki = connection_params.pinned_spki.clone();
+        let pinning_failed = pinning_failed.clone();
+        // Checked while the server certificate is verified, so that a mismatch aborts
+        // the handshake before anything is sent over the connection.
+        ssl_builder.set_verify_callback(SslVerifyMode::PEER, move |trusted, context| {
+            // only the server's own certificate is pinned, not the rest of the chain
+            if !trusted || context.error_depth() != 0 {
+                return trusted;
+            }
+            let pinned = context.current_cert().map_or(false, |certificate| {
+                spki_is_pinned(certificate, &pinned_spki).unwrap_or(false)
+            });
+            if !pinned {
+                pinning_failed.store(true, Ordering::Relaxed);
+            }
+            pinned
+        });
+    }
+    let ssl_config = ssl_builder.build().configure()?;
+
+    tokio_boring::connect(ssl_config, &connection_params.sni, tcp_stream)
         .await
-        .map_err(|_| NetError::SslFailedHandshake)?;
+        .map_err(|e| {
+            if pinning_failed.load(Ordering::Relaxed) {
+                NetError::PinningFailure
+            } else {
+                handshake_error(e.as_ssl_error_stack())
+            }
+        })
+}
+
+/// Parameters of a secure connection that were negotiated with the server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// e.g. `TLSv1.3`
+    pub tls_version: &'static str,
+    /// Application protocol selected by the server, if any.
+    pub alpn: Option<Vec<u8>>,
+    /// e.g. `TLS_AES_128_GCM_SHA256`
+    pub cipher_suite: Option<&'static str>,
+    /// SHA-256 hash of the DER-encoded SubjectPublicKeyInfo of the server certificate,
+    /// in the same form as [ConnectionParams::pinned_spki].
+    pub peer_cert_spki: Option<[u8; 32]>,
+}
+
+impl ConnectionInfo {
+    pub(crate) fn from_ssl(ssl: &SslRef) -> Result<Self, NetError> {
+        let peer_cert_spki = match ssl.peer_certificate() {
+            Some(certificate) => Some(sha256(&certificate.public_key()?.public_key_to_der()?)),
+            None => None,
+        };
+        Ok(Self {
+            tls_version: ssl.version_str(),
+            alpn: ssl.selected_alpn_protocol().map(<[u8]>::to_vec),
+            cipher_suite: ssl.current_cipher().map(|cipher| cipher.name()),
+            peer_cert_spki,
+        })
+    }
+}
+
+/// Alerts that the server sends when it doesn't accept the client certificate (or its absence).
+///
+/// The server certificate is verified on our side, so receiving any of these means that
+/// the client authentication has failed.
+const CLIENT_AUTH_ALERTS: &[&str] = &[
+    "SSLV3_ALERT_BAD_CERTIFICATE",
+    "SSLV3_ALERT_UNSUPPORTED_CERTIFICATE",
+    "SSLV3_ALERT_CERTIFICATE_REVOKED",
+    "SSLV3_ALERT_CERTIFICATE_EXPIRED",
+    "SSLV3_ALERT_CERTIFICATE_UNKNOWN",
+    "TLSV1_ALERT_UNKNOWN_CA",
+    "TLSV1_ALERT_ACCESS_DENIED",
+    "TLSV1_ALERT_CERTIFICATE_REQUIRED",
+];
 
-    Ok(ssl_stream)
+fn handshake_error(errors: Option<boring::error::ErrorStack>) -> NetError {
+    let rejected_client_auth = errors.map_or(false, |errors| {
+        errors.errors().iter().any(|e| {
+            e.reason()
+                .map_or(false, |r| CLIENT_AUTH_ALERTS.contains(&r))
+        })
+    });
+    if rejected_client_auth {
+        NetError::ClientAuthFailed
+    } else {
+        NetError::SslFailedHandshake
+    }
+}
+
+/// Checks if the SHA-256 hash of the certificate's SubjectPublicKeyInfo is in the `pinned_spki` list.
+fn spki_is_pinned(certificate: &X509Ref, pinned_spki: &[[u8; 32]]) -> Result<bool, NetError> {
+    let spki_der = certificate.public_key()?.public_key_to_der()?;
+    let spki_hash = sha256(&spki_der);
+    Ok(pinned_spki.contains(&spki_hash))
 }
 
 pub(crate) async fn connect_tcp(
     dns_resolver: &DnsResolver,
+    tcp_options: &TcpSocketOptions,
+    address_family: AddressFamily,
+    bind_address: Option<IpAddr>,
     host: &str,
     port: u16,
 ) -> Result<TcpStream, NetError> {
@@ -162,8 +750,12 @@ pub(crate) async fn connect_tcp(
         .lookup_ip(host)
         .await
         .map_err(|_| NetError::DnsError)?;
-    for ip in dns_lookup.iter() {
-        match TcpStream::connect((*ip, port)).await {
+    for ip in address_family.order_addresses(&dns_lookup) {
+        // a socket bound to an address of one family can't connect to the other one
+        if bind_address.is_some_and(|local| local.is_ipv4() != ip.is_ipv4()) {
+            continue;
+        }
+        match connect_tcp_socket(SocketAddr::new(ip, port), tcp_options, bind_address).await {
             Ok(tcp_stream) => return Ok(tcp_stream),
             Err(_) => continue,
         }
@@ -171,6 +763,22 @@ pub(crate) async fn connect_tcp(
     Err(NetError::TcpConnectionFailed)
 }
 
+async fn connect_tcp_socket(
+    addr: SocketAddr,
+    tcp_options: &TcpSocketOptions,
+    bind_address: Option<IpAddr>,
+) -> io::Result<TcpStream> {
+    let socket = match addr.ip() {
+        IpAddr::V4(_) => TcpSocket::new_v4()?,
+        IpAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    tcp_options.apply(&socket)?;
+    if let Some(local) = bind_address {
+        socket.bind(SocketAddr::new(local, 0))?;
+    }
+    socket.connect(addr).await
+}
+
 pub(crate) fn client_ssl_connector_builder(
     certs: RootCertificates,
     alpn: &[u8],
@@ -183,9 +791,32 @@ pub(crate) fn client_ssl_connector_builder(
 
 #[cfg(test)]
 pub(crate) mod test {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use boring::asn1::Asn1Time;
+    use boring::ec::{EcGroup, EcKey};
+    use boring::hash::MessageDigest;
+    use boring::nid::Nid;
+    use boring::pkey::{PKey, Private};
+    use boring::sha::sha256;
+    use boring::ssl::{select_next_proto, AlpnError, SslAcceptor, SslMethod, SslVersion};
+    use boring::x509::extension::SubjectAlternativeName;
+    use boring::x509::{X509Builder, X509NameBuilder, X509};
+    use futures_util::FutureExt;
     use hyper::Request;
 
-    use crate::infra::HttpRequestDecorator;
+    use crate::chat::errors::{connect_error, ChatNetworkError};
+    use crate::infra::certs::{ClientCertKey, RootCertificates};
+    use crate::infra::dns::{DnsResolver, ResolveFn};
+    use crate::infra::errors::NetError;
+    use crate::infra::{
+        client_ssl_connector_builder, connect_ssl, connect_tcp, handshake_error, spki_is_pinned,
+        AddressFamily, AuthStrategy, ConfigError, ConnectionInfo, ConnectionParams,
+        ConnectionParamsBuilder, HttpRequestDecorator, HttpRequestDecoratorSeq, TcpSocketOptions,
+        TlsVersion, DEFAULT_USER_AGENT,
+    };
     use crate::utils::basic_authorization;
 
     pub(crate) mod shared {
@@ -249,4 +880,589 @@ pub(crate) mod test {
             parts.headers.get(http::header::AUTHORIZATION).unwrap()
         );
     }
+
+    #[test]
+    fn test_auth_strategy() {
+        let cases = [
+            (AuthStrategy::Bearer("t0k3n".to_string()), "Bearer t0k3n"),
+            (
+                AuthStrategy::Basic("usrnm".to_string(), "psswd".to_string()),
+                "Basic dXNybm06cHNzd2Q=",
+            ),
+        ];
+        for (auth, expected) in cases {
+            let connection_params = ConnectionParams::new(
+                "chat.signal.org",
+                "chat.signal.org",
+                443,
+                HttpRequestDecoratorSeq::default(),
+                RootCertificates::Signal,
+                DnsResolver::System,
+            )
+            .with_auth(auth);
+            let builder =
+                connection_params.decorate_request(Request::get("https://chat.signal.org/"));
+            let (parts, _) = builder.body(()).unwrap().into_parts();
+            assert_eq!(
+                expected,
+                parts.headers.get(http::header::AUTHORIZATION).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_credentials_are_redacted_in_debug() {
+        let auth = AuthStrategy::Basic("usrnm".to_string(), "psswd".to_string());
+        assert_eq!(format!("{:?}", auth), r#"Basic("usrnm", "[REDACTED]")"#);
+        let auth = AuthStrategy::Bearer("t0k3n".to_string());
+        assert!(!format!("{:?}", auth).contains("t0k3n"));
+        let decorator = HttpRequestDecorator::HeaderAuth(basic_authorization("usrnm", "psswd"));
+        assert!(!format!("{:?}", decorator).contains("dXNybm06cHNzd2Q="));
+    }
+
+    #[test]
+    fn test_spki_pinning() {
+        let certificate =
+            X509::from_der(include_bytes!("../res/signal.cer")).expect("valid certificate");
+        let spki_hash = sha256(
+            &certificate
+                .public_key()
+                .expect("has public key")
+                .public_key_to_der()
+                .expect("can encode public key"),
+        );
+        assert!(spki_is_pinned(&certificate, &[[0; 32], spki_hash]).unwrap());
+        assert!(!spki_is_pinned(&certificate, &[[0; 32]]).unwrap());
+        assert!(!spki_is_pinned(&certificate, &[]).unwrap());
+    }
+
+    fn self_signed_identity() -> (X509, PKey<Private>) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).expect("known curve");
+        let key = PKey::from_ec_key(EcKey::generate(&group).expect("can generate key"))
+            .expect("valid key");
+        let mut name = X509NameBuilder::new().expect("can build name");
+        name.append_entry_by_nid(Nid::COMMONNAME, "client")
+            .expect("valid name");
+        let name = name.build();
+        let mut certificate = X509Builder::new().expect("can build certificate");
+        certificate.set_subject_name(&name).expect("valid name");
+        certificate.set_issuer_name(&name).expect("valid name");
+        certificate.set_pubkey(&key).expect("valid key");
+        certificate
+            .sign(&key, MessageDigest::sha256())
+            .expect("can sign");
+        (certificate.build(), key)
+    }
+
+    #[test]
+    fn test_client_identity_is_applied() {
+        let (certificate, key) = self_signed_identity();
+        let private_key_der = key.private_key_to_der().expect("can encode key");
+        let identity = ClientCertKey::from_der(
+            &certificate.to_der().expect("can encode certificate"),
+            private_key_der.clone(),
+        )
+        .expect("valid identity");
+
+        let mut ssl = client_ssl_connector_builder(RootCertificates::Signal, b"").unwrap();
+        identity.apply(&mut ssl).expect("can apply");
+
+        let debug = format!("{:?}", identity);
+        assert!(debug.contains("[REDACTED]"));
+        assert!(!debug.contains(&hex::encode(&private_key_der)));
+        assert!(!debug.contains(&format!("{:?}", private_key_der)));
+    }
+
+    #[test]
+    fn test_client_identity_with_mismatched_key_is_rejected() {
+        let (certificate, _) = self_signed_identity();
+        let (_, other_key) = self_signed_identity();
+        let identity = ClientCertKey::from_der(
+            &certificate.to_der().expect("can encode certificate"),
+            other_key.private_key_to_der().expect("can encode key"),
+        )
+        .expect("both parse");
+
+        let mut ssl = client_ssl_connector_builder(RootCertificates::Signal, b"").unwrap();
+        assert!(identity.apply(&mut ssl).is_err());
+
+        assert!(ClientCertKey::from_der(b"not a certificate", vec![1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn test_handshake_errors_without_details_are_not_client_auth() {
+        assert_eq!(handshake_error(None), NetError::SslFailedHandshake);
+    }
+
+    fn self_signed_server_identity(host: &str) -> (X509, PKey<Private>) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).expect("known curve");
+        let key = PKey::from_ec_key(EcKey::generate(&group).expect("can generate key"))
+            .expect("valid key");
+        let mut name = X509NameBuilder::new().expect("can build name");
+        name.append_entry_by_nid(Nid::COMMONNAME, host)
+            .expect("valid name");
+        let name = name.build();
+        let mut certificate = X509Builder::new().expect("can build certificate");
+        certificate.set_version(2).expect("valid version");
+        certificate.set_subject_name(&name).expect("valid name");
+        certificate.set_issuer_name(&name).expect("valid name");
+        certificate.set_pubkey(&key).expect("valid key");
+        certificate
+            .set_not_before(&Asn1Time::days_from_now(0).expect("valid time"))
+            .expect("valid time");
+        certificate
+            .set_not_after(&Asn1Time::days_from_now(1).expect("valid time"))
+            .expect("valid time");
+        let alt_name = SubjectAlternativeName::new()
+            .dns(host)
+            .build(&certificate.x509v3_context(None, None))
+            .expect("valid name");
+        certificate
+            .append_extension(alt_name)
+            .expect("valid extension");
+        certificate
+            .sign(&key, MessageDigest::sha256())
+            .expect("can sign");
+        (certificate.build(), key)
+    }
+
+    /// Accepts a single TLS connection on a local port, with a self-signed certificate
+    /// for `localhost` and TLS versions up to `max_version`. `h2` is selected if the client
+    /// offers it.
+    ///
+    /// Returns the port, the certificate, and the server task, which completes with
+    /// whether the handshake succeeded on the server side.
+    async fn accept_tls_once(
+        max_version: SslVersion,
+    ) -> (u16, X509, tokio::task::JoinHandle<bool>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (certificate, key) = self_signed_server_identity("localhost");
+        let mut acceptor =
+            SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).expect("can build acceptor");
+        acceptor
+            .set_certificate(&certificate)
+            .expect("valid certificate");
+        acceptor.set_private_key(&key).expect("valid key");
+        acceptor
+            .set_max_proto_version(Some(max_version))
+            .expect("valid version");
+        acceptor.set_alpn_select_callback(|_, client_protocols| {
+            select_next_proto(b"\x02h2", client_protocols).ok_or(AlpnError::NOACK)
+        });
+        let acceptor = acceptor.build();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.expect("accepted");
+            tokio_boring::accept(&acceptor, stream).await.is_ok()
+        });
+        (port, certificate, server)
+    }
+
+    /// Parameters for connecting to `localhost` on `port`, trusting only the `certificate`.
+    fn localhost_connection_params(port: u16, certificate: &X509) -> ConnectionParamsBuilder {
+        let localhost: ResolveFn = |_| async { Ok(vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]) }.boxed();
+        ConnectionParams::builder("localhost")
+            .port(port)
+            .dns_resolver(DnsResolver::GenericAsync(Arc::new(localhost)))
+            .certs(RootCertificates::FromDer(
+                certificate.to_der().expect("can encode certificate"),
+            ))
+    }
+
+    #[tokio::test]
+    async fn test_server_below_min_tls_version_fails_handshake() {
+        let (port, certificate, server) = accept_tls_once(SslVersion::TLS1_2).await;
+        let connection_params = localhost_connection_params(port, &certificate)
+            .min_tls_version(TlsVersion::Tls1_3)
+            .build()
+            .expect("valid");
+        let error = connect_ssl(&connection_params, b"")
+            .await
+            .expect_err("TLS 1.2 is not accepted");
+        assert_eq!(error, NetError::SslFailedHandshake);
+        assert!(matches!(
+            connect_error(error, ChatNetworkError::FailedToConnectHttp),
+            ChatNetworkError::TlsHandshakeFailure
+        ));
+        assert!(!server.await.expect("server didn't panic"));
+    }
+
+    #[tokio::test]
+    async fn test_connection_info_describes_negotiated_parameters() {
+        let (port, certificate, server) = accept_tls_once(SslVersion::TLS1_3).await;
+        let connection_params = localhost_connection_params(port, &certificate)
+            .build()
+            .expect("valid");
+        let stream = connect_ssl(&connection_params, b"\x02h2")
+            .await
+            .expect("connected");
+        let info = ConnectionInfo::from_ssl(stream.ssl()).expect("can describe connection");
+        assert!(server.await.expect("server didn't panic"));
+
+        assert_eq!(info.tls_version, "TLSv1.3");
+        assert_eq!(info.alpn.as_deref(), Some(&b"h2"[..]));
+        assert!(info
+            .cipher_suite
+            .is_some_and(|name| name.starts_with("TLS_")));
+        let spki_hash = sha256(
+            &certificate
+                .public_key()
+                .expect("has public key")
+                .public_key_to_der()
+                .expect("can encode public key"),
+        );
+        assert_eq!(info.peer_cert_spki, Some(spki_hash));
+    }
+
+    #[tokio::test]
+    async fn test_connection_info_without_alpn() {
+        let (port, certificate, server) = accept_tls_once(SslVersion::TLS1_2).await;
+        let connection_params = localhost_connection_params(port, &certificate)
+            .build()
+            .expect("valid");
+        let stream = connect_ssl(&connection_params, b"")
+            .await
+            .expect("connected");
+        let info = ConnectionInfo::from_ssl(stream.ssl()).expect("can describe connection");
+        assert!(server.await.expect("server didn't panic"));
+
+        assert_eq!(info.tls_version, "TLSv1.2");
+        assert_eq!(info.alpn, None);
+    }
+
+    #[tokio::test]
+    async fn test_pinned_server_key_is_accepted() {
+        let (port, certificate, server) = accept_tls_once(SslVersion::TLS1_3).await;
+        let spki_hash = sha256(
+            &certificate
+                .public_key()
+                .expect("has public key")
+                .public_key_to_der()
+                .expect("can encode public key"),
+        );
+        let connection_params = localhost_connection_params(port, &certificate)
+            .pinned_spki(vec![[0; 32], spki_hash])
+            .build()
+            .expect("valid");
+        let _stream = connect_ssl(&connection_params, b"")
+            .await
+            .expect("pinned key is accepted");
+        assert!(server.await.expect("server didn't panic"));
+    }
+
+    #[tokio::test]
+    async fn test_pinning_failure_fails_handshake() {
+        let (port, certificate, server) = accept_tls_once(SslVersion::TLS1_3).await;
+        let connection_params = localhost_connection_params(port, &certificate)
+            .pinned_spki(vec![[0; 32]])
+            .build()
+            .expect("valid");
+        assert_eq!(
+            connect_ssl(&connection_params, b"").await.unwrap_err(),
+            NetError::PinningFailure
+        );
+        // the client aborted the handshake instead of completing it
+        assert!(!server.await.expect("server didn't panic"));
+    }
+
+    #[test]
+    fn test_builder_defaults_match_direct_connection() {
+        let params = ConnectionParams::builder("chat.signal.org")
+            .build()
+            .expect("valid");
+        assert_eq!(&*params.host, "chat.signal.org");
+        assert_eq!(&*params.sni, "chat.signal.org");
+        assert_eq!(params.port, 443);
+        assert!(matches!(params.certs, RootCertificates::Signal));
+        assert!(matches!(params.dns_resolver, DnsResolver::System));
+        assert!(params.pinned_spki.is_empty());
+        assert!(params.proxy.is_none());
+        assert_eq!(params.http2_keepalive_interval, None);
+        assert_eq!(params.http2_keepalive_timeout, None);
+        assert_eq!(params.http2_keepalive_jitter_percent, 10);
+        assert_eq!(params.max_concurrent_streams, None);
+        assert_eq!(params.auth, None);
+        assert_eq!(params.address_family, AddressFamily::Auto);
+        assert_eq!(params.bind_address, None);
+        assert!(params.client_identity.is_none());
+        assert_eq!(params.min_tls_version, TlsVersion::Tls1_2);
+        assert_eq!(params.max_tls_version, None);
+    }
+
+    #[test]
+    fn test_builder_overrides() {
+        let params = ConnectionParams::builder("chat.signal.org")
+            .sni("example.com")
+            .port(8443)
+            .decorator(HttpRequestDecorator::PathPrefix("/service"))
+            .http2_keepalive_interval(Duration::from_secs(30))
+            .http2_keepalive_timeout(Duration::from_secs(5))
+            .max_concurrent_streams(10)
+            .auth(AuthStrategy::Bearer("t0k3n".to_string()))
+            .build()
+            .expect("valid");
+        assert_eq!(&*params.sni, "example.com");
+        assert_eq!(params.port, 8443);
+        assert_eq!(
+            params.http2_keepalive_interval,
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(params.http2_keepalive_timeout, Some(Duration::from_secs(5)));
+        assert_eq!(params.max_concurrent_streams, Some(10));
+
+        let builder = params.decorate_request(Request::get("https://chat.signal.org/v1/keepalive"));
+        let (parts, _) = builder.body(()).unwrap().into_parts();
+        assert_eq!(parts.uri.path(), "/service/v1/keepalive");
+        assert_eq!(
+            parts.headers.get(http::header::AUTHORIZATION).unwrap(),
+            "Bearer t0k3n"
+        );
+    }
+
+    #[test]
+    fn test_builder_rejects_invalid_params() {
+        let builder = || ConnectionParams::builder("chat.signal.org");
+        assert_eq!(
+            ConnectionParams::builder("").build().unwrap_err(),
+            ConfigError::EmptyHost
+        );
+        assert_eq!(
+            builder().sni("").build().unwrap_err(),
+            ConfigError::EmptySni
+        );
+        assert_eq!(
+            builder().port(0).build().unwrap_err(),
+            ConfigError::InvalidPort
+        );
+        assert_eq!(
+            builder()
+                .http2_keepalive_timeout(Duration::from_secs(5))
+                .build()
+                .unwrap_err(),
+            ConfigError::KeepaliveTimeoutWithoutInterval
+        );
+        assert_eq!(
+            builder()
+                .http2_keepalive_interval(Duration::ZERO)
+                .build()
+                .unwrap_err(),
+            ConfigError::ZeroKeepaliveInterval
+        );
+        assert_eq!(
+            builder()
+                .http2_keepalive_interval(Duration::from_secs(30))
+                .http2_keepalive_jitter_percent(100)
+                .build()
+                .unwrap_err(),
+            ConfigError::InvalidKeepaliveJitter
+        );
+        assert_eq!(
+            builder()
+                .decorator(HttpRequestDecorator::HeaderAuth(basic_authorization(
+                    "usrnm", "psswd"
+                )))
+                .auth(AuthStrategy::Bearer("t0k3n".to_string()))
+                .build()
+                .unwrap_err(),
+            ConfigError::ConflictingAuthorization
+        );
+        assert_eq!(
+            builder()
+                .min_tls_version(TlsVersion::Tls1_3)
+                .max_tls_version(TlsVersion::Tls1_2)
+                .build()
+                .unwrap_err(),
+            ConfigError::InvalidTlsVersionRange
+        );
+        assert!(builder()
+            .min_tls_version(TlsVersion::Tls1_3)
+            .max_tls_version(TlsVersion::Tls1_3)
+            .build()
+            .is_ok());
+    }
+
+    #[test]
+    fn test_user_agent_is_set_unless_present() {
+        let params = ConnectionParams::builder("chat.signal.org")
+            .build()
+            .expect("valid");
+        let builder = params.decorate_request(Request::get("https://chat.signal.org/"));
+        let (parts, _) = builder.body(()).unwrap().into_parts();
+        assert_eq!(
+            parts.headers.get(http::header::USER_AGENT).unwrap(),
+            DEFAULT_USER_AGENT
+        );
+        assert!(DEFAULT_USER_AGENT.starts_with("libsignal/"));
+
+        let params = ConnectionParams::builder("chat.signal.org")
+            .user_agent("Signal-Desktop/7.0.0")
+            .build()
+            .expect("valid");
+        let builder = params.decorate_request(Request::get("https://chat.signal.org/"));
+        let (parts, _) = builder.body(()).unwrap().into_parts();
+        assert_eq!(
+            parts.headers.get(http::header::USER_AGENT).unwrap(),
+            "Signal-Desktop/7.0.0"
+        );
+
+        let builder = params.decorate_request(
+            Request::get("https://chat.signal.org/").header(http::header::USER_AGENT, "custom"),
+        );
+        let (parts, _) = builder.body(()).unwrap().into_parts();
+        let user_agents: Vec<_> = parts
+            .headers
+            .get_all(http::header::USER_AGENT)
+            .iter()
+            .collect();
+        assert_eq!(user_agents, vec!["custom"]);
+
+        assert_eq!(
+            ConnectionParams::builder("chat.signal.org")
+                .user_agent("bad\nvalue")
+                .build()
+                .unwrap_err(),
+            ConfigError::InvalidUserAgent
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tcp_options_are_applied() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let localhost: ResolveFn = |_| async { Ok(vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]) }.boxed();
+        let dns_resolver = DnsResolver::GenericAsync(Arc::new(localhost));
+
+        let stream = connect_tcp(
+            &dns_resolver,
+            &TcpSocketOptions::default(),
+            AddressFamily::Auto,
+            None,
+            "localhost",
+            port,
+        )
+        .await
+        .expect("connected");
+        assert!(stream.nodelay().unwrap());
+        assert!(!socket2::SockRef::from(&stream).keepalive().unwrap());
+
+        let tcp_options = TcpSocketOptions {
+            nodelay: false,
+            recv_buffer_size: Some(64 * 1024),
+            send_buffer_size: Some(64 * 1024),
+            keepalive: Some(Duration::from_secs(30)),
+        };
+        let stream = connect_tcp(
+            &dns_resolver,
+            &tcp_options,
+            AddressFamily::Auto,
+            None,
+            "localhost",
+            port,
+        )
+        .await
+        .expect("connected");
+        assert!(!stream.nodelay().unwrap());
+        let socket = socket2::SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        // The OS may round the buffer sizes up.
+        assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+        assert!(socket.send_buffer_size().unwrap() >= 64 * 1024);
+    }
+
+    #[test]
+    fn test_address_family_ordering() {
+        let v4_1 = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let v4_2 = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+        let v6_1 = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+        let v6_2 = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2));
+        let resolved = [v4_1, v4_2, v6_1, v6_2];
+
+        assert_eq!(
+            AddressFamily::Auto.order_addresses(&resolved),
+            vec![v4_1, v6_1, v4_2, v6_2]
+        );
+        assert_eq!(
+            AddressFamily::Auto.order_addresses(&[v6_1, v4_1, v4_2]),
+            vec![v6_1, v4_1, v4_2]
+        );
+        assert_eq!(
+            AddressFamily::Ipv4Only.order_addresses(&resolved),
+            vec![v4_1, v4_2]
+        );
+        assert_eq!(
+            AddressFamily::Ipv6Only.order_addresses(&resolved),
+            vec![v6_1, v6_2]
+        );
+        assert_eq!(
+            AddressFamily::PreferIpv6.order_addresses(&resolved),
+            vec![v6_1, v6_2, v4_1, v4_2]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connect_tcp_skips_excluded_address_family() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let localhost: ResolveFn = |_| async { Ok(vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]) }.boxed();
+        let dns_resolver = DnsResolver::GenericAsync(Arc::new(localhost));
+
+        assert!(connect_tcp(
+            &dns_resolver,
+            &TcpSocketOptions::default(),
+            AddressFamily::Ipv6Only,
+            None,
+            "localhost",
+            port,
+        )
+        .await
+        .is_err());
+        assert!(connect_tcp(
+            &dns_resolver,
+            &TcpSocketOptions::default(),
+            AddressFamily::Ipv4Only,
+            None,
+            "localhost",
+            port,
+        )
+        .await
+        .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_connect_tcp_binds_to_local_address() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let localhost: ResolveFn = |_| async { Ok(vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]) }.boxed();
+        let dns_resolver = DnsResolver::GenericAsync(Arc::new(localhost));
+        let tcp_options = TcpSocketOptions::default();
+        let connect = |bind_address| {
+            connect_tcp(
+                &dns_resolver,
+                &tcp_options,
+                AddressFamily::Auto,
+                Some(bind_address),
+                "localhost",
+                port,
+            )
+        };
+
+        let stream = connect(IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .await
+            .expect("connected");
+        assert_eq!(
+            stream.local_addr().unwrap().ip(),
+            IpAddr::V4(Ipv4Addr::LOCALHOST)
+        );
+
+        // the address doesn't belong to any local interface
+        assert!(matches!(
+            connect(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))).await,
+            Err(NetError::TcpConnectionFailed)
+        ));
+        // only IPv4 addresses were resolved
+        assert!(matches!(
+            connect(IpAddr::V6(Ipv6Addr::LOCALHOST)).await,
+            Err(NetError::TcpConnectionFailed)
+        ));
+    }
 }
@@ -4,11 +4,14 @@
 //
 
 use boring::error::ErrorStack;
+use boring::pkey::PKey;
+use boring::ssl::SslConnectorBuilder;
 use boring::x509::store::{X509Store, X509StoreBuilder};
 use boring::x509::X509;
 
 use lazy_static::lazy_static;
 use rustls_native_certs::Certificate;
+use zeroize::Zeroizing;
 
 lazy_static! {
     static ref NATIVE_CERTS: Vec<Certificate> =
@@ -70,3 +73,43 @@ impl TryInto<X509Store> for RootCertificates {
         Ok(store_builder.build())
     }
 }
+
+/// Certificate and private key presented to the servers that require client authentication.
+///
+/// The private key is zeroized when dropped and is never included in the `Debug` output.
+#[derive(Clone)]
+pub struct ClientCertKey {
+    certificate: X509,
+    private_key_der: Zeroizing<Vec<u8>>,
+}
+
+impl ClientCertKey {
+    /// Fails with [Error::BadDer] if either the certificate or the private key can't be parsed.
+    pub fn from_der(certificate_der: &[u8], private_key_der: Vec<u8>) -> Result<Self, Error> {
+        let private_key_der = Zeroizing::new(private_key_der);
+        let certificate = X509::from_der(certificate_der)?;
+        let _ = PKey::private_key_from_der(&private_key_der)?;
+        Ok(Self {
+            certificate,
+            private_key_der,
+        })
+    }
+
+    /// Configures the connector to present this certificate during the handshake.
+    ///
+    /// Fails if the private key doesn't match the certificate.
+    pub(crate) fn apply(&self, ssl: &mut SslConnectorBuilder) -> Result<(), ErrorStack> {
+        let private_key = PKey::private_key_from_der(&self.private_key_der)?;
+        ssl.set_certificate(&self.certificate)?;
+        ssl.set_private_key(&private_key)?;
+        ssl.check_private_key()
+    }
+}
+
+impl std::fmt::Debug for ClientCertKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientCertKey")
+            .field("private_key", &"[REDACTED]")
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,199 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::fmt::Debug;
+use std::future::Future;
+use std::time::Duration;
+
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use tokio::time::Instant;
+
+/// Source of the current time and of timers.
+///
+/// Timeouts, reconnect backoff, and keepalive intervals are measured with a [Clock],
+/// so that tests can control the passage of time with a `MockClock` instead of waiting
+/// for the real time to pass.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Returns a future that completes once `duration` has passed according to this clock.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+
+    /// Returns a future that completes once this clock reaches `deadline`.
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        self.sleep(deadline.saturating_duration_since(self.now()))
+    }
+}
+
+/// [Clock] backed by the tokio timer.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        tokio::time::sleep(duration).boxed()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> BoxFuture<'static, ()> {
+        tokio::time::sleep_until(deadline).boxed()
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+pub use mock::MockClock;
+
+#[cfg(any(test, feature = "test-util"))]
+mod mock {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use futures_util::future::BoxFuture;
+    use futures_util::FutureExt;
+    use tokio::sync::oneshot;
+    use tokio::time::Instant;
+
+    use super::Clock;
+
+    /// [Clock] that only moves forward when [MockClock::advance] is called.
+    ///
+    /// Clones share the same time.
+    #[derive(Clone, Debug)]
+    pub struct MockClock {
+        state: Arc<Mutex<MockClockState>>,
+    }
+
+    #[derive(Debug)]
+    struct MockClockState {
+        now: Instant,
+        sleepers: Vec<(Instant, oneshot::Sender<()>)>,
+    }
+
+    impl Default for MockClock {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl MockClock {
+        pub fn new() -> Self {
+            Self {
+                state: Arc::new(Mutex::new(MockClockState {
+                    now: Instant::now(),
+                    sleepers: vec![],
+                })),
+            }
+        }
+
+        /// Moves the time forward by `duration`, completing all sleeps that end by then.
+        pub fn advance(&self, duration: Duration) {
+            let mut state = self.state.lock().expect("not poisoned");
+            state.now += duration;
+            let now = state.now;
+            let (woken, sleeping): (Vec<_>, Vec<_>) = std::mem::take(&mut state.sleepers)
+                .into_iter()
+                .partition(|(deadline, _)| *deadline <= now);
+            state.sleepers = sleeping;
+            for (_, sender) in woken {
+                // the sleep might have been dropped already
+                let _ignore_failed_send = sender.send(());
+            }
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            self.state.lock().expect("not poisoned").now
+        }
+
+        fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+            if duration.is_zero() {
+                return std::future::ready(()).boxed();
+            }
+            let mut state = self.state.lock().expect("not poisoned");
+            let deadline = state.now + duration;
+            let (sender, receiver) = oneshot::channel();
+            state.sleepers.push((deadline, sender));
+            async move {
+                if receiver.await.is_err() {
+                    // the clock is gone, so the time will never come
+                    std::future::pending().await
+                }
+            }
+            .boxed()
+        }
+    }
+}
+
+/// Like [tokio::time::timeout], but the time is measured by the `clock`.
+///
+/// Returns `None` if the `duration` has passed before the `future` completed.
+pub async fn timeout<F: Future>(
+    clock: &dyn Clock,
+    duration: Duration,
+    future: F,
+) -> Option<F::Output> {
+    tokio::select! {
+        biased;
+        output = future => Some(output),
+        _ = clock.sleep(duration) => None,
+    }
+}
+
+/// Like [timeout], but with an absolute `deadline`.
+pub async fn timeout_at<F: Future>(
+    clock: &dyn Clock,
+    deadline: Instant,
+    future: F,
+) -> Option<F::Output> {
+    tokio::select! {
+        biased;
+        output = future => Some(output),
+        _ = clock.sleep_until(deadline) => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures_util::FutureExt;
+
+    use crate::infra::clock::{timeout, Clock, MockClock};
+
+    #[test]
+    fn mock_clock_sleep_completes_after_advance() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let mut sleep = clock.sleep(Duration::from_secs(5));
+
+        assert!((&mut sleep).now_or_never().is_none());
+        clock.advance(Duration::from_secs(4));
+        assert!((&mut sleep).now_or_never().is_none());
+        clock.advance(Duration::from_secs(1));
+        assert!(sleep.now_or_never().is_some());
+        assert_eq!(clock.now(), start + Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn mock_clock_timeout() {
+        let clock = MockClock::new();
+        let mut timed_out =
+            timeout(&clock, Duration::from_secs(1), std::future::pending::<()>()).boxed_local();
+
+        assert!((&mut timed_out).now_or_never().is_none());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(timed_out.await, None);
+
+        assert_eq!(
+            timeout(&clock, Duration::from_secs(1), std::future::ready(1)).await,
+            Some(1)
+        );
+    }
+}
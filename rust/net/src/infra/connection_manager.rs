@@ -8,13 +8,17 @@ use std::fmt::Debug;
 use std::future::Future;
 use std::ops::Add;
 use std::panic::RefUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
 use async_trait::async_trait;
+use futures_util::stream::FuturesUnordered;
+use futures_util::StreamExt;
 use tokio::sync::Mutex;
-use tokio::time::{timeout_at, Instant};
+use tokio::time::Instant;
 
+use crate::infra::clock::{timeout_at, Clock, SystemClock};
 use crate::infra::errors::LogSafeDisplay;
 use crate::infra::ConnectionParams;
 
@@ -76,7 +80,14 @@ impl ThrottlingConnectionManagerState {
     /// discarded. If, however, outcomes of failed attempts are arriving out of
     /// order in which attempts started, those failures will still be reflected
     /// in `consecutive_fails`.
-    fn after_attempt(self, was_successful: bool, attempt_start_time: Instant) -> Self {
+    ///
+    /// The cooldown after a failure is counted from `now`.
+    fn after_attempt(
+        self,
+        was_successful: bool,
+        attempt_start_time: Instant,
+        now: Instant,
+    ) -> Self {
         let mut s = self;
         if was_successful {
             // comparing using `>=` to guarantee that succesful attempt takes precedence
@@ -91,7 +102,7 @@ impl ThrottlingConnectionManagerState {
             let cooldown_interval = COOLDOWN_INTERVALS
                 .get(idx)
                 .unwrap_or(&MAX_COOLDOWN_INTERVAL);
-            s.next_attempt = Instant::now() + *cooldown_interval;
+            s.next_attempt = now + *cooldown_interval;
             s.consecutive_fails = min(
                 s.consecutive_fails.saturating_add(1),
                 (COOLDOWN_INTERVALS.len() - 1).try_into().unwrap(),
@@ -109,6 +120,7 @@ pub struct SingleRouteThrottlingConnectionManager {
     state: Arc<Mutex<ThrottlingConnectionManagerState>>,
     connection_params: ConnectionParams,
     connection_timeout: Duration,
+    clock: Arc<dyn Clock>,
 }
 
 /// A connection manager that holds a list of [SingleRouteThrottlingConnectionManager] instances
@@ -119,16 +131,28 @@ pub struct SingleRouteThrottlingConnectionManager {
 pub struct MultiRouteConnectionManager {
     route_managers: Vec<SingleRouteThrottlingConnectionManager>,
     connection_timeout: Duration,
+    clock: Arc<dyn Clock>,
 }
 
 impl MultiRouteConnectionManager {
     pub fn new(
         route_managers: Vec<SingleRouteThrottlingConnectionManager>,
         connection_timeout: Duration,
+    ) -> Self {
+        Self::new_with_clock(route_managers, connection_timeout, Arc::new(SystemClock))
+    }
+
+    /// Like [MultiRouteConnectionManager::new], but the connection timeout is measured
+    /// by the given `clock`.
+    pub fn new_with_clock(
+        route_managers: Vec<SingleRouteThrottlingConnectionManager>,
+        connection_timeout: Duration,
+        clock: Arc<dyn Clock>,
     ) -> Self {
         Self {
             route_managers,
             connection_timeout,
+            clock,
         }
     }
 }
@@ -153,16 +177,21 @@ impl ConnectionManager for MultiRouteConnectionManager {
         Fun: Fn(&'a ConnectionParams) -> Fut + Send + Sync,
         Fut: Future<Output = Result<T, E>> + Send,
     {
-        let now = Instant::now();
+        let clock = &*self.clock;
+        let now = clock.now();
         let deadline = now + self.connection_timeout;
         let mut earliest_retry = now + MAX_COOLDOWN_INTERVAL;
         for route_manager in self.route_managers.iter() {
             loop {
-                let result_or_timeout =
-                    timeout_at(deadline, route_manager.connect_or_wait(&connection_fn)).await;
+                let result_or_timeout = timeout_at(
+                    clock,
+                    deadline,
+                    route_manager.connect_or_wait(&connection_fn),
+                )
+                .await;
                 let result = match result_or_timeout {
-                    Ok(r) => r,
-                    Err(_) => return ConnectionAttemptOutcome::TimedOut,
+                    Some(r) => r,
+                    None => return ConnectionAttemptOutcome::TimedOut,
                 };
                 match result {
                     ConnectionAttemptOutcome::Attempted(Ok(r)) => {
@@ -190,16 +219,185 @@ impl ConnectionManager for MultiRouteConnectionManager {
     }
 }
 
+/// A connection manager that races connection attempts over several routes.
+///
+/// Attempts are started in the order of preference, each one `attempt_delay` after the previous
+/// one, without waiting for the earlier attempts to complete (similar to "happy eyeballs").
+/// The first successful attempt wins and all the others are cancelled. The winning route is
+/// remembered and is tried first on the next call to [ConnectionManager::connect_or_wait].
+///
+/// What happens when the attempts fail is controlled by the [RaceStrategy].
+#[derive(Clone)]
+pub struct RacingMultiRouteConnectionManager {
+    route_managers: Vec<SingleRouteThrottlingConnectionManager>,
+    preferred_route: Arc<AtomicUsize>,
+    attempt_delay: Duration,
+    connection_timeout: Duration,
+    strategy: RaceStrategy,
+    clock: Arc<dyn Clock>,
+}
+
+/// Controls how [RacingMultiRouteConnectionManager] handles failed connection attempts.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum RaceStrategy {
+    /// Routes that fail are retried until their managers put them in cooldown,
+    /// and [ConnectionAttemptOutcome::WaitUntil] is returned once all of them are.
+    #[default]
+    TryAll,
+    /// Every route is attempted once. If all of them fail, the error of the attempt
+    /// that failed first is returned, without waiting for the routes to be retried.
+    FailFast,
+}
+
+impl RacingMultiRouteConnectionManager {
+    pub fn new(
+        route_managers: Vec<SingleRouteThrottlingConnectionManager>,
+        attempt_delay: Duration,
+        connection_timeout: Duration,
+    ) -> Self {
+        Self::new_with_clock(
+            route_managers,
+            attempt_delay,
+            connection_timeout,
+            Arc::new(SystemClock),
+        )
+    }
+
+    /// Like [RacingMultiRouteConnectionManager::new], but the attempt delays and the
+    /// connection timeout are measured by the given `clock`.
+    pub fn new_with_clock(
+        route_managers: Vec<SingleRouteThrottlingConnectionManager>,
+        attempt_delay: Duration,
+        connection_timeout: Duration,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Self {
+            route_managers,
+            preferred_route: Arc::new(AtomicUsize::new(0)),
+            attempt_delay,
+            connection_timeout,
+            strategy: RaceStrategy::default(),
+            clock,
+        }
+    }
+
+    pub fn with_strategy(mut self, strategy: RaceStrategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Returns the configured endpoints, with the most recently successful one first.
+    pub fn connection_params(&self) -> Vec<ConnectionParams> {
+        self.routes_in_order()
+            .map(|idx| self.route_managers[idx].connection_params.clone())
+            .collect()
+    }
+
+    fn routes_in_order(&self) -> impl Iterator<Item = usize> {
+        let preferred = self.preferred_route.load(Ordering::Relaxed);
+        let count = self.route_managers.len();
+        (0..count).map(move |i| (preferred + i) % count)
+    }
+}
+
+#[async_trait]
+impl ConnectionManager for RacingMultiRouteConnectionManager {
+    async fn connect_or_wait<'a, T, E, Fun, Fut>(
+        &'a self,
+        connection_fn: Fun,
+    ) -> ConnectionAttemptOutcome<T, E>
+    where
+        T: Send,
+        E: Send + Debug + LogSafeDisplay,
+        Fun: Fn(&'a ConnectionParams) -> Fut + Send + Sync,
+        Fut: Future<Output = Result<T, E>> + Send,
+    {
+        let clock = &*self.clock;
+        let now = clock.now();
+        let deadline = now + self.connection_timeout;
+        let mut earliest_retry = now + MAX_COOLDOWN_INTERVAL;
+        let connection_fn = &connection_fn;
+        let attempt = move |idx: usize, start: Instant| async move {
+            clock.sleep_until(start).await;
+            let outcome = self.route_managers[idx]
+                .connect_or_wait(connection_fn)
+                .await;
+            (idx, outcome)
+        };
+
+        let mut attempts: FuturesUnordered<_> = self
+            .routes_in_order()
+            .enumerate()
+            .map(|(position, idx)| attempt(idx, now + self.attempt_delay * position as u32))
+            .collect();
+        let retry_failed_routes = self.strategy == RaceStrategy::TryAll;
+        let mut first_error = None;
+        let mut timed_out = false;
+
+        loop {
+            let next = match timeout_at(clock, deadline, attempts.next()).await {
+                Some(Some(next)) => next,
+                Some(None) => break,
+                None => return ConnectionAttemptOutcome::TimedOut,
+            };
+            match next {
+                (idx, ConnectionAttemptOutcome::Attempted(Ok(r))) => {
+                    self.preferred_route.store(idx, Ordering::Relaxed);
+                    return ConnectionAttemptOutcome::Attempted(Ok(r));
+                }
+                (idx, ConnectionAttemptOutcome::Attempted(Err(e))) => {
+                    log::debug!("Connection attempt failed with an error: {:?}", e);
+                    log::info!("Connection attempt failed with an error: {}", e);
+                    if retry_failed_routes {
+                        // keep trying the route until its manager puts it in cooldown
+                        attempts.push(attempt(idx, clock.now()));
+                    } else {
+                        first_error.get_or_insert(e);
+                    }
+                }
+                (idx, ConnectionAttemptOutcome::TimedOut) => {
+                    log::info!("Connection attempt timed out");
+                    if retry_failed_routes {
+                        attempts.push(attempt(idx, clock.now()));
+                    } else {
+                        timed_out = true;
+                    }
+                }
+                (_, ConnectionAttemptOutcome::WaitUntil(i)) => {
+                    earliest_retry = min(earliest_retry, i);
+                }
+            }
+        }
+        match first_error {
+            Some(e) => ConnectionAttemptOutcome::Attempted(Err(e)),
+            None if timed_out => ConnectionAttemptOutcome::TimedOut,
+            None => ConnectionAttemptOutcome::WaitUntil(earliest_retry),
+        }
+    }
+}
+
 impl SingleRouteThrottlingConnectionManager {
     pub fn new(connection_params: ConnectionParams, connection_timeout: Duration) -> Self {
+        Self::new_with_clock(connection_params, connection_timeout, Arc::new(SystemClock))
+    }
+
+    /// Like [SingleRouteThrottlingConnectionManager::new], but the connection timeout and
+    /// the cooldowns after failed attempts are measured by the given `clock`.
+    pub fn new_with_clock(
+        connection_params: ConnectionParams,
+        connection_timeout: Duration,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let now = clock.now();
         Self {
             connection_params,
             connection_timeout,
             state: Arc::new(Mutex::new(ThrottlingConnectionManagerState {
                 consecutive_fails: 0,
-                next_attempt: Instant::now(),
-                latest_attempt: Instant::now(),
+                next_attempt: now,
+                latest_attempt: now,
             })),
+            clock,
         }
     }
 }
@@ -223,11 +421,12 @@ impl ConnectionManager for SingleRouteThrottlingConnectionManager {
         Fut: Future<Output = Result<T, E>> + Send,
     {
         let state = self.state.lock().await.clone();
-        let attempt_start_time = Instant::now();
+        let attempt_start_time = self.clock.now();
         if attempt_start_time < state.next_attempt {
             return ConnectionAttemptOutcome::WaitUntil(state.next_attempt);
         }
         let connection_result_or_timeout = timeout_at(
+            &*self.clock,
             attempt_start_time.add(self.connection_timeout),
             connection_fn(&self.connection_params),
         )
@@ -240,7 +439,9 @@ impl ConnectionManager for SingleRouteThrottlingConnectionManager {
         let was_successful = connection_result_or_timeout
             .as_ref()
             .map_or(false, |r| r.is_ok());
-        let new_state = s.clone().after_attempt(was_successful, attempt_start_time);
+        let new_state =
+            s.clone()
+                .after_attempt(was_successful, attempt_start_time, self.clock.now());
         *s = new_state;
 
         connection_result_or_timeout.map_or(ConnectionAttemptOutcome::TimedOut, |result| {
@@ -258,6 +459,7 @@ mod test {
     use tokio::time;
 
     use crate::infra::certs::RootCertificates;
+    use crate::infra::clock::MockClock;
     use crate::infra::dns::DnsResolver;
     use crate::infra::test::shared::{
         TestError, FEW_ATTEMPTS, LONG_CONNECTION_TIME, MANY_ATTEMPTS, TIMEOUT_DURATION,
@@ -367,6 +569,42 @@ mod test {
         assert_matches!(attempt_outcome, ConnectionAttemptOutcome::Attempted(Ok(())));
     }
 
+    #[tokio::test]
+    async fn single_route_manager_cooldown_is_measured_by_the_clock() {
+        let clock = MockClock::new();
+        let manager = SingleRouteThrottlingConnectionManager::new_with_clock(
+            example_connection_params("chat.staging.signal.org"),
+            TIMEOUT_DURATION,
+            Arc::new(clock.clone()),
+        );
+        clock.advance(TIME_ADVANCE_VALUE);
+        for _ in 0..2 {
+            let attempt_outcome: ConnectionAttemptOutcome<(), TestError> = manager
+                .connect_or_wait(|_| future::ready(Err(TestError::Expected)))
+                .await;
+            assert_matches!(
+                attempt_outcome,
+                ConnectionAttemptOutcome::Attempted(Err(TestError::Expected))
+            );
+        }
+
+        // the second failure starts a one-second cooldown
+        let cooldown_end = clock.now() + Duration::from_secs(1);
+        let attempt_outcome: ConnectionAttemptOutcome<(), TestError> =
+            manager.connect_or_wait(|_| future::ready(Ok(()))).await;
+        assert_matches!(attempt_outcome, ConnectionAttemptOutcome::WaitUntil(i) if i == cooldown_end);
+
+        clock.advance(Duration::from_millis(999));
+        let attempt_outcome: ConnectionAttemptOutcome<(), TestError> =
+            manager.connect_or_wait(|_| future::ready(Ok(()))).await;
+        assert_matches!(attempt_outcome, ConnectionAttemptOutcome::WaitUntil(_));
+
+        clock.advance(Duration::from_millis(1));
+        let attempt_outcome: ConnectionAttemptOutcome<(), TestError> =
+            manager.connect_or_wait(|_| future::ready(Ok(()))).await;
+        assert_matches!(attempt_outcome, ConnectionAttemptOutcome::Attempted(Ok(())));
+    }
+
     #[tokio::test(flavor = "current_thread", start_paused = true)]
     async fn multi_route_manager_picks_working_route() {
         let manager_1 = SingleRouteThrottlingConnectionManager::new(
@@ -438,6 +676,127 @@ mod test {
         assert_matches!(attempt_outcome, ConnectionAttemptOutcome::TimedOut);
     }
 
+    #[tokio::test(flavor = "current_thread", start_paused = true)]
+    async fn racing_manager_prefers_first_to_connect() {
+        let racing_manager = RacingMultiRouteConnectionManager::new(
+            vec![
+                SingleRouteThrottlingConnectionManager::new(
+                    example_connection_params(ROUTE_THAT_TIMES_OUT),
+                    TIMEOUT_DURATION,
+                ),
+                SingleRouteThrottlingConnectionManager::new(
+                    example_connection_params(ROUTE_1),
+                    TIMEOUT_DURATION,
+                ),
+            ],
+            TIME_ADVANCE_VALUE,
+            TIMEOUT_DURATION * 2,
+        );
+
+        time::advance(TIME_ADVANCE_VALUE).await;
+        let start = Instant::now();
+        let attempt_outcome: ConnectionAttemptOutcome<&str, TestError> = racing_manager
+            .connect_or_wait(|connection_params| simulate_connect(connection_params, true))
+            .await;
+        assert_matches!(
+            attempt_outcome,
+            ConnectionAttemptOutcome::Attempted(Ok(ROUTE_1))
+        );
+        // the second route was started without waiting for the first one to time out
+        assert!(start.elapsed() < TIMEOUT_DURATION);
+
+        // the winner is remembered and is now the first route
+        let hosts: Vec<_> = racing_manager
+            .connection_params()
+            .into_iter()
+            .map(|params| params.host)
+            .collect();
+        assert_eq!(hosts, [ROUTE_1.into(), ROUTE_THAT_TIMES_OUT.into()]);
+    }
+
+    #[tokio::test(flavor = "current_thread", start_paused = true)]
+    async fn racing_manager_returns_wait_until_when_all_routes_fail() {
+        let racing_manager = RacingMultiRouteConnectionManager::new(
+            vec![
+                SingleRouteThrottlingConnectionManager::new(
+                    example_connection_params(ROUTE_1),
+                    TIMEOUT_DURATION,
+                ),
+                SingleRouteThrottlingConnectionManager::new(
+                    example_connection_params("unknown.signal.org"),
+                    TIMEOUT_DURATION,
+                ),
+            ],
+            TIME_ADVANCE_VALUE,
+            TIMEOUT_DURATION * 2,
+        );
+
+        time::advance(TIME_ADVANCE_VALUE).await;
+        let attempt_outcome: ConnectionAttemptOutcome<&str, TestError> = racing_manager
+            .connect_or_wait(|connection_params| simulate_connect(connection_params, false))
+            .await;
+        assert_matches!(attempt_outcome, ConnectionAttemptOutcome::WaitUntil(_));
+    }
+
+    #[tokio::test(flavor = "current_thread", start_paused = true)]
+    async fn racing_manager_fail_fast_returns_first_error() {
+        let racing_manager = RacingMultiRouteConnectionManager::new(
+            vec![
+                SingleRouteThrottlingConnectionManager::new(
+                    example_connection_params(ROUTE_1),
+                    TIMEOUT_DURATION,
+                ),
+                SingleRouteThrottlingConnectionManager::new(
+                    example_connection_params("unknown.signal.org"),
+                    TIMEOUT_DURATION,
+                ),
+            ],
+            TIME_ADVANCE_VALUE,
+            TIMEOUT_DURATION * 2,
+        )
+        .with_strategy(RaceStrategy::FailFast);
+
+        time::advance(TIME_ADVANCE_VALUE).await;
+        let start = Instant::now();
+        let attempt_outcome: ConnectionAttemptOutcome<&str, TestError> = racing_manager
+            .connect_or_wait(|connection_params| simulate_connect(connection_params, false))
+            .await;
+        assert_matches!(
+            attempt_outcome,
+            ConnectionAttemptOutcome::Attempted(Err(TestError::Expected))
+        );
+        // the failed routes were not retried
+        assert!(start.elapsed() < TIMEOUT_DURATION);
+    }
+
+    #[tokio::test(flavor = "current_thread", start_paused = true)]
+    async fn racing_manager_fail_fast_still_prefers_successful_route() {
+        let racing_manager = RacingMultiRouteConnectionManager::new(
+            vec![
+                SingleRouteThrottlingConnectionManager::new(
+                    example_connection_params("unknown.signal.org"),
+                    TIMEOUT_DURATION,
+                ),
+                SingleRouteThrottlingConnectionManager::new(
+                    example_connection_params(ROUTE_2),
+                    TIMEOUT_DURATION,
+                ),
+            ],
+            TIME_ADVANCE_VALUE,
+            TIMEOUT_DURATION * 2,
+        )
+        .with_strategy(RaceStrategy::FailFast);
+
+        time::advance(TIME_ADVANCE_VALUE).await;
+        let attempt_outcome: ConnectionAttemptOutcome<&str, TestError> = racing_manager
+            .connect_or_wait(|connection_params| simulate_connect(connection_params, false))
+            .await;
+        assert_matches!(
+            attempt_outcome,
+            ConnectionAttemptOutcome::Attempted(Ok(ROUTE_2))
+        );
+    }
+
     async fn validate_expected_route(
         multi_route_manager: &MultiRouteConnectionManager,
         route1_healthy: bool,
@@ -8,12 +8,18 @@ use std::sync::Arc;
 
 use futures_util::future::BoxFuture;
 
+pub use doh::DnsOverHttpsResolver;
+
+mod doh;
+
 pub type LookupResult = Vec<IpAddr>;
 
 #[derive(displaydoc::Display, Debug, thiserror::Error)]
 pub enum Error {
     /// DNS lookup failed
     LookupFailed,
+    /// DNS-over-HTTPS resolver URL is invalid
+    InvalidDohUrl,
 }
 
 pub type ResolveFn = fn(&str) -> BoxFuture<Result<LookupResult, Error>>;
@@ -23,6 +29,8 @@ pub enum DnsResolver {
     Static,
     System,
     GenericAsync(Arc<ResolveFn>),
+    /// Resolves hosts with DNS-over-HTTPS queries, see [DnsOverHttpsResolver].
+    DnsOverHttps(DnsOverHttpsResolver),
 }
 
 impl DnsResolver {
@@ -42,6 +50,7 @@ impl DnsResolver {
                 _ => Err(Error::LookupFailed),
             },
             DnsResolver::GenericAsync(async_resolver) => async_resolver(host).await,
+            DnsResolver::DnsOverHttps(resolver) => resolver.lookup_ip(host).await,
             DnsResolver::System => format!("{}:443", host)
                 .to_socket_addrs()
                 .map(|addrs| {
@@ -0,0 +1,357 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use http::header::ACCEPT;
+use http::uri::Scheme;
+use http::{Method, Request, Uri};
+use serde::Deserialize;
+use tokio::time::Instant;
+
+use crate::infra::certs::RootCertificates;
+use crate::infra::dns::{Error, LookupResult};
+use crate::infra::http::{http2_channel, AggregatingHttpClient, Http2Channel};
+use crate::infra::ConnectionParams;
+
+/// Successful resolutions are cached for at most this long, even if the records
+/// have a longer TTL.
+const MAX_CACHE_TTL: Duration = Duration::from_secs(60);
+
+const DNS_JSON_CONTENT_TYPE: &str = "application/dns-json";
+
+const RECORD_TYPE_A: u16 = 1;
+const RECORD_TYPE_AAAA: u16 = 28;
+
+/// Resolves hosts by sending DNS queries over HTTPS.
+///
+/// Queries use the JSON API (`application/dns-json`) supported by the public DoH providers.
+/// The host of the DoH server itself is resolved with [DnsResolver::System](super::DnsResolver::System)
+/// and its certificate is validated against [RootCertificates::Native].
+#[derive(Clone, Debug)]
+pub struct DnsOverHttpsResolver {
+    connection_params: ConnectionParams,
+    path: String,
+    cache: Arc<Mutex<HashMap<String, CacheEntry>>>,
+}
+
+#[derive(Clone, Debug)]
+struct CacheEntry {
+    ips: LookupResult,
+    expires_at: Instant,
+}
+
+#[derive(Deserialize)]
+struct DnsJsonResponse {
+    #[serde(rename = "Status")]
+    status: u32,
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DnsJsonAnswer>,
+}
+
+#[derive(Deserialize)]
+struct DnsJsonAnswer {
+    #[serde(rename = "type")]
+    record_type: u16,
+    #[serde(rename = "TTL")]
+    ttl: u32,
+    data: String,
+}
+
+impl DnsOverHttpsResolver {
+    /// Creates a resolver that sends queries to the given `https://` URL,
+    /// e.g. `https://cloudflare-dns.com/dns-query`.
+    pub fn new(url: &str) -> Result<Self, Error> {
+        let uri = Uri::try_from(url).map_err(|_| Error::InvalidDohUrl)?;
+        if uri.scheme() != Some(&Scheme::HTTPS) {
+            return Err(Error::InvalidDohUrl);
+        }
+        let host = uri.host().ok_or(Error::InvalidDohUrl)?;
+        let connection_params = ConnectionParams::builder(host)
+            .port(uri.port_u16().unwrap_or(443))
+            .certs(RootCertificates::Native)
+            .build()
+            .map_err(|_| Error::InvalidDohUrl)?;
+        Ok(Self {
+            connection_params,
+            path: uri.path().to_string(),
+            cache: Default::default(),
+        })
+    }
+
+    /// Boxed, since connecting to the DoH server involves DNS resolution itself.
+    pub(super) fn lookup_ip<'a>(
+        &'a self,
+        host: &'a str,
+    ) -> BoxFuture<'a, Result<LookupResult, Error>> {
+        async move {
+            // The host is sent as a query parameter, so anything but a hostname is rejected
+            // rather than allowed to change the query.
+            if !is_valid_hostname(host) {
+                return Err(Error::LookupFailed);
+            }
+            if let Some(ips) = self.cached(host, Instant::now()) {
+                return Ok(ips);
+            }
+            let (ips, ttl) = self.query(host).await?;
+            self.cache.lock().expect("not poisoned").insert(
+                host.to_string(),
+                CacheEntry {
+                    ips: ips.clone(),
+                    expires_at: Instant::now() + ttl,
+                },
+            );
+            Ok(ips)
+        }
+        .boxed()
+    }
+
+    fn cached(&self, host: &str, now: Instant) -> Option<LookupResult> {
+        let mut cache = self.cache.lock().expect("not poisoned");
+        match cache.get(host) {
+            Some(entry) if entry.expires_at > now => Some(entry.ips.clone()),
+            Some(_) => {
+                cache.remove(host);
+                None
+            }
+            None => None,
+        }
+    }
+
+    async fn query(&self, host: &str) -> Result<(LookupResult, Duration), Error> {
+        let Http2Channel {
+            aggregating_client,
+            connection,
+            ..
+        } = http2_channel(&self.connection_params)
+            .await
+            .map_err(|_| Error::LookupFailed)?;
+        // The connection is closed once all clones of the client are dropped.
+        tokio::spawn(connection);
+
+        let query = |record_type: u16| {
+            let mut client = aggregating_client.clone();
+            let path_and_query = format!("{}?name={}&type={}", self.path, host, record_type);
+            async move {
+                let builder = Request::builder()
+                    .method(Method::GET)
+                    .header(ACCEPT, DNS_JSON_CONTENT_TYPE);
+                let (parts, body) = client
+                    .send_request_aggregate_response(&path_and_query, builder, Default::default())
+                    .await
+                    .map_err(|_| Error::LookupFailed)?;
+                if !parts.status.is_success() {
+                    return Err(Error::LookupFailed);
+                }
+                parse_dns_json_response(&body)
+            }
+        };
+        let (ipv4, ipv6) =
+            futures_util::future::join(query(RECORD_TYPE_A), query(RECORD_TYPE_AAAA)).await;
+
+        let mut ips = vec![];
+        let mut ttl = MAX_CACHE_TTL;
+        for (record_ips, record_ttl) in [ipv4, ipv6].into_iter().flatten() {
+            ips.extend(record_ips);
+            ttl = ttl.min(record_ttl);
+        }
+        if ips.is_empty() {
+            return Err(Error::LookupFailed);
+        }
+        Ok((ips, ttl))
+    }
+}
+
+/// Checks that `host` is made of dot-separated labels of ASCII letters, digits, and hyphens,
+/// optionally followed by a dot.
+fn is_valid_hostname(host: &str) -> bool {
+    let name = host.strip_suffix('.').unwrap_or(host);
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            (1..=63).contains(&label.len())
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        })
+}
+
+/// Extracts the IPv4 and IPv6 addresses from a DNS JSON response, along with
+/// the shortest TTL among them.
+fn parse_dns_json_response(body: &[u8]) -> Result<(LookupResult, Duration), Error> {
+    let response: DnsJsonResponse =
+        serde_json::from_slice(body).map_err(|_| Error::LookupFailed)?;
+    // Anything other than NOERROR means there are no usable answers.
+    if response.status != 0 {
+        return Err(Error::LookupFailed);
+    }
+    let mut ips = vec![];
+    let mut ttl = MAX_CACHE_TTL;
+    for answer in response.answer {
+        if answer.record_type != RECORD_TYPE_A && answer.record_type != RECORD_TYPE_AAAA {
+            // e.g. CNAME records that precede the addresses
+            continue;
+        }
+        let ip: IpAddr = answer.data.parse().map_err(|_| Error::LookupFailed)?;
+        ips.push(ip);
+        ttl = ttl.min(Duration::from_secs(answer.ttl.into()));
+    }
+    Ok((ips, ttl))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use std::time::Duration;
+
+    use assert_matches::assert_matches;
+    use tokio::time::Instant;
+
+    use crate::infra::dns::doh::{
+        is_valid_hostname, parse_dns_json_response, CacheEntry, DnsOverHttpsResolver, MAX_CACHE_TTL,
+    };
+    use crate::infra::dns::Error;
+
+    const RESOLVER_URL: &str = "https://dns.example/dns-query";
+
+    #[test]
+    fn resolver_url_must_be_https() {
+        let resolver = DnsOverHttpsResolver::new("https://dns.example:8443/dns-query").unwrap();
+        assert_eq!(&*resolver.connection_params.host, "dns.example");
+        assert_eq!(resolver.connection_params.port, 8443);
+        assert_eq!(resolver.path, "/dns-query");
+
+        assert_matches!(
+            DnsOverHttpsResolver::new("http://dns.example/dns-query"),
+            Err(Error::InvalidDohUrl)
+        );
+        assert_matches!(
+            DnsOverHttpsResolver::new("/dns-query"),
+            Err(Error::InvalidDohUrl)
+        );
+    }
+
+    #[test]
+    fn addresses_are_parsed() {
+        let body = br#"{
+            "Status": 0,
+            "Answer": [
+                {"name": "chat.signal.org", "type": 5, "TTL": 10, "data": "alias.signal.org."},
+                {"name": "alias.signal.org", "type": 1, "TTL": 300, "data": "76.223.92.165"},
+                {"name": "alias.signal.org", "type": 28, "TTL": 30, "data": "2600:9000::1"}
+            ]
+        }"#;
+        let (ips, ttl) = parse_dns_json_response(body).unwrap();
+        assert_eq!(
+            ips,
+            vec![
+                IpAddr::V4(Ipv4Addr::new(76, 223, 92, 165)),
+                IpAddr::V6(Ipv6Addr::new(0x2600, 0x9000, 0, 0, 0, 0, 0, 1)),
+            ]
+        );
+        assert_eq!(ttl, Duration::from_secs(30));
+    }
+
+    #[test]
+    fn ttl_is_capped() {
+        let body = br#"{"Status": 0, "Answer": [{"type": 1, "TTL": 86400, "data": "1.2.3.4"}]}"#;
+        let (_, ttl) = parse_dns_json_response(body).unwrap();
+        assert_eq!(ttl, MAX_CACHE_TTL);
+    }
+
+    #[test]
+    fn error_responses_are_rejected() {
+        assert_matches!(
+            parse_dns_json_response(br#"{"Status": 3}"#),
+            Err(Error::LookupFailed)
+        );
+        assert_matches!(
+            parse_dns_json_response(b"<html></html>"),
+            Err(Error::LookupFailed)
+        );
+        assert_matches!(
+            parse_dns_json_response(
+                br#"{"Status": 0, "Answer": [{"type": 1, "TTL": 1, "data": "not an ip"}]}"#
+            ),
+            Err(Error::LookupFailed)
+        );
+    }
+
+    #[test]
+    fn only_hostnames_are_looked_up() {
+        for host in [
+            "chat.signal.org",
+            "chat.signal.org.",
+            "xn--bcher-kva.example",
+            "a-1",
+        ] {
+            assert!(is_valid_hostname(host), "{host}");
+        }
+        for host in [
+            "",
+            ".",
+            "chat..signal.org",
+            "-chat.signal.org",
+            "chat.signal.org&type=16",
+            "chat.signal.org#",
+            "chat signal.org",
+            "bücher.example",
+            "a".repeat(64).as_str(),
+        ] {
+            assert!(!is_valid_hostname(host), "{host}");
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_host_is_not_queried() {
+        let resolver = DnsOverHttpsResolver::new(RESOLVER_URL).unwrap();
+        let host = "chat.signal.org&type=16";
+        // even a cached resolution isn't used for such a host
+        resolver.cache.lock().unwrap().insert(
+            host.to_string(),
+            CacheEntry {
+                ips: vec![IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))],
+                expires_at: Instant::now() + Duration::from_secs(10),
+            },
+        );
+        assert_matches!(resolver.lookup_ip(host).await, Err(Error::LookupFailed));
+    }
+
+    #[tokio::test]
+    async fn cached_resolution_is_used_until_it_expires() {
+        let resolver = DnsOverHttpsResolver::new(RESOLVER_URL).unwrap();
+        let ips = vec![IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))];
+        let now = Instant::now();
+        resolver.cache.lock().unwrap().insert(
+            "chat.signal.org".to_string(),
+            CacheEntry {
+                ips: ips.clone(),
+                expires_at: now + Duration::from_secs(10),
+            },
+        );
+
+        assert_eq!(resolver.cached("chat.signal.org", now), Some(ips.clone()));
+        assert_eq!(
+            resolver
+                .lookup_ip("chat.signal.org")
+                .await
+                .expect("served from cache"),
+            ips
+        );
+        assert_eq!(resolver.cached("cdsi.signal.org", now), None);
+        assert_eq!(
+            resolver.cached("chat.signal.org", now + Duration::from_secs(10)),
+            None
+        );
+        assert!(resolver.cache.lock().unwrap().is_empty());
+    }
+}
@@ -17,18 +17,36 @@ pub enum NetError {
     DnsError,
     /// Failed to establish TCP connection to any of the IPs
     TcpConnectionFailed,
+    /// Failed to establish connection through the proxy
+    ProxyFailure,
     /// SSL error
     SslError,
     /// Failed to establish SSL connection
     SslFailedHandshake,
+    /// Server rejected the client certificate
+    ClientAuthFailed,
+    /// Server certificate doesn't match any of the pinned public keys
+    PinningFailure,
     /// `Content-Length` header value is invalid
     ContentLengthHeaderInvalid,
     /// Content stream is not consistent with the `Content-Length` header
     ContentLengthHeaderDoesntMatchDataSize,
+    /// Failed to decompress response body
+    DecompressionFailed,
+    /// Decompressed response body exceeds the size limit
+    DecompressedBodyTooLarge,
+    /// Response body exceeds the size limit
+    ResponseTooLarge,
     /// Failed to upgrade HTTP connection to WebSockets
     WsFailedHandshake,
     /// Failed to upgrade to H2
     Http2FailedHandshake,
+    /// Connection was closed before the response was received
+    ConnectionInterrupted,
+    /// Server refused the stream without processing the request
+    StreamRefused,
+    /// Server reset the stream, but not the connection
+    StreamReset,
     /// Operation timed out
     Timeout,
     /// Failure
@@ -3,23 +3,99 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
+use crate::infra::clock::Clock;
 use crate::infra::errors::NetError;
+use crate::infra::reconnect::ServiceStatus;
 use crate::infra::tokio_executor::TokioExecutor;
 use crate::infra::tokio_io::TokioIo;
-use crate::infra::{connect_ssl, ConnectionParams};
+use crate::infra::tokio_timer::TokioTimer;
+use crate::infra::{connect_ssl, ConnectionInfo, ConnectionParams};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::future::Future;
+use std::io::{Read, Write};
+use std::pin::{pin, Pin};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
 use async_trait::async_trait;
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures_util::{stream, Stream};
+use http::header::{CONTENT_ENCODING, CONTENT_LENGTH, EXPECT};
 use http::request::Builder;
 use http::response::Parts;
-use http_body_util::{BodyExt, Full, Limited};
+use http::{HeaderMap, Request, Response};
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyExt, Full, Limited, StreamBody};
+use hyper::body::{Body, Frame, Incoming};
 use hyper::client::conn::http2;
+use pin_project_lite::pin_project;
+use rand::Rng;
 use tokio::net::TcpStream;
+use tokio::sync::{oneshot, Mutex, OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
 use tokio_boring::SslStream;
 
 const HTTP_ALPN_H2_ONLY: &[u8] = b"\x02h2";
 
+/// Body type for all requests sent over [Http2Connection].
+///
+/// It is either a fully buffered body, a [StreamingBody], or the body held back by
+/// [AggregatingHttp2Client::send_request_expect_continue], which fails with [BodyAborted]
+/// if it turns out not to be needed.
+pub(crate) type RequestBody = UnsyncBoxBody<Bytes, BodyAborted>;
+
+// Failing the body makes the HTTP/2 client reset the stream, which is the only way to stop
+// sending a request without ending it as if the body was complete.
+#[derive(Debug, displaydoc::Display, thiserror::Error)]
+/// request body abandoned before it was sent
+pub(crate) struct BodyAborted;
+
 pub(crate) type Http2Connection =
-    http2::Connection<TokioIo<SslStream<TcpStream>>, Full<Bytes>, TokioExecutor>;
+    http2::Connection<TokioIo<SslStream<TcpStream>>, RequestBody, TokioExecutor>;
+
+pin_project! {
+    /// Request body that is produced by a [Stream] of chunks.
+    ///
+    /// The stream is only polled when the HTTP/2 connection is ready to send more data,
+    /// i.e. when the flow control window allows it, so the body is never buffered as a whole.
+    pub struct StreamingBody<S> {
+        #[pin]
+        stream: S,
+    }
+}
+
+impl<S> StreamingBody<S> {
+    pub fn new(stream: S) -> Self {
+        Self { stream }
+    }
+}
+
+impl<S: Stream<Item = Bytes>> Body for StreamingBody<S> {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        self.project()
+            .stream
+            .poll_next(cx)
+            .map(|maybe_chunk| maybe_chunk.map(|chunk| Ok(Frame::data(chunk))))
+    }
+}
+
+/// Trailing headers of a response.
+///
+/// If the server sent any trailers, [AggregatingHttpClient] stores them in the
+/// extensions of the returned response [Parts].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ResponseTrailers(pub HeaderMap);
 
 #[async_trait]
 pub trait AggregatingHttpClient: Send + Sync + Clone {
@@ -29,32 +105,70 @@ pub trait AggregatingHttpClient: Send + Sync + Clone {
         request_builder: Builder,
         body: Bytes,
     ) -> Result<(Parts, Bytes), NetError>;
+
+    /// Same as [AggregatingHttpClient::send_request_aggregate_response], except that
+    /// the request body is read from the given `body_stream` as it's being sent.
+    async fn send_streaming_request_aggregate_response<S>(
+        &mut self,
+        path_and_query: &str,
+        request_builder: Builder,
+        body_stream: S,
+    ) -> Result<(Parts, Bytes), NetError>
+    where
+        S: Stream<Item = Bytes> + Send + 'static;
 }
 
 pub struct Http2Channel<T> {
     pub aggregating_client: T,
     pub connection: Http2Connection,
+    pub connection_info: ConnectionInfo,
 }
 
+/// Sends requests over an HTTP/2 connection and aggregates the response bodies.
+///
+/// All clones of a client share the same connection. If the [ConnectionParams] used
+/// to establish the connection have `max_concurrent_streams` set, the clones also share
+/// a limit on the number of requests in flight: once it's reached, new requests wait
+/// until one of the in-flight requests completes instead of failing. A request counts
+/// towards the limit until its response body is fully aggregated. Note that the server
+/// may announce a lower limit in its SETTINGS frame, in which case the requests
+/// are additionally queued by the HTTP/2 connection itself.
 #[derive(Clone)]
 pub struct AggregatingHttp2Client {
-    service: http2::SendRequest<Full<Bytes>>,
+    service: http2::SendRequest<RequestBody>,
     connection_params: ConnectionParams,
+    concurrency_limit: Option<Arc<Semaphore>>,
+    /// Responses with a longer body fail with [NetError::ResponseTooLarge].
+    pub(crate) max_response_size: usize,
+    /// If set, response bodies are aggregated into buffers taken from this pool.
+    pub(crate) buffer_pool: Option<Arc<BufferPool>>,
 }
 
 impl AggregatingHttp2Client {
     #[allow(dead_code)]
     pub fn new(
-        service: http2::SendRequest<Full<Bytes>>,
+        service: http2::SendRequest<RequestBody>,
         connection_params: ConnectionParams,
     ) -> Self {
+        let concurrency_limit = concurrency_limit(&connection_params);
         Self {
             service,
             connection_params,
+            concurrency_limit,
+            max_response_size: usize::MAX,
+            buffer_pool: None,
         }
     }
 }
 
+/// `max_concurrent_streams` of zero is treated as one, since otherwise no request
+/// could ever be sent.
+fn concurrency_limit(connection_params: &ConnectionParams) -> Option<Arc<Semaphore>> {
+    connection_params
+        .max_concurrent_streams
+        .map(|max_streams| Arc::new(Semaphore::new(max_streams.max(1) as usize)))
+}
+
 #[async_trait]
 impl AggregatingHttpClient for AggregatingHttp2Client {
     async fn send_request_aggregate_response(
@@ -63,45 +177,459 @@ impl AggregatingHttpClient for AggregatingHttp2Client {
         request_builder: Builder,
         body: Bytes,
     ) -> Result<(Parts, Bytes), NetError> {
+        self.send_body_aggregate_response(
+            path_and_query,
+            request_builder,
+            Full::new(body)
+                .map_err(|never| match never {})
+                .boxed_unsync(),
+        )
+        .await
+    }
+
+    async fn send_streaming_request_aggregate_response<S>(
+        &mut self,
+        path_and_query: &str,
+        request_builder: Builder,
+        body_stream: S,
+    ) -> Result<(Parts, Bytes), NetError>
+    where
+        S: Stream<Item = Bytes> + Send + 'static,
+    {
+        self.send_body_aggregate_response(
+            path_and_query,
+            request_builder,
+            StreamingBody::new(body_stream)
+                .map_err(|never| match never {})
+                .boxed_unsync(),
+        )
+        .await
+    }
+}
+
+impl AggregatingHttp2Client {
+    /// Same as [AggregatingHttpClient::send_request_aggregate_response], except that
+    /// the response body is passed to `on_chunk` frame by frame as it's received,
+    /// instead of being aggregated.
+    ///
+    /// Unlike the aggregated bodies, the body is read until the end of the stream,
+    /// regardless of the `Content-Length` header.
+    pub(crate) async fn send_request_with_chunks<F>(
+        &mut self,
+        path_and_query: &str,
+        request_builder: Builder,
+        body: Bytes,
+        on_chunk: F,
+    ) -> Result<Parts, NetError>
+    where
+        F: FnMut(&[u8]),
+    {
+        let request = self.build_request(
+            path_and_query,
+            request_builder,
+            Full::new(body)
+                .map_err(|never| match never {})
+                .boxed_unsync(),
+        )?;
+        let _permit = self.acquire_permit().await;
+        let (mut parts, body) = self.send(request).await?.into_parts();
+        let trailers = stream_body(body, self.max_response_size, on_chunk).await?;
+        if let Some(trailers) = trailers {
+            parts.extensions.insert(ResponseTrailers(trailers));
+        }
+
+        Ok(parts)
+    }
+
+    /// Same as [AggregatingHttpClient::send_request_aggregate_response], except that the request
+    /// is sent with `Expect: 100-continue`, and its body is held back for up to
+    /// `continue_timeout` after the headers are sent, as measured by `clock`.
+    ///
+    /// If the server responds within that time, e.g. rejects the request with a `4xx` status,
+    /// the body is never sent and that response is returned. Otherwise the body is sent once
+    /// the time is up, and `on_body_released` is called with it.
+    ///
+    /// The HTTP/2 client consumes the interim responses without passing them on, so a
+    /// `100 Continue` can't be observed and doesn't cut the wait short: unless the request is
+    /// answered early, its body is always delayed by the whole `continue_timeout`. Any other
+    /// `1xx` response is ignored as well, as RFC 9110 requires of clients that don't expect it.
+    pub(crate) async fn send_request_expect_continue<F>(
+        &mut self,
+        path_and_query: &str,
+        mut request_builder: Builder,
+        body: Bytes,
+        continue_timeout: Duration,
+        clock: &dyn Clock,
+        on_body_released: F,
+    ) -> Result<(Parts, Bytes), NetError>
+    where
+        F: FnOnce(&Bytes) + Send + 'static,
+    {
+        let has_expectation = request_builder
+            .headers_ref()
+            .map_or(false, |headers| headers.contains_key(EXPECT));
+        if !has_expectation {
+            request_builder = request_builder.header(EXPECT, "100-continue");
+        }
+        // Dropped without being used if the request is answered before the body is released,
+        // i.e. once this function returns, after the response is read in full.
+        let (release_body, body_released) = oneshot::channel::<()>();
+        let body_stream = stream::once(async move {
+            match body_released.await {
+                Ok(()) => {
+                    on_body_released(&body);
+                    Ok(Frame::data(body))
+                }
+                // The body is never sent, and the stream is reset instead of being left open
+                // until the server resets it or the connection is closed.
+                Err(_) => Err(BodyAborted),
+            }
+        });
+        let request = self.build_request(
+            path_and_query,
+            request_builder,
+            StreamBody::new(body_stream).boxed_unsync(),
+        )?;
+        let _permit = self.acquire_permit().await;
+        let response = {
+            let mut response_future = pin!(self.send(request));
+            tokio::select! {
+                response = &mut response_future => response,
+                () = clock.sleep(continue_timeout) => {
+                    let _ignore_answered = release_body.send(());
+                    response_future.await
+                }
+            }
+        };
+        let (mut parts, body) = response?.into_parts();
+        let (content, trailers) = aggregate_body(
+            &parts,
+            body,
+            self.max_response_size,
+            self.buffer_pool.as_deref(),
+        )
+        .await?;
+        if let Some(trailers) = trailers {
+            parts.extensions.insert(ResponseTrailers(trailers));
+        }
+
+        Ok((parts, content))
+    }
+
+    async fn send_body_aggregate_response(
+        &mut self,
+        path_and_query: &str,
+        request_builder: Builder,
+        body: RequestBody,
+    ) -> Result<(Parts, Bytes), NetError> {
+        let request = self.build_request(path_and_query, request_builder, body)?;
+        let _permit = self.acquire_permit().await;
+        let (mut parts, body) = self.send(request).await?.into_parts();
+        let (content, trailers) = aggregate_body(
+            &parts,
+            body,
+            self.max_response_size,
+            self.buffer_pool.as_deref(),
+        )
+        .await?;
+        if let Some(trailers) = trailers {
+            parts.extensions.insert(ResponseTrailers(trailers));
+        }
+
+        Ok((parts, content))
+    }
+
+    fn build_request(
+        &self,
+        path_and_query: &str,
+        request_builder: Builder,
+        body: RequestBody,
+    ) -> Result<Request<RequestBody>, NetError> {
         let uri = format!(
             "https://{}:{}{}",
             self.connection_params.sni, self.connection_params.port, path_and_query
         );
         let request_builder = request_builder.uri(uri);
-        let request_builder = self
-            .connection_params
-            .http_request_decorator
-            .decorate_request(request_builder);
-
-        let request = request_builder
-            .body(Full::new(body))
-            .map_err(|_| NetError::Failure)?;
-
-        let res = self
-            .service
-            .send_request(request)
-            .await
-            .map_err(|_| NetError::Failure)?;
-
-        let (parts, body) = res.into_parts();
-
-        let content = match parts.headers.get(hyper::header::CONTENT_LENGTH) {
-            Some(content_length_str) => {
-                let content_length = content_length_str
-                    .to_str()
-                    .map_err(|_| NetError::ContentLengthHeaderInvalid)?
-                    .parse::<usize>()
-                    .map_err(|_| NetError::ContentLengthHeaderInvalid)?;
-                Limited::new(body, content_length)
-                    .collect()
+        let request_builder = self.connection_params.decorate_request(request_builder);
+
+        request_builder.body(body).map_err(|_| NetError::Failure)
+    }
+
+    /// Waits until the request can be sent without exceeding the concurrency limit.
+    ///
+    /// The request counts towards the limit until the returned permit is dropped.
+    async fn acquire_permit(&self) -> Option<OwnedSemaphorePermit> {
+        match &self.concurrency_limit {
+            Some(semaphore) => Some(
+                semaphore
+                    .clone()
+                    .acquire_owned()
                     .await
-                    .map_err(|_| NetError::ContentLengthHeaderDoesntMatchDataSize)?
-                    .to_bytes()
+                    .expect("semaphore is never closed"),
+            ),
+            None => None,
+        }
+    }
+
+    async fn send(
+        &mut self,
+        request: Request<RequestBody>,
+    ) -> Result<Response<Incoming>, NetError> {
+        self.service.send_request(request).await.map_err(|e| {
+            if is_refused_stream(&e) {
+                NetError::StreamRefused
+            } else if is_stream_reset(&e) {
+                NetError::StreamReset
+            } else if e.is_canceled() || e.is_closed() {
+                NetError::ConnectionInterrupted
+            } else {
+                NetError::Failure
             }
-            None => Bytes::new(),
-        };
+        })
+    }
+}
 
-        Ok((parts, content))
+/// Whether the request failed because the server reset its stream with `REFUSED_STREAM`,
+/// which guarantees that the request was not processed (RFC 9113, section 8.7).
+fn is_refused_stream(error: &hyper::Error) -> bool {
+    std::error::Error::source(error)
+        .and_then(|source| source.downcast_ref::<h2::Error>())
+        .is_some_and(|h2_error| h2_error.reason() == Some(h2::Reason::REFUSED_STREAM))
+}
+
+/// Whether the request failed because the server reset its stream, rather than the whole
+/// connection, so that the connection can still be used for other requests.
+fn is_stream_reset(error: &hyper::Error) -> bool {
+    std::error::Error::source(error)
+        .and_then(|source| source.downcast_ref::<h2::Error>())
+        .is_some_and(|h2_error| h2_error.is_reset() && h2_error.is_remote())
+}
+
+/// Collects a response body of the length given by its `Content-Length` header,
+/// along with the trailers that follow it, if any.
+///
+/// Bodies longer than `max_size` are rejected before any of their data is read.
+/// If a `buffer_pool` is given, the body is collected with [BufferPool::aggregate].
+async fn aggregate_body<B>(
+    parts: &Parts,
+    body: B,
+    max_size: usize,
+    buffer_pool: Option<&BufferPool>,
+) -> Result<(Bytes, Option<HeaderMap>), NetError>
+where
+    B: Body<Data = Bytes>,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let content_length = match parts.headers.get(CONTENT_LENGTH) {
+        Some(content_length_str) => content_length_str
+            .to_str()
+            .map_err(|_| NetError::ContentLengthHeaderInvalid)?
+            .parse::<usize>()
+            .map_err(|_| NetError::ContentLengthHeaderInvalid)?,
+        None => return Ok((Bytes::new(), None)),
+    };
+    if content_length > max_size {
+        return Err(NetError::ResponseTooLarge);
+    }
+    if let Some(buffer_pool) = buffer_pool {
+        return buffer_pool.aggregate(body, content_length).await;
+    }
+    let collected = Limited::new(body, content_length)
+        .collect()
+        .await
+        .map_err(|_| NetError::ContentLengthHeaderDoesntMatchDataSize)?;
+    let trailers = collected.trailers().cloned();
+    Ok((collected.to_bytes(), trailers))
+}
+
+/// Buffers that are reused to aggregate response bodies, so that a client sending many requests
+/// doesn't allocate and grow a new buffer for each response.
+///
+/// The aggregated bodies are copied out of the pooled buffers, so they stay unchanged
+/// after the buffers are reused. Bodies that arrive in a single data frame don't need
+/// a buffer, and are returned without copying.
+#[derive(Debug)]
+pub struct BufferPool {
+    buffers: std::sync::Mutex<Vec<BytesMut>>,
+    max_pooled_buffers: usize,
+    max_buffer_capacity: usize,
+}
+
+impl BufferPool {
+    /// Creates a pool that keeps up to `max_pooled_buffers` buffers around between requests.
+    ///
+    /// Buffers that grew beyond `max_buffer_capacity` while aggregating a large body are
+    /// dropped rather than kept in the pool.
+    pub fn new(max_pooled_buffers: usize, max_buffer_capacity: usize) -> Self {
+        Self {
+            buffers: Default::default(),
+            max_pooled_buffers,
+            max_buffer_capacity,
+        }
+    }
+
+    /// Collects the `content_length` bytes of `body` into a pooled buffer, along with
+    /// the trailers that follow them, if any.
+    ///
+    /// The returned body is an independent copy of the buffer contents, unless the body
+    /// arrived in a single data frame, which is returned as it is.
+    pub async fn aggregate<B>(
+        &self,
+        body: B,
+        content_length: usize,
+    ) -> Result<(Bytes, Option<HeaderMap>), NetError>
+    where
+        B: Body<Data = Bytes>,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let mut buffer = self.take();
+        // If this future is dropped midway, the buffer is dropped rather than returned.
+        let collected = collect_into(body, content_length, &mut buffer).await;
+        let collected = collected.map(|(single_frame, trailers)| {
+            let content = single_frame.unwrap_or_else(|| Bytes::copy_from_slice(&buffer));
+            (content, trailers)
+        });
+        self.put_back(buffer);
+        collected
+    }
+
+    /// The number of buffers currently available for reuse.
+    pub fn available(&self) -> usize {
+        self.buffers.lock().expect("not poisoned").len()
+    }
+
+    fn take(&self) -> BytesMut {
+        self.buffers
+            .lock()
+            .expect("not poisoned")
+            .pop()
+            .unwrap_or_default()
+    }
+
+    fn put_back(&self, mut buffer: BytesMut) {
+        if buffer.capacity() > self.max_buffer_capacity {
+            return;
+        }
+        buffer.clear();
+        let mut buffers = self.buffers.lock().expect("not poisoned");
+        if buffers.len() < self.max_pooled_buffers {
+            buffers.push(buffer);
+        }
+    }
+}
+
+/// Collects the data frames of `body`, failing if there are more than `content_length` bytes
+/// of them, and returns the trailers that follow them, if any.
+///
+/// The data is returned as it is if it all arrived in one frame. Otherwise it's appended
+/// to `buffer`, and `None` is returned in its place.
+async fn collect_into<B>(
+    body: B,
+    content_length: usize,
+    buffer: &mut BytesMut,
+) -> Result<(Option<Bytes>, Option<HeaderMap>), NetError>
+where
+    B: Body<Data = Bytes>,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let mut body = pin!(Limited::new(body, content_length));
+    let mut single_frame = None;
+    let mut trailers = None;
+    while let Some(frame) = body.frame().await {
+        let frame = frame.map_err(|_| NetError::ContentLengthHeaderDoesntMatchDataSize)?;
+        match frame.into_data() {
+            Ok(data) => match single_frame.take() {
+                None if buffer.is_empty() => single_frame = Some(data),
+                None => buffer.extend_from_slice(&data),
+                Some(first) => {
+                    buffer.reserve(content_length);
+                    buffer.extend_from_slice(&first);
+                    buffer.extend_from_slice(&data);
+                }
+            },
+            Err(frame) => {
+                if let Ok(frame_trailers) = frame.into_trailers() {
+                    trailers = Some(frame_trailers);
+                }
+            }
+        }
+    }
+    Ok((single_frame, trailers))
+}
+
+/// Passes the data frames of a response `body` to `on_chunk` as they are received,
+/// returning the trailers that follow them, if any.
+///
+/// Fails with [NetError::ResponseTooLarge] as soon as the body exceeds `max_size`;
+/// the data received up to that point has already been passed to `on_chunk` by then.
+async fn stream_body<B, F>(
+    body: B,
+    max_size: usize,
+    mut on_chunk: F,
+) -> Result<Option<HeaderMap>, NetError>
+where
+    B: Body<Data = Bytes>,
+    F: FnMut(&[u8]),
+{
+    let mut body = pin!(body);
+    let mut received = 0usize;
+    let mut trailers = None;
+    while let Some(frame) = body.frame().await {
+        let frame = frame.map_err(|_| NetError::ConnectionInterrupted)?;
+        match frame.into_data() {
+            Ok(data) => {
+                received = received.saturating_add(data.len());
+                if received > max_size {
+                    return Err(NetError::ResponseTooLarge);
+                }
+                on_chunk(&data);
+            }
+            Err(frame) => {
+                if let Ok(frame_trailers) = frame.into_trailers() {
+                    trailers = Some(frame_trailers);
+                }
+            }
+        }
+    }
+    Ok(trailers)
+}
+
+/// Picks the keepalive interval of a connection at random, within `jitter_percent` of `interval`
+/// either way.
+///
+/// The interval is picked once per connection, as hyper sends the PINGs at a fixed interval.
+///
+/// [ConnectionParamsBuilder](crate::infra::ConnectionParamsBuilder) rejects a jitter of 100% or
+/// more, but the field can be set directly, so it's clamped to 99% to keep the interval positive.
+fn jittered_keepalive_interval(
+    interval: Duration,
+    jitter_percent: u8,
+    rng: &mut impl Rng,
+) -> Duration {
+    let max_jitter = interval * u32::from(jitter_percent.min(99)) / 100;
+    interval - max_jitter + rng.gen_range(Duration::ZERO..=max_jitter * 2)
+}
+
+/// Makes the connections built by `builder` send PINGs as configured by `connection_params`.
+pub(crate) fn configure_keepalive(
+    builder: &mut http2::Builder<TokioExecutor>,
+    connection_params: &ConnectionParams,
+) {
+    if let Some(keepalive_interval) = connection_params.http2_keepalive_interval {
+        let keepalive_interval = jittered_keepalive_interval(
+            keepalive_interval,
+            connection_params.http2_keepalive_jitter_percent,
+            &mut rand::thread_rng(),
+        );
+        // hyper schedules the PINGs with its own timer, which can't be backed by a `Clock`,
+        // so tests of the keepalive have to pause the tokio time instead of using a `MockClock`.
+        builder
+            .timer(TokioTimer::new())
+            .keep_alive_interval(keepalive_interval)
+            .keep_alive_while_idle(true);
+        if let Some(keepalive_timeout) = connection_params.http2_keepalive_timeout {
+            builder.keep_alive_timeout(keepalive_timeout);
+        }
     }
 }
 
@@ -109,20 +637,994 @@ pub(crate) async fn http2_channel(
     connection_params: &ConnectionParams,
 ) -> Result<Http2Channel<AggregatingHttp2Client>, NetError> {
     let ssl_stream = connect_ssl(connection_params, HTTP_ALPN_H2_ONLY).await?;
+    let connection_info = ConnectionInfo::from_ssl(ssl_stream.ssl())?;
     let io = TokioIo::new(ssl_stream);
-    let (sender, connection) = http2::handshake::<_, _, Full<Bytes>>(TokioExecutor::new(), io)
+    let mut builder = http2::Builder::new(TokioExecutor::new());
+    configure_keepalive(&mut builder, connection_params);
+    let (sender, connection) = builder
+        .handshake::<_, RequestBody>(io)
         .await
         .map_err(|_| NetError::Http2FailedHandshake)?;
 
-    let clone = connection_params.clone();
     Ok(Http2Channel {
         aggregating_client: AggregatingHttp2Client {
             service: sender,
-            connection_params: ConnectionParams {
-                sni: connection_params.host.clone(),
-                ..clone
-            },
+            connection_params: client_connection_params(connection_params),
+            concurrency_limit: concurrency_limit(connection_params),
+            max_response_size: usize::MAX,
+            buffer_pool: None,
         },
         connection,
+        connection_info,
     })
 }
+
+/// Content codings supported for the request and response bodies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentEncoding {
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+impl ContentEncoding {
+    /// Value of the `Accept-Encoding` header that lists all the supported codings.
+    pub(crate) const ACCEPT_ALL: &'static str = "gzip, br, zstd";
+
+    /// The name of the coding used in the `Content-Encoding` header.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Brotli => "br",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Self::Brotli => {
+                let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+                encoder.write_all(data)?;
+                Ok(encoder.into_inner())
+            }
+            Self::Zstd => zstd::stream::encode_all(data, 0),
+        }
+    }
+}
+
+/// Compression of the request bodies.
+///
+/// Bodies shorter than `min_size` bytes are sent as they are, and so are the ones
+/// that don't get any smaller when compressed.
+#[derive(Clone, Debug)]
+pub struct RequestCompression {
+    pub encoding: ContentEncoding,
+    pub min_size: usize,
+}
+
+impl RequestCompression {
+    /// Returns the compressed `body`, or `None` if it should be sent uncompressed.
+    pub(crate) fn compress(&self, body: &[u8]) -> Option<Bytes> {
+        if body.len() < self.min_size {
+            return None;
+        }
+        match self.encoding.compress(body) {
+            Ok(compressed) if compressed.len() < body.len() => Some(compressed.into()),
+            _ => None,
+        }
+    }
+}
+
+/// Decodes the response `body` according to its `Content-Encoding` header.
+///
+/// `gzip`, `br`, and `zstd` encodings are supported; the bodies with any other (or no) encoding
+/// are returned untouched. Once a body is decoded, the `Content-Encoding` and `Content-Length`
+/// headers are removed from the `parts`, since they no longer describe the body.
+///
+/// Decoding stops with [NetError::DecompressedBodyTooLarge] as soon as the decoded body
+/// exceeds `max_decompressed_size` bytes, so a small malicious body can't exhaust the memory.
+pub(crate) fn decompress_body(
+    parts: &mut Parts,
+    body: Bytes,
+    max_decompressed_size: usize,
+) -> Result<Bytes, NetError> {
+    let encoding = match parts.headers.get(CONTENT_ENCODING) {
+        Some(encoding) => encoding.as_bytes(),
+        None => return Ok(body),
+    };
+    let decoder: Box<dyn Read + '_> = if encoding.eq_ignore_ascii_case(b"gzip") {
+        Box::new(GzDecoder::new(&body[..]))
+    } else if encoding.eq_ignore_ascii_case(b"br") {
+        Box::new(brotli::Decompressor::new(&body[..], 4096))
+    } else if encoding.eq_ignore_ascii_case(b"zstd") {
+        Box::new(
+            zstd::stream::read::Decoder::new(&body[..])
+                .map_err(|_| NetError::DecompressionFailed)?,
+        )
+    } else {
+        return Ok(body);
+    };
+
+    let mut decompressed = Vec::new();
+    decoder
+        .take(max_decompressed_size as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(|_| NetError::DecompressionFailed)?;
+    if decompressed.len() > max_decompressed_size {
+        return Err(NetError::DecompressedBodyTooLarge);
+    }
+
+    parts.headers.remove(CONTENT_ENCODING);
+    parts.headers.remove(CONTENT_LENGTH);
+    Ok(decompressed.into())
+}
+
+/// Parameters used by the [AggregatingHttp2Client] when building requests
+/// for a connection established with the given `connection_params`.
+fn client_connection_params(connection_params: &ConnectionParams) -> ConnectionParams {
+    let clone = connection_params.clone();
+    ConnectionParams {
+        sni: connection_params.host.clone(),
+        ..clone
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Http2ConnectionPoolConfig {
+    /// Connections that were not handed out for this long are not reused.
+    pub max_idle: Duration,
+    /// Connections that were established this long ago are not reused.
+    pub max_lifetime: Duration,
+}
+
+impl Default for Http2ConnectionPoolConfig {
+    fn default() -> Self {
+        Self {
+            max_idle: Duration::from_secs(60),
+            max_lifetime: Duration::from_secs(600),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct PoolKey {
+    sni: Arc<str>,
+    host: Arc<str>,
+    port: u16,
+}
+
+impl From<&ConnectionParams> for PoolKey {
+    fn from(value: &ConnectionParams) -> Self {
+        Self {
+            sni: value.sni.clone(),
+            host: value.host.clone(),
+            port: value.port,
+        }
+    }
+}
+
+struct PooledConnection {
+    service: http2::SendRequest<RequestBody>,
+    concurrency_limit: Option<Arc<Semaphore>>,
+    service_status: ServiceStatus<NetError>,
+    connection_info: ConnectionInfo,
+    created_at: Instant,
+    last_used: Instant,
+}
+
+impl PooledConnection {
+    fn is_reusable(&self, now: Instant, config: &Http2ConnectionPoolConfig) -> bool {
+        !self.service_status.is_stopped()
+            && !self.service.is_closed()
+            && now.duration_since(self.created_at) < config.max_lifetime
+            && now.duration_since(self.last_used) < config.max_idle
+    }
+
+    fn channel(&self, connection_params: &ConnectionParams) -> PooledHttp2Channel {
+        PooledHttp2Channel {
+            aggregating_client: AggregatingHttp2Client {
+                service: self.service.clone(),
+                connection_params: client_connection_params(connection_params),
+                concurrency_limit: self.concurrency_limit.clone(),
+                max_response_size: usize::MAX,
+                buffer_pool: None,
+            },
+            service_status: self.service_status.clone(),
+            connection_info: self.connection_info.clone(),
+        }
+    }
+}
+
+/// A connection handed out by an [Http2ConnectionPool].
+pub struct PooledHttp2Channel {
+    pub aggregating_client: AggregatingHttp2Client,
+    /// Stopped once the connection is closed.
+    ///
+    /// Unlike [Http2Channel::connection], there is nothing to drive: the connection is driven
+    /// by the pool, since it's shared with the other clients.
+    pub service_status: ServiceStatus<NetError>,
+    pub connection_info: ConnectionInfo,
+}
+
+type PoolSlot = Arc<Mutex<Option<PooledConnection>>>;
+
+/// A pool of HTTP/2 connections that allows multiple [AggregatingHttp2Client]s
+/// to share a connection to the same endpoint.
+///
+/// Connections are keyed by the `sni`, `host`, and `port` of the [ConnectionParams].
+/// The rest of the parameters are only used when a new connection is being established,
+/// except for the `http_request_decorator` and `auth`, which are always taken from the parameters
+/// passed to [Http2ConnectionPool::get_or_connect].
+///
+/// A connection is evicted once it's closed, or when it reaches the configured idle time
+/// or lifetime. Eviction doesn't affect requests that are already in flight: the connection
+/// is closed after all clients that share it are dropped.
+#[derive(Clone, Default)]
+pub struct Http2ConnectionPool {
+    config: Http2ConnectionPoolConfig,
+    slots: Arc<std::sync::Mutex<HashMap<PoolKey, PoolSlot>>>,
+}
+
+impl std::fmt::Debug for Http2ConnectionPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Http2ConnectionPool")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Http2ConnectionPool {
+    pub fn new(config: Http2ConnectionPoolConfig) -> Self {
+        Self {
+            config,
+            slots: Default::default(),
+        }
+    }
+
+    /// Returns a client for a pooled connection to the endpoint described by `connection_params`,
+    /// establishing a new connection if there is no reusable one.
+    ///
+    /// While a new connection is being established, the callers asking for the same endpoint
+    /// wait for it, so that they end up sharing it. The callers asking for other endpoints
+    /// don't wait.
+    pub async fn get_or_connect(
+        &self,
+        connection_params: &ConnectionParams,
+    ) -> Result<AggregatingHttp2Client, NetError> {
+        Ok(self
+            .get_or_connect_channel(connection_params)
+            .await?
+            .aggregating_client)
+    }
+
+    /// Same as [Http2ConnectionPool::get_or_connect], but also returns what's needed to keep
+    /// track of the connection.
+    pub async fn get_or_connect_channel(
+        &self,
+        connection_params: &ConnectionParams,
+    ) -> Result<PooledHttp2Channel, NetError> {
+        self.get_or_connect_by(connection_params, || async {
+            let Http2Channel {
+                aggregating_client,
+                connection,
+                connection_info,
+            } = http2_channel(connection_params).await?;
+            Ok((aggregating_client, connection, connection_info))
+        })
+        .await
+    }
+
+    /// Returns a pooled connection to the endpoint described by `connection_params`, calling
+    /// `connect` to establish a new one if there is no reusable one.
+    async fn get_or_connect_by<F, Fut, C>(
+        &self,
+        connection_params: &ConnectionParams,
+        connect: F,
+    ) -> Result<PooledHttp2Channel, NetError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<(AggregatingHttp2Client, C, ConnectionInfo), NetError>>,
+        C: Future<Output = Result<(), hyper::Error>> + Send + 'static,
+    {
+        let slot = self.slot(connection_params);
+        let mut slot = slot.lock().await;
+
+        let now = Instant::now();
+        if let Some(pooled) = slot
+            .as_mut()
+            .filter(|pooled| pooled.is_reusable(now, &self.config))
+        {
+            pooled.last_used = now;
+            return Ok(pooled.channel(connection_params));
+        }
+
+        let (aggregating_client, connection, connection_info) = connect().await?;
+        let service_status = ServiceStatus::new();
+        let listener_status = service_status.clone();
+        tokio::spawn(async move {
+            let _ignore_result = connection.await;
+            listener_status.stop_service();
+        });
+
+        let now = Instant::now();
+        let pooled = slot.insert(PooledConnection {
+            service: aggregating_client.service,
+            concurrency_limit: aggregating_client.concurrency_limit,
+            service_status,
+            connection_info,
+            created_at: now,
+            last_used: now,
+        });
+        Ok(pooled.channel(connection_params))
+    }
+
+    /// Returns the slot for the connection to the endpoint described by `connection_params`,
+    /// after dropping the slots of the connections that can't be reused anymore.
+    ///
+    /// The slots are locked while their connections are being established, and kept until then.
+    fn slot(&self, connection_params: &ConnectionParams) -> PoolSlot {
+        let now = Instant::now();
+        let mut slots = self.slots.lock().expect("not poisoned");
+        slots.retain(|_, slot| {
+            slot.try_lock().map_or(true, |pooled| {
+                pooled
+                    .as_ref()
+                    .is_some_and(|pooled| pooled.is_reusable(now, &self.config))
+            })
+        });
+        slots
+            .entry(PoolKey::from(connection_params))
+            .or_default()
+            .clone()
+    }
+
+    /// Returns the number of connections in the pool that can still be reused.
+    pub fn reusable_connections(&self) -> usize {
+        let now = Instant::now();
+        self.slots
+            .lock()
+            .expect("not poisoned")
+            .values()
+            .filter(|slot| {
+                slot.try_lock().is_ok_and(|pooled| {
+                    pooled
+                        .as_ref()
+                        .is_some_and(|pooled| pooled.is_reusable(now, &self.config))
+                })
+            })
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+    use std::future::Future;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use assert_matches::assert_matches;
+    use bytes::Bytes;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use futures_util::stream;
+    use http::header::{CONTENT_ENCODING, CONTENT_LENGTH};
+    use http::response::Parts;
+    use http::{HeaderMap, HeaderValue};
+    use http_body_util::{BodyExt, Full, StreamBody};
+    use hyper::body::Frame;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use tokio_util::sync::CancellationToken;
+
+    use crate::infra::certs::RootCertificates;
+    use crate::infra::dns::DnsResolver;
+    use crate::infra::errors::NetError;
+    use crate::infra::http::{
+        aggregate_body, concurrency_limit, decompress_body, jittered_keepalive_interval,
+        stream_body, AggregatingHttp2Client, AggregatingHttpClient, BufferPool, ContentEncoding,
+        Http2ConnectionPool, Http2ConnectionPoolConfig, RequestCompression, ResponseTrailers,
+        StreamingBody,
+    };
+    use crate::infra::{in_memory, ConnectionInfo, ConnectionParams, HttpRequestDecoratorSeq};
+
+    const MAX_DECOMPRESSED_SIZE: usize = 1024;
+    const MAX_RESPONSE_SIZE: usize = 1024;
+
+    fn response_parts(content_encoding: Option<&str>, content_length: usize) -> Parts {
+        let mut builder = http::Response::builder()
+            .status(200)
+            .header(CONTENT_LENGTH, content_length);
+        if let Some(encoding) = content_encoding {
+            builder = builder.header(CONTENT_ENCODING, encoding);
+        }
+        builder.body(()).unwrap().into_parts().0
+    }
+
+    fn gzip_compress(data: &[u8]) -> Bytes {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap().into()
+    }
+
+    fn brotli_compress(data: &[u8]) -> Bytes {
+        let mut compressed = Vec::new();
+        {
+            let mut encoder = brotli::CompressorWriter::new(&mut compressed, 4096, 5, 22);
+            encoder.write_all(data).unwrap();
+        }
+        compressed.into()
+    }
+
+    #[test]
+    fn gzip_body_is_decompressed() {
+        let body = gzip_compress(b"hello");
+        let mut parts = response_parts(Some("gzip"), body.len());
+        let decompressed = decompress_body(&mut parts, body, MAX_DECOMPRESSED_SIZE).unwrap();
+        assert_eq!(decompressed, Bytes::from_static(b"hello"));
+        assert!(parts.headers.get(CONTENT_ENCODING).is_none());
+        assert!(parts.headers.get(CONTENT_LENGTH).is_none());
+    }
+
+    #[test]
+    fn brotli_body_is_decompressed() {
+        let body = brotli_compress(b"hello");
+        let mut parts = response_parts(Some("br"), body.len());
+        let decompressed = decompress_body(&mut parts, body, MAX_DECOMPRESSED_SIZE).unwrap();
+        assert_eq!(decompressed, Bytes::from_static(b"hello"));
+        assert!(parts.headers.get(CONTENT_ENCODING).is_none());
+    }
+
+    #[test]
+    fn zstd_body_is_decompressed() {
+        let body: Bytes = zstd::stream::encode_all(&b"hello"[..], 0).unwrap().into();
+        let mut parts = response_parts(Some("zstd"), body.len());
+        let decompressed = decompress_body(&mut parts, body, MAX_DECOMPRESSED_SIZE).unwrap();
+        assert_eq!(decompressed, Bytes::from_static(b"hello"));
+        assert!(parts.headers.get(CONTENT_ENCODING).is_none());
+    }
+
+    #[test]
+    fn compressed_request_bodies_round_trip() {
+        let body = [b'a'; 1024];
+        for encoding in [
+            ContentEncoding::Gzip,
+            ContentEncoding::Brotli,
+            ContentEncoding::Zstd,
+        ] {
+            let compression = RequestCompression {
+                encoding,
+                min_size: 100,
+            };
+            let compressed = compression.compress(&body).expect("compressed");
+            assert!(compressed.len() < body.len());
+            let mut parts = response_parts(Some(encoding.name()), compressed.len());
+            let decompressed =
+                decompress_body(&mut parts, compressed, MAX_DECOMPRESSED_SIZE).unwrap();
+            assert_eq!(decompressed, &body[..]);
+
+            assert_eq!(compression.compress(&body[..99]), None);
+        }
+    }
+
+    #[test]
+    fn incompressible_request_body_is_not_compressed() {
+        let compression = RequestCompression {
+            encoding: ContentEncoding::Gzip,
+            min_size: 0,
+        };
+        assert_eq!(compression.compress(b"x"), None);
+    }
+
+    #[test]
+    fn uncompressed_body_is_untouched() {
+        for encoding in [None, Some("identity")] {
+            let body = Bytes::from_static(b"hello");
+            let mut parts = response_parts(encoding, body.len());
+            let decompressed =
+                decompress_body(&mut parts, body.clone(), MAX_DECOMPRESSED_SIZE).unwrap();
+            assert_eq!(decompressed, body);
+            assert!(parts.headers.get(CONTENT_LENGTH).is_some());
+        }
+    }
+
+    #[test]
+    fn decompression_bomb_is_rejected() {
+        let body = gzip_compress(&[0; 100 * MAX_DECOMPRESSED_SIZE]);
+        assert!(body.len() < MAX_DECOMPRESSED_SIZE);
+        let mut parts = response_parts(Some("gzip"), body.len());
+        assert_matches!(
+            decompress_body(&mut parts, body, MAX_DECOMPRESSED_SIZE),
+            Err(NetError::DecompressedBodyTooLarge)
+        );
+    }
+
+    #[test]
+    fn corrupted_body_fails_to_decompress() {
+        let body = Bytes::from_static(b"not really gzip");
+        let mut parts = response_parts(Some("gzip"), body.len());
+        assert_matches!(
+            decompress_body(&mut parts, body, MAX_DECOMPRESSED_SIZE),
+            Err(NetError::DecompressionFailed)
+        );
+    }
+
+    #[tokio::test]
+    async fn streaming_body_yields_all_chunks() {
+        let chunks = vec![Bytes::from_static(b"abc"), Bytes::from_static(b"def")];
+        let body = StreamingBody::new(stream::iter(chunks));
+        let collected = body.collect().await.expect("infallible").to_bytes();
+        assert_eq!(collected, Bytes::from_static(b"abcdef"));
+    }
+
+    #[test]
+    fn concurrency_limit_follows_max_concurrent_streams() {
+        let mut connection_params = ConnectionParams::new(
+            "chat.signal.org",
+            "chat.signal.org",
+            443,
+            HttpRequestDecoratorSeq::default(),
+            RootCertificates::Signal,
+            DnsResolver::System,
+        );
+        assert!(concurrency_limit(&connection_params).is_none());
+
+        connection_params.max_concurrent_streams = Some(5);
+        let limit = concurrency_limit(&connection_params).expect("limit is set");
+        assert_eq!(limit.available_permits(), 5);
+
+        connection_params.max_concurrent_streams = Some(0);
+        let limit = concurrency_limit(&connection_params).expect("limit is set");
+        assert_eq!(limit.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn response_body_at_the_size_limit_is_aggregated() {
+        let body = Bytes::from(vec![1; MAX_RESPONSE_SIZE]);
+        let parts = response_parts(None, body.len());
+        let (aggregated, trailers) =
+            aggregate_body(&parts, Full::new(body.clone()), MAX_RESPONSE_SIZE, None)
+                .await
+                .unwrap();
+        assert_eq!(aggregated, body);
+        assert_eq!(trailers, None);
+    }
+
+    #[tokio::test]
+    async fn response_body_over_the_size_limit_is_rejected() {
+        let body = Bytes::from(vec![1; MAX_RESPONSE_SIZE + 1]);
+        let parts = response_parts(None, body.len());
+        assert_matches!(
+            aggregate_body(&parts, Full::new(body), MAX_RESPONSE_SIZE, None).await,
+            Err(NetError::ResponseTooLarge)
+        );
+    }
+
+    #[tokio::test]
+    async fn response_trailers_are_aggregated() {
+        let mut expected_trailers = HeaderMap::new();
+        expected_trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        let frames = vec![
+            Ok::<_, Infallible>(Frame::data(Bytes::from_static(b"body"))),
+            Ok(Frame::trailers(expected_trailers.clone())),
+        ];
+        let parts = response_parts(None, 4);
+        let (aggregated, trailers) = aggregate_body(
+            &parts,
+            StreamBody::new(stream::iter(frames)),
+            MAX_RESPONSE_SIZE,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(aggregated, Bytes::from_static(b"body"));
+        assert_eq!(trailers, Some(expected_trailers));
+    }
+
+    fn chunked_body(
+        chunks: &[&'static [u8]],
+    ) -> StreamBody<impl futures_util::Stream<Item = Result<Frame<Bytes>, Infallible>>> {
+        let frames: Vec<_> = chunks
+            .iter()
+            .map(|chunk| Ok(Frame::data(Bytes::from_static(chunk))))
+            .collect();
+        StreamBody::new(stream::iter(frames))
+    }
+
+    #[tokio::test]
+    async fn pooled_buffers_are_reused_and_bodies_stay_independent() {
+        let pool = BufferPool::new(1, 1024);
+
+        let parts = response_parts(None, 8);
+        let (first, _) = aggregate_body(
+            &parts,
+            chunked_body(&[b"firs", b"t..."]),
+            MAX_RESPONSE_SIZE,
+            Some(&pool),
+        )
+        .await
+        .unwrap();
+        assert_eq!(pool.available(), 1);
+
+        let (second, _) = aggregate_body(
+            &parts,
+            chunked_body(&[b"seco", b"nd.."]),
+            MAX_RESPONSE_SIZE,
+            Some(&pool),
+        )
+        .await
+        .unwrap();
+        assert_eq!(pool.available(), 1);
+        assert_eq!(first, Bytes::from_static(b"first..."));
+        assert_eq!(second, Bytes::from_static(b"second.."));
+
+        // a body that doesn't match its Content-Length still gives the buffer back
+        assert_matches!(
+            aggregate_body(
+                &response_parts(None, 4),
+                chunked_body(&[b"too long"]),
+                MAX_RESPONSE_SIZE,
+                Some(&pool),
+            )
+            .await,
+            Err(NetError::ContentLengthHeaderDoesntMatchDataSize)
+        );
+        assert_eq!(pool.available(), 1);
+    }
+
+    #[tokio::test]
+    async fn oversized_buffers_are_not_pooled() {
+        let pool = BufferPool::new(4, 16);
+        const CHUNK: &[u8] = &[1; 32];
+        let (aggregated, _) = aggregate_body(
+            &response_parts(None, 2 * CHUNK.len()),
+            chunked_body(&[CHUNK, CHUNK]),
+            MAX_RESPONSE_SIZE,
+            Some(&pool),
+        )
+        .await
+        .unwrap();
+        assert_eq!(aggregated, Bytes::from(vec![1; 64]));
+        assert_eq!(pool.available(), 0);
+    }
+
+    #[tokio::test]
+    async fn single_frame_bodies_are_not_copied() {
+        let pool = BufferPool::new(1, 1024);
+        let body = Bytes::from(vec![1; 64]);
+        let (aggregated, _) = aggregate_body(
+            &response_parts(None, body.len()),
+            Full::new(body.clone()),
+            MAX_RESPONSE_SIZE,
+            Some(&pool),
+        )
+        .await
+        .unwrap();
+        assert_eq!(aggregated, body);
+        assert_eq!(aggregated.as_ptr(), body.as_ptr());
+        assert_eq!(pool.available(), 1);
+    }
+
+    #[tokio::test]
+    async fn http2_client_captures_response_trailers() {
+        let (mut client, connection) = in_memory::connect(|io| {
+            in_memory::serve_fn(io, |_request| async {
+                let mut trailers = HeaderMap::new();
+                trailers.insert("grpc-status", HeaderValue::from_static("0"));
+                let frames = vec![
+                    Ok::<_, Infallible>(Frame::data(Bytes::from_static(b"body"))),
+                    Ok(Frame::trailers(trailers)),
+                ];
+                http::Response::builder()
+                    .status(200)
+                    .header(CONTENT_LENGTH, 4)
+                    .body(StreamBody::new(stream::iter(frames)))
+            })
+        })
+        .await;
+        tokio::spawn(connection);
+
+        let (parts, body) = client
+            .send_request_aggregate_response(
+                "/v1/trailers",
+                http::Request::builder().method(http::Method::GET),
+                Bytes::new(),
+            )
+            .await
+            .expect("response is received");
+        assert_eq!(body, Bytes::from_static(b"body"));
+        let ResponseTrailers(trailers) = parts
+            .extensions
+            .get::<ResponseTrailers>()
+            .expect("trailers are captured");
+        assert_eq!(trailers.get("grpc-status").unwrap(), "0");
+    }
+
+    #[tokio::test]
+    async fn streamed_body_is_passed_in_chunks_with_trailers() {
+        let mut expected_trailers = HeaderMap::new();
+        expected_trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        let frames = vec![
+            Ok::<_, Infallible>(Frame::data(Bytes::from_static(b"abc"))),
+            Ok(Frame::data(Bytes::from_static(b"def"))),
+            Ok(Frame::trailers(expected_trailers.clone())),
+        ];
+        let mut chunks = Vec::new();
+        let trailers = stream_body(
+            StreamBody::new(stream::iter(frames)),
+            MAX_RESPONSE_SIZE,
+            |chunk| chunks.push(chunk.to_vec()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(chunks, vec![b"abc".to_vec(), b"def".to_vec()]);
+        assert_eq!(trailers, Some(expected_trailers));
+    }
+
+    #[tokio::test]
+    async fn streamed_body_over_the_size_limit_is_rejected() {
+        let frames = vec![
+            Ok::<_, Infallible>(Frame::data(Bytes::from(vec![1; MAX_RESPONSE_SIZE]))),
+            Ok(Frame::data(Bytes::from_static(b"!"))),
+        ];
+        let mut received = 0;
+        assert_matches!(
+            stream_body(
+                StreamBody::new(stream::iter(frames)),
+                MAX_RESPONSE_SIZE,
+                |chunk| received += chunk.len(),
+            )
+            .await,
+            Err(NetError::ResponseTooLarge)
+        );
+        assert_eq!(received, MAX_RESPONSE_SIZE);
+    }
+
+    #[tokio::test]
+    async fn http2_client_passes_response_body_in_chunks() {
+        let (mut client, connection) = in_memory::connect(|io| {
+            in_memory::serve_fn(io, |_request| async {
+                let frames = vec![
+                    Ok::<_, Infallible>(Frame::data(Bytes::from_static(b"first"))),
+                    Ok(Frame::data(Bytes::from_static(b"second"))),
+                ];
+                http::Response::builder()
+                    .status(200)
+                    .body(StreamBody::new(stream::iter(frames)))
+            })
+        })
+        .await;
+        tokio::spawn(connection);
+
+        let mut body = Vec::new();
+        let parts = client
+            .send_request_with_chunks(
+                "/v1/download",
+                http::Request::builder().method(http::Method::GET),
+                Bytes::new(),
+                |chunk| body.extend_from_slice(chunk),
+            )
+            .await
+            .expect("response is received");
+        assert_eq!(parts.status, 200);
+        assert_eq!(body, b"firstsecond");
+    }
+
+    #[test]
+    fn keepalive_interval_jitter_is_bounded() {
+        let interval = Duration::from_secs(30);
+        let mut rng = StdRng::seed_from_u64(0);
+        let intervals: Vec<Duration> = (0..100)
+            .map(|_| jittered_keepalive_interval(interval, 10, &mut rng))
+            .collect();
+
+        for jittered in &intervals {
+            assert!(
+                (Duration::from_secs(27)..=Duration::from_secs(33)).contains(jittered),
+                "{jittered:?}"
+            );
+        }
+        assert!(intervals.iter().any(|jittered| *jittered < interval));
+        assert!(intervals.iter().any(|jittered| *jittered > interval));
+
+        // the same seed gives the same intervals
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(
+            intervals[0],
+            jittered_keepalive_interval(interval, 10, &mut rng)
+        );
+    }
+
+    #[test]
+    fn keepalive_interval_without_jitter_is_exact() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let interval = Duration::from_secs(30);
+        assert_eq!(jittered_keepalive_interval(interval, 0, &mut rng), interval);
+    }
+
+    #[test]
+    fn keepalive_interval_jitter_is_clamped() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let interval = Duration::from_secs(30);
+        for _ in 0..100 {
+            let jittered = jittered_keepalive_interval(interval, u8::MAX, &mut rng);
+            assert!(
+                (Duration::from_millis(300)..=Duration::from_millis(59_700)).contains(&jittered),
+                "{jittered:?}"
+            );
+        }
+    }
+
+    const POOL_CONFIG: Http2ConnectionPoolConfig = Http2ConnectionPoolConfig {
+        max_idle: Duration::from_secs(60),
+        max_lifetime: Duration::from_secs(600),
+    };
+
+    /// Connects to an in-memory echo server that closes the connection once `close` is cancelled,
+    /// counting the connections in `connects`.
+    async fn connect_echo(
+        connects: &AtomicUsize,
+        close: &CancellationToken,
+    ) -> Result<
+        (
+            AggregatingHttp2Client,
+            impl Future<Output = Result<(), hyper::Error>> + Send + 'static,
+            ConnectionInfo,
+        ),
+        NetError,
+    > {
+        connects.fetch_add(1, Ordering::SeqCst);
+        let close = close.clone();
+        let (client, connection) = in_memory::connect(|io| in_memory::serve_echo(io, close)).await;
+        Ok((client, connection, in_memory::connection_info()))
+    }
+
+    #[tokio::test]
+    async fn pool_shares_the_connection_to_the_same_endpoint() {
+        let pool = Http2ConnectionPool::new(POOL_CONFIG);
+        let connects = AtomicUsize::new(0);
+        let close = CancellationToken::new();
+        let params = in_memory::connection_params();
+
+        let first = pool
+            .get_or_connect_by(&params, || connect_echo(&connects, &close))
+            .await
+            .expect("connected");
+        let second = pool
+            .get_or_connect_by(&params, || connect_echo(&connects, &close))
+            .await
+            .expect("connected");
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+        assert_eq!(pool.reusable_connections(), 1);
+
+        for mut channel in [first, second] {
+            let (_, body) = channel
+                .aggregating_client
+                .send_request_aggregate_response(
+                    "/v1/echo",
+                    http::Request::builder().method(http::Method::PUT),
+                    Bytes::from_static(b"hello"),
+                )
+                .await
+                .expect("sent");
+            assert_eq!(body, Bytes::from_static(b"hello"));
+        }
+    }
+
+    #[tokio::test]
+    async fn pool_replaces_a_closed_connection() {
+        let pool = Http2ConnectionPool::new(POOL_CONFIG);
+        let connects = AtomicUsize::new(0);
+        let close = CancellationToken::new();
+        let params = in_memory::connection_params();
+
+        let first = pool
+            .get_or_connect_by(&params, || connect_echo(&connects, &close))
+            .await
+            .expect("connected");
+        close.cancel();
+        first.service_status.stopped().await;
+        assert_eq!(pool.reusable_connections(), 0);
+
+        let close = CancellationToken::new();
+        let second = pool
+            .get_or_connect_by(&params, || connect_echo(&connects, &close))
+            .await
+            .expect("connected");
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+        assert!(!second.service_status.is_stopped());
+        assert_eq!(pool.reusable_connections(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pool_replaces_an_idle_connection() {
+        let pool = Http2ConnectionPool::new(POOL_CONFIG);
+        let connects = AtomicUsize::new(0);
+        let close = CancellationToken::new();
+        let params = in_memory::connection_params();
+
+        let _first = pool
+            .get_or_connect_by(&params, || connect_echo(&connects, &close))
+            .await
+            .expect("connected");
+        tokio::time::advance(POOL_CONFIG.max_idle).await;
+        assert_eq!(pool.reusable_connections(), 0);
+
+        let _second = pool
+            .get_or_connect_by(&params, || connect_echo(&connects, &close))
+            .await
+            .expect("connected");
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn pool_replaces_a_connection_past_its_lifetime() {
+        let pool = Http2ConnectionPool::new(POOL_CONFIG);
+        let connects = AtomicUsize::new(0);
+        let close = CancellationToken::new();
+        let params = in_memory::connection_params();
+
+        let mut elapsed = Duration::ZERO;
+        while elapsed < POOL_CONFIG.max_lifetime {
+            let _channel = pool
+                .get_or_connect_by(&params, || connect_echo(&connects, &close))
+                .await
+                .expect("connected");
+            assert_eq!(connects.load(Ordering::SeqCst), 1);
+            // staying active, so that the connection is not evicted for being idle
+            tokio::time::advance(POOL_CONFIG.max_idle / 2).await;
+            elapsed += POOL_CONFIG.max_idle / 2;
+        }
+
+        let _channel = pool
+            .get_or_connect_by(&params, || connect_echo(&connects, &close))
+            .await
+            .expect("connected");
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn pool_connects_to_different_endpoints_independently() {
+        let pool = Http2ConnectionPool::new(POOL_CONFIG);
+        let connects = AtomicUsize::new(0);
+        let close = CancellationToken::new();
+        let stuck_params = in_memory::connection_params();
+        let mut params = in_memory::connection_params();
+        params.port += 1;
+
+        let stuck_pool = pool.clone();
+        let stuck = tokio::spawn(async move {
+            stuck_pool
+                .get_or_connect_by(&stuck_params, || {
+                    std::future::pending::<
+                        Result<
+                            (
+                                AggregatingHttp2Client,
+                                std::future::Ready<Result<(), hyper::Error>>,
+                                ConnectionInfo,
+                            ),
+                            NetError,
+                        >,
+                    >()
+                })
+                .await
+        });
+        tokio::task::yield_now().await;
+
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            pool.get_or_connect_by(&params, || connect_echo(&connects, &close)),
+        )
+        .await
+        .expect("not waiting for the other endpoint")
+        .expect("connected");
+        assert!(!stuck.is_finished());
+        stuck.abort();
+    }
+}
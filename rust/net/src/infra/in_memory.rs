@@ -0,0 +1,159 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! HTTP/2 connections over in-memory pipes, for testing the HTTP/2 clients without a network.
+
+use std::future::Future;
+
+use bytes::{Bytes, BytesMut};
+use http::header::CONTENT_LENGTH;
+use tokio::io::DuplexStream;
+use tokio_util::sync::CancellationToken;
+
+use crate::infra::http::AggregatingHttp2Client;
+use crate::infra::tokio_executor::TokioExecutor;
+use crate::infra::tokio_io::TokioIo;
+use crate::infra::{ConnectionInfo, ConnectionParams};
+
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// The parameters the in-memory connections pretend to be established with.
+pub fn connection_params() -> ConnectionParams {
+    ConnectionParams::builder("chat.signal.org")
+        .build()
+        .expect("valid params")
+}
+
+/// What the in-memory connections pretend to have negotiated with the server.
+pub fn connection_info() -> ConnectionInfo {
+    ConnectionInfo {
+        tls_version: "TLSv1.3",
+        alpn: Some(b"h2".to_vec()),
+        cipher_suite: None,
+        peer_cert_spki: None,
+    }
+}
+
+/// Establishes an HTTP/2 connection over an in-memory pipe, whose server end is passed to `serve`
+/// on a new task.
+///
+/// Returns the client for the connection along with the connection itself, which has to be
+/// polled for the client to make progress.
+pub async fn connect<F, Fut>(
+    serve: F,
+) -> (
+    AggregatingHttp2Client,
+    impl Future<Output = Result<(), hyper::Error>> + Send + 'static,
+)
+where
+    F: FnOnce(DuplexStream) -> Fut,
+    Fut: Future + Send + 'static,
+    Fut::Output: Send + 'static,
+{
+    let (client_io, server_io) = tokio::io::duplex(PIPE_CAPACITY);
+    tokio::spawn(serve(server_io));
+    let (sender, connection) =
+        hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(client_io))
+            .await
+            .expect("handshake succeeds");
+    (
+        AggregatingHttp2Client::new(sender, connection_params()),
+        connection,
+    )
+}
+
+/// Like [connect], but the client sends PINGs as configured by the keepalive settings of
+/// `connection_params`.
+#[cfg(test)]
+pub(crate) async fn connect_with_keepalive<F, Fut>(
+    connection_params: &ConnectionParams,
+    serve: F,
+) -> (
+    AggregatingHttp2Client,
+    impl Future<Output = Result<(), hyper::Error>> + Send + 'static,
+)
+where
+    F: FnOnce(DuplexStream) -> Fut,
+    Fut: Future + Send + 'static,
+    Fut::Output: Send + 'static,
+{
+    let (client_io, server_io) = tokio::io::duplex(PIPE_CAPACITY);
+    tokio::spawn(serve(server_io));
+    let mut builder = hyper::client::conn::http2::Builder::new(TokioExecutor::new());
+    crate::infra::http::configure_keepalive(&mut builder, connection_params);
+    let (sender, connection) = builder
+        .handshake(TokioIo::new(client_io))
+        .await
+        .expect("handshake succeeds");
+    (
+        AggregatingHttp2Client::new(sender, self::connection_params()),
+        connection,
+    )
+}
+
+/// Serves an in-memory connection, responding to every request with `200` and the body of
+/// the request, until `close` is cancelled.
+///
+/// The connection is then closed from the server side: the server sends `GOAWAY`, and closes
+/// the connection once the requests in flight are answered.
+pub async fn serve_echo(io: DuplexStream, close: CancellationToken) {
+    let mut connection = match h2::server::handshake(io).await {
+        Ok(connection) => connection,
+        Err(_) => return,
+    };
+    loop {
+        tokio::select! {
+            request = connection.accept() => match request {
+                Some(Ok((request, respond))) => {
+                    tokio::spawn(echo(request, respond));
+                }
+                _ => return,
+            },
+            () = close.cancelled() => break,
+        }
+    }
+    connection.graceful_shutdown();
+    while let Some(Ok(_)) = connection.accept().await {}
+}
+
+/// Serves an in-memory connection with hyper, responding to every request with `respond`.
+#[cfg(test)]
+pub(crate) async fn serve_fn<F, Fut, B, E>(io: DuplexStream, respond: F) -> Result<(), hyper::Error>
+where
+    F: Fn(http::Request<hyper::body::Incoming>) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<http::Response<B>, E>> + Send + 'static,
+    B: hyper::body::Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+        .serve_connection(TokioIo::new(io), hyper::service::service_fn(respond))
+        .await
+}
+
+async fn echo(
+    request: http::Request<h2::RecvStream>,
+    mut respond: h2::server::SendResponse<Bytes>,
+) {
+    let mut body = request.into_body();
+    let mut received = BytesMut::new();
+    while let Some(Ok(chunk)) = body.data().await {
+        let _ignore_error = body.flow_control().release_capacity(chunk.len());
+        received.extend_from_slice(&chunk);
+    }
+    let response = http::Response::builder()
+        .status(200)
+        .header(CONTENT_LENGTH, received.len())
+        .body(())
+        .expect("valid response");
+    let mut send_body = match respond.send_response(response, received.is_empty()) {
+        Ok(send_body) => send_body,
+        Err(_) => return,
+    };
+    if !received.is_empty() {
+        let _ignore_error = send_body.send_data(received.freeze(), true);
+    }
+}
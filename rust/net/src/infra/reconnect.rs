@@ -3,16 +3,22 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
+use std::cmp::min;
+use std::collections::VecDeque;
 use std::fmt::Debug;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::{Arc, OnceLock};
 use std::time::Duration;
 
 use async_trait::async_trait;
 use derive_where::derive_where;
-use tokio::sync::Mutex;
-use tokio::time::{timeout_at, Instant};
+use rand::Rng;
+use tokio::sync::{broadcast, watch, Mutex};
+use tokio::time::Instant;
 use tokio_util::sync::CancellationToken;
 
+use crate::infra::clock::{timeout, timeout_at, Clock, SystemClock};
 use crate::infra::connection_manager::{ConnectionAttemptOutcome, ConnectionManager};
 use crate::infra::errors::LogSafeDisplay;
 use crate::infra::ConnectionParams;
@@ -32,6 +38,51 @@ pub enum ServiceState<T, E> {
     TimedOut,
 }
 
+/// Connection state of a [ServiceWithReconnect] as observed by its users.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConnectionState {
+    /// The service is connected and ready to use
+    Connected,
+    /// A connection is being established
+    Connecting,
+    /// There is no connection; one will be established for the next request,
+    /// unless all routes are in cooldown
+    Disconnected,
+}
+
+/// Detailed connectivity of a [ServiceWithReconnect], e.g. to tell the user whether
+/// the app is connecting or connected.
+///
+/// Unlike [ConnectionState], which is sampled when asked for, the changes are published
+/// as they happen, see [ServiceWithReconnect::subscribe_connectivity].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ConnectivityStatus {
+    /// The first connection is being established
+    Connecting,
+    /// The service is connected and ready to use
+    Connected,
+    /// The service is connected, but the last request on the connection failed
+    /// without a response, so the connection may be unusable
+    Degraded,
+    /// The previous connection was lost and a new one is being established
+    Reconnecting,
+    /// There is no connection and no connection attempt in progress
+    Stopped(StopReason),
+}
+
+/// Why a [ServiceWithReconnect] is in the [ConnectivityStatus::Stopped] state.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StopReason {
+    /// No connection has been requested yet
+    NotStarted,
+    /// The connection was closed without an error
+    Closed,
+    /// The connection was closed because of an error, the value is a log-safe description of it
+    Error(String),
+    /// The last connection attempt failed, timed out, or all routes are in cooldown
+    ConnectFailed,
+}
+
 /// Represents the logic needed to establish a connection over some transport.
 /// See [crate::chat::http::ChatOverHttp2ServiceConnector]
 /// and [crate::chat::ws::ChatOverWebSocketServiceConnector]
@@ -49,28 +100,121 @@ pub trait ServiceConnector: Clone {
     fn start_service(&self, channel: Self::Channel) -> (Self::Service, ServiceStatus<Self::Error>);
 }
 
+/// Number of [ConnectionEvent]s buffered for each subscriber.
+///
+/// Subscribers that fall behind by more than this many events miss the oldest ones.
+pub(crate) const CONNECTION_EVENTS_CAPACITY: usize = 16;
+
+/// Lifecycle events of a connection, published by [ServiceStatus].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ConnectionEvent {
+    /// Connection was established and the service is ready to use
+    Connected,
+    /// Connection was closed without an error
+    Closed(CloseReason),
+    /// Connection was closed because of an error, the value is a log-safe description of it
+    Error(String),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CloseReason {
+    /// Closed by this side, e.g. because the service was stopped or the connection was idle
+    LocalPeer,
+    /// Closed by the server, or the server stopped responding
+    RemotePeer,
+}
+
 #[derive(Debug)]
 #[derive_where(Clone)]
 pub struct ServiceStatus<E> {
     maybe_error: Arc<OnceLock<E>>,
     service_cancellation: CancellationToken,
+    events: broadcast::Sender<ConnectionEvent>,
+    connectivity: Arc<OnceLock<Arc<watch::Sender<ConnectivityStatus>>>>,
 }
 
 impl<E> ServiceStatus<E> {
     pub fn new() -> Self {
+        Self::with_events(broadcast::channel(CONNECTION_EVENTS_CAPACITY).0)
+    }
+
+    /// Creates a status that publishes [ConnectionEvent]s to the given channel.
+    ///
+    /// This allows the events of multiple consecutive connections to be observed
+    /// with a single subscription.
+    pub fn with_events(events: broadcast::Sender<ConnectionEvent>) -> Self {
         Self {
             maybe_error: Arc::new(OnceLock::new()),
             service_cancellation: CancellationToken::new(),
+            events,
+            connectivity: Arc::new(OnceLock::new()),
         }
     }
 
+    pub fn emit_event(&self, event: ConnectionEvent) {
+        // it's fine if there are no subscribers
+        let _ignore_result = self.events.send(event);
+    }
+
     pub fn stop_service(&self) {
-        self.service_cancellation.cancel();
+        self.stop(StopReason::Closed);
     }
 
-    pub fn stop_service_with_error(&self, error: E) {
-        self.stop_service();
+    pub fn stop_service_with_error(&self, error: E)
+    where
+        E: LogSafeDisplay,
+    {
+        let reason = StopReason::Error(error.to_string());
         self.maybe_error.get_or_init(|| error);
+        self.stop(reason);
+    }
+
+    fn stop(&self, reason: StopReason) {
+        let connectivity = match self.connectivity.get() {
+            Some(connectivity) => connectivity,
+            None => {
+                self.service_cancellation.cancel();
+                return;
+            }
+        };
+        // cancelling while the status is locked guarantees that the service
+        // isn't published as connected after it has been stopped
+        connectivity.send_if_modified(|current| {
+            let was_running = !self.is_stopped();
+            self.service_cancellation.cancel();
+            was_running && transition_from_connected(current, ConnectivityStatus::Stopped(reason))
+        });
+    }
+
+    /// Marks the connection as [ConnectivityStatus::Degraded], or as healthy again,
+    /// for the [ServiceWithReconnect] that started the service.
+    pub fn set_degraded(&self, degraded: bool) {
+        let status = if degraded {
+            ConnectivityStatus::Degraded
+        } else {
+            ConnectivityStatus::Connected
+        };
+        if let Some(connectivity) = self.connectivity.get() {
+            connectivity.send_if_modified(|current| {
+                !self.is_stopped() && transition_from_connected(current, status)
+            });
+        }
+    }
+
+    /// Starts publishing the connectivity of this service to `connectivity`,
+    /// beginning with its current status.
+    fn report_connectivity_to(&self, connectivity: Arc<watch::Sender<ConnectivityStatus>>)
+    where
+        E: LogSafeDisplay,
+    {
+        let connectivity = self.connectivity.get_or_init(|| connectivity);
+        connectivity.send_modify(|current| {
+            *current = match self.get_error() {
+                _ if !self.is_stopped() => ConnectivityStatus::Connected,
+                Some(error) => ConnectivityStatus::Stopped(StopReason::Error(error.to_string())),
+                None => ConnectivityStatus::Stopped(StopReason::Closed),
+            }
+        });
     }
 
     pub fn is_stopped(&self) -> bool {
@@ -86,16 +230,241 @@ impl<E> ServiceStatus<E> {
     }
 }
 
+/// Only the transitions from a connected status are made by the [ServiceStatus],
+/// the other ones are made by [ServiceWithReconnect].
+fn transition_from_connected(current: &mut ConnectivityStatus, status: ConnectivityStatus) -> bool {
+    match current {
+        ConnectivityStatus::Connected | ConnectivityStatus::Degraded if *current != status => {
+            *current = status;
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Controls the delay [ServiceWithReconnect] waits for between consecutive failed
+/// connection attempts.
+///
+/// After the first failure the delay is `initial`, and it doubles with every consecutive
+/// failure until it reaches `max`. A random duration of up to `jitter` is added to each delay
+/// so that many clients don't retry in lockstep. The failure count resets as soon as
+/// a connection is established and the started service reports healthy.
+#[derive(Clone, Debug)]
+pub struct ReconnectBackoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub jitter: Duration,
+}
+
+impl Default for ReconnectBackoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(10),
+            max: Duration::from_secs(1),
+            jitter: Duration::from_millis(10),
+        }
+    }
+}
+
+impl ReconnectBackoff {
+    /// Returns the delay to wait for after `consecutive_failures` failed attempts in a row.
+    pub(crate) fn delay(&self, consecutive_failures: u32) -> Duration {
+        if consecutive_failures == 0 {
+            return Duration::ZERO;
+        }
+        let exponent = min(consecutive_failures - 1, 31);
+        let delay = self.initial.saturating_mul(1 << exponent).min(self.max);
+        if self.jitter.is_zero() {
+            return delay;
+        }
+        delay + rand::thread_rng().gen_range(Duration::ZERO..=self.jitter)
+    }
+}
+
+/// Limits of a [RetryBudget].
+#[derive(Clone, Copy, Debug)]
+pub struct RetryBudgetConfig {
+    /// How many retries can be made in a burst, and how many the budget starts with
+    pub capacity: u32,
+    /// How often the budget allows one more retry, up to the `capacity`
+    pub refill_interval: Duration,
+}
+
+/// Token bucket that bounds how many retries are made in total.
+///
+/// Each retry, whether it's re-sending a request or attempting to connect again after
+/// a failure, takes a token from the bucket, and a token is added back every
+/// [RetryBudgetConfig::refill_interval]. Once the bucket is empty, operations fail
+/// with the error of their last attempt instead of retrying, so that a flapping server
+/// doesn't cause a retry storm.
+///
+/// A budget is meant to be shared, via an [Arc], by everything that retries on behalf
+/// of the same client, e.g. [ServiceWithReconnect::with_retry_budget] and
+/// [ChatOverHttp2Config::retry_budget](crate::chat::http::ChatOverHttp2Config::retry_budget).
+#[derive(Debug)]
+pub struct RetryBudget {
+    config: RetryBudgetConfig,
+    clock: Arc<dyn Clock>,
+    state: std::sync::Mutex<RetryBudgetState>,
+}
+
+#[derive(Debug)]
+struct RetryBudgetState {
+    tokens: u32,
+    last_refill: Instant,
+}
+
+impl RetryBudget {
+    pub fn new(config: RetryBudgetConfig) -> Self {
+        Self::new_with_clock(config, Arc::new(SystemClock))
+    }
+
+    /// Like [RetryBudget::new], but the refill interval is measured by the given `clock`.
+    pub fn new_with_clock(config: RetryBudgetConfig, clock: Arc<dyn Clock>) -> Self {
+        let last_refill = clock.now();
+        Self {
+            config,
+            clock,
+            state: std::sync::Mutex::new(RetryBudgetState {
+                tokens: config.capacity,
+                last_refill,
+            }),
+        }
+    }
+
+    /// Takes a token for a retry, returning `false` if there is none left.
+    pub fn try_acquire(&self) -> bool {
+        let now = self.clock.now();
+        let RetryBudgetConfig {
+            capacity,
+            refill_interval,
+        } = self.config;
+        let mut state = self.state.lock().expect("not poisoned");
+        let refills = if refill_interval.is_zero() {
+            u128::from(capacity)
+        } else {
+            now.saturating_duration_since(state.last_refill).as_nanos() / refill_interval.as_nanos()
+        };
+        let tokens = min(u128::from(state.tokens) + refills, u128::from(capacity));
+        if tokens == u128::from(capacity) {
+            state.last_refill = now;
+        } else {
+            // less than `capacity` refills happened, so the count fits in u32
+            state.last_refill += refill_interval * refills as u32;
+        }
+        state.tokens = tokens as u32;
+        if state.tokens == 0 {
+            return false;
+        }
+        state.tokens -= 1;
+        true
+    }
+}
+
+/// Limits of the queue for the requests made while a [ServiceWithReconnect] is reconnecting,
+/// see [ServiceWithReconnect::with_send_queue].
+#[derive(Clone, Copy, Debug)]
+pub struct SendQueueConfig {
+    /// How many requests can wait in the queue at the same time
+    pub capacity: usize,
+    /// Whether the requests that aren't safe to repeat are queued too;
+    /// otherwise they're sent as if there was no queue
+    pub queue_non_idempotent: bool,
+}
+
+/// Why a request didn't get its turn in the send queue.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum SendQueueError {
+    /// The queue already holds as many requests as its capacity allows
+    Full,
+    /// The requests ahead in the queue weren't sent in time
+    TimedOut,
+}
+
+/// Requests waiting for a [ServiceWithReconnect] to reconnect, in the order they were made.
+#[derive(Debug)]
+struct SendQueue {
+    config: SendQueueConfig,
+    next_id: AtomicU64,
+    waiting: watch::Sender<VecDeque<u64>>,
+}
+
+impl SendQueue {
+    fn new(config: SendQueueConfig) -> Self {
+        Self {
+            config,
+            next_id: AtomicU64::new(0),
+            waiting: watch::channel(VecDeque::new()).0,
+        }
+    }
+
+    fn enqueue(self: &Arc<Self>) -> Result<SendQueueTicket, SendQueueError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let capacity = self.config.capacity;
+        let added = self.waiting.send_if_modified(|waiting| {
+            if waiting.len() >= capacity {
+                return false;
+            }
+            waiting.push_back(id);
+            true
+        });
+        if !added {
+            return Err(SendQueueError::Full);
+        }
+        Ok(SendQueueTicket {
+            queue: self.clone(),
+            id,
+        })
+    }
+}
+
+/// Place of a request in the send queue.
+///
+/// The request leaves the queue when the ticket is dropped, letting the next one through.
+#[derive(Debug)]
+pub(crate) struct SendQueueTicket {
+    queue: Arc<SendQueue>,
+    id: u64,
+}
+
+impl SendQueueTicket {
+    /// Waits until all the requests queued before this one have left the queue.
+    async fn wait_turn(&self) {
+        let mut waiting = self.queue.waiting.subscribe();
+        while waiting.borrow_and_update().front() != Some(&self.id) {
+            waiting
+                .changed()
+                .await
+                .expect("the queue outlives its tickets");
+        }
+    }
+}
+
+impl Drop for SendQueueTicket {
+    fn drop(&mut self) {
+        self.queue
+            .waiting
+            .send_modify(|waiting| waiting.retain(|id| *id != self.id));
+    }
+}
+
 struct ServiceWithReconnectData<C: ServiceConnector, M> {
     state: Mutex<ServiceState<C::Service, C::Error>>,
     service_connector: C,
     connection_manager: M,
     connection_timeout: Duration,
+    backoff: ReconnectBackoff,
+    consecutive_failures: AtomicU32,
+    clock: Arc<dyn Clock>,
+    connectivity: Arc<watch::Sender<ConnectivityStatus>>,
+    has_connected: AtomicBool,
 }
 
 #[derive(Clone)]
 pub struct ServiceWithReconnect<C: ServiceConnector, M> {
     data: Arc<ServiceWithReconnectData<C, M>>,
+    send_queue: Option<Arc<SendQueue>>,
+    retry_budget: Option<Arc<RetryBudget>>,
 }
 
 impl<C, M> ServiceWithReconnect<C, M>
@@ -107,23 +476,159 @@ where
     C::Error: Send + Sync + Debug + LogSafeDisplay,
 {
     pub fn new(service_connector: C, connection_manager: M, connection_timeout: Duration) -> Self {
+        Self::new_with_backoff(
+            service_connector,
+            connection_manager,
+            connection_timeout,
+            ReconnectBackoff::default(),
+        )
+    }
+
+    pub fn new_with_backoff(
+        service_connector: C,
+        connection_manager: M,
+        connection_timeout: Duration,
+        backoff: ReconnectBackoff,
+    ) -> Self {
+        Self::new_with_clock(
+            service_connector,
+            connection_manager,
+            connection_timeout,
+            backoff,
+            Arc::new(SystemClock),
+        )
+    }
+
+    /// Like [ServiceWithReconnect::new_with_backoff], but the connection timeout and
+    /// the backoff delays are measured by the given `clock`.
+    pub fn new_with_clock(
+        service_connector: C,
+        connection_manager: M,
+        connection_timeout: Duration,
+        backoff: ReconnectBackoff,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
         // We're starting in a `Cooldown` state with a `next_attempt_time` set to `now`,
         // which effectively allows for an immediate use.
         Self {
             data: Arc::new(ServiceWithReconnectData {
-                state: Mutex::new(ServiceState::Cooldown(Instant::now())),
+                state: Mutex::new(ServiceState::Cooldown(clock.now())),
                 service_connector,
                 connection_manager,
                 connection_timeout,
+                backoff,
+                consecutive_failures: AtomicU32::new(0),
+                clock,
+                connectivity: Arc::new(
+                    watch::channel(ConnectivityStatus::Stopped(StopReason::NotStarted)).0,
+                ),
+                has_connected: AtomicBool::new(false),
             }),
+            send_queue: None,
+            retry_budget: None,
+        }
+    }
+
+    /// Makes the requests sent while the service is reconnecting wait in a queue, to be sent
+    /// in the order they were made once the service is connected again.
+    ///
+    /// Requests that don't fit in the queue fail right away, see [SendQueueConfig].
+    pub fn with_send_queue(mut self, config: SendQueueConfig) -> Self {
+        self.send_queue = Some(Arc::new(SendQueue::new(config)));
+        self
+    }
+
+    /// Makes the service take a token from the `retry_budget` for every connection attempt
+    /// made after a failed one, and for every request re-sent after reconnecting.
+    ///
+    /// Once the budget is exhausted, the service stops retrying and fails right away.
+    pub fn with_retry_budget(mut self, retry_budget: Arc<RetryBudget>) -> Self {
+        self.retry_budget = Some(retry_budget);
+        self
+    }
+
+    /// Returns whether a retry can be made according to the retry budget, if there is one.
+    pub(crate) fn try_acquire_retry(&self) -> bool {
+        self.retry_budget
+            .as_ref()
+            .map_or(true, |budget| budget.try_acquire())
+    }
+
+    /// Returns whether a connection attempt can be made now. Only the attempts following
+    /// a failed one are retries, which have to fit in the retry budget.
+    fn may_attempt_connection(&self) -> bool {
+        self.data.consecutive_failures.load(Ordering::Relaxed) == 0 || self.try_acquire_retry()
+    }
+
+    pub fn connection_state(&self) -> ConnectionState {
+        match self.data.state.try_lock() {
+            Ok(guard) => match &*guard {
+                ServiceState::Active(_, service_status) if !service_status.is_stopped() => {
+                    ConnectionState::Connected
+                }
+                _ => ConnectionState::Disconnected,
+            },
+            // the lock is held for the duration of a connection attempt
+            Err(_) => ConnectionState::Connecting,
+        }
+    }
+
+    pub fn connectivity(&self) -> ConnectivityStatus {
+        self.data.connectivity.borrow().clone()
+    }
+
+    /// Subscribes to the changes of the [ConnectivityStatus], across all connections
+    /// established by this service.
+    pub fn subscribe_connectivity(&self) -> watch::Receiver<ConnectivityStatus> {
+        self.data.connectivity.subscribe()
+    }
+
+    /// Establishes a connection ahead of the first request, e.g. when the app comes
+    /// to the foreground, so that the request doesn't have to wait for it.
+    ///
+    /// Does nothing if the service is already connected. The returned future doesn't borrow
+    /// `self`, so it can be spawned; it resolves to whether the service is connected.
+    pub fn prewarm(&self) -> impl Future<Output = bool> + Send + 'static {
+        let mut service_with_reconnect = Self {
+            data: self.data.clone(),
+            send_queue: self.send_queue.clone(),
+            retry_budget: self.retry_budget.clone(),
+        };
+        async move { service_with_reconnect.service_clone().await.is_some() }
+    }
+
+    /// Takes a place in the send queue for a request if the service is reconnecting,
+    /// or if the requests queued earlier haven't been sent yet, and waits for its turn.
+    ///
+    /// Returns `None` if the request doesn't have to wait. Otherwise the ticket must be held
+    /// until the request is dispatched to the connection, so that the requests queued after it
+    /// keep waiting, and dropped right after that, so that they don't wait for its response.
+    pub(crate) async fn wait_in_send_queue(
+        &self,
+        idempotent: bool,
+        timeout_duration: Duration,
+    ) -> Result<Option<SendQueueTicket>, SendQueueError> {
+        let queue = match &self.send_queue {
+            Some(queue) if idempotent || queue.config.queue_non_idempotent => queue,
+            _ => return Ok(None),
+        };
+        let reconnecting = *self.data.connectivity.borrow() == ConnectivityStatus::Reconnecting;
+        if !reconnecting && queue.waiting.borrow().is_empty() {
+            return Ok(None);
+        }
+        let ticket = queue.enqueue()?;
+        match timeout(&*self.data.clock, timeout_duration, ticket.wait_turn()).await {
+            Some(()) => Ok(Some(ticket)),
+            None => Err(SendQueueError::TimedOut),
         }
     }
 
     pub(crate) async fn service_clone(&mut self) -> Option<C::Service> {
-        let deadline = Instant::now() + self.data.connection_timeout;
-        let mut guard = match timeout_at(deadline, self.data.state.lock()).await {
-            Ok(guard) => guard,
-            Err(_) => {
+        let clock = &*self.data.clock;
+        let deadline = clock.now() + self.data.connection_timeout;
+        let mut guard = match timeout_at(clock, deadline, self.data.state.lock()).await {
+            Some(guard) => guard,
+            None => {
                 log::info!("Timed out waiting for the state lock");
                 return None;
             }
@@ -149,23 +654,57 @@ where
                     }
                     // it's safe to sleep without a `timeout`
                     // because we just checked that we'll wake before the deadline
-                    tokio::time::sleep_until(*next_attempt_time).await;
+                    clock.sleep_until(*next_attempt_time).await;
                 }
                 ServiceState::TimedOut => {
                     // keep trying until we hit our own timeout deadline
                     log::info!("Connection attempt timed out");
                 }
             };
-            match timeout_at(deadline, self.reconnect()).await {
-                Ok(next_state) => *guard = next_state,
-                Err(_) => {
+            if !self.may_attempt_connection() {
+                log::info!("Retry budget exhausted, not attempting to connect");
+                self.data
+                    .connectivity
+                    .send_replace(ConnectivityStatus::Stopped(StopReason::ConnectFailed));
+                return None;
+            }
+            self.data.connectivity.send_replace(
+                if self.data.has_connected.load(Ordering::Relaxed) {
+                    ConnectivityStatus::Reconnecting
+                } else {
+                    ConnectivityStatus::Connecting
+                },
+            );
+            match timeout_at(clock, deadline, self.reconnect()).await {
+                Some(next_state) => {
+                    self.publish_connectivity(&next_state);
+                    *guard = next_state;
+                }
+                None => {
                     log::info!("Timed out waiting for a connection attempt to finish");
+                    self.data
+                        .connectivity
+                        .send_replace(ConnectivityStatus::Stopped(StopReason::ConnectFailed));
                     return None;
                 }
             }
         }
     }
 
+    fn publish_connectivity(&self, state: &ServiceState<C::Service, C::Error>) {
+        match state {
+            ServiceState::Active(_, service_status) => {
+                self.data.has_connected.store(true, Ordering::Relaxed);
+                service_status.report_connectivity_to(self.data.connectivity.clone());
+            }
+            ServiceState::Cooldown(_) | ServiceState::TimedOut => {
+                self.data
+                    .connectivity
+                    .send_replace(ConnectivityStatus::Stopped(StopReason::ConnectFailed));
+            }
+        }
+    }
+
     async fn reconnect(&self) -> ServiceState<C::Service, C::Error> {
         // attempting to establish a connection until we're connected or instructed to cooldown
         loop {
@@ -185,20 +724,35 @@ where
                     log::debug!("connection attempt succeeded");
                     let (service, service_status) =
                         self.data.service_connector.start_service(channel);
+                    if !service_status.is_stopped() {
+                        self.data.consecutive_failures.store(0, Ordering::Relaxed);
+                    }
                     return ServiceState::Active(service, service_status);
                 }
                 ConnectionAttemptOutcome::Attempted(Err(e)) => {
                     log::debug!("connection attempt failed due to an error: {:?}", e);
+                    let consecutive_failures = self
+                        .data
+                        .consecutive_failures
+                        .fetch_add(1, Ordering::Relaxed)
+                        .saturating_add(1);
+                    let delay = self.data.backoff.delay(consecutive_failures);
+                    if !self.try_acquire_retry() {
+                        log::debug!("retry budget exhausted, cooling down for {:?}", delay);
+                        return ServiceState::Cooldown(self.data.clock.now() + delay);
+                    }
+                    log::debug!("waiting for {:?} before the next attempt", delay);
+                    self.data.clock.sleep(delay).await;
                     continue;
                 }
-                ConnectionAttemptOutcome::WaitUntil(i) if i <= Instant::now() => {
+                ConnectionAttemptOutcome::WaitUntil(i) if i <= self.data.clock.now() => {
                     log::debug!("cooldown time is in the past, retrying immediately");
                     continue;
                 }
                 ConnectionAttemptOutcome::WaitUntil(i) => {
                     log::debug!(
                         "connection will not be attempted for another {} seconds",
-                        i.duration_since(Instant::now()).as_secs()
+                        i.duration_since(self.data.clock.now()).as_secs()
                     );
                     return ServiceState::Cooldown(i);
                 }
@@ -221,19 +775,25 @@ mod test {
     use assert_matches::assert_matches;
     use async_trait::async_trait;
     use futures_util::FutureExt;
+    use tokio::sync::broadcast;
     use tokio::time;
     use tokio::time::Instant;
 
     use crate::infra::certs::RootCertificates;
+    use crate::infra::clock::MockClock;
     use crate::infra::connection_manager::{
-        SingleRouteThrottlingConnectionManager, MAX_COOLDOWN_INTERVAL,
+        ConnectionAttemptOutcome, ConnectionManager, SingleRouteThrottlingConnectionManager,
+        MAX_COOLDOWN_INTERVAL,
     };
     use crate::infra::dns::DnsResolver;
+    use crate::infra::errors::LogSafeDisplay;
     use crate::infra::reconnect::{
-        ServiceConnector, ServiceState, ServiceStatus, ServiceWithReconnect,
+        CloseReason, ConnectionEvent, ConnectionState, ConnectivityStatus, ReconnectBackoff,
+        RetryBudget, RetryBudgetConfig, ServiceConnector, ServiceState, ServiceStatus,
+        ServiceWithReconnect, StopReason, CONNECTION_EVENTS_CAPACITY,
     };
     use crate::infra::test::shared::{
-        TestError, LONG_CONNECTION_TIME, NORMAL_CONNECTION_TIME, TIMEOUT_DURATION,
+        TestError, FEW_ATTEMPTS, LONG_CONNECTION_TIME, NORMAL_CONNECTION_TIME, TIMEOUT_DURATION,
         TIME_ADVANCE_VALUE,
     };
     use crate::infra::{ConnectionParams, HttpRequestDecoratorSeq};
@@ -315,6 +875,25 @@ mod test {
         }
     }
 
+    /// Attempts every connection, without any cooldown of its own.
+    struct AlwaysAttemptingConnectionManager(ConnectionParams);
+
+    #[async_trait]
+    impl ConnectionManager for AlwaysAttemptingConnectionManager {
+        async fn connect_or_wait<'a, T, E, Fun, Fut>(
+            &'a self,
+            connection_fn: Fun,
+        ) -> ConnectionAttemptOutcome<T, E>
+        where
+            T: Send,
+            E: Send + Debug + LogSafeDisplay,
+            Fun: Fn(&'a ConnectionParams) -> Fut + Send + Sync,
+            Fut: std::future::Future<Output = Result<T, E>> + Send,
+        {
+            ConnectionAttemptOutcome::Attempted(connection_fn(&self.0).await)
+        }
+    }
+
     fn example_connection_params() -> ConnectionParams {
         ConnectionParams::new(
             "chat.signal.org",
@@ -350,6 +929,133 @@ mod test {
         assert_eq!(connector.attempts_made(), 1);
     }
 
+    #[tokio::test]
+    async fn prewarm_connects_only_once() {
+        let connector = TestServiceConnector::new();
+        let manager = SingleRouteThrottlingConnectionManager::new(
+            example_connection_params(),
+            TIMEOUT_DURATION,
+        );
+        let mut service_with_reconnect =
+            ServiceWithReconnect::new(connector.clone(), manager, TIMEOUT_DURATION);
+
+        assert!(tokio::spawn(service_with_reconnect.prewarm())
+            .await
+            .expect("completed"));
+        assert_eq!(connector.attempts_made(), 1);
+        assert_eq!(
+            service_with_reconnect.connection_state(),
+            ConnectionState::Connected
+        );
+
+        assert!(service_with_reconnect.prewarm().await);
+        let service = service_with_reconnect.service_clone().await;
+        assert!(service.is_some());
+        assert_eq!(connector.attempts_made(), 1);
+    }
+
+    #[tokio::test]
+    async fn connectivity_transitions() {
+        let connector = TestServiceConnector::new();
+        let manager = SingleRouteThrottlingConnectionManager::new(
+            example_connection_params(),
+            TIMEOUT_DURATION,
+        );
+        let mut service_with_reconnect =
+            ServiceWithReconnect::new(connector.clone(), manager, TIMEOUT_DURATION);
+        let mut connectivity = service_with_reconnect.subscribe_connectivity();
+        assert_eq!(
+            *connectivity.borrow_and_update(),
+            ConnectivityStatus::Stopped(StopReason::NotStarted)
+        );
+
+        let connected = tokio::spawn(service_with_reconnect.prewarm());
+        connectivity.changed().await.expect("not closed");
+        assert_eq!(
+            *connectivity.borrow_and_update(),
+            ConnectivityStatus::Connecting
+        );
+        assert!(connected.await.expect("completed"));
+        let service = service_with_reconnect
+            .service_clone()
+            .await
+            .expect("connected");
+        assert_eq!(
+            service_with_reconnect.connectivity(),
+            ConnectivityStatus::Connected
+        );
+
+        service.service_status.set_degraded(true);
+        assert_eq!(
+            service_with_reconnect.connectivity(),
+            ConnectivityStatus::Degraded
+        );
+        service.service_status.set_degraded(false);
+        assert_eq!(
+            service_with_reconnect.connectivity(),
+            ConnectivityStatus::Connected
+        );
+
+        service
+            .service_status
+            .stop_service_with_error(TestError::Expected);
+        assert_eq!(
+            service_with_reconnect.connectivity(),
+            ConnectivityStatus::Stopped(StopReason::Error(TestError::Expected.to_string()))
+        );
+        // a stopped service can't change the status anymore
+        service.service_status.set_degraded(true);
+        assert_matches!(
+            service_with_reconnect.connectivity(),
+            ConnectivityStatus::Stopped(_)
+        );
+
+        connectivity.borrow_and_update();
+        let reconnected = tokio::spawn(service_with_reconnect.prewarm());
+        connectivity.changed().await.expect("not closed");
+        assert_eq!(
+            *connectivity.borrow_and_update(),
+            ConnectivityStatus::Reconnecting
+        );
+        assert!(reconnected.await.expect("completed"));
+        let service = service_with_reconnect
+            .service_clone()
+            .await
+            .expect("reconnected");
+        assert_eq!(
+            service_with_reconnect.connectivity(),
+            ConnectivityStatus::Connected
+        );
+
+        service.close_channel();
+        assert_eq!(
+            service_with_reconnect.connectivity(),
+            ConnectivityStatus::Stopped(StopReason::Closed)
+        );
+        // the status is kept in sync with the sampled state
+        assert_eq!(
+            service_with_reconnect.connection_state(),
+            ConnectionState::Disconnected
+        );
+    }
+
+    #[tokio::test]
+    async fn connectivity_reports_failed_connection_attempts() {
+        let connector = TestServiceConnector::new();
+        connector.set_service_healthy(false);
+        let manager = SingleRouteThrottlingConnectionManager::new(
+            example_connection_params(),
+            TIMEOUT_DURATION,
+        );
+        let mut service_with_reconnect =
+            ServiceWithReconnect::new(connector.clone(), manager, TIMEOUT_DURATION);
+        assert!(service_with_reconnect.service_clone().await.is_none());
+        assert_eq!(
+            service_with_reconnect.connectivity(),
+            ConnectivityStatus::Stopped(StopReason::ConnectFailed)
+        );
+    }
+
     #[tokio::test]
     async fn moving_to_inactive_on_channel_closed() {
         let connector = TestServiceConnector::new();
@@ -531,4 +1237,190 @@ mod test {
         let service = service_with_reconnect.service_clone().await;
         assert!(service.is_some());
     }
+
+    #[test]
+    fn backoff_delay_doubles_until_max() {
+        let backoff = ReconnectBackoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_millis(500),
+            jitter: Duration::ZERO,
+        };
+        assert_eq!(backoff.delay(0), Duration::ZERO);
+        assert_eq!(backoff.delay(1), Duration::from_millis(100));
+        assert_eq!(backoff.delay(2), Duration::from_millis(200));
+        assert_eq!(backoff.delay(3), Duration::from_millis(400));
+        assert_eq!(backoff.delay(4), Duration::from_millis(500));
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn backoff_jitter_is_bounded() {
+        let backoff = ReconnectBackoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_millis(500),
+            jitter: Duration::from_millis(50),
+        };
+        for _ in 0..FEW_ATTEMPTS {
+            let delay = backoff.delay(1);
+            assert!(delay >= Duration::from_millis(100));
+            assert!(delay <= Duration::from_millis(150));
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread", start_paused = true)]
+    async fn backoff_resets_after_successful_connection() {
+        let connector = TestServiceConnector::new();
+        let manager = SingleRouteThrottlingConnectionManager::new(
+            example_connection_params(),
+            TIMEOUT_DURATION,
+        );
+        let mut service_with_reconnect =
+            ServiceWithReconnect::new(connector.clone(), manager, TIMEOUT_DURATION);
+
+        time::advance(TIME_ADVANCE_VALUE).await;
+        connector.set_service_healthy(false);
+        let service = service_with_reconnect.service_clone().await;
+        assert!(service.is_none());
+        assert!(
+            service_with_reconnect
+                .data
+                .consecutive_failures
+                .load(Ordering::Relaxed)
+                > 0
+        );
+
+        time::advance(MAX_COOLDOWN_INTERVAL).await;
+
+        connector.set_service_healthy(true);
+        let service = service_with_reconnect.service_clone().await;
+        assert!(service.is_some());
+        assert_eq!(
+            service_with_reconnect
+                .data
+                .consecutive_failures
+                .load(Ordering::Relaxed),
+            0
+        );
+    }
+
+    #[test]
+    fn retry_budget_refills_up_to_capacity() {
+        let clock = MockClock::new();
+        let budget = RetryBudget::new_with_clock(
+            RetryBudgetConfig {
+                capacity: 2,
+                refill_interval: Duration::from_secs(10),
+            },
+            Arc::new(clock.clone()),
+        );
+        assert!(budget.try_acquire());
+        assert!(budget.try_acquire());
+        assert!(!budget.try_acquire());
+
+        clock.advance(Duration::from_secs(9));
+        assert!(!budget.try_acquire());
+        clock.advance(Duration::from_secs(1));
+        assert!(budget.try_acquire());
+        assert!(!budget.try_acquire());
+
+        clock.advance(Duration::from_secs(100));
+        assert!(budget.try_acquire());
+        assert!(budget.try_acquire());
+        assert!(!budget.try_acquire());
+    }
+
+    #[tokio::test(flavor = "current_thread", start_paused = true)]
+    async fn connection_attempts_stop_once_retry_budget_is_exhausted() {
+        let connector = TestServiceConnector::new();
+        connector.set_time_to_connect(Duration::ZERO);
+        connector.set_service_healthy(false);
+        let mut service_with_reconnect = ServiceWithReconnect::new_with_backoff(
+            connector.clone(),
+            AlwaysAttemptingConnectionManager(example_connection_params()),
+            Duration::from_secs(60),
+            ReconnectBackoff {
+                initial: Duration::from_millis(10),
+                max: Duration::from_millis(10),
+                jitter: Duration::ZERO,
+            },
+        )
+        .with_retry_budget(Arc::new(RetryBudget::new(RetryBudgetConfig {
+            capacity: 2,
+            refill_interval: Duration::from_secs(3600),
+        })));
+
+        let start = Instant::now();
+        assert!(service_with_reconnect.service_clone().await.is_none());
+        // the first attempt and the two retries the budget allows
+        assert_eq!(connector.attempts_made(), 3);
+        // the failure is reported without waiting for the connection timeout
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        assert!(service_with_reconnect.service_clone().await.is_none());
+        assert_eq!(connector.attempts_made(), 3);
+    }
+
+    #[tokio::test(flavor = "current_thread", start_paused = true)]
+    async fn backoff_delays_are_measured_by_the_clock() {
+        let clock = MockClock::new();
+        let connector = TestServiceConnector::new();
+        connector.set_time_to_connect(Duration::ZERO);
+        connector.set_service_healthy(false);
+        let mut service_with_reconnect = ServiceWithReconnect::new_with_clock(
+            connector.clone(),
+            AlwaysAttemptingConnectionManager(example_connection_params()),
+            Duration::from_secs(60),
+            ReconnectBackoff {
+                initial: Duration::from_secs(1),
+                max: Duration::from_secs(10),
+                jitter: Duration::ZERO,
+            },
+            Arc::new(clock.clone()),
+        );
+        let service_task =
+            tokio::spawn(async move { service_with_reconnect.service_clone().await.is_some() });
+
+        // lets the connection attempts that are due run to completion
+        let settle = || time::sleep(NORMAL_CONNECTION_TIME);
+
+        settle().await;
+        assert_eq!(connector.attempts_made(), 1);
+
+        clock.advance(Duration::from_millis(999));
+        settle().await;
+        assert_eq!(connector.attempts_made(), 1);
+        clock.advance(Duration::from_millis(1));
+        settle().await;
+        assert_eq!(connector.attempts_made(), 2);
+
+        clock.advance(Duration::from_millis(1999));
+        settle().await;
+        assert_eq!(connector.attempts_made(), 2);
+        clock.advance(Duration::from_millis(1));
+        settle().await;
+        assert_eq!(connector.attempts_made(), 3);
+
+        connector.set_service_healthy(true);
+        clock.advance(Duration::from_secs(4));
+        assert!(service_task.await.expect("completed"));
+        assert_eq!(connector.attempts_made(), 4);
+    }
+
+    #[tokio::test]
+    async fn service_status_delivers_events_to_all_subscribers() {
+        let (events, mut first) = broadcast::channel(CONNECTION_EVENTS_CAPACITY);
+        let mut second = events.subscribe();
+        let service_status = ServiceStatus::<TestError>::with_events(events);
+
+        service_status.emit_event(ConnectionEvent::Connected);
+        service_status.emit_event(ConnectionEvent::Closed(CloseReason::RemotePeer));
+
+        for receiver in [&mut first, &mut second] {
+            assert_eq!(receiver.recv().await.unwrap(), ConnectionEvent::Connected);
+            assert_eq!(
+                receiver.recv().await.unwrap(),
+                ConnectionEvent::Closed(CloseReason::RemotePeer)
+            );
+        }
+    }
 }
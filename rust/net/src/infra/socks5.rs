@@ -0,0 +1,272 @@
+//
+// Copyright 2023 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+//! Minimal client side of the SOCKS5 protocol ([RFC 1928]) that only supports
+//! the `CONNECT` command, with optional username/password authentication ([RFC 1929]).
+//!
+//! [RFC 1928]: https://www.rfc-editor.org/rfc/rfc1928
+//! [RFC 1929]: https://www.rfc-editor.org/rfc/rfc1929
+
+use std::fmt::{Debug, Formatter};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::infra::dns::DnsResolver;
+use crate::infra::errors::NetError;
+use crate::infra::{connect_tcp, AddressFamily, TcpSocketOptions};
+
+const SOCKS_VERSION: u8 = 0x05;
+const AUTH_METHOD_NONE: u8 = 0x00;
+const AUTH_METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const AUTH_METHOD_NOT_ACCEPTABLE: u8 = 0xFF;
+const USERNAME_PASSWORD_VERSION: u8 = 0x01;
+const COMMAND_CONNECT: u8 = 0x01;
+const ADDRESS_TYPE_IPV4: u8 = 0x01;
+const ADDRESS_TYPE_DOMAIN_NAME: u8 = 0x03;
+const ADDRESS_TYPE_IPV6: u8 = 0x04;
+const REPLY_SUCCEEDED: u8 = 0x00;
+
+/// SOCKS5 proxy to tunnel connections through.
+#[derive(Clone, Debug)]
+pub struct ProxyConfig {
+    pub host: Arc<str>,
+    pub port: u16,
+    pub auth: Option<ProxyAuth>,
+}
+
+/// Credentials for the username/password authentication method.
+#[derive(Clone)]
+pub struct ProxyAuth {
+    pub username: String,
+    pub password: String,
+}
+
+impl Debug for ProxyAuth {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProxyAuth")
+            .field("username", &self.username)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Establishes a TCP connection to the proxy and asks it to connect to `host:port`.
+///
+/// The `host` is passed to the proxy as a domain name, so it's resolved by the proxy.
+pub(crate) async fn connect_tcp_via_proxy(
+    proxy: &ProxyConfig,
+    dns_resolver: &DnsResolver,
+    tcp_options: &TcpSocketOptions,
+    address_family: AddressFamily,
+    bind_address: Option<IpAddr>,
+    host: &str,
+    port: u16,
+) -> Result<TcpStream, NetError> {
+    let mut tcp_stream = connect_tcp(
+        dns_resolver,
+        tcp_options,
+        address_family,
+        bind_address,
+        &proxy.host,
+        proxy.port,
+    )
+    .await
+    .map_err(|_| NetError::ProxyFailure)?;
+    handshake(&mut tcp_stream, proxy.auth.as_ref(), host, port).await?;
+    Ok(tcp_stream)
+}
+
+async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    auth: Option<&ProxyAuth>,
+    host: &str,
+    port: u16,
+) -> Result<(), NetError> {
+    let auth_method = match auth {
+        Some(_) => AUTH_METHOD_USERNAME_PASSWORD,
+        None => AUTH_METHOD_NONE,
+    };
+    stream
+        .write_all(&[SOCKS_VERSION, 1, auth_method])
+        .await
+        .map_err(|_| NetError::ProxyFailure)?;
+    match read_array(stream).await? {
+        [SOCKS_VERSION, AUTH_METHOD_NOT_ACCEPTABLE] => return Err(NetError::ProxyFailure),
+        [SOCKS_VERSION, method] if method == auth_method => {}
+        _ => return Err(NetError::ProxyFailure),
+    }
+
+    if let Some(ProxyAuth { username, password }) = auth {
+        let mut request = vec![USERNAME_PASSWORD_VERSION];
+        push_length_prefixed(&mut request, username.as_bytes())?;
+        push_length_prefixed(&mut request, password.as_bytes())?;
+        stream
+            .write_all(&request)
+            .await
+            .map_err(|_| NetError::ProxyFailure)?;
+        match read_array(stream).await? {
+            [USERNAME_PASSWORD_VERSION, REPLY_SUCCEEDED] => {}
+            _ => return Err(NetError::ProxyFailure),
+        }
+    }
+
+    let mut request = vec![
+        SOCKS_VERSION,
+        COMMAND_CONNECT,
+        0x00,
+        ADDRESS_TYPE_DOMAIN_NAME,
+    ];
+    push_length_prefixed(&mut request, host.as_bytes())?;
+    request.extend_from_slice(&port.to_be_bytes());
+    stream
+        .write_all(&request)
+        .await
+        .map_err(|_| NetError::ProxyFailure)?;
+
+    let [version, reply, _reserved, address_type] = read_array(stream).await?;
+    if version != SOCKS_VERSION || reply != REPLY_SUCCEEDED {
+        return Err(NetError::ProxyFailure);
+    }
+    // the bound address is of no use to us, but it has to be consumed
+    let address_len = match address_type {
+        ADDRESS_TYPE_IPV4 => 4,
+        ADDRESS_TYPE_IPV6 => 16,
+        ADDRESS_TYPE_DOMAIN_NAME => {
+            let [len] = read_array(stream).await?;
+            len.into()
+        }
+        _ => return Err(NetError::ProxyFailure),
+    };
+    let mut bound_address_and_port = vec![0; address_len + 2];
+    stream
+        .read_exact(&mut bound_address_and_port)
+        .await
+        .map_err(|_| NetError::ProxyFailure)?;
+    Ok(())
+}
+
+async fn read_array<const N: usize, S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<[u8; N], NetError> {
+    let mut buf = [0; N];
+    stream
+        .read_exact(&mut buf)
+        .await
+        .map_err(|_| NetError::ProxyFailure)?;
+    Ok(buf)
+}
+
+fn push_length_prefixed(buf: &mut Vec<u8>, value: &[u8]) -> Result<(), NetError> {
+    let len = u8::try_from(value.len()).map_err(|_| NetError::ProxyFailure)?;
+    buf.push(len);
+    buf.extend_from_slice(value);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use crate::infra::errors::NetError;
+    use crate::infra::socks5::{handshake, ProxyAuth};
+
+    const HOST: &str = "chat.signal.org";
+    const PORT: u16 = 443;
+
+    async fn expect_bytes(stream: &mut DuplexStream, expected: &[u8]) {
+        let mut buf = vec![0; expected.len()];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, expected);
+    }
+
+    fn connect_request() -> Vec<u8> {
+        let mut request = vec![0x05, 0x01, 0x00, 0x03, HOST.len() as u8];
+        request.extend_from_slice(HOST.as_bytes());
+        request.extend_from_slice(&PORT.to_be_bytes());
+        request
+    }
+
+    #[tokio::test]
+    async fn handshake_without_auth() {
+        let (mut client, mut server) = duplex(1024);
+        let server_task = tokio::spawn(async move {
+            expect_bytes(&mut server, &[0x05, 0x01, 0x00]).await;
+            server.write_all(&[0x05, 0x00]).await.unwrap();
+            expect_bytes(&mut server, &connect_request()).await;
+            server
+                .write_all(&[0x05, 0x00, 0x00, 0x01, 127, 0, 0, 1, 0x1F, 0x90])
+                .await
+                .unwrap();
+        });
+        handshake(&mut client, None, HOST, PORT).await.unwrap();
+        server_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn handshake_with_auth() {
+        let (mut client, mut server) = duplex(1024);
+        let server_task = tokio::spawn(async move {
+            expect_bytes(&mut server, &[0x05, 0x01, 0x02]).await;
+            server.write_all(&[0x05, 0x02]).await.unwrap();
+            expect_bytes(&mut server, b"\x01\x04user\x08password").await;
+            server.write_all(&[0x01, 0x00]).await.unwrap();
+            expect_bytes(&mut server, &connect_request()).await;
+            server
+                .write_all(&[0x05, 0x00, 0x00, 0x03, 0x01, b'a', 0x1F, 0x90])
+                .await
+                .unwrap();
+        });
+        let auth = ProxyAuth {
+            username: "user".to_string(),
+            password: "password".to_string(),
+        };
+        handshake(&mut client, Some(&auth), HOST, PORT)
+            .await
+            .unwrap();
+        server_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejected_credentials_are_proxy_failure() {
+        let (mut client, mut server) = duplex(1024);
+        tokio::spawn(async move {
+            expect_bytes(&mut server, &[0x05, 0x01, 0x02]).await;
+            server.write_all(&[0x05, 0x02]).await.unwrap();
+            expect_bytes(&mut server, b"\x01\x04user\x05wrong").await;
+            server.write_all(&[0x01, 0x01]).await.unwrap();
+        });
+        let auth = ProxyAuth {
+            username: "user".to_string(),
+            password: "wrong".to_string(),
+        };
+        assert_matches!(
+            handshake(&mut client, Some(&auth), HOST, PORT).await,
+            Err(NetError::ProxyFailure)
+        );
+    }
+
+    #[tokio::test]
+    async fn refused_connection_is_proxy_failure() {
+        let (mut client, mut server) = duplex(1024);
+        tokio::spawn(async move {
+            expect_bytes(&mut server, &[0x05, 0x01, 0x00]).await;
+            server.write_all(&[0x05, 0x00]).await.unwrap();
+            expect_bytes(&mut server, &connect_request()).await;
+            // connection refused
+            server
+                .write_all(&[0x05, 0x05, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+        });
+        assert_matches!(
+            handshake(&mut client, None, HOST, PORT).await,
+            Err(NetError::ProxyFailure)
+        );
+    }
+}
@@ -49,9 +49,7 @@ pub(crate) async fn connect_websocket(
                 .unwrap(),
         );
 
-    let request_builder = connection_params
-        .http_request_decorator
-        .decorate_request(request_builder);
+    let request_builder = connection_params.decorate_request(request_builder);
 
     let (ws_stream, _response) = tokio_tungstenite::client_async_with_config(
         request_builder.body(()).expect("can get request body"),
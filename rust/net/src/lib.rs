@@ -9,4 +9,4 @@ pub mod env;
 pub mod infra;
 pub mod proto;
 
-mod utils;
+pub(crate) mod utils;
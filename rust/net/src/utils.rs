@@ -7,6 +7,8 @@ use base64::prelude::{Engine as _, BASE64_STANDARD};
 use std::future::Future;
 use std::time::Duration;
 
+use crate::infra::clock::{self, Clock, SystemClock};
+
 /// Constructs the value of the `Authorization` header for the `Basic` auth scheme.
 pub(crate) fn basic_authorization(username: &str, password: &str) -> String {
     let auth = BASE64_STANDARD.encode(format!("{}:{}", username, password).as_bytes());
@@ -24,8 +26,78 @@ pub async fn timeout<T, E, F>(duration: Duration, timeout_error: E, future: F) -
 where
     F: Future<Output = Result<T, E>>,
 {
-    match tokio::time::timeout(duration, future).await {
-        Ok(r) => r,
-        Err(_) => Err(timeout_error),
+    timeout_with_clock(&SystemClock, duration, timeout_error, future).await
+}
+
+/// Like [timeout], but the time is measured by the given [Clock].
+pub async fn timeout_with_clock<T, E, F>(
+    clock: &dyn Clock,
+    duration: Duration,
+    timeout_error: E,
+    future: F,
+) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    match clock::timeout(clock, duration, future).await {
+        Some(r) => r,
+        None => Err(timeout_error),
+    }
+}
+
+/// Like [timeout_with_clock], but the error for the timeout case is built by `timeout_error`
+/// from the time that has actually elapsed, e.g. to report the latency.
+pub async fn timeout_with_elapsed<T, E, F>(
+    clock: &dyn Clock,
+    duration: Duration,
+    timeout_error: impl FnOnce(Duration) -> E,
+    future: F,
+) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    let start = clock.now();
+    match clock::timeout(clock, duration, future).await {
+        Some(r) => r,
+        None => Err(timeout_error(clock.now() - start)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures_util::FutureExt;
+
+    use crate::infra::clock::{MockClock, SystemClock};
+    use crate::utils::{timeout_with_clock, timeout_with_elapsed};
+
+    #[tokio::test(start_paused = true)]
+    async fn timeout_error_carries_elapsed_time() {
+        let result: Result<(), Duration> = timeout_with_elapsed(
+            &SystemClock,
+            Duration::from_secs(5),
+            |elapsed| elapsed,
+            std::future::pending(),
+        )
+        .await;
+        let elapsed = result.expect_err("timed out");
+        assert!(elapsed >= Duration::from_secs(5), "{elapsed:?}");
+    }
+
+    #[test]
+    fn timeout_with_clock_fires_when_clock_advances() {
+        let clock = MockClock::new();
+        let mut result = timeout_with_clock(
+            &clock,
+            Duration::from_secs(5),
+            "timed out",
+            std::future::pending::<Result<(), &str>>(),
+        )
+        .boxed_local();
+
+        assert!((&mut result).now_or_never().is_none());
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(result.now_or_never(), Some(Err("timed out")));
     }
 }
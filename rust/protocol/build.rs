@@ -13,6 +13,11 @@ fn main() {
     ];
     let mut prost_build = prost_build::Config::new();
     prost_build.protoc_arg("--experimental_allow_proto3_optional");
+    // Applies to the nested message types as well.
+    prost_build.type_attribute(
+        ".signal.proto.storage.SessionStructure",
+        "#[cfg_attr(feature = \"cbor\", derive(serde::Serialize, serde::Deserialize))]",
+    );
     prost_build
         .compile_protos(&protos, &["src"])
         .expect("Protobufs in src are valid");
@@ -63,7 +63,8 @@ pub use protocol::{
 };
 pub use ratchet::{
     initialize_alice_session_record, initialize_bob_session_record, AliceSignalProtocolParameters,
-    BobSignalProtocolParameters,
+    BobSignalProtocolParameters, ChainKey, DecryptError, MessageEncryptionMode, MessageKeys,
+    TooManySkipped,
 };
 pub use sealed_sender::{
     sealed_sender_decrypt, sealed_sender_decrypt_to_usmc, sealed_sender_encrypt,
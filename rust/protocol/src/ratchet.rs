@@ -5,16 +5,23 @@
 
 mod keys;
 mod params;
+#[cfg(test)]
+pub(crate) mod test_util;
 
-pub(crate) use self::keys::{ChainKey, MessageKeys, RootKey};
+pub(crate) use self::keys::RootKey;
+pub use self::keys::{ChainKey, DecryptError, MessageEncryptionMode, MessageKeys, TooManySkipped};
 pub use self::params::{AliceSignalProtocolParameters, BobSignalProtocolParameters};
 use crate::protocol::{CIPHERTEXT_MESSAGE_CURRENT_VERSION, CIPHERTEXT_MESSAGE_PRE_KYBER_VERSION};
 use crate::state::SessionState;
 use crate::{KeyPair, Result, SessionRecord};
 use rand::{CryptoRng, Rng};
 
-fn derive_keys(has_kyber: bool, secret_input: &[u8]) -> (RootKey, ChainKey) {
-    let label = if has_kyber {
+/// Derives the initial keys of a session, with the KDF info selected by the `session_version`.
+///
+/// Both sides store the version they derived the keys with in the [SessionState], so a session
+/// whose sides disagree on the version can't decrypt anything.
+fn derive_keys(session_version: u8, secret_input: &[u8]) -> (RootKey, ChainKey) {
+    let label = if session_version >= CIPHERTEXT_MESSAGE_CURRENT_VERSION {
         b"WhisperText_X25519_SHA-256_CRYSTALS-KYBER-1024".as_slice()
     } else {
         b"WhisperText".as_slice()
@@ -22,7 +29,9 @@ fn derive_keys(has_kyber: bool, secret_input: &[u8]) -> (RootKey, ChainKey) {
     derive_keys_with_label(label, secret_input)
 }
 
-fn message_version(has_kyber: bool) -> u8 {
+/// Sessions that include a Kyber shared secret use the current version,
+/// the ones that don't use the last version before Kyber was introduced.
+fn session_version(has_kyber: bool) -> u8 {
     if has_kyber {
         CIPHERTEXT_MESSAGE_CURRENT_VERSION
     } else {
@@ -82,9 +91,9 @@ pub(crate) fn initialize_alice_session<R: Rng + CryptoRng>(
         secrets.extend_from_slice(ss.as_ref());
         ct
     });
-    let has_kyber = parameters.their_kyber_pre_key().is_some();
+    let version = session_version(parameters.their_kyber_pre_key().is_some());
 
-    let (root_key, chain_key) = derive_keys(has_kyber, &secrets);
+    let (root_key, chain_key) = derive_keys(version, &secrets);
 
     let (sending_chain_root_key, sending_chain_chain_key) = root_key.create_chain(
         parameters.their_ratchet_key(),
@@ -92,7 +101,7 @@ pub(crate) fn initialize_alice_session<R: Rng + CryptoRng>(
     )?;
 
     let mut session = SessionState::new(
-        message_version(has_kyber),
+        version,
         local_identity,
         parameters.their_identity_key(),
         &sending_chain_root_key,
@@ -100,6 +109,7 @@ pub(crate) fn initialize_alice_session<R: Rng + CryptoRng>(
     )
     .with_receiver_chain(parameters.their_ratchet_key(), &chain_key)
     .with_sender_chain(&sending_ratchet_key, &sending_chain_chain_key);
+    session.set_sender_chain_root_key(&root_key);
 
     if let Some(kyber_ciphertext) = kyber_ciphertext {
         session.set_kyber_ciphertext(kyber_ciphertext);
@@ -159,12 +169,12 @@ pub(crate) fn initialize_bob_session(
             panic!("Either both or none of the kyber key pair and ciphertext can be provided")
         }
     }
-    let has_kyber = parameters.our_kyber_pre_key_pair().is_some();
+    let version = session_version(parameters.our_kyber_pre_key_pair().is_some());
 
-    let (root_key, chain_key) = derive_keys(has_kyber, &secrets);
+    let (root_key, chain_key) = derive_keys(version, &secrets);
 
     let session = SessionState::new(
-        message_version(has_kyber),
+        version,
         local_identity,
         parameters.their_identity_key(),
         &root_key,
@@ -189,3 +199,86 @@ pub fn initialize_bob_session_record(
 ) -> Result<SessionRecord> {
     Ok(SessionRecord::new(initialize_bob_session(parameters)?))
 }
+
+#[cfg(test)]
+mod test {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::test_util::run_handshake;
+    use super::{
+        derive_keys, initialize_alice_session, initialize_bob_session, session_version,
+        AliceSignalProtocolParameters, BobSignalProtocolParameters,
+    };
+    use crate::protocol::{
+        CIPHERTEXT_MESSAGE_CURRENT_VERSION, CIPHERTEXT_MESSAGE_PRE_KYBER_VERSION,
+    };
+    use crate::Result;
+
+    #[test]
+    fn test_session_versions_use_distinct_derivations() {
+        let secret_input = [0x42; 32 * 4];
+        let (pre_kyber_root, pre_kyber_chain) =
+            derive_keys(CIPHERTEXT_MESSAGE_PRE_KYBER_VERSION, &secret_input);
+        let (kyber_root, kyber_chain) =
+            derive_keys(CIPHERTEXT_MESSAGE_CURRENT_VERSION, &secret_input);
+        assert_ne!(pre_kyber_root.key(), kyber_root.key());
+        assert_ne!(pre_kyber_chain.key(), kyber_chain.key());
+
+        assert_eq!(session_version(false), CIPHERTEXT_MESSAGE_PRE_KYBER_VERSION);
+        assert_eq!(session_version(true), CIPHERTEXT_MESSAGE_CURRENT_VERSION);
+    }
+
+    #[test]
+    fn test_handshake_stores_the_session_version() -> Result<()> {
+        let (alice_state, bob_state) = run_handshake()?;
+        let expected = u32::from(CIPHERTEXT_MESSAGE_PRE_KYBER_VERSION);
+        assert_eq!(alice_state.session_version()?, expected);
+        assert_eq!(bob_state.session_version()?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_message_from_alice_is_decrypted_by_bob() -> Result<()> {
+        let (alice_state, bob_state) = run_handshake()?;
+        assert_eq!(alice_state.alice_base_key(), bob_state.alice_base_key());
+
+        let alice_message_keys = alice_state.get_sender_chain_key()?.message_keys();
+        // Bob derives his receiver chain from Alice's ratchet key once her first message arrives.
+        let (_, bob_receiver_chain_key) = bob_state.root_key()?.create_chain(
+            &alice_state.sender_ratchet_key()?,
+            &bob_state.sender_ratchet_private_key()?,
+        )?;
+        let bob_message_keys = bob_receiver_chain_key.message_keys();
+
+        let ciphertext = alice_message_keys.encrypt(b"Hello, Bob!", b"associated data");
+        assert_eq!(
+            b"Hello, Bob!".as_slice(),
+            bob_message_keys.decrypt(&ciphertext, b"associated data")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parameters_from_the_same_seed_derive_the_same_keys() -> Result<()> {
+        let root_keys = |seed: [u8; 32]| -> Result<([u8; 32], [u8; 32])> {
+            let alice_state = initialize_alice_session(
+                &AliceSignalProtocolParameters::from_seed(&seed),
+                &mut StdRng::from_seed(seed),
+            )?;
+            let bob_state = initialize_bob_session(&BobSignalProtocolParameters::from_seed(&seed))?;
+            assert_eq!(alice_state.alice_base_key(), bob_state.alice_base_key());
+            Ok((*alice_state.root_key()?.key(), *bob_state.root_key()?.key()))
+        };
+
+        let (alice_root_key, bob_root_key) = root_keys([1; 32])?;
+        assert_eq!(root_keys([1; 32])?, (alice_root_key, bob_root_key));
+        // Alice has already taken her first ratchet step, Bob takes it once her message arrives
+        assert_ne!(alice_root_key, bob_root_key);
+
+        let (other_alice_root_key, other_bob_root_key) = root_keys([2; 32])?;
+        assert_ne!(other_alice_root_key, alice_root_key);
+        assert_ne!(other_bob_root_key, bob_root_key);
+        Ok(())
+    }
+}
@@ -3,12 +3,38 @@
 // SPDX-License-Identifier: AGPL-3.0-only
 //
 
+use aes_gcm_siv::aead::Aead;
+use aes_gcm_siv::{Aes256GcmSiv, KeyInit};
 use arrayref::array_ref;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
 
-use crate::{crypto, PrivateKey, PublicKey, Result};
+use crate::{
+    consts, crypto, CiphertextMessageType, PrivateKey, PublicKey, Result, SignalProtocolError,
+};
 use std::fmt;
 
-pub(crate) struct MessageKeys {
+/// The scheme used by [MessageKeys::encrypt_with_mode] and [MessageKeys::decrypt_with_mode].
+///
+/// The two schemes are not interoperable: a message must be decrypted with the same mode
+/// it was encrypted with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MessageEncryptionMode {
+    /// AES-256-CBC with a truncated HMAC-SHA256 tag, as used by the protocol.
+    #[default]
+    CbcHmac,
+    /// AES-256-GCM-SIV, which doesn't lose confidentiality beyond revealing repeated messages
+    /// if the same keys are ever used more than once (e.g. because a session store
+    /// was rolled back).
+    ///
+    /// Uses the cipher key and the first 12 bytes of the IV as the nonce; the MAC key is unused.
+    AesGcmSiv,
+}
+
+/// Keys used to encrypt and authenticate a single message.
+pub struct MessageKeys {
     cipher_key: [u8; 32],
     mac_key: [u8; 32],
     iv: [u8; 16],
@@ -40,28 +66,222 @@ impl MessageKeys {
     }
 
     #[inline]
-    pub(crate) fn cipher_key(&self) -> &[u8; 32] {
+    pub fn cipher_key(&self) -> &[u8; 32] {
         &self.cipher_key
     }
 
     #[inline]
-    pub(crate) fn mac_key(&self) -> &[u8; 32] {
+    pub fn mac_key(&self) -> &[u8; 32] {
         &self.mac_key
     }
 
     #[inline]
-    pub(crate) fn iv(&self) -> &[u8; 16] {
+    pub fn iv(&self) -> &[u8; 16] {
         &self.iv
     }
 
     #[inline]
-    pub(crate) fn counter(&self) -> u32 {
+    pub fn counter(&self) -> u32 {
         self.counter
     }
+
+    /// Length of the truncated HMAC-SHA256 tag appended by [MessageKeys::encrypt].
+    pub const MAC_LENGTH: usize = 8;
+
+    /// Encrypts `plaintext` with AES-256-CBC (PKCS#7 padding) and appends a truncated
+    /// HMAC-SHA256 of `associated_data || ciphertext`.
+    pub fn encrypt(&self, plaintext: &[u8], associated_data: &[u8]) -> Vec<u8> {
+        let mut ciphertext =
+            signal_crypto::aes_256_cbc_encrypt(plaintext, &self.cipher_key, &self.iv)
+                .expect("key and IV have valid lengths");
+        let mac = self.mac(associated_data, &ciphertext);
+        ciphertext.extend_from_slice(&mac);
+        ciphertext
+    }
+
+    /// Length of the authentication tag appended in [MessageEncryptionMode::AesGcmSiv].
+    pub const SIV_TAG_LENGTH: usize = 16;
+
+    /// Same as [MessageKeys::encrypt] for [MessageEncryptionMode::CbcHmac],
+    /// otherwise encrypts `plaintext` with the given scheme.
+    pub fn encrypt_with_mode(
+        &self,
+        mode: MessageEncryptionMode,
+        plaintext: &[u8],
+        associated_data: &[u8],
+    ) -> Vec<u8> {
+        match mode {
+            MessageEncryptionMode::CbcHmac => self.encrypt(plaintext, associated_data),
+            MessageEncryptionMode::AesGcmSiv => self
+                .siv_cipher()
+                .encrypt(
+                    self.siv_nonce(),
+                    aes_gcm_siv::aead::Payload {
+                        msg: plaintext,
+                        aad: associated_data,
+                    },
+                )
+                .expect("AES-GCM-SIV encryption should not fail for in-memory messages"),
+        }
+    }
+
+    /// Verifies and decrypts the output of [MessageKeys::encrypt_with_mode]
+    /// produced with the same `mode`.
+    ///
+    /// In [MessageEncryptionMode::AesGcmSiv], a ciphertext that fails authentication
+    /// is reported as [DecryptError::MacMismatch].
+    pub fn decrypt_with_mode(
+        &self,
+        mode: MessageEncryptionMode,
+        ciphertext: &[u8],
+        associated_data: &[u8],
+    ) -> std::result::Result<Vec<u8>, DecryptError> {
+        match mode {
+            MessageEncryptionMode::CbcHmac => self.decrypt(ciphertext, associated_data),
+            MessageEncryptionMode::AesGcmSiv => {
+                if ciphertext.len() < Self::SIV_TAG_LENGTH {
+                    return Err(DecryptError::BadCiphertextLength(ciphertext.len()));
+                }
+                self.siv_cipher()
+                    .decrypt(
+                        self.siv_nonce(),
+                        aes_gcm_siv::aead::Payload {
+                            msg: ciphertext,
+                            aad: associated_data,
+                        },
+                    )
+                    .map_err(|_| DecryptError::MacMismatch)
+            }
+        }
+    }
+
+    fn siv_cipher(&self) -> Aes256GcmSiv {
+        Aes256GcmSiv::new(&self.cipher_key.into())
+    }
+
+    fn siv_nonce(&self) -> &aes_gcm_siv::Nonce {
+        aes_gcm_siv::Nonce::from_slice(&self.iv[..12])
+    }
+
+    /// Verifies and decrypts the output of [MessageKeys::encrypt].
+    ///
+    /// The length is checked before the MAC, since it isn't secret, and the MAC is compared
+    /// in constant time, so that the time taken doesn't reveal how much of a forged MAC
+    /// was correct.
+    pub fn decrypt(
+        &self,
+        ciphertext: &[u8],
+        associated_data: &[u8],
+    ) -> std::result::Result<Vec<u8>, DecryptError> {
+        let body_len = ciphertext.len().saturating_sub(Self::MAC_LENGTH);
+        if body_len == 0 || body_len % Self::CBC_BLOCK_LENGTH != 0 {
+            return Err(DecryptError::BadCiphertextLength(ciphertext.len()));
+        }
+        let (body, their_mac) = ciphertext.split_at(body_len);
+        let our_mac = self.mac(associated_data, body);
+        if !bool::from(our_mac.ct_eq(their_mac)) {
+            return Err(DecryptError::MacMismatch);
+        }
+        signal_crypto::aes_256_cbc_decrypt(body, &self.cipher_key, &self.iv)
+            .map_err(|_| DecryptError::DecodeFailed)
+    }
+
+    /// The AES block size; the padded body of a [MessageKeys::encrypt] ciphertext
+    /// is a non-empty multiple of it.
+    const CBC_BLOCK_LENGTH: usize = 16;
+
+    fn mac(&self, associated_data: &[u8], ciphertext: &[u8]) -> [u8; Self::MAC_LENGTH] {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.mac_key)
+            .expect("HMAC-SHA256 should accept any size key");
+        mac.update(associated_data);
+        mac.update(ciphertext);
+        let mac = mac.finalize().into_bytes();
+        *array_ref![mac, 0, Self::MAC_LENGTH]
+    }
+}
+
+impl Drop for MessageKeys {
+    fn drop(&mut self) {
+        self.cipher_key.zeroize();
+        self.mac_key.zeroize();
+        self.iv.zeroize();
+    }
+}
+
+/// Why [MessageKeys::decrypt] or [MessageKeys::decrypt_with_mode] failed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecryptError {
+    /// The ciphertext is authenticated, but its authentication tag doesn't match,
+    /// i.e. the message was tampered with, forged, or encrypted with other keys
+    MacMismatch,
+    /// The ciphertext, of the given length, can't have been produced by the encryption,
+    /// e.g. because it was truncated
+    BadCiphertextLength(usize),
+    /// The ciphertext is authentic, but doesn't decrypt to a correctly padded plaintext
+    DecodeFailed,
+}
+
+impl fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MacMismatch => write!(f, "MAC verification failed"),
+            Self::BadCiphertextLength(length) => {
+                write!(f, "invalid ciphertext length {}", length)
+            }
+            Self::DecodeFailed => write!(f, "failed to decrypt"),
+        }
+    }
+}
+
+impl std::error::Error for DecryptError {}
+
+impl From<DecryptError> for SignalProtocolError {
+    fn from(error: DecryptError) -> Self {
+        match error {
+            DecryptError::MacMismatch => SignalProtocolError::InvalidMessage(
+                CiphertextMessageType::Whisper,
+                "MAC verification failed",
+            ),
+            // Not necessarily too short: a body that isn't a whole number of blocks is
+            // rejected as well.
+            DecryptError::BadCiphertextLength(_) => SignalProtocolError::InvalidMessage(
+                CiphertextMessageType::Whisper,
+                "invalid ciphertext length",
+            ),
+            DecryptError::DecodeFailed => SignalProtocolError::InvalidMessage(
+                CiphertextMessageType::Whisper,
+                "failed to decrypt",
+            ),
+        }
+    }
+}
+
+/// Returned by [ChainKey::advance_to] when reaching the requested index would skip
+/// more message keys than allowed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TooManySkipped {
+    pub skipped: u32,
+    pub max_skip: u32,
+}
+
+impl fmt::Display for TooManySkipped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cannot skip {} message keys, at most {} are allowed",
+            self.skipped, self.max_skip
+        )
+    }
 }
 
+impl std::error::Error for TooManySkipped {}
+
+/// A key in a symmetric-key ratchet chain, along with its index in the chain.
+///
+/// Each chain key produces the [MessageKeys] for the message with the same index,
+/// and the chain key for the next index.
 #[derive(Clone, Debug)]
-pub(crate) struct ChainKey {
+pub struct ChainKey {
     key: [u8; 32],
     index: u32,
 }
@@ -70,28 +290,63 @@ impl ChainKey {
     const MESSAGE_KEY_SEED: [u8; 1] = [0x01u8];
     const CHAIN_KEY_SEED: [u8; 1] = [0x02u8];
 
-    pub(crate) fn new(key: [u8; 32], index: u32) -> Self {
+    pub fn new(key: [u8; 32], index: u32) -> Self {
         Self { key, index }
     }
 
     #[inline]
-    pub(crate) fn key(&self) -> &[u8; 32] {
+    pub fn key(&self) -> &[u8; 32] {
         &self.key
     }
 
     #[inline]
-    pub(crate) fn index(&self) -> u32 {
+    pub fn index(&self) -> u32 {
         self.index
     }
 
-    pub(crate) fn next_chain_key(&self) -> Self {
+    /// Advances the chain, returning the chain key with the next index.
+    pub fn next_chain_key(&self) -> Self {
         Self {
             key: self.calculate_base_material(Self::CHAIN_KEY_SEED),
             index: self.index + 1,
         }
     }
 
-    pub(crate) fn message_keys(&self) -> MessageKeys {
+    /// Advances the chain to `index`, returning the chain key at `index` along with the
+    /// [MessageKeys] for the indices that were skipped on the way, in order.
+    ///
+    /// Only the keys for the last `MAX_MESSAGE_KEYS` (2000) skipped indices are kept, since
+    /// a session wouldn't store any older ones anyway. The keys are derived one at a time,
+    /// so memory use stays bounded however far the chain is advanced.
+    ///
+    /// Fails without doing any work if more than `max_skip` keys would be skipped, so that
+    /// a message with a huge counter can't make the receiver derive an unbounded number
+    /// of keys. If `index` isn't ahead of this key's index, nothing is skipped and this key
+    /// is returned as is.
+    pub fn advance_to(
+        &self,
+        index: u32,
+        max_skip: u32,
+    ) -> std::result::Result<(Self, Vec<MessageKeys>), TooManySkipped> {
+        let skipped = index.saturating_sub(self.index);
+        if skipped > max_skip {
+            return Err(TooManySkipped { skipped, max_skip });
+        }
+        let kept = skipped.min(consts::MAX_MESSAGE_KEYS as u32);
+        let first_kept_index = index - kept;
+        let mut chain_key = self.clone();
+        let mut skipped_keys = Vec::with_capacity(kept as usize);
+        while chain_key.index < index {
+            if chain_key.index >= first_kept_index {
+                skipped_keys.push(chain_key.message_keys());
+            }
+            chain_key = chain_key.next_chain_key();
+        }
+        Ok((chain_key, skipped_keys))
+    }
+
+    /// Derives the keys for the message whose counter is equal to this key's index.
+    pub fn message_keys(&self) -> MessageKeys {
         MessageKeys::derive_keys(
             &self.calculate_base_material(Self::MESSAGE_KEY_SEED),
             self.index,
@@ -103,6 +358,12 @@ impl ChainKey {
     }
 }
 
+impl Drop for ChainKey {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct RootKey {
     key: [u8; 32],
@@ -117,6 +378,10 @@ impl RootKey {
         &self.key
     }
 
+    /// Performs a DH ratchet step (`KDF_RK` in the Double Ratchet specification).
+    ///
+    /// Derives the next root key and a new chain key (at index 0) from this root key and
+    /// the agreement between `their_ratchet_key` and `our_ratchet_key`.
     pub(crate) fn create_chain(
         self,
         their_ratchet_key: &PublicKey,
@@ -140,6 +405,12 @@ impl RootKey {
     }
 }
 
+impl Drop for RootKey {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
 impl fmt::Display for RootKey {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", hex::encode(self.key))
@@ -148,6 +419,8 @@ impl fmt::Display for RootKey {
 
 #[cfg(test)]
 mod tests {
+    use hex_literal::hex;
+
     use super::*;
 
     #[test]
@@ -184,4 +457,293 @@ mod tests {
         assert_eq!(1, chain_key.next_chain_key().message_keys().counter());
         Ok(())
     }
+
+    #[test]
+    fn test_chain_key_derivation_after_skip() {
+        let seed = hex!("8ab72d6f4cc5ac0d387eaf463378ddb28edd07385b1cb01250c715982e7ad48f");
+        let chain_key = (0..2000).fold(ChainKey::new(seed, 0), |chain_key, _| {
+            chain_key.next_chain_key()
+        });
+
+        assert_eq!(2000, chain_key.index());
+        assert_eq!(
+            &hex!("4611aaf58b0006ce4d0822656ffb2f9b01ec37e487a4000f0fd1d31e53cca3e3"),
+            chain_key.key()
+        );
+        let message_keys = chain_key.message_keys();
+        assert_eq!(2000, message_keys.counter());
+        assert_eq!(
+            &hex!("088db2c8c3665e353740909837680ef5ebd235f343c3216170b2499d4f4c00fc"),
+            message_keys.cipher_key()
+        );
+        assert_eq!(
+            &hex!("3f3f97aff7980762dd5efd43edd3fe6404fe64e9506fe478fe5e67da10353b16"),
+            message_keys.mac_key()
+        );
+        assert_eq!(&hex!("fddc5082eb67fa560aff2c067e10ba32"), message_keys.iv());
+    }
+
+    #[test]
+    #[allow(unsafe_code)]
+    fn test_keys_are_zeroized_on_drop() {
+        use std::mem::ManuallyDrop;
+
+        // ManuallyDrop keeps the storage alive after running the destructor in place,
+        // so the key bytes can still be inspected.
+        let mut message_keys = ManuallyDrop::new(MessageKeys::new([1; 32], [2; 32], [3; 16], 7));
+        unsafe { ManuallyDrop::drop(&mut message_keys) };
+        assert_eq!([0; 32], message_keys.cipher_key);
+        assert_eq!([0; 32], message_keys.mac_key);
+        assert_eq!([0; 16], message_keys.iv);
+
+        let mut chain_key = ManuallyDrop::new(ChainKey::new([4; 32], 0));
+        unsafe { ManuallyDrop::drop(&mut chain_key) };
+        assert_eq!([0; 32], chain_key.key);
+
+        let mut root_key = ManuallyDrop::new(RootKey::new([5; 32]));
+        unsafe { ManuallyDrop::drop(&mut root_key) };
+        assert_eq!([0; 32], root_key.key);
+    }
+
+    #[test]
+    fn test_message_keys_encrypt_decrypt() -> Result<()> {
+        let cipher_key = hex!("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f");
+        let mac_key = hex!("202122232425262728292a2b2c2d2e2f303132333435363738393a3b3c3d3e3f");
+        let iv = hex!("404142434445464748494a4b4c4d4e4f");
+        let message_keys = MessageKeys::new(cipher_key, mac_key, iv, 0);
+
+        let ciphertext = message_keys.encrypt(b"Hello, Signal!", b"associated data");
+        assert_eq!(
+            hex!("2dc749cba6e5e2ce068330ca56e73c9c828d709b3670c334").as_slice(),
+            ciphertext
+        );
+        assert_eq!(
+            b"Hello, Signal!".as_slice(),
+            message_keys.decrypt(&ciphertext, b"associated data")?
+        );
+
+        assert_eq!(
+            Err(DecryptError::MacMismatch),
+            message_keys.decrypt(&ciphertext, b"other data")
+        );
+        let mut tampered = ciphertext.clone();
+        tampered[0] ^= 1;
+        assert_eq!(
+            Err(DecryptError::MacMismatch),
+            message_keys.decrypt(&tampered, b"associated data")
+        );
+        assert_eq!(
+            Err(DecryptError::BadCiphertextLength(4)),
+            message_keys.decrypt(&ciphertext[..4], b"associated data")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_message_keys_decrypt_errors() {
+        let message_keys = MessageKeys::new([1; 32], [2; 32], [3; 16], 0);
+        let ciphertext = message_keys.encrypt(b"Hello, Signal!", b"ad");
+        let mac_start = ciphertext.len() - MessageKeys::MAC_LENGTH;
+
+        for i in mac_start..ciphertext.len() {
+            let mut forged = ciphertext.clone();
+            forged[i] ^= 0x80;
+            assert_eq!(
+                Err(DecryptError::MacMismatch),
+                message_keys.decrypt(&forged, b"ad"),
+                "byte {i} of the MAC flipped"
+            );
+        }
+
+        for len in [
+            0,
+            1,
+            MessageKeys::MAC_LENGTH,
+            mac_start,
+            ciphertext.len() - 1,
+        ] {
+            assert_eq!(
+                Err(DecryptError::BadCiphertextLength(len)),
+                message_keys.decrypt(&ciphertext[..len], b"ad"),
+                "truncated to {len} bytes"
+            );
+        }
+        let mut extended = ciphertext.clone();
+        extended.push(0);
+        assert_eq!(
+            Err(DecryptError::BadCiphertextLength(ciphertext.len() + 1)),
+            message_keys.decrypt(&extended, b"ad")
+        );
+        assert!(matches!(
+            SignalProtocolError::from(DecryptError::BadCiphertextLength(extended.len())),
+            SignalProtocolError::InvalidMessage(
+                CiphertextMessageType::Whisper,
+                "invalid ciphertext length"
+            )
+        ));
+
+        // An authentic body that doesn't end with valid padding: the first block of an
+        // encrypted all-zero block decrypts to zeros, without the padding block.
+        let mut unpadded = signal_crypto::aes_256_cbc_encrypt(
+            &[0; 16],
+            message_keys.cipher_key(),
+            message_keys.iv(),
+        )
+        .expect("valid key and IV");
+        unpadded.truncate(16);
+        let mac = message_keys.mac(b"ad", &unpadded);
+        unpadded.extend_from_slice(&mac);
+        assert_eq!(
+            Err(DecryptError::DecodeFailed),
+            message_keys.decrypt(&unpadded, b"ad")
+        );
+    }
+
+    #[test]
+    fn test_message_keys_siv_round_trip() -> Result<()> {
+        let message_keys = MessageKeys::new([1; 32], [2; 32], [3; 16], 0);
+        let mode = MessageEncryptionMode::AesGcmSiv;
+
+        let plaintexts: [&[u8]; 3] = [b"", b"Hello, Signal!", &[0xAB; 1000]];
+        for plaintext in plaintexts {
+            let ciphertext = message_keys.encrypt_with_mode(mode, plaintext, b"associated data");
+            assert_eq!(
+                plaintext.len() + MessageKeys::SIV_TAG_LENGTH,
+                ciphertext.len()
+            );
+            assert_eq!(
+                plaintext,
+                message_keys.decrypt_with_mode(mode, &ciphertext, b"associated data")?
+            );
+        }
+
+        let ciphertext = message_keys.encrypt_with_mode(mode, b"Hello, Signal!", b"ad");
+        assert_eq!(
+            Err(DecryptError::MacMismatch),
+            message_keys.decrypt_with_mode(mode, &ciphertext, b"other data")
+        );
+        let mut tampered = ciphertext.clone();
+        tampered[0] ^= 1;
+        assert_eq!(
+            Err(DecryptError::MacMismatch),
+            message_keys.decrypt_with_mode(mode, &tampered, b"ad")
+        );
+        assert_eq!(
+            Err(DecryptError::BadCiphertextLength(4)),
+            message_keys.decrypt_with_mode(mode, &ciphertext[..4], b"ad")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_message_encryption_modes_are_not_interoperable() -> Result<()> {
+        let message_keys = MessageKeys::new([1; 32], [2; 32], [3; 16], 0);
+
+        let cbc = message_keys.encrypt_with_mode(MessageEncryptionMode::CbcHmac, b"Hi", b"ad");
+        assert_eq!(message_keys.encrypt(b"Hi", b"ad"), cbc);
+        assert_eq!(
+            b"Hi".as_slice(),
+            message_keys.decrypt_with_mode(MessageEncryptionMode::default(), &cbc, b"ad")?
+        );
+        assert!(message_keys
+            .decrypt_with_mode(MessageEncryptionMode::AesGcmSiv, &cbc, b"ad")
+            .is_err());
+
+        let siv = message_keys.encrypt_with_mode(MessageEncryptionMode::AesGcmSiv, b"Hi", b"ad");
+        assert!(message_keys.decrypt(&siv, b"ad").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_root_key_create_chain() -> Result<()> {
+        let root_seed = hex!("7ba6debc2bc1bbf91abbc1367404176ca623095b7ec66b45f602d93538942dcc");
+        let alice_private = PrivateKey::deserialize(&hex!(
+            "216822ec67eb38049ebae7b939baeaebb151bbb32db80fd389245ac37a948e50"
+        ))?;
+        let alice_public = PublicKey::deserialize(&hex!(
+            "05add6f5f5abe07387d9fb3ad55f7285a07de2d889bef4bb8ed201643c6c42656f"
+        ))?;
+        let bob_private = PrivateKey::deserialize(&hex!(
+            "58ab3e4dc1b7a2ea1a06e4f8a2f1d8ef44f0c5b1ad5ec3b9e1b7d4c3a8e1f260"
+        ))?;
+        let bob_public = PublicKey::deserialize(&hex!(
+            "054a544c79fd1e0700b9dce75faca7bd3c88a2f3ee4af79052cea8148c94199c30"
+        ))?;
+        assert_eq!(alice_public, alice_private.public_key()?);
+        assert_eq!(bob_public, bob_private.public_key()?);
+
+        let next_root_key =
+            hex!("3fc9be4da1de1933f6d5fe313f5ed53dd52083707a6a27052eacbe412957e75d");
+        let next_chain_key =
+            hex!("07eb045d1dc96419c72fd4843d65609fa96b7ef6049aca278c95dad379bcd23d");
+
+        let (alice_root_key, alice_chain_key) =
+            RootKey::new(root_seed).create_chain(&bob_public, &alice_private)?;
+        assert_eq!(&next_root_key, alice_root_key.key());
+        assert_eq!(&next_chain_key, alice_chain_key.key());
+        assert_eq!(0, alice_chain_key.index());
+
+        // Both sides of the agreement derive the same keys.
+        let (bob_root_key, bob_chain_key) =
+            RootKey::new(root_seed).create_chain(&alice_public, &bob_private)?;
+        assert_eq!(&next_root_key, bob_root_key.key());
+        assert_eq!(&next_chain_key, bob_chain_key.key());
+        Ok(())
+    }
+
+    #[test]
+    fn test_chain_key_advance_to() {
+        let chain_key = ChainKey::new([7; 32], 3);
+
+        let (advanced, skipped) = chain_key.advance_to(6, 3).expect("within the cap");
+        assert_eq!(advanced.index(), 6);
+        assert_eq!(
+            advanced.key(),
+            chain_key
+                .next_chain_key()
+                .next_chain_key()
+                .next_chain_key()
+                .key()
+        );
+        assert_eq!(
+            skipped.iter().map(MessageKeys::counter).collect::<Vec<_>>(),
+            vec![3, 4, 5]
+        );
+        assert_eq!(
+            skipped[1].cipher_key(),
+            chain_key.next_chain_key().message_keys().cipher_key()
+        );
+
+        assert_eq!(
+            chain_key.advance_to(7, 3).unwrap_err(),
+            TooManySkipped {
+                skipped: 4,
+                max_skip: 3
+            }
+        );
+
+        let (unchanged, skipped) = chain_key.advance_to(3, 0).expect("nothing to skip");
+        assert_eq!(unchanged.index(), 3);
+        assert!(skipped.is_empty());
+    }
+
+    #[test]
+    fn test_chain_key_advance_to_keeps_only_the_last_message_keys() {
+        let chain_key = ChainKey::new([7; 32], 0);
+        let far_index = 3 * consts::MAX_MESSAGE_KEYS as u32;
+
+        let (advanced, skipped) = chain_key
+            .advance_to(far_index, far_index)
+            .expect("within the cap");
+        assert_eq!(advanced.index(), far_index);
+        assert_eq!(skipped.len(), consts::MAX_MESSAGE_KEYS);
+        assert_eq!(
+            skipped.first().map(MessageKeys::counter),
+            Some(far_index - consts::MAX_MESSAGE_KEYS as u32)
+        );
+        assert_eq!(
+            skipped.last().map(MessageKeys::counter),
+            Some(far_index - 1)
+        );
+    }
 }
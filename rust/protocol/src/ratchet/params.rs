@@ -5,6 +5,35 @@
 
 use crate::{kem, IdentityKey, IdentityKeyPair, KeyPair, PublicKey};
 
+#[cfg(any(test, feature = "test-util"))]
+use seeded::*;
+
+#[cfg(any(test, feature = "test-util"))]
+mod seeded {
+    use crate::{KeyPair, PrivateKey};
+
+    /// Labels of the keys derived by [AliceSignalProtocolParameters::from_seed] and
+    /// [BobSignalProtocolParameters::from_seed].
+    ///
+    /// [AliceSignalProtocolParameters::from_seed]: super::AliceSignalProtocolParameters::from_seed
+    /// [BobSignalProtocolParameters::from_seed]: super::BobSignalProtocolParameters::from_seed
+    pub(super) const ALICE_IDENTITY_KEY_LABEL: &[u8] = b"Alice identity key";
+    pub(super) const ALICE_BASE_KEY_LABEL: &[u8] = b"Alice base key";
+    pub(super) const BOB_IDENTITY_KEY_LABEL: &[u8] = b"Bob identity key";
+    pub(super) const BOB_SIGNED_PRE_KEY_LABEL: &[u8] = b"Bob signed pre-key";
+    pub(super) const BOB_ONE_TIME_PRE_KEY_LABEL: &[u8] = b"Bob one-time pre-key";
+
+    /// Derives the key pair identified by `label` from `seed`.
+    pub(super) fn key_pair_from_seed(seed: &[u8; 32], label: &[u8]) -> KeyPair {
+        let mut private_key = [0; 32];
+        hkdf::Hkdf::<sha2::Sha256>::new(None, seed)
+            .expand(label, &mut private_key)
+            .expect("valid length");
+        let private_key = PrivateKey::deserialize(&private_key).expect("valid private key");
+        KeyPair::try_from(private_key).expect("can derive public key")
+    }
+}
+
 pub struct AliceSignalProtocolParameters {
     our_identity_key_pair: IdentityKeyPair,
     our_base_key_pair: KeyPair,
@@ -35,6 +64,32 @@ impl AliceSignalProtocolParameters {
         }
     }
 
+    /// Creates Alice's parameters with all the keys of both sides derived from `seed`, matching
+    /// the parameters created by [BobSignalProtocolParameters::from_seed] from the same seed.
+    ///
+    /// Sessions set up this way are reproducible, e.g. to generate test vectors or to shrink
+    /// fuzzing inputs. Alice's first sending ratchet key comes from the RNG passed to
+    /// [initialize_alice_session_record](crate::initialize_alice_session_record), so it has to be
+    /// seeded as well. There is a one-time pre-key, but no Kyber pre-key, since Kyber
+    /// encapsulation always uses fresh randomness.
+    ///
+    /// Anyone who knows the seed knows all the private keys, so this is only available for
+    /// testing, with the `test-util` feature.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn from_seed(seed: &[u8; 32]) -> Self {
+        let bob_signed_pre_key = key_pair_from_seed(seed, BOB_SIGNED_PRE_KEY_LABEL).public_key;
+        Self::new(
+            key_pair_from_seed(seed, ALICE_IDENTITY_KEY_LABEL).into(),
+            key_pair_from_seed(seed, ALICE_BASE_KEY_LABEL),
+            IdentityKey::new(key_pair_from_seed(seed, BOB_IDENTITY_KEY_LABEL).public_key),
+            bob_signed_pre_key,
+            bob_signed_pre_key,
+        )
+        .with_their_one_time_pre_key(
+            key_pair_from_seed(seed, BOB_ONE_TIME_PRE_KEY_LABEL).public_key,
+        )
+    }
+
     pub fn set_their_one_time_pre_key(&mut self, ec_public: PublicKey) {
         self.their_one_time_pre_key = Some(ec_public);
     }
@@ -125,6 +180,26 @@ impl<'a> BobSignalProtocolParameters<'a> {
         }
     }
 
+    /// Creates Bob's parameters with all the keys of both sides derived from `seed`, matching
+    /// the parameters created by [AliceSignalProtocolParameters::from_seed] from the same seed.
+    ///
+    /// Anyone who knows the seed knows all the private keys, so this is only available for
+    /// testing, with the `test-util` feature.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn from_seed(seed: &[u8; 32]) -> Self {
+        let signed_pre_key = key_pair_from_seed(seed, BOB_SIGNED_PRE_KEY_LABEL);
+        Self::new(
+            key_pair_from_seed(seed, BOB_IDENTITY_KEY_LABEL).into(),
+            signed_pre_key,
+            Some(key_pair_from_seed(seed, BOB_ONE_TIME_PRE_KEY_LABEL)),
+            signed_pre_key,
+            None,
+            IdentityKey::new(key_pair_from_seed(seed, ALICE_IDENTITY_KEY_LABEL).public_key),
+            key_pair_from_seed(seed, ALICE_BASE_KEY_LABEL).public_key,
+            None,
+        )
+    }
+
     #[inline]
     pub fn our_identity_key_pair(&self) -> &IdentityKeyPair {
         &self.our_identity_key_pair
@@ -0,0 +1,48 @@
+//
+// Copyright 2024 Signal Messenger, LLC.
+// SPDX-License-Identifier: AGPL-3.0-only
+//
+
+use rand::rngs::OsRng;
+
+use crate::ratchet::{
+    initialize_alice_session, initialize_bob_session, AliceSignalProtocolParameters,
+    BobSignalProtocolParameters,
+};
+use crate::state::SessionState;
+use crate::{IdentityKeyPair, KeyPair, Result};
+
+/// Sets up a session between Alice and Bob from freshly generated keys, without
+/// a one-time pre-key or a Kyber pre-key.
+///
+/// Returns Alice's session state, followed by Bob's.
+pub(crate) fn run_handshake() -> Result<(SessionState, SessionState)> {
+    let mut csprng = OsRng;
+
+    let alice_identity = IdentityKeyPair::generate(&mut csprng);
+    let alice_base_key = KeyPair::generate(&mut csprng);
+    let bob_identity = IdentityKeyPair::generate(&mut csprng);
+    let bob_signed_pre_key = KeyPair::generate(&mut csprng);
+
+    let alice_parameters = AliceSignalProtocolParameters::new(
+        alice_identity,
+        alice_base_key,
+        *bob_identity.identity_key(),
+        bob_signed_pre_key.public_key,
+        bob_signed_pre_key.public_key,
+    );
+    let bob_parameters = BobSignalProtocolParameters::new(
+        bob_identity,
+        bob_signed_pre_key,
+        None,
+        bob_signed_pre_key,
+        None,
+        *alice_identity.identity_key(),
+        alice_base_key.public_key,
+        None,
+    );
+
+    let alice_state = initialize_alice_session(&alice_parameters, &mut csprng)?;
+    let bob_state = initialize_bob_session(&bob_parameters)?;
+    Ok((alice_state, bob_state))
+}
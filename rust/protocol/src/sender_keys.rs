@@ -583,3 +583,42 @@ mod sender_key_record_add_sender_key_state_tests {
         context.assert_record_order(vec![record_key_1, record_key_2]);
     }
 }
+
+#[cfg(test)]
+mod sender_chain_key_tests {
+    use hex_literal::hex;
+
+    use super::*;
+
+    #[test]
+    fn derivation_matches_test_vectors() {
+        let chain_key = SenderChainKey::new(0, (0u8..32).collect());
+
+        let message_key = chain_key.sender_message_key();
+        assert_eq!(message_key.iteration(), 0);
+        assert_eq!(
+            message_key.seed,
+            hex!("9b4c8120a4823a95f47cde17a244f4507244ee6e3957d1fab9fa29b44d3829b7")
+        );
+        assert_eq!(message_key.iv(), hex!("ed1f5e26325b1399f6a34c76e47ff047"));
+        assert_eq!(
+            message_key.cipher_key(),
+            hex!("d89f10a08215e845ceb4df3fc59c052ad09e01cd499650025ff83df48ed656e6")
+        );
+
+        let next = chain_key.next();
+        assert_eq!(next.iteration(), 1);
+        assert_eq!(
+            next.seed(),
+            hex!("4304c22c84a53755ab08ead8d97a8d429be5efa480682d7ad1da27f73e1fbe1d")
+        );
+
+        let message_key = next.sender_message_key();
+        assert_eq!(message_key.iteration(), 1);
+        assert_eq!(message_key.iv(), hex!("574f6661ef5e42e6d75902e5d361e82e"));
+        assert_eq!(
+            message_key.cipher_key(),
+            hex!("884e6819a3409f789eab3bf21b662b9263d03c62709a800906823961bd3dcb22")
+        );
+    }
+}
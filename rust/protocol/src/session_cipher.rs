@@ -664,6 +664,7 @@ fn get_or_create_chain_key<R: Rng + CryptoRng>(
         .create_chain(their_ephemeral, &our_new_ephemeral.private_key)?;
 
     state.set_root_key(&sender_chain.0);
+    state.set_sender_chain_root_key(&receiver_chain.0);
     state.add_receiver_chain(their_ephemeral, &receiver_chain.1);
 
     let current_index = state.get_sender_chain_key()?.index();
@@ -704,18 +705,25 @@ fn get_or_create_message_key(
 
     assert!(chain_index <= counter);
 
-    let jump = (counter - chain_index) as usize;
+    let jump = counter - chain_index;
+    let max_forward_jumps = MAX_FORWARD_JUMPS as u32;
 
-    if jump > MAX_FORWARD_JUMPS {
-        if state.session_with_self()? {
-            log::info!(
-                "{} Jumping ahead {} messages (index: {}, counter: {})",
-                remote_address,
-                jump,
-                chain_index,
-                counter
-            );
-        } else {
+    let max_skip = if jump > max_forward_jumps && state.session_with_self()? {
+        log::info!(
+            "{} Jumping ahead {} messages (index: {}, counter: {})",
+            remote_address,
+            jump,
+            chain_index,
+            counter
+        );
+        jump
+    } else {
+        max_forward_jumps
+    };
+
+    let (chain_key, skipped_keys) = match chain_key.advance_to(counter, max_skip) {
+        Ok(advanced) => advanced,
+        Err(_) => {
             log::error!(
                 "{} Exceeded future message limit: {}, index: {}, counter: {})",
                 remote_address,
@@ -728,14 +736,10 @@ fn get_or_create_message_key(
                 "message from too far into the future",
             ));
         }
-    }
-
-    let mut chain_key = chain_key.clone();
+    };
 
-    while chain_key.index() < counter {
-        let message_keys = chain_key.message_keys();
-        state.set_message_keys(their_ephemeral, &message_keys)?;
-        chain_key = chain_key.next_chain_key();
+    for message_keys in &skipped_keys {
+        state.set_message_keys(their_ephemeral, message_keys)?;
     }
 
     state.set_receiver_chain_key(their_ephemeral, &chain_key.next_chain_key())?;
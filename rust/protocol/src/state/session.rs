@@ -7,7 +7,9 @@ use std::result::Result;
 use std::time::{Duration, SystemTime};
 
 use prost::Message;
+use rand::{CryptoRng, Rng};
 use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
 
 use crate::ratchet::{ChainKey, MessageKeys, RootKey};
 use crate::{kem, IdentityKey, KeyPair, PrivateKey, PublicKey, SignalProtocolError};
@@ -98,6 +100,33 @@ impl SessionState {
         Self { session }
     }
 
+    /// Encodes the state, including the ratchet keys, chain indices, and skipped message keys,
+    /// as a `SessionStructure` protobuf.
+    pub(crate) fn serialize(&self) -> Vec<u8> {
+        self.session.encode_to_vec()
+    }
+
+    pub(crate) fn deserialize(bytes: &[u8]) -> Result<Self, InvalidSessionError> {
+        let session = SessionStructure::decode(bytes)
+            .map_err(|_| InvalidSessionError("failed to decode session state protobuf"))?;
+        Ok(Self { session })
+    }
+
+    /// Encodes the same content as [SessionState::serialize] as CBOR with named fields.
+    #[cfg(feature = "cbor")]
+    pub(crate) fn serialize_cbor(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&self.session, &mut bytes).expect("can write to Vec");
+        bytes
+    }
+
+    #[cfg(feature = "cbor")]
+    pub(crate) fn deserialize_cbor(bytes: &[u8]) -> Result<Self, InvalidSessionError> {
+        let session = ciborium::de::from_reader(bytes)
+            .map_err(|_| InvalidSessionError("failed to decode session state CBOR"))?;
+        Ok(Self { session })
+    }
+
     pub(crate) fn new(
         version: u8,
         our_identity: &IdentityKey,
@@ -119,6 +148,7 @@ impl SessionState {
                 remote_registration_id: 0,
                 local_registration_id: 0,
                 alice_base_key: alice_base_key.serialize().into_vec(),
+                sender_chain_root_key: vec![],
             },
         }
     }
@@ -145,7 +175,8 @@ impl SessionState {
         }
     }
 
-    pub(crate) fn remote_identity_key_bytes(&self) -> Result<Option<Vec<u8>>, InvalidSessionError> {
+    /// The serialized public identity key of the remote party, if known.
+    pub(crate) fn remote_identity_public(&self) -> Result<Option<Vec<u8>>, InvalidSessionError> {
         Ok(self.remote_identity_key()?.map(|k| k.serialize().to_vec()))
     }
 
@@ -154,13 +185,14 @@ impl SessionState {
             .map_err(|_| InvalidSessionError("invalid local identity key"))
     }
 
-    pub(crate) fn local_identity_key_bytes(&self) -> Result<Vec<u8>, InvalidSessionError> {
+    /// The serialized public identity key of the local party.
+    pub(crate) fn local_identity_public(&self) -> Result<Vec<u8>, InvalidSessionError> {
         Ok(self.local_identity_key()?.serialize().to_vec())
     }
 
     pub(crate) fn session_with_self(&self) -> Result<bool, InvalidSessionError> {
-        if let Some(remote_id) = self.remote_identity_key_bytes()? {
-            let local_id = self.local_identity_key_bytes()?;
+        if let Some(remote_id) = self.remote_identity_public()? {
+            let local_id = self.local_identity_public()?;
             return Ok(remote_id == local_id);
         }
 
@@ -187,6 +219,48 @@ impl SessionState {
         self.session.root_key = root_key.key().to_vec();
     }
 
+    /// Records the root key that the current sender chain was derived from,
+    /// see [SessionState::force_ratchet_step].
+    pub(crate) fn set_sender_chain_root_key(&mut self, root_key: &RootKey) {
+        self.session.sender_chain_root_key = root_key.key().to_vec();
+    }
+
+    /// Replaces the sending ratchet key pair, and the sender chain derived from it, with fresh
+    /// ones, so that the next message advertises a ratchet key that was never used before.
+    ///
+    /// The new chain is derived with [RootKey::create_chain] from the same root key and remote
+    /// ratchet key as the chain it replaces, so the remote party derives it the same way when
+    /// the next message arrives. That's only possible while no message has been sent on the
+    /// current sender chain: once the remote party may have seen the current ratchet key,
+    /// the ratchet can only move forward after receiving a new ratchet key from them.
+    pub(crate) fn force_ratchet_step<R: Rng + CryptoRng>(
+        &mut self,
+        csprng: &mut R,
+    ) -> Result<(), SignalProtocolError> {
+        if self.get_sender_chain_key()?.index() != 0 {
+            return Err(SignalProtocolError::InvalidState(
+                "force_ratchet_step",
+                "messages were already sent with the current ratchet key".into(),
+            ));
+        }
+        let root_key_bytes = self.session.sender_chain_root_key[..]
+            .try_into()
+            .map_err(|_| InvalidSessionError("unknown root key of the sender chain"))?;
+        let root_key = RootKey::new(root_key_bytes);
+        let their_ratchet_key = match self.session.receiver_chains.last() {
+            Some(chain) => PublicKey::deserialize(&chain.sender_ratchet_key)
+                .map_err(|_| InvalidSessionError("invalid receiver chain ratchet key"))?,
+            None => return Err(InvalidSessionError("missing receiver chain").into()),
+        };
+
+        let our_new_ephemeral = KeyPair::generate(csprng);
+        let (new_root_key, sender_chain_key) =
+            root_key.create_chain(&their_ratchet_key, &our_new_ephemeral.private_key)?;
+        self.set_root_key(&new_root_key);
+        self.set_sender_chain(&our_new_ephemeral, &sender_chain_key);
+        Ok(())
+    }
+
     pub(crate) fn sender_ratchet_key(&self) -> Result<PublicKey, InvalidSessionError> {
         match self.session.sender_chain {
             None => Err(InvalidSessionError("missing sender chain")),
@@ -195,6 +269,14 @@ impl SessionState {
         }
     }
 
+    /// The serialized public half of the ratchet key currently advertised to the remote party.
+    ///
+    /// Unlike [Self::sender_ratchet_private_key], this never touches the private half of the key,
+    /// so the result can be handed out freely.
+    pub(crate) fn current_ratchet_public(&self) -> Result<Vec<u8>, InvalidSessionError> {
+        Ok(self.sender_ratchet_key()?.serialize().to_vec())
+    }
+
     pub(crate) fn sender_ratchet_key_for_logging(&self) -> Result<String, InvalidSessionError> {
         Ok(hex::encode(
             self.sender_ratchet_key()?
@@ -438,6 +520,25 @@ impl SessionState {
         Ok(())
     }
 
+    /// Returns how many [MessageKeys] of skipped messages are cached, across all receiver chains.
+    pub(crate) fn skipped_key_count(&self) -> usize {
+        self.session
+            .receiver_chains
+            .iter()
+            .map(|chain| chain.message_keys.len())
+            .sum()
+    }
+
+    /// Removes all cached [MessageKeys] of skipped messages, zeroizing them.
+    ///
+    /// The skipped messages can't be decrypted afterwards.
+    pub(crate) fn clear_skipped_keys(&mut self) {
+        for chain in &mut self.session.receiver_chains {
+            chain.message_keys.iter_mut().for_each(zeroize_message_key);
+            chain.message_keys.clear();
+        }
+    }
+
     pub(crate) fn set_receiver_chain_key(
         &mut self,
         sender: &PublicKey,
@@ -543,6 +644,12 @@ impl SessionState {
     }
 }
 
+fn zeroize_message_key(message_key: &mut session_structure::chain::MessageKey) {
+    message_key.cipher_key.zeroize();
+    message_key.mac_key.zeroize();
+    message_key.iv.zeroize();
+}
+
 impl From<SessionStructure> for SessionState {
     fn from(value: SessionStructure) -> SessionState {
         SessionState::from_session_structure(value)
@@ -634,11 +741,9 @@ impl SessionRecord {
     pub(crate) fn previous_session_states(
         &self,
     ) -> impl ExactSizeIterator<Item = Result<SessionState, InvalidSessionError>> + '_ {
-        self.previous_sessions.iter().map(|bytes| {
-            Ok(SessionStructure::decode(&bytes[..])
-                .map_err(|_| InvalidSessionError("failed to decode previous session protobuf"))?
-                .into())
-        })
+        self.previous_sessions
+            .iter()
+            .map(|bytes| SessionState::deserialize(bytes))
     }
 
     pub(crate) fn promote_old_session(
@@ -664,7 +769,7 @@ impl SessionRecord {
                 self.previous_sessions.pop();
             }
             self.previous_sessions
-                .insert(0, current_session.session.encode_to_vec());
+                .insert(0, current_session.serialize());
             true
         } else {
             false
@@ -686,6 +791,28 @@ impl SessionRecord {
         Ok(record.encode_to_vec())
     }
 
+    /// Encodes the current session state as CBOR with named fields, so that debugging tools
+    /// can show it without knowing the protobuf schema.
+    ///
+    /// This is not a storage format: use [SessionRecord::serialize] for that. Archived states
+    /// are not included.
+    #[cfg(feature = "cbor")]
+    pub fn serialize_cbor(&self) -> Result<Vec<u8>, SignalProtocolError> {
+        Ok(self
+            .session_state()
+            .ok_or_else(|| {
+                SignalProtocolError::InvalidState("serialize_cbor", "No current session".into())
+            })?
+            .serialize_cbor())
+    }
+
+    /// Decodes the output of [SessionRecord::serialize_cbor] into a record with that state
+    /// as the current one.
+    #[cfg(feature = "cbor")]
+    pub fn deserialize_cbor(bytes: &[u8]) -> Result<Self, SignalProtocolError> {
+        Ok(Self::new(SessionState::deserialize_cbor(bytes)?))
+    }
+
     pub fn remote_registration_id(&self) -> Result<u32, SignalProtocolError> {
         Ok(self
             .session_state()
@@ -728,7 +855,7 @@ impl SessionRecord {
                     "No current session".into(),
                 )
             })?
-            .local_identity_key_bytes()?)
+            .local_identity_public()?)
     }
 
     pub fn remote_identity_key_bytes(&self) -> Result<Option<Vec<u8>>, SignalProtocolError> {
@@ -740,7 +867,7 @@ impl SessionRecord {
                     "No current session".into(),
                 )
             })?
-            .remote_identity_key_bytes()?)
+            .remote_identity_public()?)
     }
 
     pub fn has_usable_sender_chain(&self, now: SystemTime) -> Result<bool, SignalProtocolError> {
@@ -750,6 +877,24 @@ impl SessionRecord {
         }
     }
 
+    /// Returns how many message keys the current session keeps for messages that were skipped,
+    /// so that they can be decrypted when they arrive out of order.
+    pub fn skipped_key_count(&self) -> usize {
+        self.current_session
+            .as_ref()
+            .map_or(0, SessionState::skipped_key_count)
+    }
+
+    /// Removes the message keys the current session keeps for skipped messages, zeroizing them,
+    /// e.g. to wipe the key material or to save memory.
+    ///
+    /// The skipped messages can't be decrypted afterwards. Archived sessions are not affected.
+    pub fn clear_skipped_keys(&mut self) {
+        if let Some(session) = &mut self.current_session {
+            session.clear_skipped_keys();
+        }
+    }
+
     pub fn alice_base_key(&self) -> Result<&[u8], SignalProtocolError> {
         Ok(self
             .session_state()
@@ -775,6 +920,21 @@ impl SessionRecord {
             .map(|chain| chain.key()[..].into()))
     }
 
+    /// Makes the next message sent in the current session advertise a fresh ratchet key,
+    /// see [SessionState::force_ratchet_step].
+    ///
+    /// Fails if a message was already sent with the current ratchet key.
+    pub fn force_ratchet_step<R: Rng + CryptoRng>(
+        &mut self,
+        csprng: &mut R,
+    ) -> Result<(), SignalProtocolError> {
+        self.session_state_mut()
+            .ok_or_else(|| {
+                SignalProtocolError::InvalidState("force_ratchet_step", "No current session".into())
+            })?
+            .force_ratchet_step(csprng)
+    }
+
     pub fn get_sender_chain_key_bytes(&self) -> Result<Vec<u8>, SignalProtocolError> {
         Ok(self
             .session_state()
@@ -797,6 +957,23 @@ impl SessionRecord {
         }
     }
 
+    /// Returns the serialized public ratchet key the current session advertises to the remote
+    /// party.
+    ///
+    /// Only public key material is returned; see also [Self::local_identity_key_bytes] and
+    /// [Self::remote_identity_key_bytes].
+    pub fn current_ratchet_public(&self) -> Result<Vec<u8>, SignalProtocolError> {
+        Ok(self
+            .session_state()
+            .ok_or_else(|| {
+                SignalProtocolError::InvalidState(
+                    "current_ratchet_public",
+                    "No current session".into(),
+                )
+            })?
+            .current_ratchet_public()?)
+    }
+
     pub fn get_kyber_ciphertext(&self) -> Result<Option<&Vec<u8>>, SignalProtocolError> {
         Ok(self
             .session_state()
@@ -809,3 +986,322 @@ impl SessionRecord {
             .get_kyber_ciphertext())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn chain_strategy() -> impl Strategy<Value = session_structure::Chain> {
+        (
+            proptest::collection::vec(any::<u8>(), 33),
+            proptest::collection::vec(any::<u8>(), 0..=32),
+            any::<u32>(),
+            proptest::collection::vec(any::<u8>(), 32),
+            proptest::collection::vec(
+                (
+                    any::<u32>(),
+                    any::<[u8; 32]>(),
+                    any::<[u8; 32]>(),
+                    any::<[u8; 16]>(),
+                ),
+                0..5,
+            ),
+        )
+            .prop_map(
+                |(sender_ratchet_key, sender_ratchet_key_private, index, key, message_keys)| {
+                    session_structure::Chain {
+                        sender_ratchet_key,
+                        sender_ratchet_key_private,
+                        chain_key: Some(session_structure::chain::ChainKey { index, key }),
+                        message_keys: message_keys
+                            .into_iter()
+                            .map(|(index, cipher_key, mac_key, iv)| {
+                                session_structure::chain::MessageKey {
+                                    index,
+                                    cipher_key: cipher_key.to_vec(),
+                                    mac_key: mac_key.to_vec(),
+                                    iv: iv.to_vec(),
+                                }
+                            })
+                            .collect(),
+                    }
+                },
+            )
+    }
+
+    fn session_structure_strategy() -> impl Strategy<Value = SessionStructure> {
+        (
+            (
+                any::<u32>(),
+                proptest::collection::vec(any::<u8>(), 33),
+                proptest::collection::vec(any::<u8>(), 33),
+                any::<[u8; 32]>(),
+                any::<u32>(),
+                proptest::option::of(any::<[u8; 32]>()),
+            ),
+            proptest::option::of(chain_strategy()),
+            proptest::collection::vec(chain_strategy(), 0..3),
+            (
+                proptest::option::of((
+                    proptest::option::of(any::<u32>()),
+                    any::<i32>(),
+                    proptest::collection::vec(any::<u8>(), 33),
+                    any::<u64>(),
+                )),
+                proptest::option::of((any::<u32>(), proptest::collection::vec(any::<u8>(), 0..64))),
+                any::<u32>(),
+                any::<u32>(),
+                proptest::collection::vec(any::<u8>(), 33),
+            ),
+        )
+            .prop_map(
+                |(
+                    (
+                        session_version,
+                        local_identity_public,
+                        remote_identity_public,
+                        root_key,
+                        previous_counter,
+                        sender_chain_root_key,
+                    ),
+                    sender_chain,
+                    receiver_chains,
+                    (
+                        pending_pre_key,
+                        pending_kyber_pre_key,
+                        remote_registration_id,
+                        local_registration_id,
+                        alice_base_key,
+                    ),
+                )| SessionStructure {
+                    session_version,
+                    local_identity_public,
+                    remote_identity_public,
+                    root_key: root_key.to_vec(),
+                    previous_counter,
+                    sender_chain,
+                    receiver_chains,
+                    pending_pre_key: pending_pre_key.map(
+                        |(pre_key_id, signed_pre_key_id, base_key, timestamp)| {
+                            session_structure::PendingPreKey {
+                                pre_key_id,
+                                signed_pre_key_id,
+                                base_key,
+                                timestamp,
+                            }
+                        },
+                    ),
+                    pending_kyber_pre_key: pending_kyber_pre_key.map(|(pre_key_id, ciphertext)| {
+                        session_structure::PendingKyberPreKey {
+                            pre_key_id,
+                            ciphertext,
+                        }
+                    }),
+                    remote_registration_id,
+                    local_registration_id,
+                    alice_base_key,
+                    sender_chain_root_key: sender_chain_root_key
+                        .map_or_else(Vec::new, |key| key.to_vec()),
+                },
+            )
+    }
+
+    #[test]
+    fn session_state_round_trip() {
+        proptest!(|(session in session_structure_strategy())| {
+            let state = SessionState::from_session_structure(session.clone());
+            let bytes = state.serialize();
+            let decoded = SessionState::deserialize(&bytes).expect("valid session state");
+            prop_assert_eq!(session, SessionStructure::from(&decoded));
+            prop_assert_eq!(bytes, decoded.serialize());
+        });
+    }
+
+    #[test]
+    fn session_state_rejects_invalid_bytes() {
+        assert!(SessionState::deserialize(&[0xff; 3]).is_err());
+    }
+
+    fn chain_with_skipped_keys(indices: &[u32]) -> session_structure::Chain {
+        session_structure::Chain {
+            sender_ratchet_key: vec![5; 33],
+            sender_ratchet_key_private: vec![],
+            chain_key: Some(session_structure::chain::ChainKey {
+                index: 10,
+                key: vec![4; 32],
+            }),
+            message_keys: indices
+                .iter()
+                .map(|&index| session_structure::chain::MessageKey {
+                    index,
+                    cipher_key: vec![1; 32],
+                    mac_key: vec![2; 32],
+                    iv: vec![3; 16],
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn skipped_keys_are_counted_and_cleared() {
+        let mut state = SessionState::from_session_structure(SessionStructure {
+            receiver_chains: vec![
+                chain_with_skipped_keys(&[1, 2]),
+                chain_with_skipped_keys(&[7]),
+            ],
+            ..Default::default()
+        });
+        assert_eq!(3, state.skipped_key_count());
+
+        state.clear_skipped_keys();
+        assert_eq!(0, state.skipped_key_count());
+        // the chains themselves are kept
+        let session = SessionStructure::from(&state);
+        assert_eq!(2, session.receiver_chains.len());
+        assert!(session
+            .receiver_chains
+            .iter()
+            .all(|chain| chain.chain_key.is_some()));
+
+        let mut record =
+            SessionRecord::new(SessionState::from_session_structure(SessionStructure {
+                receiver_chains: vec![chain_with_skipped_keys(&[1, 2])],
+                ..Default::default()
+            }));
+        assert_eq!(2, record.skipped_key_count());
+        record.clear_skipped_keys();
+        assert_eq!(0, record.skipped_key_count());
+        assert_eq!(0, SessionRecord::new_fresh().skipped_key_count());
+    }
+
+    #[test]
+    #[cfg(feature = "cbor")]
+    fn cbor_round_trip_matches_protobuf() {
+        let state = SessionState::from_session_structure(SessionStructure {
+            session_version: 4,
+            local_identity_public: vec![5; 33],
+            remote_identity_public: vec![6; 33],
+            root_key: vec![7; 32],
+            previous_counter: 3,
+            sender_chain: Some(chain_with_skipped_keys(&[])),
+            receiver_chains: vec![
+                chain_with_skipped_keys(&[1, 2]),
+                chain_with_skipped_keys(&[7]),
+            ],
+            pending_pre_key: Some(session_structure::PendingPreKey {
+                pre_key_id: Some(2),
+                signed_pre_key_id: 3,
+                base_key: vec![8; 33],
+                timestamp: 1_700_000_000,
+            }),
+            pending_kyber_pre_key: Some(session_structure::PendingKyberPreKey {
+                pre_key_id: 4,
+                ciphertext: vec![9; 1568],
+            }),
+            remote_registration_id: 10,
+            local_registration_id: 11,
+            alice_base_key: vec![12; 33],
+            sender_chain_root_key: vec![13; 32],
+        });
+
+        let from_protobuf = SessionState::deserialize(&state.serialize()).expect("valid");
+        let from_cbor = SessionState::deserialize_cbor(&state.serialize_cbor()).expect("valid");
+        assert_eq!(
+            SessionStructure::from(&from_protobuf),
+            SessionStructure::from(&from_cbor)
+        );
+        assert_eq!(
+            SessionStructure::from(&state),
+            SessionStructure::from(&from_cbor)
+        );
+
+        let record = SessionRecord::deserialize_cbor(&state.serialize_cbor()).expect("valid");
+        assert_eq!(
+            record.serialize().expect("can serialize"),
+            SessionRecord::new(state)
+                .serialize()
+                .expect("can serialize")
+        );
+        assert!(SessionState::deserialize_cbor(&[0xff; 3]).is_err());
+        assert!(SessionRecord::new_fresh().serialize_cbor().is_err());
+    }
+
+    #[test]
+    fn exported_keys_are_public_only() {
+        let mut rng = rand::rngs::OsRng;
+        let local_identity = crate::IdentityKeyPair::generate(&mut rng);
+        let remote_identity = crate::IdentityKeyPair::generate(&mut rng);
+        let ratchet = KeyPair::generate(&mut rng);
+
+        let record = SessionRecord::new(SessionState::from_session_structure(SessionStructure {
+            local_identity_public: local_identity.identity_key().serialize().to_vec(),
+            remote_identity_public: remote_identity.identity_key().serialize().to_vec(),
+            sender_chain: Some(session_structure::Chain {
+                sender_ratchet_key: ratchet.public_key.serialize().to_vec(),
+                sender_ratchet_key_private: ratchet.private_key.serialize().to_vec(),
+                chain_key: Some(session_structure::chain::ChainKey {
+                    index: 0,
+                    key: vec![4; 32],
+                }),
+                message_keys: vec![],
+            }),
+            ..Default::default()
+        }));
+
+        let exported = [
+            record
+                .local_identity_key_bytes()
+                .expect("has local identity"),
+            record
+                .remote_identity_key_bytes()
+                .expect("valid remote identity")
+                .expect("has remote identity"),
+            record.current_ratchet_public().expect("has sender chain"),
+        ];
+        assert_eq!(
+            exported,
+            [
+                local_identity.identity_key().serialize().to_vec(),
+                remote_identity.identity_key().serialize().to_vec(),
+                ratchet.public_key.serialize().to_vec(),
+            ]
+        );
+
+        let private_keys = [
+            local_identity.private_key().serialize(),
+            remote_identity.private_key().serialize(),
+            ratchet.private_key.serialize(),
+        ];
+        for bytes in &exported {
+            for private_key in &private_keys {
+                assert!(!bytes
+                    .windows(private_key.len())
+                    .any(|window| window == &private_key[..]));
+            }
+        }
+
+        assert!(SessionRecord::new_fresh().current_ratchet_public().is_err());
+    }
+
+    #[test]
+    #[allow(unsafe_code)]
+    fn cleared_skipped_keys_are_zeroized() {
+        let mut chain = chain_with_skipped_keys(&[1]);
+        let message_key = &mut chain.message_keys[0];
+        zeroize_message_key(message_key);
+
+        // Zeroizing a Vec keeps its allocation, overwriting all of it with zeros.
+        for (bytes, len) in [
+            (&message_key.cipher_key, 32),
+            (&message_key.mac_key, 32),
+            (&message_key.iv, 16),
+        ] {
+            assert!(bytes.is_empty());
+            assert!(bytes.capacity() >= len);
+            let scrubbed = unsafe { std::slice::from_raw_parts(bytes.as_ptr(), len) };
+            assert!(scrubbed.iter().all(|&b| b == 0));
+        }
+    }
+}
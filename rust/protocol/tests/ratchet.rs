@@ -169,6 +169,65 @@ fn test_ratcheting_session_as_alice() -> Result<(), SignalProtocolError> {
     Ok(())
 }
 
+#[test]
+fn test_alice_and_bob_agree_on_chain_keys() -> Result<(), SignalProtocolError> {
+    let mut csprng = rand::rngs::OsRng;
+
+    let alice_identity_key_pair = IdentityKeyPair::generate(&mut csprng);
+    let alice_base_key_pair = KeyPair::generate(&mut csprng);
+
+    let bob_ephemeral_key_pair = KeyPair::generate(&mut csprng);
+    let bob_identity_key_pair = IdentityKeyPair::generate(&mut csprng);
+    let bob_signed_pre_key_pair = KeyPair::generate(&mut csprng);
+    let bob_one_time_pre_key_pair = KeyPair::generate(&mut csprng);
+
+    let alice_parameters = AliceSignalProtocolParameters::new(
+        alice_identity_key_pair,
+        alice_base_key_pair,
+        *bob_identity_key_pair.identity_key(),
+        bob_signed_pre_key_pair.public_key,
+        bob_ephemeral_key_pair.public_key,
+    )
+    .with_their_one_time_pre_key(bob_one_time_pre_key_pair.public_key);
+
+    let alice_record = initialize_alice_session_record(&alice_parameters, &mut csprng)?;
+
+    assert_eq!(
+        PRE_KYBER_MESSAGE_VERSION,
+        alice_record.session_version().expect("must have a version")
+    );
+
+    let bob_parameters = BobSignalProtocolParameters::new(
+        bob_identity_key_pair,
+        bob_signed_pre_key_pair,
+        Some(bob_one_time_pre_key_pair),
+        bob_ephemeral_key_pair,
+        None,
+        *alice_identity_key_pair.identity_key(),
+        alice_base_key_pair.public_key,
+        None,
+    );
+    let bob_record = initialize_bob_session_record(&bob_parameters)?;
+
+    assert_eq!(
+        PRE_KYBER_MESSAGE_VERSION,
+        bob_record.session_version().expect("must have a version")
+    );
+
+    assert_eq!(
+        bob_record
+            .get_sender_chain_key_bytes()
+            .expect("bob should have chain key"),
+        alice_record
+            .get_receiver_chain_key_bytes(&bob_ephemeral_key_pair.public_key)
+            .expect("should have chain key")
+            .expect("")
+            .to_vec()
+    );
+
+    Ok(())
+}
+
 #[test]
 fn test_alice_and_bob_agree_on_chain_keys_with_kyber() -> Result<(), SignalProtocolError> {
     let mut csprng = rand::rngs::OsRng;
@@ -235,3 +294,77 @@ fn test_alice_and_bob_agree_on_chain_keys_with_kyber() -> Result<(), SignalProto
 
     Ok(())
 }
+
+#[test]
+fn test_kyber_changes_derived_keys() -> Result<(), SignalProtocolError> {
+    let mut csprng = rand::rngs::OsRng;
+
+    let alice_identity_key_pair = IdentityKeyPair::generate(&mut csprng);
+    let alice_base_key_pair = KeyPair::generate(&mut csprng);
+
+    let bob_ephemeral_key_pair = KeyPair::generate(&mut csprng);
+    let bob_identity_key_pair = IdentityKeyPair::generate(&mut csprng);
+    let bob_signed_pre_key_pair = KeyPair::generate(&mut csprng);
+
+    let bob_kyber_pre_key_pair = kem::KeyPair::generate(kem::KeyType::Kyber1024);
+
+    let alice_parameters = AliceSignalProtocolParameters::new(
+        alice_identity_key_pair,
+        alice_base_key_pair,
+        *bob_identity_key_pair.identity_key(),
+        bob_signed_pre_key_pair.public_key,
+        bob_ephemeral_key_pair.public_key,
+    )
+    .with_their_kyber_pre_key(&bob_kyber_pre_key_pair.public_key);
+    let alice_record = initialize_alice_session_record(&alice_parameters, &mut csprng)?;
+    let kyber_ciphertext = alice_record
+        .get_kyber_ciphertext()
+        .expect("must have session")
+        .expect("must have kyber ciphertext")
+        .clone()
+        .into_boxed_slice();
+
+    let classical_bob_record = initialize_bob_session_record(&BobSignalProtocolParameters::new(
+        bob_identity_key_pair,
+        bob_signed_pre_key_pair,
+        None,
+        bob_ephemeral_key_pair,
+        None,
+        *alice_identity_key_pair.identity_key(),
+        alice_base_key_pair.public_key,
+        None,
+    ))?;
+    let hybrid_bob_record = initialize_bob_session_record(&BobSignalProtocolParameters::new(
+        bob_identity_key_pair,
+        bob_signed_pre_key_pair,
+        None,
+        bob_ephemeral_key_pair,
+        Some(bob_kyber_pre_key_pair),
+        *alice_identity_key_pair.identity_key(),
+        alice_base_key_pair.public_key,
+        Some(&kyber_ciphertext),
+    ))?;
+
+    assert_eq!(
+        PRE_KYBER_MESSAGE_VERSION,
+        classical_bob_record
+            .session_version()
+            .expect("must have a version")
+    );
+    assert_eq!(
+        KYBER_AWARE_MESSAGE_VERSION,
+        hybrid_bob_record
+            .session_version()
+            .expect("must have a version")
+    );
+    assert_ne!(
+        classical_bob_record
+            .get_sender_chain_key_bytes()
+            .expect("bob should have chain key"),
+        hybrid_bob_record
+            .get_sender_chain_key_bytes()
+            .expect("bob should have chain key"),
+    );
+
+    Ok(())
+}
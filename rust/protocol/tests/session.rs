@@ -809,6 +809,141 @@ fn test_message_key_limits() -> TestResult {
     Ok(())
 }
 
+#[test]
+fn test_replayed_message_is_rejected() -> TestResult {
+    run(initialize_sessions_v3()?)?;
+    run(initialize_sessions_v4()?)?;
+
+    fn run(sessions: (SessionRecord, SessionRecord)) -> TestResult {
+        async {
+            let (alice_session_record, bob_session_record) = sessions;
+
+            let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1.into());
+            let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1.into());
+
+            let mut alice_store = TestStoreBuilder::new().store;
+            let mut bob_store = TestStoreBuilder::new().store;
+
+            alice_store
+                .store_session(&bob_address, &alice_session_record)
+                .await?;
+            bob_store
+                .store_session(&alice_address, &bob_session_record)
+                .await?;
+
+            let mut inflight = Vec::new();
+            for i in 0..3 {
+                inflight.push(
+                    encrypt(&mut alice_store, &bob_address, &format!("message {}", i)).await?,
+                );
+            }
+
+            // The latest message first, so that the earlier ones are decrypted
+            // with the skipped keys.
+            for i in [2, 0] {
+                assert_eq!(
+                    String::from_utf8(decrypt(&mut bob_store, &alice_address, &inflight[i]).await?)
+                        .expect("valid utf8"),
+                    format!("message {}", i)
+                );
+            }
+
+            for counter in [2, 0] {
+                let err = decrypt(&mut bob_store, &alice_address, &inflight[counter as usize])
+                    .await
+                    .unwrap_err();
+                assert!(
+                    matches!(err, SignalProtocolError::DuplicatedMessage(3, c) if c == counter),
+                    "{err:?}"
+                );
+            }
+
+            // The message that was never decrypted is still accepted.
+            assert_eq!(
+                String::from_utf8(decrypt(&mut bob_store, &alice_address, &inflight[1]).await?)
+                    .expect("valid utf8"),
+                "message 1"
+            );
+            Ok(())
+        }
+        .now_or_never()
+        .expect("sync")
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_force_ratchet_step() -> TestResult {
+    async {
+        let (alice_session_record, bob_session_record) = initialize_sessions_v4()?;
+
+        let alice_address = ProtocolAddress::new("+14159999999".to_owned(), 1.into());
+        let bob_address = ProtocolAddress::new("+14158888888".to_owned(), 1.into());
+
+        let mut alice_store = TestStoreBuilder::new().store;
+        let mut bob_store = TestStoreBuilder::new().store;
+
+        alice_store
+            .store_session(&bob_address, &alice_session_record)
+            .await?;
+        bob_store
+            .store_session(&alice_address, &bob_session_record)
+            .await?;
+
+        let alice_message = encrypt(&mut alice_store, &bob_address, "hi bob").await?;
+        decrypt(&mut bob_store, &alice_address, &alice_message).await?;
+        let bob_message = encrypt(&mut bob_store, &alice_address, "hi alice").await?;
+        decrypt(&mut alice_store, &bob_address, &bob_message).await?;
+
+        let mut alice_session = alice_store
+            .load_session(&bob_address)
+            .await?
+            .expect("session found");
+        let old_chain_key = alice_session.get_sender_chain_key_bytes()?;
+        let old_ratchet_key =
+            match encrypt(&mut alice_store.clone(), &bob_address, "unsent").await? {
+                CiphertextMessage::SignalMessage(m) => *m.sender_ratchet_key(),
+                other => panic!("unexpected message type {:?}", other.message_type()),
+            };
+
+        alice_session.force_ratchet_step(&mut OsRng)?;
+        assert_ne!(alice_session.get_sender_chain_key_bytes()?, old_chain_key);
+        assert!(!alice_session.current_ratchet_key_matches(&old_ratchet_key)?);
+        alice_store
+            .store_session(&bob_address, &alice_session)
+            .await?;
+
+        let alice_message = encrypt(&mut alice_store, &bob_address, "new key").await?;
+        let new_ratchet_key = match &alice_message {
+            CiphertextMessage::SignalMessage(m) => *m.sender_ratchet_key(),
+            other => panic!("unexpected message type {:?}", other.message_type()),
+        };
+        assert_ne!(new_ratchet_key, old_ratchet_key);
+        assert!(alice_session.current_ratchet_key_matches(&new_ratchet_key)?);
+
+        assert_eq!(
+            String::from_utf8(decrypt(&mut bob_store, &alice_address, &alice_message).await?)
+                .expect("valid utf8"),
+            "new key"
+        );
+
+        // Once a message was sent with the new key, it can't be replaced anymore.
+        let mut alice_session = alice_store
+            .load_session(&bob_address)
+            .await?
+            .expect("session found");
+        assert!(matches!(
+            alice_session.force_ratchet_step(&mut OsRng),
+            Err(SignalProtocolError::InvalidState("force_ratchet_step", _))
+        ));
+
+        Ok(())
+    }
+    .now_or_never()
+    .expect("sync")
+}
+
 #[test]
 fn test_basic_simultaneous_initiate() -> TestResult {
     let mut alice_store_builder = TestStoreBuilder::new()
@@ -189,6 +189,8 @@ typedef struct SignalAes256GcmEncryption SignalAes256GcmEncryption;
 
 typedef struct SignalAes256GcmSiv SignalAes256GcmSiv;
 
+typedef struct SignalCancellationHandle SignalCancellationHandle;
+
 typedef struct SignalCiphertextMessage SignalCiphertextMessage;
 
 typedef struct SignalDecryptionErrorMessage SignalDecryptionErrorMessage;
@@ -874,6 +876,8 @@ SignalFfiError *signal_session_record_get_local_registration_id(uint32_t *out, c
 
 SignalFfiError *signal_session_record_get_remote_registration_id(uint32_t *out, const SignalSessionRecord *obj);
 
+SignalFfiError *signal_session_record_get_current_ratchet_public_key(SignalOwnedBuffer *out, const SignalSessionRecord *obj);
+
 SignalFfiError *signal_process_prekey_bundle(const SignalPreKeyBundle *bundle, const SignalProtocolAddress *protocol_address, const SignalSessionStore *session_store, const SignalIdentityKeyStore *identity_key_store, uint64_t now);
 
 SignalFfiError *signal_encrypt_message(SignalCiphertextMessage **out, SignalBorrowedBuffer ptext, const SignalProtocolAddress *protocol_address, const SignalSessionStore *session_store, const SignalIdentityKeyStore *identity_key_store, uint64_t now);
@@ -1176,6 +1180,12 @@ SignalFfiError *signal_pin_verify_local_hash(bool *out, const char *encoded_hash
 
 SignalFfiError *signal_svr2_client_new(SignalSgxClientState **out, SignalBorrowedBuffer mrenclave, SignalBorrowedBuffer attestation_msg, uint64_t current_timestamp);
 
+SignalFfiError *signal_cancellation_handle_destroy(SignalCancellationHandle *p);
+
+SignalFfiError *signal_cancellation_handle_new(SignalCancellationHandle **out);
+
+SignalFfiError *signal_cancellation_handle_cancel(const SignalCancellationHandle *handle);
+
 SignalFfiError *signal_incremental_mac_calculate_chunk_size(uint32_t *out, uint32_t data_size);
 
 SignalFfiError *signal_incremental_mac_destroy(SignalIncrementalMac *p);
@@ -1280,4 +1290,8 @@ SignalFfiError *signal_testing_error_on_return_async(const void **out, const voi
 
 SignalFfiError *signal_testing_error_on_return_io(SignalCPromiseRawPointer promise, const void *promise_context, const SignalNonSuspendingBackgroundThreadRuntime *async_runtime, const void *_needs_cleanup);
 
+SignalFfiError *signal_testing_cleanup_order(const void *_a, const void *_b, const void *_c);
+
+SignalFfiError *signal_testing_take_cleanup_order(SignalOwnedBuffer *out);
+
 #endif /* SIGNAL_FFI_H_ */
@@ -98,6 +98,12 @@ final class BridgingTests: XCTestCase {
         }
     }
 
+    func testCleanupOrder() throws {
+        _ = try invokeFnReturningArray { signal_testing_take_cleanup_order($0) }
+        try checkError(signal_testing_cleanup_order(nil, nil, nil))
+        XCTAssertEqual([2, 1, 0], try invokeFnReturningArray { signal_testing_take_cleanup_order($0) })
+    }
+
     func testPanicInBody() async throws {
         do {
             try checkError(signal_testing_panic_in_body_sync(nil))